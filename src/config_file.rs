@@ -0,0 +1,186 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use log::LevelFilter;
+use serde::Deserialize;
+
+use crate::log_format::LogFormat;
+
+/// Settings read from a TOML config file, named after the command-line options, like
+/// `include-targets = ["sfc", "smc"]`. Options given on the command line take precedence.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ConfigFile {
+    // Settings applied while mounted by reloading the config file
+    pub log_level: Option<LevelFilter>,
+    pub extensions: Option<Vec<String>>,
+    pub include_targets: Option<Vec<String>>,
+    pub exclude_targets: Option<Vec<String>>,
+    pub checksum_freshness: Option<bool>,
+    pub verify_targets: Option<bool>,
+    pub require_all_sources: Option<bool>,
+    pub strict_ips_eof: Option<bool>,
+    /// Megabytes.
+    pub cache_size: Option<u64>,
+
+    // Settings needing a remount
+    pub foreground: Option<bool>,
+    pub once: Option<bool>,
+    pub archive: Option<bool>,
+    pub manifest: Option<bool>,
+    pub header_sidecars: Option<bool>,
+    pub source_crc_sidecars: Option<bool>,
+    pub diff_sidecars: Option<bool>,
+    pub header_variants: Option<bool>,
+    pub byte_order_variants: Option<bool>,
+    pub target_sha1: Option<bool>,
+    pub ab_toggle: Option<bool>,
+    pub disk_cache: Option<bool>,
+    pub compress_cache: Option<i32>,
+    pub warm_cache: Option<usize>,
+    pub temp_dir: Option<PathBuf>,
+    pub follow_symlinks: Option<bool>,
+    pub map_sources: Option<bool>,
+    pub source_dir: Option<PathBuf>,
+    pub catalog: Option<PathBuf>,
+    pub name_from_metadata: Option<bool>,
+    pub name_sidecars: Option<bool>,
+    pub name_template: Option<String>,
+    /// Milliseconds.
+    pub refresh_interval: Option<u64>,
+    /// Milliseconds.
+    pub debounce: Option<u64>,
+    pub log_format: Option<LogFormat>,
+}
+
+/// Names of the settings which differ between the two configs, as written in the config file.
+macro_rules! changed_settings {
+    ($old:expr, $new:expr, $($field:ident),*) => {{
+        let mut changed = Vec::new();
+        $(
+            if $old.$field != $new.$field {
+                changed.push(stringify!($field).replace('_', "-"));
+            }
+        )*
+        changed
+    }};
+}
+
+/// Settings of `$config`, with the ones it leaves unset taken from `$defaults`.
+macro_rules! merged_settings {
+    ($config:expr, $defaults:expr, $($field:ident),*) => {
+        ConfigFile {
+            $($field: $config.$field.or($defaults.$field),)*
+        }
+    };
+}
+
+impl ConfigFile {
+    pub fn load(config_path: &Path) -> io::Result<Self> {
+        let config = fs::read_to_string(config_path)?;
+        toml::from_str(&config).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    /// Settings of this config, taking the ones it leaves unset from `defaults`.
+    /// Command-line options are kept in a config of their own, merged over the config file.
+    pub fn or(self, defaults: ConfigFile) -> ConfigFile {
+        merged_settings!(
+            self,
+            defaults,
+            log_level,
+            extensions,
+            include_targets,
+            exclude_targets,
+            checksum_freshness,
+            verify_targets,
+            require_all_sources,
+            strict_ips_eof,
+            cache_size,
+            foreground,
+            once,
+            archive,
+            manifest,
+            header_sidecars,
+            source_crc_sidecars,
+            diff_sidecars,
+            header_variants,
+            byte_order_variants,
+            target_sha1,
+            ab_toggle,
+            disk_cache,
+            compress_cache,
+            warm_cache,
+            temp_dir,
+            follow_symlinks,
+            map_sources,
+            source_dir,
+            catalog,
+            name_from_metadata,
+            name_sidecars,
+            name_template,
+            refresh_interval,
+            debounce,
+            log_format
+        )
+    }
+
+    /// Settings changed since `previous` which are only applied by remounting.
+    pub fn changed_remount_settings(&self, previous: &ConfigFile) -> Vec<String> {
+        changed_settings!(
+            previous,
+            self,
+            foreground,
+            once,
+            archive,
+            manifest,
+            header_sidecars,
+            source_crc_sidecars,
+            diff_sidecars,
+            header_variants,
+            byte_order_variants,
+            target_sha1,
+            ab_toggle,
+            disk_cache,
+            compress_cache,
+            warm_cache,
+            temp_dir,
+            follow_symlinks,
+            map_sources,
+            source_dir,
+            catalog,
+            name_from_metadata,
+            name_sidecars,
+            name_template,
+            refresh_interval,
+            debounce,
+            log_format
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merges_over_defaults() {
+        let overrides = ConfigFile {
+            extensions: Some(vec!["sfc".to_owned()]),
+            verify_targets: Some(false),
+            ..ConfigFile::default()
+        };
+        let config_file: ConfigFile = toml::from_str(concat!(
+            "extensions = [\"nes\"]\n",
+            "verify-targets = true\n",
+            "strict-ips-eof = true\n"
+        ))
+        .unwrap();
+
+        let config = overrides.or(config_file);
+        assert_eq!(config.extensions, Some(vec!["sfc".to_owned()]));
+        assert_eq!(config.verify_targets, Some(false));
+        assert_eq!(config.strict_ips_eof, Some(true));
+        assert_eq!(config.cache_size, None);
+    }
+}
@@ -0,0 +1,184 @@
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use log::{error, info, warn};
+use signal_hook::consts::SIGHUP;
+use signal_hook::iterator::Signals;
+
+use crate::config_file::ConfigFile;
+use crate::log_format;
+use crate::rom_manager::{self, RomManager};
+use crate::rom_memory_cache::{self, RomMemoryCache};
+
+pub struct ConfigReloader;
+
+impl ConfigReloader {
+    /// Reloads the config file on `SIGHUP`, see `reload`.
+    /// `config` is the config file as merged under the `overrides` of the command line at startup.
+    pub fn new(
+        config_path: &Path,
+        overrides: ConfigFile,
+        config: ConfigFile,
+        rom_manager: Arc<Mutex<RomManager>>,
+        memory_cache: Arc<RomMemoryCache>,
+    ) -> io::Result<Self> {
+        let mut signals = Signals::new([SIGHUP])?;
+        let config_path = config_path.to_owned();
+
+        thread::spawn(move || {
+            let mut config = config;
+            for _ in signals.forever() {
+                match ConfigReloader::reload(&config_path, &overrides, &config, &rom_manager, &memory_cache) {
+                    Ok(reloaded_config) => config = reloaded_config,
+                    Err(err) => error!("Failed to reload {:?}: {}", config_path, err),
+                }
+            }
+        });
+
+        Ok(Self)
+    }
+
+    /// Reads the config file again and applies the settings changed since `previous`, without remounting.
+    /// The ROMs are refreshed when the changes affect which ROMs are exposed, changes of the other
+    /// settings are only applied by remounting.
+    ///
+    /// The config file is merged under the `overrides` of the command line, like at startup, so options
+    /// given on the command line keep their values. Other settings removed from the config file fall back
+    /// to their defaults.
+    pub fn reload(
+        config_path: &Path,
+        overrides: &ConfigFile,
+        previous: &ConfigFile,
+        rom_manager: &Mutex<RomManager>,
+        memory_cache: &RomMemoryCache,
+    ) -> io::Result<ConfigFile> {
+        let config = overrides.clone().or(ConfigFile::load(config_path)?);
+        info!("Reloaded {:?}", config_path);
+
+        if config.log_level != previous.log_level {
+            log_format::set_level(config.log_level);
+        }
+
+        if config.cache_size != previous.cache_size {
+            let max_size = config.cache_size.map(|megabytes| megabytes * 1024 * 1024);
+            memory_cache.set_max_size(max_size.unwrap_or(rom_memory_cache::DEFAULT_MAX_SIZE));
+        }
+
+        let mut rom_manager = rom_manager.lock().unwrap();
+        let mut refresh = false;
+
+        if config.extensions != previous.extensions {
+            match &config.extensions {
+                Some(rom_extensions) => rom_manager.set_rom_extensions(rom_extensions),
+                None => rom_manager.set_rom_extensions(rom_manager::ROM_EXTENSIONS),
+            }
+            refresh = true;
+        }
+
+        if config.include_targets != previous.include_targets {
+            rom_manager.set_included_target_extensions(config.include_targets.as_deref());
+            refresh = true;
+        }
+
+        if config.exclude_targets != previous.exclude_targets {
+            rom_manager.set_excluded_target_extensions(config.exclude_targets.as_deref().unwrap_or_default());
+            refresh = true;
+        }
+
+        if config.checksum_freshness != previous.checksum_freshness {
+            rom_manager.set_checksum_freshness(config.checksum_freshness.unwrap_or(false));
+            refresh = true;
+        }
+
+        if config.verify_targets != previous.verify_targets {
+            rom_manager.set_verify_targets(config.verify_targets.unwrap_or(true));
+            refresh = true;
+        }
+
+        if config.require_all_sources != previous.require_all_sources {
+            rom_manager.set_require_all_sources(config.require_all_sources.unwrap_or(false));
+            refresh = true;
+        }
+
+        if config.strict_ips_eof != previous.strict_ips_eof {
+            rom_manager.set_strict_ips_eof(config.strict_ips_eof.unwrap_or(false));
+            refresh = true;
+        }
+
+        for setting in config.changed_remount_settings(previous) {
+            warn!("Changing {} in {:?} requires remounting", setting, config_path);
+        }
+
+        if refresh {
+            rom_manager.refresh()?;
+        }
+
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{make_bps, make_dir_fixture, make_hack, make_rom, write_file};
+    use std::path::PathBuf;
+
+    fn reload_fixture() -> (tempfile::TempDir, Mutex<RomManager>, RomMemoryCache) {
+        // A source ROM with an extension outside of the default list
+        let source = make_rom(1024, 1);
+        let directory = make_dir_fixture(&[
+            ("game.xyz", &source),
+            ("hack.bps", &make_bps(&source, &make_hack(&source))),
+            ("config.toml", b""),
+        ]);
+        let rom_manager = Mutex::new(RomManager::new(directory.path()).unwrap());
+        (directory, rom_manager, RomMemoryCache::new(0))
+    }
+
+    #[test]
+    fn picks_up_roms_of_reloaded_extensions() {
+        let (directory, rom_manager, memory_cache) = reload_fixture();
+        let config_path = directory.path().join("config.toml");
+        assert!(rom_manager.lock().unwrap().target_roms.is_empty());
+
+        write_file(directory.path(), "config.toml", b"extensions = [\"xyz\"]\n");
+        let overrides = ConfigFile::default();
+        let config = ConfigReloader::reload(
+            &config_path,
+            &overrides,
+            &ConfigFile::default(),
+            &rom_manager,
+            &memory_cache,
+        )
+        .unwrap();
+
+        assert_eq!(config.extensions, Some(vec!["xyz".to_owned()]));
+        let target_paths: Vec<PathBuf> = rom_manager.lock().unwrap().target_roms.keys().cloned().collect();
+        assert_eq!(target_paths, [PathBuf::from("hack.xyz")]);
+    }
+
+    #[test]
+    fn keeps_command_line_options_over_reloaded_settings() {
+        let (directory, rom_manager, memory_cache) = reload_fixture();
+        let config_path = directory.path().join("config.toml");
+
+        let overrides = ConfigFile {
+            extensions: Some(vec!["sfc".to_owned()]),
+            ..ConfigFile::default()
+        };
+        let previous = overrides.clone().or(ConfigFile::default());
+
+        write_file(
+            directory.path(),
+            "config.toml",
+            b"extensions = [\"xyz\"]\nstrict-ips-eof = true\n",
+        );
+        let config = ConfigReloader::reload(&config_path, &overrides, &previous, &rom_manager, &memory_cache).unwrap();
+
+        assert_eq!(config.extensions, Some(vec!["sfc".to_owned()]));
+        assert_eq!(config.strict_ips_eof, Some(true));
+        assert!(rom_manager.lock().unwrap().target_roms.is_empty());
+    }
+}
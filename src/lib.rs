@@ -0,0 +1,20 @@
+pub mod config_file;
+pub mod config_reloader;
+pub mod log_format;
+pub mod patch;
+pub mod patch_info;
+pub mod rom_archive;
+pub mod rom_cache;
+pub mod rom_cache_warmer;
+pub mod rom_catalog;
+pub mod rom_export;
+pub mod rom_filesystem;
+pub mod rom_header;
+pub mod rom_manager;
+pub mod rom_memory_cache;
+pub mod rom_system;
+pub mod rom_watcher;
+pub mod source_cache;
+#[cfg(test)]
+mod testutil;
+mod utils;
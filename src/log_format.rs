@@ -0,0 +1,127 @@
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::io::Write;
+use std::str::FromStr;
+
+use log::{warn, LevelFilter, Record};
+use serde::Deserialize;
+
+use crate::utils::json_string;
+
+/// Level of the logged messages without `RUST_LOG`, like the default of `env_logger`.
+const DEFAULT_LOG_LEVEL: LevelFilter = LevelFilter::Error;
+
+/// Format of the log messages written to the standard error.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    /// One JSON object per line, for frontends displaying the warnings of the refreshes.
+    Json,
+}
+
+#[derive(Debug)]
+pub struct UnknownLogFormat(String);
+
+impl fmt::Display for UnknownLogFormat {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "unknown log format {:?} (expected: text or json)", self.0)
+    }
+}
+
+impl Error for UnknownLogFormat {}
+
+impl FromStr for LogFormat {
+    type Err = UnknownLogFormat;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(UnknownLogFormat(s.to_owned())),
+        }
+    }
+}
+
+/// Renders a log record as a JSON object, like `{"level": "WARN", "target": "bps_fuse::rom_manager",
+/// "message": "No source ROMs were found in \"roms\""}`.
+pub fn to_json(record: &Record) -> String {
+    format!(
+        "{{\"level\": {}, \"target\": {}, \"message\": {}}}",
+        json_string(record.level().as_str()),
+        json_string(record.target()),
+        json_string(&record.args().to_string())
+    )
+}
+
+/// Sets up the logger, filtered by the `RUST_LOG` environment variable, or by `log_level` without it.
+pub fn init(log_format: LogFormat, log_level: Option<LevelFilter>) {
+    let filters = env::var("RUST_LOG").ok();
+
+    let mut builder = pretty_env_logger::formatted_builder();
+    if let Some(filters) = &filters {
+        builder.parse_filters(filters);
+    } else {
+        // Messages are filtered by the maximum level instead, which can be changed later on
+        builder.filter_level(LevelFilter::Trace);
+    }
+
+    if log_format == LogFormat::Json {
+        builder.format(|formatter, record| writeln!(formatter, "{}", to_json(record)));
+    }
+
+    builder.init();
+
+    if filters.is_none() {
+        log::set_max_level(log_level.unwrap_or(DEFAULT_LOG_LEVEL));
+    }
+}
+
+/// Changes the level of the logged messages, unless they are filtered by `RUST_LOG`.
+pub fn set_level(log_level: Option<LevelFilter>) {
+    if env::var_os("RUST_LOG").is_some() {
+        warn!("Messages are filtered by RUST_LOG, ignoring the log level");
+    } else {
+        log::set_max_level(log_level.unwrap_or(DEFAULT_LOG_LEVEL));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Level;
+
+    #[test]
+    fn renders_warnings_as_json() {
+        let path = "roms/\"quoted\" hack.bps";
+        let json = to_json(
+            &Record::builder()
+                .level(Level::Warn)
+                .target("bps_fuse::rom_manager")
+                .args(format_args!(
+                    "No source ROM was found for {:?} (CRC32=0x{:08X})",
+                    path, 0x1234ABCD
+                ))
+                .build(),
+        );
+
+        assert_eq!(
+            json,
+            concat!(
+                r#"{"level": "WARN", "target": "bps_fuse::rom_manager", "#,
+                r#""message": "No source ROM was found for \"roms/\\\"quoted\\\" hack.bps\" (CRC32=0x1234ABCD)"}"#
+            )
+        );
+    }
+
+    #[test]
+    fn parses_log_formats() {
+        assert_eq!("text".parse::<LogFormat>().unwrap(), LogFormat::Text);
+        assert_eq!("json".parse::<LogFormat>().unwrap(), LogFormat::Json);
+        assert_eq!(
+            "xml".parse::<LogFormat>().unwrap_err().to_string(),
+            "unknown log format \"xml\" (expected: text or json)"
+        );
+    }
+}
@@ -1,41 +1,617 @@
-#![feature(seek_convenience)]
-#![feature(slice_fill)]
-
 use std::env;
 use std::error::Error;
-use std::ffi::{OsStr, OsString};
-use std::path::PathBuf;
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
 use std::process;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-mod patch;
-mod rom_filesystem;
-mod rom_manager;
-mod rom_watcher;
-mod utils;
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, Subcommand};
+use log::error;
 
-use rom_filesystem::RomFilesystem;
-use rom_manager::RomManager;
-use rom_watcher::RomWatcher;
+use bps_fuse::config_file::ConfigFile;
+use bps_fuse::config_reloader::ConfigReloader;
+use bps_fuse::log_format::{self, LogFormat};
+use bps_fuse::patch_info::{PatchInfo, SortOrder};
+use bps_fuse::rom_cache::RomCache;
+use bps_fuse::rom_cache_warmer::RomCacheWarmer;
+use bps_fuse::rom_catalog::RomCatalog;
+use bps_fuse::rom_export;
+use bps_fuse::rom_filesystem::{RomFilesystem, RomFilesystemConfig};
+use bps_fuse::rom_manager::{RomManager, RomManagerBuilder};
+use bps_fuse::rom_watcher::{self, RomWatcher};
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let args: Vec<OsString> = env::args_os().collect();
+/// Exposes patched ROMs in a virtual filesystem, patching them on the fly.
+#[derive(Parser)]
+#[command(
+    version,
+    subcommand_negates_reqs = true,
+    override_usage = "bps-fuse [OPTIONS] <BASE_DIRECTORY> <MOUNT_POINT>
+       bps-fuse [OPTIONS] list <BASE_DIRECTORY>
+       bps-fuse [OPTIONS] export <BASE_DIRECTORY> <OUTPUT_DIRECTORY>
+       bps-fuse umount <MOUNT_POINT>"
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Directory of the source ROMs and the patches
+    #[arg(required = true)]
+    base_directory: Option<PathBuf>,
+    /// Directory the patched ROMs are mounted on
+    #[arg(required = true)]
+    mount_point: Option<PathBuf>,
+
+    /// Read options from a TOML file, reloaded on SIGHUP
+    #[arg(long, value_name = "CONFIG_FILE")]
+    config: Option<PathBuf>,
+    /// Stay in the foreground, instead of detaching from the terminal once mounted
+    #[arg(short, long)]
+    foreground: bool,
+    /// Serve a snapshot, don't watch for changes
+    #[arg(long)]
+    once: bool,
+    /// Expose a ZIP archive of all patched ROMs
+    #[arg(long)]
+    archive: bool,
+    /// Expose a manifest of the patched ROMs in JSON and CSV
+    #[arg(long)]
+    manifest: bool,
+    /// Expose NES/SNES ROM headers as JSON sidecars
+    #[arg(long)]
+    header_sidecars: bool,
+    /// Expose the source ROM CRC32 of BPS patches as sidecars
+    #[arg(long)]
+    source_crc_sidecars: bool,
+    /// Expose the ranges changed by patches as sidecars
+    #[arg(long)]
+    diff_sidecars: bool,
+    /// Expose NES/SNES ROMs both with and without headers
+    #[arg(long)]
+    header_variants: bool,
+    /// Expose N64 ROMs in every byte order
+    #[arg(long)]
+    byte_order_variants: bool,
+    /// Expose the SHA-1 of patched ROMs via xattrs
+    #[arg(long)]
+    target_sha1: bool,
+    /// Allow toggling patching per ROM via xattrs
+    #[arg(long)]
+    ab_toggle: bool,
+    /// Keep patched ROMs in an on-disk cache
+    #[arg(long)]
+    disk_cache: bool,
+    /// Memory for patched ROMs between opens, 256 by default
+    #[arg(long, value_name = "MEGABYTES")]
+    cache_size: Option<u64>,
+    /// Compress the disk cache with zstd, levels 1 to 22
+    #[arg(long, value_name = "LEVEL")]
+    compress_cache: Option<i32>,
+    /// Fill the disk cache in the background after mounting
+    #[arg(long, value_name = "JOBS")]
+    warm_cache: Option<usize>,
+    /// Directory of temporary files, like the disk cache
+    #[arg(long, value_name = "DIRECTORY")]
+    temp_dir: Option<PathBuf>,
+    /// Detect changed patches by checksum, not modification time
+    #[arg(long)]
+    checksum_freshness: bool,
+    /// Skip verifying the checksum of patched ROMs
+    #[arg(long)]
+    trust_targets: bool,
+    /// Refuse to mount when any patch has no source ROM
+    #[arg(long)]
+    require_all_sources: bool,
+    /// Always end IPS patches at the EOF marker
+    #[arg(long)]
+    strict_ips_eof: bool,
+    /// Ignore symbolic links in the base directory
+    #[arg(long)]
+    no_follow_symlinks: bool,
+    /// Memory map source ROMs instead of reading them, they must not be truncated while mounted
+    #[arg(long)]
+    map_sources: bool,
+    /// Look for source ROMs in another directory
+    #[arg(long, value_name = "DIRECTORY")]
+    source_dir: Option<PathBuf>,
+    /// Apply a patch to the source ROM given by the matching --source, repeatable
+    #[arg(long, value_name = "PATCH")]
+    patch: Vec<PathBuf>,
+    /// Source ROM of the matching --patch, repeatable
+    #[arg(long, value_name = "ROM")]
+    source: Vec<PathBuf>,
+    /// Look up missing source ROMs in a catalog
+    #[arg(long, value_name = "CATALOG_FILE")]
+    catalog: Option<PathBuf>,
+    /// Extensions of source ROMs, replacing the default list
+    #[arg(long, value_name = "EXT,...", value_delimiter = ',')]
+    extensions: Option<Vec<String>>,
+    /// Only expose ROMs with these extensions
+    #[arg(long, value_name = "EXT,...", value_delimiter = ',')]
+    include_targets: Option<Vec<String>>,
+    /// Hide ROMs with these extensions
+    #[arg(long, value_name = "EXT,...", value_delimiter = ',')]
+    exclude_targets: Option<Vec<String>>,
+    /// Name ROMs after the BPS patch metadata
+    #[arg(long)]
+    name_from_metadata: bool,
+    /// Name ROMs after `<patch>.name` files
+    #[arg(long)]
+    name_sidecars: bool,
+    /// Name ROMs after a template of {patch} and {source}
+    #[arg(long, value_name = "TEMPLATE")]
+    name_template: Option<String>,
+    /// Minimum time between refreshes
+    #[arg(long, value_name = "MILLISECONDS")]
+    refresh_interval: Option<u64>,
+    /// Quiet time after changes before refreshing, 500 by default
+    #[arg(long, value_name = "MILLISECONDS")]
+    debounce: Option<u64>,
+    /// Order of the ROMs printed by `list`: name, size or source
+    #[arg(long, value_name = "ORDER", default_value = "name")]
+    sort: SortOrder,
+    /// Format of the log messages: text, or json for one JSON object per line
+    #[arg(long, value_name = "FORMAT")]
+    log_format: Option<LogFormat>,
+    /// ROMs patched at once by `export`, one per CPU by default
+    #[arg(long, value_name = "COUNT", default_value_t = 0, hide_default_value = true)]
+    jobs: usize,
+}
+
+impl Cli {
+    /// The options given on the command line, as settings of a config file.
+    /// Flags left off are unset, rather than off, so they don't override the config file.
+    fn overrides(&self) -> ConfigFile {
+        ConfigFile {
+            log_level: None,
+            extensions: self.extensions.clone(),
+            include_targets: self.include_targets.clone(),
+            exclude_targets: self.exclude_targets.clone(),
+            checksum_freshness: self.checksum_freshness.then_some(true),
+            verify_targets: self.trust_targets.then_some(false),
+            require_all_sources: self.require_all_sources.then_some(true),
+            strict_ips_eof: self.strict_ips_eof.then_some(true),
+            cache_size: self.cache_size,
+            foreground: self.foreground.then_some(true),
+            once: self.once.then_some(true),
+            archive: self.archive.then_some(true),
+            manifest: self.manifest.then_some(true),
+            header_sidecars: self.header_sidecars.then_some(true),
+            source_crc_sidecars: self.source_crc_sidecars.then_some(true),
+            diff_sidecars: self.diff_sidecars.then_some(true),
+            header_variants: self.header_variants.then_some(true),
+            byte_order_variants: self.byte_order_variants.then_some(true),
+            target_sha1: self.target_sha1.then_some(true),
+            ab_toggle: self.ab_toggle.then_some(true),
+            disk_cache: self.disk_cache.then_some(true),
+            compress_cache: self.compress_cache,
+            warm_cache: self.warm_cache,
+            temp_dir: self.temp_dir.clone(),
+            follow_symlinks: self.no_follow_symlinks.then_some(false),
+            map_sources: self.map_sources.then_some(true),
+            source_dir: self.source_dir.clone(),
+            catalog: self.catalog.clone(),
+            name_from_metadata: self.name_from_metadata.then_some(true),
+            name_sidecars: self.name_sidecars.then_some(true),
+            name_template: self.name_template.clone(),
+            refresh_interval: self.refresh_interval,
+            debounce: self.debounce,
+            log_format: self.log_format,
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print the patched ROMs without mounting them
+    List { base_directory: PathBuf },
+    /// Write the patched ROMs to a directory
+    Export {
+        base_directory: PathBuf,
+        output_directory: PathBuf,
+    },
+    /// Unmount a previously mounted filesystem
+    Umount { mount_point: PathBuf },
+}
+
+/// Forks a background process, which continues with mounting the filesystem in a new session.
+/// The original process waits until the filesystem is mounted, exiting with the exit code of the
+/// background process if mounting fails, so errors are still reported on the terminal.
+///
+/// Must be called before any threads are started, as they don't survive forking.
+/// Returns the pipe to pass to `detach` once mounted.
+fn daemonize() -> io::Result<File> {
+    let mut pipe_fds = [0; 2];
+    if unsafe { libc::pipe(pipe_fds.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => unsafe {
+            libc::close(pipe_fds[0]);
+            libc::setsid();
+            Ok(File::from_raw_fd(pipe_fds[1]))
+        },
+        pid => {
+            let mut pipe = unsafe {
+                libc::close(pipe_fds[1]);
+                File::from_raw_fd(pipe_fds[0])
+            };
+
+            // The pipe is closed without a message when the background process exits
+            let mut mounted = [0; 1];
+            if pipe.read(&mut mounted).unwrap_or(0) == 1 {
+                process::exit(0);
+            }
+
+            let mut status = 0;
+            unsafe { libc::waitpid(pid, &mut status, 0) };
+            process::exit(if libc::WIFEXITED(status) {
+                libc::WEXITSTATUS(status)
+            } else {
+                -1
+            });
+        }
+    }
+}
+
+/// Detaches the background process from the terminal, letting the original process exit.
+fn detach(mut pipe: &File) {
+    if let Ok(dev_null) = OpenOptions::new().read(true).write(true).open("/dev/null") {
+        for fd in 0..3 {
+            unsafe { libc::dup2(dev_null.as_raw_fd(), fd) };
+        }
+    }
+
+    if let Err(err) = pipe.write_all(&[1]) {
+        error!("Failed to detach from the terminal: {}", err);
+    }
+}
 
-    if args.len() != 3 {
-        println!("Usage: {} <base_directory> <mount_point>", &env::args().next().unwrap());
+/// Checks whether a mount failure was caused by FUSE missing from the system.
+fn is_fuse_unavailable(err: &io::Error) -> bool {
+    let missing_device = cfg!(target_os = "linux") && !Path::new("/dev/fuse").exists();
+    missing_device || matches!(err.raw_os_error(), Some(libc::ENODEV))
+}
+
+/// Explains mounting failing for the lack of FUSE, pointing out the subcommands working without it.
+fn fuse_unavailable_message(mount_point: &Path, err: &io::Error) -> String {
+    format!(
+        concat!(
+            "Failed to mount {:?}: FUSE is not available ({})\n",
+            "Install FUSE (fuse on Linux, macFUSE on macOS) and make sure the kernel module is loaded.\n",
+            "The patched ROMs can be listed and written to a directory without FUSE, ",
+            "see `bps-fuse list --help` and `bps-fuse export --help`.\n"
+        ),
+        mount_point, err
+    )
+}
+
+/// Watches the base directory for changes, unless serving a snapshot in `--once` mode,
+/// where the ROM list stays the one taken at mount time.
+fn watch_roms(
+    rom_manager: &Arc<Mutex<RomManager>>,
+    once: bool,
+    refresh_interval: Duration,
+    debounce_window: Duration,
+) -> notify::Result<Option<RomWatcher>> {
+    if once {
+        return Ok(None);
+    }
+
+    RomWatcher::new(rom_manager.clone(), refresh_interval, debounce_window).map(Some)
+}
+
+/// A mount point lives on a different device than its parent directory.
+fn is_mount_point(path: &Path) -> bool {
+    match (fs::metadata(path), fs::metadata(path.join(".."))) {
+        (Ok(metadata), Ok(parent_metadata)) => metadata.dev() != parent_metadata.dev(),
+        (Err(err), _) if err.kind() == io::ErrorKind::NotFound => false,
+        // Mount points of crashed filesystems can't be accessed anymore, but can be unmounted
+        _ => true,
+    }
+}
+
+/// The unmount tool of the platform, unprivileged users can only unmount FUSE filesystems with `fusermount` on Linux.
+fn unmount_command(mount_point: &Path) -> process::Command {
+    if cfg!(target_os = "linux") {
+        let mut command = process::Command::new("fusermount");
+        command.arg("-u").arg(mount_point);
+        command
+    } else {
+        let mut command = process::Command::new("umount");
+        command.arg(mount_point);
+        command
+    }
+}
+
+/// Unmounts a previously mounted filesystem with the unmount tool of the platform.
+fn unmount(mount_point: &Path) {
+    if !is_mount_point(mount_point) {
+        eprintln!("Failed to unmount {:?}: not mounted", mount_point);
         process::exit(-1);
     }
 
-    pretty_env_logger::init();
+    match unmount_command(mount_point).status() {
+        Ok(status) if status.success() => {}
+        Ok(status) => {
+            eprintln!("Failed to unmount {:?}: {}", mount_point, status);
+            process::exit(-1);
+        }
+        Err(err) => {
+            eprintln!("Failed to unmount {:?}: {}", mount_point, err);
+            process::exit(-1);
+        }
+    }
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let cli = Cli::parse();
+
+    if cli.patch.len() != cli.source.len() {
+        Cli::command()
+            .error(
+                ErrorKind::WrongNumberOfValues,
+                "--patch and --source must be given in pairs",
+            )
+            .exit();
+    }
+
+    // Options of the config file are overridden by the command line, also when it is reloaded
+    let overrides = cli.overrides();
+    let config = match &cli.config {
+        Some(config_path) => ConfigFile::load(config_path).unwrap_or_else(|err| {
+            eprintln!("Failed to load {:?}: {}", config_path, err);
+            process::exit(-1);
+        }),
+        None => ConfigFile::default(),
+    };
+    let config = overrides.clone().or(config);
 
-    let base_directory = PathBuf::from(&args[1]);
-    let rom_manager = Arc::new(Mutex::new(RomManager::new(&base_directory)?));
+    let foreground = config.foreground.unwrap_or(false);
+    let once = config.once.unwrap_or(false);
+    let archive = config.archive.unwrap_or(false);
+    let manifest = config.manifest.unwrap_or(false);
+    let header_sidecars = config.header_sidecars.unwrap_or(false);
+    let source_crc_sidecars = config.source_crc_sidecars.unwrap_or(false);
+    let diff_sidecars = config.diff_sidecars.unwrap_or(false);
+    let header_variants = config.header_variants.unwrap_or(false);
+    let byte_order_variants = config.byte_order_variants.unwrap_or(false);
+    let ab_toggle = config.ab_toggle.unwrap_or(false);
+    let target_sha1 = config.target_sha1.unwrap_or(false);
+    let cache_compression_level = config.compress_cache;
+    let warm_cache_jobs = config.warm_cache;
+    let disk_cache =
+        config.disk_cache.unwrap_or(false) || cache_compression_level.is_some() || warm_cache_jobs.is_some();
+    let memory_cache_size = config.cache_size.map(|megabytes| megabytes * 1024 * 1024);
+    let checksum_freshness = config.checksum_freshness.unwrap_or(false);
+    let verify_targets = config.verify_targets.unwrap_or(true);
+    let require_all_sources = config.require_all_sources.unwrap_or(false);
+    let strict_ips_eof = config.strict_ips_eof.unwrap_or(false);
+    let follow_symlinks = config.follow_symlinks.unwrap_or(true);
+    let map_sources = config.map_sources.unwrap_or(false);
+    let temp_directory = config.temp_dir.clone().unwrap_or_else(env::temp_dir);
+    let source_directory = config.source_dir.clone();
+    let catalog_path = config.catalog.clone();
+    let rom_extensions = config.extensions.clone();
+    let included_targets = config.include_targets.clone();
+    let excluded_targets = config.exclude_targets.clone().unwrap_or_default();
+    let name_from_metadata = config.name_from_metadata.unwrap_or(false);
+    let name_sidecars = config.name_sidecars.unwrap_or(false);
+    let name_template = config.name_template.clone();
+    let refresh_interval = config
+        .refresh_interval
+        .map(Duration::from_millis)
+        .unwrap_or(rom_watcher::DEFAULT_MIN_REFRESH_INTERVAL);
+    let debounce_window = config
+        .debounce
+        .map(Duration::from_millis)
+        .unwrap_or(rom_watcher::DEFAULT_DEBOUNCE_WINDOW);
+    let log_format = config.log_format.unwrap_or(LogFormat::Text);
 
-    let rom_filesystem = RomFilesystem::new(rom_manager.clone());
-    let _rom_watcher = RomWatcher::new(rom_manager.clone())?;
+    log_format::init(log_format, config.log_level);
 
+    let (base_directory, mount_point) = match &cli.command {
+        Some(Command::Umount { mount_point }) => {
+            unmount(mount_point);
+            return Ok(());
+        }
+        Some(Command::List { base_directory }) | Some(Command::Export { base_directory, .. }) => {
+            (base_directory.clone(), None)
+        }
+        // Both are required without a subcommand
+        None => (cli.base_directory.unwrap(), cli.mount_point),
+    };
+
+    let mut rom_manager_builder = RomManagerBuilder::new(&base_directory)
+        .name_from_metadata(name_from_metadata)
+        .name_sidecars(name_sidecars)
+        .checksum_freshness(checksum_freshness)
+        .verify_targets(verify_targets)
+        .require_all_sources(require_all_sources)
+        .strict_ips_eof(strict_ips_eof)
+        .follow_symlinks(follow_symlinks)
+        .map_sources(map_sources)
+        .exclude_target_extensions(&excluded_targets);
+
+    if let Some(rom_extensions) = rom_extensions {
+        rom_manager_builder = rom_manager_builder.rom_extensions(&rom_extensions);
+    }
+
+    if let Some(included_targets) = included_targets {
+        rom_manager_builder = rom_manager_builder.include_target_extensions(&included_targets);
+    }
+
+    for (patch_path, source_path) in cli.patch.iter().zip(&cli.source) {
+        rom_manager_builder = rom_manager_builder.pairing(patch_path, source_path);
+    }
+
+    if let Some(source_directory) = source_directory {
+        rom_manager_builder = rom_manager_builder.source_directory(&source_directory);
+    }
+
+    if let Some(catalog_path) = catalog_path {
+        rom_manager_builder = rom_manager_builder.catalog(RomCatalog::load(&catalog_path)?);
+    }
+
+    if let Some(name_template) = name_template {
+        rom_manager_builder = rom_manager_builder.name_template(&name_template);
+    }
+
+    if let Some(mount_point) = &mount_point {
+        rom_manager_builder = rom_manager_builder.mount_point(mount_point);
+    }
+
+    if let Some(Command::List { .. }) = &cli.command {
+        let mut patch_infos = PatchInfo::collect(&rom_manager_builder.build()?);
+        cli.sort.sort(&mut patch_infos);
+
+        for patch_info in patch_infos {
+            let source_path = patch_info.source_path.unwrap_or_else(|| PathBuf::from("-"));
+            println!(
+                "{}\t{}\t{}",
+                patch_info.target_path.display(),
+                patch_info.target_size,
+                source_path.display()
+            );
+        }
+        return Ok(());
+    }
+
+    if let Some(Command::Export { output_directory, .. }) = &cli.command {
+        let failures = rom_export::export(&rom_manager_builder.build()?, output_directory, cli.jobs)?;
+
+        for (target_path, err) in &failures {
+            eprintln!("Failed to export {:?}: {}", target_path, err);
+        }
+        if !failures.is_empty() {
+            process::exit(-1);
+        }
+        return Ok(());
+    }
+
+    // Nothing is written to the terminal once detached, `--foreground` keeps the log messages
+    let daemon_pipe = if foreground { None } else { Some(daemonize()?) };
+
+    let rom_manager = Arc::new(Mutex::new(rom_manager_builder.build()?));
+
+    let rom_cache = if disk_cache {
+        let mut rom_cache = RomCache::new(&temp_directory)?;
+        rom_cache.set_compression_level(cache_compression_level);
+        Some(Arc::new(rom_cache))
+    } else {
+        None
+    };
+
+    let _rom_cache_warmer = match (&rom_cache, warm_cache_jobs) {
+        (Some(rom_cache), Some(jobs)) => Some(RomCacheWarmer::new(rom_manager.clone(), rom_cache.clone(), jobs)),
+        _ => None,
+    };
+
+    let rom_filesystem_config = RomFilesystemConfig {
+        expose_archive: archive,
+        manifest,
+        header_sidecars,
+        source_crc_sidecars,
+        diff_sidecars,
+        header_variants,
+        byte_order_variants,
+        ab_toggle,
+        target_sha1,
+        rom_cache,
+        watching: !once,
+        memory_cache_size,
+        on_mount: daemon_pipe.map(|pipe| Box::new(move || detach(&pipe)) as Box<dyn Fn() + Send + Sync>),
+    };
+    let rom_filesystem = RomFilesystem::with_config(rom_manager.clone(), rom_filesystem_config);
+
+    let _config_reloader = match &cli.config {
+        Some(config_path) if !once => Some(ConfigReloader::new(
+            config_path,
+            overrides,
+            config,
+            rom_manager.clone(),
+            rom_filesystem.memory_cache(),
+        )?),
+        _ => None,
+    };
+
+    let _rom_watcher = watch_roms(&rom_manager, once, refresh_interval, debounce_window)?;
+
+    let mount_point = mount_point.unwrap();
     let fuse_args: Vec<&OsStr> = vec![&OsStr::new("-o"), &OsStr::new("auto_unmount")];
-    fuse_mt::mount(fuse_mt::FuseMT::new(rom_filesystem, 1), &args[2], &fuse_args)?;
+    if let Err(err) = fuse_mt::mount(fuse_mt::FuseMT::new(rom_filesystem, 1), &mount_point, &fuse_args) {
+        if is_fuse_unavailable(&err) {
+            eprint!("{}", fuse_unavailable_message(&mount_point, &err));
+            process::exit(-1);
+        }
+        return Err(Box::new(err));
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn explains_fuse_being_unavailable() {
+        let err = io::Error::from_raw_os_error(libc::ENODEV);
+        assert!(is_fuse_unavailable(&err));
+
+        let message = fuse_unavailable_message(Path::new("/mnt/roms"), &err);
+        assert!(message.starts_with("Failed to mount \"/mnt/roms\": FUSE is not available"));
+        assert!(message.contains("bps-fuse list"));
+        assert!(message.contains("bps-fuse export"));
+    }
+
+    #[test]
+    fn unmounts_with_the_tool_of_the_platform() {
+        let command = unmount_command(Path::new("/mnt/roms"));
+        let args: Vec<&OsStr> = command.get_args().collect();
+        if cfg!(target_os = "linux") {
+            assert_eq!(command.get_program(), "fusermount");
+            assert_eq!(args, ["-u", "/mnt/roms"]);
+        } else {
+            assert_eq!(command.get_program(), "umount");
+            assert_eq!(args, ["/mnt/roms"]);
+        }
+
+        // Only mount points are unmounted
+        let directory = tempfile::tempdir().unwrap();
+        assert!(!is_mount_point(directory.path()));
+        assert!(!is_mount_point(&directory.path().join("missing")));
+    }
+
+    #[test]
+    fn only_refreshes_without_once() {
+        let directory = tempfile::tempdir().unwrap();
+        let rom_manager = Arc::new(Mutex::new(RomManager::new(directory.path()).unwrap()));
+        let generation = || rom_manager.lock().unwrap().generation();
+        let started_generation = generation();
+        let interval = Duration::from_millis(10);
+
+        let rom_watcher = watch_roms(&rom_manager, true, interval, interval).unwrap();
+        assert!(rom_watcher.is_none());
+        fs::write(directory.path().join("game.sfc"), b"game").unwrap();
+        std::thread::sleep(Duration::from_millis(200));
+        assert_eq!(generation(), started_generation);
+
+        let _rom_watcher = watch_roms(&rom_manager, false, interval, interval).unwrap();
+        fs::write(directory.path().join("other.sfc"), b"other").unwrap();
+        for _ in 0..100 {
+            if generation() != started_generation {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        panic!("no refresh after a change");
+    }
+}
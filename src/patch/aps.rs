@@ -0,0 +1,322 @@
+use std::cmp;
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use byteorder::{LittleEndian, ReadBytesExt};
+
+use crate::patch::{detect_format, Patch};
+use crate::source_cache::SourceCache;
+
+const APS_FORMAT_MARKER: [u8; 5] = [b'A', b'P', b'S', b'1', b'0'];
+const APS_DESCRIPTION_SIZE: usize = 50;
+const APS_N64_PADDING_SIZE: usize = 5;
+
+const APS_TYPE_SIMPLE: u8 = 0;
+const APS_TYPE_N64: u8 = 1;
+
+/// Offset of the internal checksum in the header of Nintendo 64 ROMs.
+pub const N64_CHECKSUM_OFFSET: usize = 0x10;
+/// Offset of the cartridge ID in the header of Nintendo 64 ROMs.
+const N64_CART_ID_OFFSET: usize = 0x3C;
+
+#[derive(Debug)]
+pub enum ApsError {
+    FormatMarker {
+        expected: [u8; 5],
+        received: [u8; 5],
+    },
+    PatchType(u8),
+    EncodingMethod(u8),
+    SourceChecksum {
+        expected: [u8; 8],
+        received: Option<[u8; 8]>,
+    },
+    MissingSource,
+    WrongFormat {
+        extension: &'static str,
+    },
+    RecordOutOfBounds {
+        offset: usize,
+        size: usize,
+    },
+}
+
+impl fmt::Display for ApsError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ApsError::FormatMarker { expected, received } => write!(
+                formatter,
+                "invalid format marker (expected: {:?}, received: {:?})",
+                expected, received
+            ),
+            ApsError::PatchType(patch_type) => write!(formatter, "unknown patch type {}", patch_type),
+            ApsError::EncodingMethod(encoding_method) => {
+                write!(formatter, "unknown encoding method {}", encoding_method)
+            }
+            ApsError::SourceChecksum { expected, received } => write!(
+                formatter,
+                "invalid N64 source checksum (expected: {:02X?}, received: {:02X?})",
+                expected, received
+            ),
+            ApsError::MissingSource => write!(formatter, "no source ROM was given"),
+            ApsError::WrongFormat { extension } => write!(
+                formatter,
+                "not an APS patch, the file is in the {} format (rename it to .{})",
+                extension.to_ascii_uppercase(),
+                extension
+            ),
+            ApsError::RecordOutOfBounds { offset, size } => write!(
+                formatter,
+                "record of 0x{:X} bytes at 0x{:X} ends past the address space",
+                size, offset
+            ),
+        }
+    }
+}
+
+impl Error for ApsError {}
+
+/// The N64 specific part of the APS header, identifying the source ROM by its internal header.
+#[derive(Clone, Debug)]
+pub struct ApsN64Header {
+    /// The source ROM is in the byte-swapped V64 format, instead of the big-endian Z64 format.
+    pub byte_swapped: bool,
+    pub cart_id: [u8; 3],
+    pub checksum: [u8; 8],
+}
+
+/// A record of an APS patch, overwriting the target ROM from an offset.
+#[derive(Clone, Debug)]
+enum ApsRecord {
+    Data { offset: usize, data: Vec<u8> },
+    Rle { offset: usize, size: usize, value: u8 },
+}
+
+#[derive(Clone, Debug)]
+pub struct ApsPatch {
+    source_path: Option<PathBuf>,
+    description: String,
+    n64_header: Option<ApsN64Header>,
+
+    target_size: u64,
+    records: Vec<ApsRecord>,
+
+    patch_modified: SystemTime,
+    patch_created: Option<SystemTime>,
+    source_cache: Option<Arc<SourceCache>>,
+}
+
+impl ApsPatch {
+    pub fn new(patch_path: &Path) -> Result<Self, Box<dyn Error>> {
+        let patch_file = File::open(patch_path)?;
+        let patch_file_metadata = patch_file.metadata()?;
+        let mut patch_file = BufReader::new(patch_file);
+
+        let mut format_marker: [u8; 5] = [0; 5];
+        patch_file.read_exact(&mut format_marker)?;
+        if format_marker != APS_FORMAT_MARKER {
+            if let Some(extension) = detect_format(&format_marker) {
+                return Err(Box::new(ApsError::WrongFormat { extension }));
+            }
+
+            return Err(Box::new(ApsError::FormatMarker {
+                expected: APS_FORMAT_MARKER,
+                received: format_marker,
+            }));
+        }
+
+        let patch_type = patch_file.read_u8()?;
+        if patch_type != APS_TYPE_SIMPLE && patch_type != APS_TYPE_N64 {
+            return Err(Box::new(ApsError::PatchType(patch_type)));
+        }
+
+        let encoding_method = patch_file.read_u8()?;
+        if encoding_method != 0 {
+            return Err(Box::new(ApsError::EncodingMethod(encoding_method)));
+        }
+
+        let mut description = [0; APS_DESCRIPTION_SIZE];
+        patch_file.read_exact(&mut description)?;
+        let description = String::from_utf8_lossy(&description)
+            .trim_end_matches(&['\0', ' '][..])
+            .to_owned();
+
+        let n64_header = if patch_type == APS_TYPE_N64 {
+            let byte_swapped = patch_file.read_u8()? == 0;
+
+            let mut cart_id = [0; 3];
+            patch_file.read_exact(&mut cart_id)?;
+
+            let mut checksum = [0; 8];
+            patch_file.read_exact(&mut checksum)?;
+
+            let mut padding = [0; APS_N64_PADDING_SIZE];
+            patch_file.read_exact(&mut padding)?;
+
+            Some(ApsN64Header {
+                byte_swapped,
+                cart_id,
+                checksum,
+            })
+        } else {
+            None
+        };
+
+        let target_size = patch_file.read_u32::<LittleEndian>()? as u64;
+
+        let mut records = Vec::new();
+        loop {
+            let offset = match patch_file.read_u32::<LittleEndian>() {
+                Ok(offset) => offset as usize,
+                // Records continue up to the end of the file, without an end marker
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(err) => return Err(Box::new(err)),
+            };
+
+            let size = patch_file.read_u8()? as usize;
+            if size == 0 {
+                let value = patch_file.read_u8()?;
+                let size = patch_file.read_u8()? as usize;
+                records.push(ApsRecord::Rle { offset, size, value });
+            } else {
+                let mut data = vec![0; size];
+                patch_file.read_exact(&mut data)?;
+                records.push(ApsRecord::Data { offset, data });
+            }
+        }
+
+        Ok(Self {
+            source_path: None,
+            description,
+            n64_header,
+            target_size,
+            records,
+            patch_modified: patch_file_metadata.modified()?,
+            patch_created: patch_file_metadata.created().ok(),
+            source_cache: None,
+        })
+    }
+
+    pub fn set_source_path(&mut self, source_path: &Path) {
+        self.source_path = Some(source_path.to_path_buf());
+    }
+
+    /// Reads the source ROM through a cache shared with other patches.
+    pub fn set_source_cache(&mut self, source_cache: Arc<SourceCache>) {
+        self.source_cache = Some(source_cache);
+    }
+
+    /// Identifies the source ROM of N64 patches, simple patches don't record their source ROM.
+    pub fn n64_header(&self) -> Option<&ApsN64Header> {
+        self.n64_header.as_ref()
+    }
+}
+
+impl Patch for ApsPatch {
+    fn target_size(&self) -> u64 {
+        self.target_size
+    }
+
+    fn source_path(&self) -> Option<&Path> {
+        self.source_path.as_deref()
+    }
+
+    fn format(&self) -> Option<&'static str> {
+        Some("aps")
+    }
+
+    fn modified(&self) -> Option<SystemTime> {
+        Some(self.patch_modified)
+    }
+
+    fn created(&self) -> Option<SystemTime> {
+        self.patch_created
+    }
+
+    fn operation_count(&self) -> Option<usize> {
+        Some(self.records.len())
+    }
+
+    fn title(&self) -> Option<&str> {
+        Some(self.description.as_str()).filter(|description| !description.is_empty())
+    }
+
+    fn patched_rom(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let source_path = self.source_path.as_deref().ok_or(ApsError::MissingSource)?;
+        let mut target = SourceCache::load(self.source_cache.as_deref(), source_path)?.to_vec();
+
+        if let Some(n64_header) = &self.n64_header {
+            let checksum = target.get(N64_CHECKSUM_OFFSET..N64_CHECKSUM_OFFSET + 8);
+            let cart_id = target.get(N64_CART_ID_OFFSET..N64_CART_ID_OFFSET + 3);
+            if checksum != Some(&n64_header.checksum) || cart_id != Some(&n64_header.cart_id) {
+                return Err(Box::new(ApsError::SourceChecksum {
+                    expected: n64_header.checksum,
+                    received: checksum.and_then(|checksum| <[u8; 8]>::try_from(checksum).ok()),
+                }));
+            }
+        }
+
+        target.resize(self.target_size as usize, 0);
+
+        // Only the parts of records within the target ROM are written, the rest would be truncated anyway
+        for record in &self.records {
+            let (offset, size) = match record {
+                ApsRecord::Data { offset, data } => (*offset, data.len()),
+                ApsRecord::Rle { offset, size, .. } => (*offset, *size),
+            };
+            let end = offset
+                .checked_add(size)
+                .ok_or(ApsError::RecordOutOfBounds { offset, size })?;
+            let start = cmp::min(offset, target.len());
+            let end = cmp::min(end, target.len());
+
+            match record {
+                ApsRecord::Data { data, .. } => target[start..end].copy_from_slice(&data[..(end - start)]),
+                ApsRecord::Rle { value, .. } => target[start..end].fill(*value),
+            }
+        }
+
+        Ok(target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{make_dir_fixture, make_rom};
+    use byteorder::WriteBytesExt;
+    use std::io::Write;
+
+    #[test]
+    fn cuts_off_records_past_the_target() {
+        let source = make_rom(8, 1);
+        let mut patch = Vec::new();
+        patch.write_all(&APS_FORMAT_MARKER).unwrap();
+        patch.write_u8(APS_TYPE_SIMPLE).unwrap();
+        patch.write_u8(0).unwrap();
+        patch.write_all(&[b' '; APS_DESCRIPTION_SIZE]).unwrap();
+        patch.write_u32::<LittleEndian>(8).unwrap();
+        // A data record crossing the end of the target ROM, and an RLE record way past it
+        patch.write_u32::<LittleEndian>(6).unwrap();
+        patch.write_u8(4).unwrap();
+        patch.write_all(b"WXYZ").unwrap();
+        patch.write_u32::<LittleEndian>(u32::MAX).unwrap();
+        patch.write_u8(0).unwrap();
+        patch.write_u8(0xAA).unwrap();
+        patch.write_u8(0xFF).unwrap();
+
+        let directory = make_dir_fixture(&[("game.sfc", &source), ("hack.aps", &patch)]);
+        let mut patch = ApsPatch::new(&directory.path().join("hack.aps")).unwrap();
+        patch.set_source_path(&directory.path().join("game.sfc"));
+
+        let mut target = source[..6].to_vec();
+        target.extend_from_slice(b"WX");
+        assert_eq!(patch.patched_rom().unwrap(), target);
+    }
+}
@@ -1,21 +1,41 @@
+use std::cmp;
+use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::error::Error;
 use std::fmt;
 use std::fs::{self, File};
-use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
+use std::str;
+use std::sync::{Arc, Mutex};
 use std::time::SystemTime;
 
-use byteorder::{LittleEndian, ReadBytesExt};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use crc::crc32;
 use num_enum::TryFromPrimitive;
+use zip::ZipArchive;
 
-use crate::patch::Patch;
-use crate::utils::ReadExt;
+use crate::patch::{detect_format, Patch};
+use crate::source_cache::{SourceCache, SourceData};
+use crate::utils::{ReadExt, WriteExt};
 
 const BPS_FORMAT_MARKER: [u8; 4] = [b'B', b'P', b'S', b'1'];
 const BPS_FOOTER_SIZE: usize = 12;
 
+/// Shortest match worth a command when creating patches, shorter ones are stored as target reads.
+const BPS_MIN_MATCH_LENGTH: usize = 4;
+/// Number of earlier occurrences of a sequence tried when creating patches, the most recent ones first.
+const BPS_MAX_MATCH_CANDIDATES: usize = 16;
+
+#[derive(Clone, Copy, TryFromPrimitive)]
+#[repr(usize)]
+enum BpsCommand {
+    SourceRead,
+    TargetRead,
+    SourceCopy,
+    TargetCopy,
+}
+
 #[derive(Debug)]
 pub enum BpsError {
     OutdatedCache,
@@ -25,6 +45,10 @@ pub enum BpsError {
     SourceChecksum { expected: u32, received: u32 },
     TargetChecksum { expected: u32, received: u32 },
     PatchChecksum { expected: u32, received: u32 },
+    PatchOffset { offset: u64, limit: u64 },
+    MissingSource,
+    OutOfBounds { offset: i64, length: u64, bound: u64 },
+    WrongFormat { extension: &'static str },
 }
 
 impl fmt::Display for BpsError {
@@ -61,15 +85,33 @@ impl fmt::Display for BpsError {
                 "invalid patch checksum (expected: 0x{:08X}, received: 0x{:08X})",
                 expected, received
             ),
+            BpsError::PatchOffset { offset, limit } => write!(
+                formatter,
+                "patch data starts past the footer (offset: {}, footer offset: {})",
+                offset, limit
+            ),
+            BpsError::MissingSource => write!(formatter, "source-less patch reads from the source"),
+            BpsError::OutOfBounds { offset, length, bound } => write!(
+                formatter,
+                "command reads out of bounds (offset: {}, length: {}, bound: {})",
+                offset, length, bound
+            ),
+            BpsError::WrongFormat { extension } => write!(
+                formatter,
+                "not a BPS patch, the file is in the {} format (rename it to .{})",
+                extension.to_ascii_uppercase(),
+                extension
+            ),
         }
     }
 }
 
 impl Error for BpsError {}
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct BpsPatch {
     source_path: Option<PathBuf>,
+    source_header_size: u64,
     source_size: u64,
     source_checksum: u32,
 
@@ -77,19 +119,199 @@ pub struct BpsPatch {
     target_checksum: u32,
 
     patch_path: PathBuf,
+    patch_archive_entry: Option<String>,
     patch_offset: u64,
     patch_checksum: u32,
     patch_metadata: Vec<u8>,
     patch_modified: SystemTime,
+    patch_created: Option<SystemTime>,
+    operation_count: usize,
+    checksum_freshness: bool,
+    verify_target: bool,
+    source_cache: Option<Arc<SourceCache>>,
+    /// The patch data and the source ROM as last verified, shared by the clones of the patch.
+    verified_inputs: Arc<Mutex<Option<VerifiedInputs>>>,
+}
+
+/// Inputs of a patch which passed their checksums, reused while their files are unchanged
+/// instead of reading and verifying the patch and the source ROM again on every partial read.
+struct VerifiedInputs {
+    patch_stamp: FileStamp,
+    source_stamp: Option<FileStamp>,
+    patch_data: Arc<[u8]>,
+    source: Arc<SourceData>,
+}
+
+impl fmt::Debug for VerifiedInputs {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter
+            .debug_struct("VerifiedInputs")
+            .field("patch_stamp", &self.patch_stamp)
+            .field("source_stamp", &self.source_stamp)
+            .finish()
+    }
+}
+
+/// Modification time and length of a file, telling whether it changed since it was read.
+type FileStamp = (SystemTime, u64);
+
+fn file_stamp(path: &Path) -> io::Result<FileStamp> {
+    let metadata = fs::metadata(path)?;
+    Ok((metadata.modified()?, metadata.len()))
 }
 
 impl BpsPatch {
     pub fn new(patch_path: &Path) -> Result<Self, Box<dyn Error>> {
         let mut patch_file = File::open(patch_path)?;
+        let patch_file_metadata = patch_file.metadata()?;
+
+        // Counting the commands takes many small reads, which are cheaper in memory
+        let mut patch_data = Vec::new();
+        patch_file.read_to_end(&mut patch_data)?;
+
+        let patch_size = patch_data.len() as u64;
+        BpsPatch::parse(
+            &mut Cursor::new(patch_data),
+            patch_size,
+            patch_path,
+            None,
+            patch_file_metadata.modified()?,
+            patch_file_metadata.created().ok(),
+        )
+    }
+
+    /// Loads a patch stored inside a ZIP archive.
+    /// The patch is read from the archive again each time it gets applied.
+    pub fn from_archive(archive_path: &Path, entry_name: &str) -> Result<Self, Box<dyn Error>> {
+        let archive_file = File::open(archive_path)?;
+        let archive_file_metadata = archive_file.metadata()?;
+
+        let mut patch_data = Vec::new();
+        ZipArchive::new(archive_file)?
+            .by_name(entry_name)?
+            .read_to_end(&mut patch_data)?;
+
+        let patch_size = patch_data.len() as u64;
+        BpsPatch::parse(
+            &mut Cursor::new(patch_data),
+            patch_size,
+            archive_path,
+            Some(entry_name.to_owned()),
+            archive_file_metadata.modified()?,
+            archive_file_metadata.created().ok(),
+        )
+    }
+
+    /// Creates a patch turning `source` into `target`, in the format of a `.bps` file.
+    /// Matches are searched greedily, the patches are valid but larger than the ones of dedicated tools.
+    pub fn create(source: &[u8], target: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        fn match_length(a: &[u8], b: &[u8]) -> usize {
+            a.iter().zip(b).take_while(|(a, b)| a == b).count()
+        }
+
+        let mut patch = Vec::new();
+        patch.write_all(&BPS_FORMAT_MARKER)?;
+        patch.write_vlq(source.len() as u64)?;
+        patch.write_vlq(target.len() as u64)?;
+        patch.write_vlq(0)?;
+
+        let source_index = MatchIndex::new(source);
+        let mut target_index = MatchIndex::default();
+
+        let mut output_offset = 0;
+        let mut target_read_offset = 0;
+        let mut source_relative_offset = 0;
+        let mut target_relative_offset = 0;
+
+        let write_target_read = |patch: &mut Vec<u8>, data: &[u8]| -> io::Result<()> {
+            if !data.is_empty() {
+                patch.write_vlq((((data.len() - 1) << 2) | BpsCommand::TargetRead as usize) as u64)?;
+                patch.write_all(data)?;
+            }
+            Ok(())
+        };
+
+        while output_offset < target.len() {
+            let remaining = &target[output_offset..];
+            let mut best_match = (
+                BpsCommand::SourceRead,
+                match_length(source.get(output_offset..).unwrap_or_default(), remaining),
+                output_offset,
+            );
+
+            for &offset in source_index.candidates(remaining) {
+                let length = match_length(&source[offset..], remaining);
+                if length > best_match.1 {
+                    best_match = (BpsCommand::SourceCopy, length, offset);
+                }
+            }
 
+            // Target copies may overlap with the bytes they produce, the target is known in advance
+            for &offset in target_index.candidates(remaining) {
+                let length = match_length(&target[offset..], remaining);
+                if length > best_match.1 {
+                    best_match = (BpsCommand::TargetCopy, length, offset);
+                }
+            }
+
+            let (command, length, offset) = best_match;
+            if length < BPS_MIN_MATCH_LENGTH {
+                target_index.insert(target, output_offset);
+                output_offset += 1;
+                continue;
+            }
+
+            write_target_read(&mut patch, &target[target_read_offset..output_offset])?;
+            patch.write_vlq((((length - 1) << 2) | command as usize) as u64)?;
+            match command {
+                BpsCommand::SourceCopy => {
+                    patch.write_signed_vlq(offset as i64 - source_relative_offset as i64)?;
+                    source_relative_offset = offset + length;
+                }
+                BpsCommand::TargetCopy => {
+                    patch.write_signed_vlq(offset as i64 - target_relative_offset as i64)?;
+                    target_relative_offset = offset + length;
+                }
+                _ => {}
+            }
+
+            for position in output_offset..(output_offset + length) {
+                target_index.insert(target, position);
+            }
+            output_offset += length;
+            target_read_offset = output_offset;
+        }
+        write_target_read(&mut patch, &target[target_read_offset..])?;
+
+        patch.write_u32::<LittleEndian>(crc32::checksum_ieee(source))?;
+        patch.write_u32::<LittleEndian>(crc32::checksum_ieee(target))?;
+        let patch_checksum = crc32::checksum_ieee(&patch);
+        patch.write_u32::<LittleEndian>(patch_checksum)?;
+
+        Ok(patch)
+    }
+
+    fn parse<R: Read + Seek>(
+        patch_file: &mut R,
+        patch_size: u64,
+        patch_path: &Path,
+        patch_archive_entry: Option<String>,
+        patch_modified: SystemTime,
+        patch_created: Option<SystemTime>,
+    ) -> Result<Self, Box<dyn Error>> {
         let mut format_marker: [u8; 4] = [0; 4];
         patch_file.read_exact(&mut format_marker)?;
         if format_marker != BPS_FORMAT_MARKER {
+            // The IPS marker is a byte longer than the BPS one
+            let mut header = format_marker.to_vec();
+            if let Ok(byte) = patch_file.read_u8() {
+                header.push(byte);
+            }
+
+            if let Some(extension) = detect_format(&header) {
+                return Err(Box::new(BpsError::WrongFormat { extension }));
+            }
+
             return Err(Box::new(BpsError::FormatMarker {
                 expected: BPS_FORMAT_MARKER,
                 received: format_marker,
@@ -100,60 +322,199 @@ impl BpsPatch {
         let target_size = patch_file.read_vlq()?;
         let patch_metadata_size = patch_file.read_vlq()?;
 
+        // A corrupt metadata size would make the patch data overlap with the footer, or overflow the offset
+        let footer_offset = patch_size.saturating_sub(BPS_FOOTER_SIZE as u64);
+        let patch_offset = match patch_file.stream_position()?.checked_add(patch_metadata_size) {
+            Some(patch_offset) if patch_offset <= footer_offset => patch_offset,
+            patch_offset => {
+                return Err(Box::new(BpsError::PatchOffset {
+                    offset: patch_offset.unwrap_or(u64::MAX),
+                    limit: footer_offset,
+                }));
+            }
+        };
+
         let mut patch_metadata: Vec<u8> = vec![0; patch_metadata_size as usize];
         patch_file.read_exact(&mut patch_metadata)?;
 
-        let patch_offset = patch_file.stream_position()?;
+        let mut operation_count = 0;
+        while patch_file.stream_position()? < footer_offset {
+            let data = patch_file.read_vlq()?;
+            match BpsCommand::try_from((data & 3) as usize)? {
+                BpsCommand::SourceRead => {}
+                BpsCommand::TargetRead => {
+                    patch_file.seek(SeekFrom::Current((data >> 2) as i64 + 1))?;
+                }
+                BpsCommand::SourceCopy | BpsCommand::TargetCopy => {
+                    patch_file.read_signed_vlq()?;
+                }
+            }
+            operation_count += 1;
+        }
 
         patch_file.seek(SeekFrom::End(-(BPS_FOOTER_SIZE as i64)))?;
         let source_checksum = patch_file.read_u32::<LittleEndian>()?;
         let target_checksum = patch_file.read_u32::<LittleEndian>()?;
         let patch_checksum = patch_file.read_u32::<LittleEndian>()?;
 
-        let patch_modified = patch_file.metadata()?.modified()?;
-
         Ok(Self {
             source_path: None,
+            source_header_size: 0,
             source_size,
             source_checksum,
             target_size,
             target_checksum,
             patch_path: patch_path.to_owned(),
+            patch_archive_entry,
             patch_offset,
             patch_checksum,
             patch_metadata,
             patch_modified,
+            patch_created,
+            operation_count,
+            checksum_freshness: false,
+            verify_target: true,
+            source_cache: None,
+            verified_inputs: Arc::default(),
         })
     }
 
     pub fn set_source_path(&mut self, source_path: &Path) {
         self.source_path = Some(source_path.to_path_buf());
+        self.verified_inputs = Arc::default();
+    }
+
+    /// Skips the external header of the source ROM, like a copier header,
+    /// for patches made against the headerless ROM.
+    pub fn set_source_header_size(&mut self, source_header_size: u64) {
+        self.source_header_size = source_header_size;
+        self.verified_inputs = Arc::default();
+    }
+
+    /// Decides whether the patch file is still the one that was parsed by its checksum
+    /// instead of its modification time, which may change without the contents changing.
+    pub fn set_checksum_freshness(&mut self, checksum_freshness: bool) {
+        self.checksum_freshness = checksum_freshness;
+    }
+
+    /// Reads the source ROM through a cache shared with other patches.
+    pub fn set_source_cache(&mut self, source_cache: Arc<SourceCache>) {
+        self.source_cache = Some(source_cache);
+    }
+
+    /// Trusts the patched ROM without verifying its checksum, saving a pass over the whole ROM.
+    /// The patch itself and the source ROM are still verified.
+    pub fn set_verify_target(&mut self, verify_target: bool) {
+        self.verify_target = verify_target;
+    }
+
+    pub fn source_size(&self) -> u64 {
+        self.source_size
     }
 
     pub fn source_checksum(&self) -> u32 {
         self.source_checksum
     }
-}
 
-impl Patch for BpsPatch {
-    fn target_size(&self) -> u64 {
-        self.target_size
+    /// CRC32 of the patch itself, identical patch files share it.
+    pub fn patch_checksum(&self) -> u32 {
+        self.patch_checksum
     }
 
-    fn patched_rom(&self) -> Result<Vec<u8>, Box<dyn Error>> {
-        let patch_data = {
+    /// The patch metadata decoded as UTF-8 text, usually XML.
+    /// Returns `None` for empty or binary metadata.
+    pub fn metadata_str(&self) -> Option<&str> {
+        if self.patch_metadata.is_empty() {
+            None
+        } else {
+            // Byte order marks left by text editors would hide the XML declaration
+            str::from_utf8(&self.patch_metadata)
+                .ok()
+                .map(|metadata| metadata.trim_start_matches('\u{FEFF}'))
+        }
+    }
+
+    /// A part of the raw patch metadata, for reading large metadata piece by piece.
+    /// Parts reaching past the end of the metadata are cut short, like reads at the end of a file.
+    pub fn metadata_range(&self, offset: usize, length: usize) -> &[u8] {
+        let start = cmp::min(offset, self.patch_metadata.len());
+        let end = cmp::min(start.saturating_add(length), self.patch_metadata.len());
+        &self.patch_metadata[start..end]
+    }
+
+    /// The name of the patched ROM, as stored in the patch metadata.
+    /// Both plain text metadata and the `<name>` element of XML metadata are understood.
+    pub fn metadata_name(&self) -> Option<&str> {
+        let metadata = self.metadata_str()?.trim();
+        if metadata.starts_with('<') {
+            self.metadata_element("name")
+        } else {
+            metadata.lines().next()
+        }
+    }
+
+    /// The text of an element of XML metadata, like `<author>`.
+    fn metadata_element(&self, element: &str) -> Option<&str> {
+        let metadata = self.metadata_str()?;
+        let start_tag = format!("<{}>", element);
+        let end_tag = format!("</{}>", element);
+
+        let start = metadata.find(&start_tag)? + start_tag.len();
+        let end = start + metadata[start..].find(&end_tag)?;
+        Some(metadata[start..end].trim()).filter(|text| !text.is_empty())
+    }
+
+    /// Source-less patches embed the whole target ROM and don't need a source ROM to be applied.
+    pub fn is_source_less(&self) -> bool {
+        self.source_size == 0
+    }
+
+    /// Reads and verifies the patch and the source ROM, ready for executing the patch commands.
+    /// Both are verified once and kept until either of their files changes.
+    fn bps_reader(&self) -> Result<BpsReader, Box<dyn Error>> {
+        let patch_stamp = file_stamp(&self.patch_path)?;
+        let source_stamp = self.source_path.as_deref().map(file_stamp).transpose()?;
+
+        if let Some(verified_inputs) = self.verified_inputs.lock().unwrap().as_ref() {
+            if verified_inputs.patch_stamp == patch_stamp && verified_inputs.source_stamp == source_stamp {
+                return Ok(self.reader_for(verified_inputs.patch_data.clone(), verified_inputs.source.clone()));
+            }
+        }
+
+        let (patch_data, patch_modified) = {
             let mut patch_file = File::open(&self.patch_path)?;
 
-            if patch_file.metadata()?.modified()? != self.patch_modified {
+            let patch_modified = patch_file.metadata()?.modified()? != self.patch_modified;
+            if patch_modified && !self.checksum_freshness {
                 return Err(Box::new(BpsError::OutdatedCache));
             }
 
             let mut patch_data = Vec::new();
-            patch_file.read_to_end(&mut patch_data)?;
-            patch_data
+            match &self.patch_archive_entry {
+                Some(entry_name) => ZipArchive::new(patch_file)?
+                    .by_name(entry_name)?
+                    .read_to_end(&mut patch_data)?,
+                None => patch_file.read_to_end(&mut patch_data)?,
+            };
+            (patch_data, patch_modified)
         };
 
+        // Patches rewritten since they were parsed may have become too short for their footer
+        let footer_offset = (patch_data.len() as u64).saturating_sub(BPS_FOOTER_SIZE as u64);
+        if footer_offset < self.patch_offset {
+            if patch_modified {
+                return Err(Box::new(BpsError::OutdatedCache));
+            }
+            return Err(Box::new(BpsError::PatchOffset {
+                offset: self.patch_offset,
+                limit: footer_offset,
+            }));
+        }
+
         let patch_checksum = crc32::checksum_ieee(&patch_data[0..(patch_data.len() - 4)]);
+        if patch_modified && patch_checksum != self.patch_checksum {
+            return Err(Box::new(BpsError::OutdatedCache));
+        }
         if patch_checksum != self.patch_checksum {
             return Err(Box::new(BpsError::PatchChecksum {
                 expected: self.patch_checksum,
@@ -161,19 +522,20 @@ impl Patch for BpsPatch {
             }));
         }
 
-        let mut patch_cursor =
-            Cursor::new(&patch_data[self.patch_offset as usize..(patch_data.len() - BPS_FOOTER_SIZE)]);
-
-        let source = fs::read(self.source_path.as_ref().unwrap())?;
+        let source = match &self.source_path {
+            Some(source_path) => SourceCache::load(self.source_cache.as_deref(), source_path)?,
+            None => Arc::new(SourceData::Empty),
+        };
 
-        if source.len() as u64 != self.source_size {
+        let source_offset = cmp::min(self.source_header_size as usize, source.len());
+        if (source.len() - source_offset) as u64 != self.source_size {
             return Err(Box::new(BpsError::SourceLength {
                 expected: self.source_size,
-                received: source.len() as u64,
+                received: (source.len() - source_offset) as u64,
             }));
         }
 
-        let source_checksum = crc32::checksum_ieee(&source);
+        let source_checksum = crc32::checksum_ieee(&source[source_offset..]);
         if source_checksum != self.source_checksum {
             return Err(Box::new(BpsError::SourceChecksum {
                 expected: self.source_checksum,
@@ -181,76 +543,785 @@ impl Patch for BpsPatch {
             }));
         }
 
-        let mut target = vec![0; self.target_size as usize];
+        let patch_data: Arc<[u8]> = patch_data.into();
+        *self.verified_inputs.lock().unwrap() = Some(VerifiedInputs {
+            patch_stamp,
+            source_stamp,
+            patch_data: patch_data.clone(),
+            source: source.clone(),
+        });
 
-        let mut output_offset = 0;
-        let mut source_relative_offset = 0;
-        let mut target_relative_offset = 0;
+        Ok(self.reader_for(patch_data, source))
+    }
 
-        while patch_cursor.stream_position()? < patch_cursor.stream_len()? {
-            #[derive(TryFromPrimitive)]
-            #[repr(usize)]
-            enum BpsCommand {
-                SourceRead,
-                TargetRead,
-                SourceCopy,
-                TargetCopy,
-            }
+    /// Frees the verified inputs once the whole patched ROM is produced, the ROM is cached from then on
+    /// rather than patched again. Partial reads keep them, as more of them tend to follow.
+    fn release_verified_inputs(&self) {
+        self.verified_inputs.lock().unwrap().take();
+    }
+
+    fn reader_for(&self, patch_data: Arc<[u8]>, source: Arc<SourceData>) -> BpsReader {
+        let source_offset = cmp::min(self.source_header_size as usize, source.len());
+        let commands_end = (patch_data.len() as u64).saturating_sub(BPS_FOOTER_SIZE as u64);
+        let mut patch_cursor = Cursor::new(patch_data);
+        patch_cursor.set_position(self.patch_offset);
 
-            let (command, length) = {
-                let data = patch_cursor.read_vlq()? as usize;
-                (BpsCommand::try_from(data & 3)?, (data >> 2) + 1)
+        BpsReader {
+            patch_cursor,
+            commands_end,
+            source,
+            source_offset,
+            source_less: self.is_source_less(),
+            target: Vec::with_capacity(self.target_size as usize),
+            target_size: self.target_size,
+            target_checksum: self.target_checksum,
+            verify_target: self.verify_target,
+            command: None,
+            source_relative_offset: 0,
+            target_relative_offset: 0,
+            read_offset: 0,
+            verified: false,
+        }
+    }
+}
+
+impl Patch for BpsPatch {
+    fn target_size(&self) -> u64 {
+        self.target_size
+    }
+
+    fn source_path(&self) -> Option<&Path> {
+        self.source_path.as_deref()
+    }
+
+    fn source_header_size(&self) -> u64 {
+        self.source_header_size
+    }
+
+    fn format(&self) -> Option<&'static str> {
+        Some("bps")
+    }
+
+    fn modified(&self) -> Option<SystemTime> {
+        Some(self.patch_modified)
+    }
+
+    fn created(&self) -> Option<SystemTime> {
+        self.patch_created
+    }
+
+    fn source_checksum(&self) -> Option<u32> {
+        Some(self.source_checksum)
+    }
+
+    fn operation_count(&self) -> Option<usize> {
+        Some(self.operation_count)
+    }
+
+    fn metadata(&self) -> Option<&[u8]> {
+        if self.patch_metadata.is_empty() {
+            None
+        } else {
+            Some(&self.patch_metadata)
+        }
+    }
+
+    fn title(&self) -> Option<&str> {
+        self.metadata_element("title").or_else(|| self.metadata_name())
+    }
+
+    fn author(&self) -> Option<&str> {
+        self.metadata_element("author")
+    }
+
+    fn validate(&self) -> Result<(), Box<dyn Error>> {
+        let source_path = match &self.source_path {
+            Some(source_path) => source_path,
+            None => return Ok(()),
+        };
+
+        let source_size = fs::metadata(source_path)?.len().saturating_sub(self.source_header_size);
+        if source_size != self.source_size {
+            return Err(Box::new(BpsError::SourceLength {
+                expected: self.source_size,
+                received: source_size,
+            }));
+        }
+        Ok(())
+    }
+
+    fn patched_rom(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut reader = self.bps_reader()?;
+        reader.produce(self.target_size as usize)?;
+        reader.verify()?;
+        self.release_verified_inputs();
+        Ok(reader.target)
+    }
+
+    /// Streams produce the whole patched ROM, the reader holds on to the inputs as long as it needs them.
+    fn reader(&self) -> Result<Box<dyn Read + Send + '_>, Box<dyn Error>> {
+        let reader = self.bps_reader()?;
+        self.release_verified_inputs();
+        Ok(Box::new(reader))
+    }
+
+    /// Executes the commands only up to the end of the range, the patched ROM is verified
+    /// only when the range reaches the end of the ROM.
+    fn patched_range(&self, offset: u64, length: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+        let end = cmp::min(offset.saturating_add(length as u64), self.target_size) as usize;
+
+        let mut reader = self.bps_reader()?;
+        reader.produce(end)?;
+        if end as u64 == self.target_size {
+            reader.verify()?;
+            self.release_verified_inputs();
+        }
+
+        // Truncated command streams run out before the end of the range
+        if reader.target.len() < end {
+            return Err(Box::new(BpsError::TargetLength {
+                expected: self.target_size,
+                received: reader.target.len() as u64,
+            }));
+        }
+
+        let start = cmp::min(offset as usize, end);
+        Ok(reader.target[start..end].to_vec())
+    }
+
+    fn partial_patching(&self) -> bool {
+        true
+    }
+}
+
+/// Offsets of the short sequences of some data, for finding matches when creating patches.
+#[derive(Default)]
+struct MatchIndex {
+    offsets: HashMap<[u8; BPS_MIN_MATCH_LENGTH], Vec<usize>>,
+}
+
+impl MatchIndex {
+    fn new(data: &[u8]) -> Self {
+        let mut match_index = MatchIndex::default();
+        for offset in 0..data.len() {
+            match_index.insert(data, offset);
+        }
+        match_index
+    }
+
+    fn insert(&mut self, data: &[u8], offset: usize) {
+        if let Some(key) = MatchIndex::key(&data[offset..]) {
+            self.offsets.entry(key).or_default().push(offset);
+        }
+    }
+
+    /// Offsets where the data may match the start of `data`, the most recent ones first.
+    fn candidates(&self, data: &[u8]) -> impl Iterator<Item = &usize> {
+        MatchIndex::key(data)
+            .and_then(|key| self.offsets.get(&key))
+            .into_iter()
+            .flat_map(|offsets| offsets.iter().rev().take(BPS_MAX_MATCH_CANDIDATES))
+    }
+
+    fn key(data: &[u8]) -> Option<[u8; BPS_MIN_MATCH_LENGTH]> {
+        <[u8; BPS_MIN_MATCH_LENGTH]>::try_from(data.get(..BPS_MIN_MATCH_LENGTH)?).ok()
+    }
+}
+
+/// Executes the commands of a BPS patch on demand, as far as the patched ROM is read.
+/// Target copies may refer to anything produced so far, so the produced part of the
+/// patched ROM is kept until the reader is dropped.
+struct BpsReader {
+    patch_cursor: Cursor<Arc<[u8]>>,
+    commands_end: u64,
+    source: Arc<SourceData>,
+    /// Start of the source data the patch was made against, after the external header of the source ROM.
+    source_offset: usize,
+    source_less: bool,
+
+    target: Vec<u8>,
+    target_size: u64,
+    target_checksum: u32,
+    verify_target: bool,
+
+    /// The command being executed, along with the number of bytes it has yet to produce.
+    command: Option<(BpsCommand, usize)>,
+    source_relative_offset: usize,
+    target_relative_offset: usize,
+
+    read_offset: usize,
+    verified: bool,
+}
+
+impl BpsReader {
+    /// Executes commands until the patched ROM is produced up to `length` bytes, or the commands run out.
+    fn produce(&mut self, length: usize) -> Result<(), Box<dyn Error>> {
+        while self.target.len() < length {
+            let (command, remaining) = match self.command.take() {
+                Some(command) => command,
+                None if self.patch_cursor.position() < self.commands_end => self.next_command()?,
+                None => break,
             };
 
+            let output_offset = self.target.len();
+            let size = cmp::min(remaining, length - output_offset);
+
             match command {
                 BpsCommand::SourceRead => {
-                    target[output_offset..(output_offset + length)]
-                        .clone_from_slice(&source[output_offset..(output_offset + length)]);
-                    output_offset += length;
+                    self.target
+                        .extend_from_slice(&self.source[self.source_offset..][output_offset..(output_offset + size)]);
                 }
                 BpsCommand::TargetRead => {
-                    patch_cursor.read_exact(&mut target[output_offset..(output_offset + length)])?;
-                    output_offset += length;
+                    self.target.resize(output_offset + size, 0);
+                    self.patch_cursor.read_exact(&mut self.target[output_offset..])?;
                 }
                 BpsCommand::SourceCopy => {
-                    let offset = patch_cursor.read_signed_vlq()?;
-                    source_relative_offset = (source_relative_offset as isize + offset as isize) as usize; // unsafe
-
-                    target[output_offset..(output_offset + length)]
-                        .clone_from_slice(&source[source_relative_offset..(source_relative_offset + length)]);
-
-                    source_relative_offset += length;
-                    output_offset += length;
+                    self.target.extend_from_slice(
+                        &self.source[self.source_offset..]
+                            [self.source_relative_offset..(self.source_relative_offset + size)],
+                    );
+                    self.source_relative_offset += size;
                 }
                 BpsCommand::TargetCopy => {
-                    let offset = patch_cursor.read_signed_vlq()?;
-                    target_relative_offset = (target_relative_offset as isize + offset as isize) as usize; // unsafe
-
-                    for i in 0..length {
-                        target[output_offset + i] = target[target_relative_offset + i];
+                    // The copied range may overlap with the bytes being produced
+                    for _ in 0..size {
+                        self.target.push(self.target[self.target_relative_offset]);
+                        self.target_relative_offset += 1;
                     }
-
-                    target_relative_offset += length;
-                    output_offset += length;
                 }
             }
+
+            if remaining > size {
+                self.command = Some((command, remaining - size));
+            }
         }
 
-        if target.len() as u64 != self.target_size {
+        Ok(())
+    }
+
+    fn next_command(&mut self) -> Result<(BpsCommand, usize), Box<dyn Error>> {
+        let (command, length) = {
+            let data = self.patch_cursor.read_vlq()? as usize;
+            (BpsCommand::try_from(data & 3)?, (data >> 2) + 1)
+        };
+
+        // Source-less patches have an empty source, reading from it is a malformed patch
+        if self.source_less && matches!(command, BpsCommand::SourceRead | BpsCommand::SourceCopy) {
+            return Err(Box::new(BpsError::MissingSource));
+        }
+
+        // Over-long command streams would write past the end of the target ROM
+        let target_end = self.target.len().saturating_add(length) as u64;
+        if target_end > self.target_size {
             return Err(Box::new(BpsError::TargetLength {
                 expected: self.target_size,
-                received: target.len() as u64,
+                received: target_end,
+            }));
+        }
+
+        // Malformed patches may point anywhere, every read is checked against the data read from
+        let (offset, bound) = match command {
+            BpsCommand::SourceRead => (self.target.len() as i64, self.source.len() - self.source_offset),
+            BpsCommand::TargetRead => return Ok((command, length)),
+            BpsCommand::SourceCopy => {
+                let offset = (self.source_relative_offset as i64).saturating_add(self.patch_cursor.read_signed_vlq()?);
+                (offset, self.source.len() - self.source_offset)
+            }
+            // Target copies may overlap with the bytes they produce, but must start before them
+            BpsCommand::TargetCopy => {
+                let offset = (self.target_relative_offset as i64).saturating_add(self.patch_cursor.read_signed_vlq()?);
+                (offset, self.target.len())
+            }
+        };
+
+        let in_bounds = match command {
+            BpsCommand::TargetCopy => offset >= 0 && (offset as usize) < bound,
+            _ => offset >= 0 && (offset as usize).checked_add(length).is_some_and(|end| end <= bound),
+        };
+        if !in_bounds {
+            return Err(Box::new(BpsError::OutOfBounds {
+                offset,
+                length: length as u64,
+                bound: bound as u64,
             }));
         }
 
-        let target_checksum = crc32::checksum_ieee(&target);
-        if target_checksum != self.target_checksum {
-            return Err(Box::new(BpsError::TargetChecksum {
-                expected: self.target_checksum,
-                received: target_checksum,
+        match command {
+            BpsCommand::SourceCopy => self.source_relative_offset = offset as usize,
+            BpsCommand::TargetCopy => self.target_relative_offset = offset as usize,
+            BpsCommand::SourceRead | BpsCommand::TargetRead => {}
+        }
+
+        Ok((command, length))
+    }
+
+    /// Checks the length and the checksum of the patched ROM, after every command was executed.
+    fn verify(&mut self) -> Result<(), Box<dyn Error>> {
+        // Any command left after the target ROM was completed is over-long
+        self.produce(self.target_size as usize + 1)?;
+
+        // Truncated command streams leave the target ROM short
+        if self.target.len() as u64 != self.target_size {
+            return Err(Box::new(BpsError::TargetLength {
+                expected: self.target_size,
+                received: self.target.len() as u64,
             }));
         }
 
-        Ok(target)
+        if self.verify_target {
+            let target_checksum = crc32::checksum_ieee(&self.target);
+            if target_checksum != self.target_checksum {
+                return Err(Box::new(BpsError::TargetChecksum {
+                    expected: self.target_checksum,
+                    received: target_checksum,
+                }));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Read for BpsReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        fn to_io_error(err: Box<dyn Error>) -> io::Error {
+            io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+        }
+
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        self.produce(self.read_offset + buf.len()).map_err(to_io_error)?;
+
+        // The end of the stream is only reported once the patched ROM is verified
+        if self.read_offset == self.target.len() {
+            if !self.verified {
+                self.verify().map_err(to_io_error)?;
+                self.verified = true;
+            }
+            return Ok(0);
+        }
+
+        let size = cmp::min(buf.len(), self.target.len() - self.read_offset);
+        buf[..size].copy_from_slice(&self.target[self.read_offset..(self.read_offset + size)]);
+        self.read_offset += size;
+        Ok(size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{
+        make_bps, make_bps_with_metadata, make_dir_fixture, make_hack, make_ips, make_rom, write_file,
+    };
+
+    /// A patch with the given header sizes and commands, which the checksums of the footer don't verify.
+    fn raw_patch(source: &[u8], target_size: u64, commands: &[u8]) -> Vec<u8> {
+        let mut patch = Vec::new();
+        patch.write_all(&BPS_FORMAT_MARKER).unwrap();
+        patch.write_vlq(source.len() as u64).unwrap();
+        patch.write_vlq(target_size).unwrap();
+        patch.write_vlq(0).unwrap();
+        patch.write_all(commands).unwrap();
+        patch.write_u32::<LittleEndian>(crc32::checksum_ieee(source)).unwrap();
+        patch.write_u32::<LittleEndian>(0).unwrap();
+        let patch_checksum = crc32::checksum_ieee(&patch);
+        patch.write_u32::<LittleEndian>(patch_checksum).unwrap();
+        patch
+    }
+
+    #[test]
+    fn rejects_metadata_past_the_footer() {
+        for metadata_size in [1, u64::MAX - 1] {
+            let mut patch = Vec::new();
+            patch.write_all(&BPS_FORMAT_MARKER).unwrap();
+            patch.write_vlq(0).unwrap();
+            patch.write_vlq(0).unwrap();
+            patch.write_vlq(metadata_size).unwrap();
+            let footer_offset = patch.len() as u64;
+            patch.write_all(&[0; BPS_FOOTER_SIZE]).unwrap();
+
+            let directory = make_dir_fixture(&[("hack.bps", &patch)]);
+            let err = BpsPatch::new(&directory.path().join("hack.bps")).unwrap_err();
+            match *err.downcast::<BpsError>().unwrap() {
+                BpsError::PatchOffset { offset, limit } => {
+                    assert_eq!(offset, footer_offset.saturating_add(metadata_size));
+                    assert_eq!(limit, footer_offset);
+                }
+                err => panic!("unexpected error: {}", err),
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_ranges_past_truncated_commands() {
+        // A single target read of 4 bytes, for a target ROM of 16 bytes
+        let mut commands = Vec::new();
+        commands
+            .write_vlq(((4 - 1) << 2) | BpsCommand::TargetRead as u64)
+            .unwrap();
+        commands.write_all(b"ABCD").unwrap();
+        let directory = make_dir_fixture(&[("short.bps", &raw_patch(&[], 16, &commands))]);
+        let patch = BpsPatch::new(&directory.path().join("short.bps")).unwrap();
+
+        assert_eq!(patch.patched_range(0, 4).unwrap(), b"ABCD");
+        assert_eq!(patch.patched_range(1, 2).unwrap(), b"BC");
+
+        let err = patch.patched_range(2, 4).unwrap_err();
+        assert!(err.to_string().starts_with("target length mismatch"), "{}", err);
+        assert!(patch.patched_range(8, 4).is_err());
+        assert!(patch.patched_range(0, 16).is_err());
+    }
+
+    #[test]
+    fn verifies_the_inputs_once_while_unchanged() {
+        let source = make_rom(4096, 1);
+        let target = make_hack(&source);
+        let directory = make_dir_fixture(&[("game.sfc", &source), ("hack.bps", &make_bps(&source, &target))]);
+        let source_path = directory.path().join("game.sfc");
+
+        let mut patch = BpsPatch::new(&directory.path().join("hack.bps")).unwrap();
+        patch.set_source_path(&source_path);
+        assert_eq!(patch.patched_range(0, 64).unwrap(), target[..64]);
+
+        // A change keeping the modification time and the length isn't noticed, the source isn't read again
+        let modified = fs::metadata(&source_path).unwrap().modified().unwrap();
+        fs::write(&source_path, make_rom(4096, 2)).unwrap();
+        File::options()
+            .write(true)
+            .open(&source_path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        assert_eq!(patch.patched_range(64, 64).unwrap(), target[64..128]);
+        assert_eq!(patch.clone().patched_range(0, 16).unwrap(), target[..16]);
+
+        // Any other change verifies the source again
+        File::options()
+            .write(true)
+            .open(&source_path)
+            .unwrap()
+            .set_modified(modified + std::time::Duration::from_secs(1))
+            .unwrap();
+        let err = patch.patched_range(0, 64).unwrap_err();
+        assert!(err.to_string().starts_with("invalid source checksum"), "{}", err);
+    }
+
+    #[test]
+    fn rejects_source_copies_past_the_source() {
+        let source = make_rom(16, 1);
+        let directory = make_dir_fixture(&[("game.sfc", &source)]);
+
+        for (relative_offset, offset) in [(14, 14), (i64::MAX, i64::MAX)] {
+            // A single source copy of 4 bytes, for a target ROM of 4 bytes
+            let mut commands = Vec::new();
+            commands
+                .write_vlq(((4 - 1) << 2) | BpsCommand::SourceCopy as u64)
+                .unwrap();
+            commands.write_signed_vlq(relative_offset).unwrap();
+            write_file(directory.path(), "hack.bps", &raw_patch(&source, 4, &commands));
+
+            let mut patch = BpsPatch::new(&directory.path().join("hack.bps")).unwrap();
+            patch.set_source_path(&directory.path().join("game.sfc"));
+            let err = patch.patched_rom().unwrap_err();
+            match *err.downcast::<BpsError>().unwrap() {
+                BpsError::OutOfBounds {
+                    offset: received,
+                    length,
+                    bound,
+                } => assert_eq!((received, length, bound), (offset, 4, 16)),
+                err => panic!("unexpected error: {}", err),
+            }
+        }
+    }
+
+    #[test]
+    fn exposes_utf8_metadata_as_text() {
+        let source = make_rom(1024, 1);
+        let target = make_hack(&source);
+        let metadata = "\u{FEFF}<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<patch><name>Hack – Édition</name></patch>";
+        let directory = make_dir_fixture(&[
+            (
+                "text.bps",
+                &make_bps_with_metadata(&source, &target, metadata.as_bytes()),
+            ),
+            (
+                "binary.bps",
+                &make_bps_with_metadata(&source, &target, &[0x00, 0xFF, 0xFE, 0x80]),
+            ),
+            ("plain.bps", &make_bps(&source, &target)),
+        ]);
+        let patch = |name: &str| BpsPatch::new(&directory.path().join(name)).unwrap();
+
+        let text_patch = patch("text.bps");
+        assert_eq!(text_patch.metadata_str(), Some(metadata.trim_start_matches('\u{FEFF}')));
+        assert_eq!(text_patch.metadata(), Some(metadata.as_bytes()));
+        assert_eq!(text_patch.metadata_name(), Some("Hack – Édition"));
+
+        // Binary metadata is only exposed raw
+        let binary_patch = patch("binary.bps");
+        assert_eq!(binary_patch.metadata_str(), None);
+        assert_eq!(binary_patch.metadata(), Some(&[0x00, 0xFF, 0xFE, 0x80][..]));
+        assert_eq!(binary_patch.metadata_name(), None);
+
+        assert_eq!(patch("plain.bps").metadata_str(), None);
+        assert_eq!(patch("plain.bps").metadata(), None);
+    }
+
+    #[test]
+    fn rejects_source_commands_of_source_less_patches() {
+        for command in [BpsCommand::SourceRead, BpsCommand::SourceCopy] {
+            let mut commands = Vec::new();
+            // The relative offset is only read by source copies
+            commands.write_vlq(((4 - 1) << 2) | command as u64).unwrap();
+            commands.write_signed_vlq(0).unwrap();
+            let directory = make_dir_fixture(&[("hack.bps", &raw_patch(&[], 4, &commands))]);
+
+            let patch = BpsPatch::new(&directory.path().join("hack.bps")).unwrap();
+            assert!(patch.is_source_less());
+            let err = patch.patched_rom().unwrap_err();
+            assert!(matches!(*err.downcast::<BpsError>().unwrap(), BpsError::MissingSource));
+            assert!(patch.patched_range(0, 4).is_err());
+        }
+    }
+
+    #[test]
+    fn checks_freshness_by_checksum() {
+        let source = make_rom(1024, 1);
+        let target = make_hack(&source);
+        let directory = make_dir_fixture(&[("game.sfc", &source), ("hack.bps", &make_bps(&source, &target))]);
+        let patch_path = directory.path().join("hack.bps");
+
+        let mut patch = BpsPatch::new(&patch_path).unwrap();
+        patch.set_source_path(&directory.path().join("game.sfc"));
+
+        // Restored from a backup, the same contents with a newer modification time
+        let modified = fs::metadata(&patch_path).unwrap().modified().unwrap();
+        File::options()
+            .write(true)
+            .open(&patch_path)
+            .unwrap()
+            .set_modified(modified + std::time::Duration::from_secs(60))
+            .unwrap();
+
+        let err = patch.patched_rom().unwrap_err();
+        assert!(matches!(*err.downcast::<BpsError>().unwrap(), BpsError::OutdatedCache));
+
+        patch.set_checksum_freshness(true);
+        assert_eq!(patch.patched_rom().unwrap(), target);
+
+        // Changed contents are still outdated
+        let mut changed_target = target.clone();
+        changed_target[1] ^= 0xFF;
+        fs::write(&patch_path, make_bps(&source, &changed_target)).unwrap();
+        let err = patch.patched_rom().unwrap_err();
+        assert!(matches!(*err.downcast::<BpsError>().unwrap(), BpsError::OutdatedCache));
+    }
+
+    #[test]
+    fn counts_commands() {
+        // Two bytes of the source, two new bytes, then the first two bytes once more
+        let mut commands = Vec::new();
+        commands
+            .write_vlq(((2 - 1) << 2) | BpsCommand::SourceRead as u64)
+            .unwrap();
+        commands
+            .write_vlq(((2 - 1) << 2) | BpsCommand::TargetRead as u64)
+            .unwrap();
+        commands.write_all(b"XY").unwrap();
+        commands
+            .write_vlq(((2 - 1) << 2) | BpsCommand::TargetCopy as u64)
+            .unwrap();
+        commands.write_signed_vlq(0).unwrap();
+        let directory = make_dir_fixture(&[("hack.bps", &raw_patch(b"abcd", 6, &commands))]);
+
+        let patch = BpsPatch::new(&directory.path().join("hack.bps")).unwrap();
+        assert_eq!(patch.operation_count(), Some(3));
+    }
+
+    #[test]
+    fn verifies_the_target_checksum_only_at_the_end() {
+        // The footer of raw patches has a wrong target checksum
+        let mut commands = Vec::new();
+        commands
+            .write_vlq(((8 - 1) << 2) | BpsCommand::TargetRead as u64)
+            .unwrap();
+        commands.write_all(b"ABCDEFGH").unwrap();
+        let directory = make_dir_fixture(&[("hack.bps", &raw_patch(&[], 8, &commands))]);
+        let mut patch = BpsPatch::new(&directory.path().join("hack.bps")).unwrap();
+
+        // Windows short of the end never check the whole target
+        assert_eq!(patch.patched_range(0, 2).unwrap(), b"AB");
+        assert_eq!(patch.patched_range(2, 2).unwrap(), b"CD");
+        assert_eq!(patch.patched_range(4, 2).unwrap(), b"EF");
+
+        for err in [patch.patched_range(6, 2).unwrap_err(), patch.patched_rom().unwrap_err()] {
+            assert!(err.to_string().starts_with("invalid target checksum"), "{}", err);
+        }
+
+        patch.set_verify_target(false);
+        assert_eq!(patch.patched_range(6, 2).unwrap(), b"GH");
+        assert_eq!(patch.patched_rom().unwrap(), b"ABCDEFGH");
+    }
+
+    #[test]
+    fn rejects_command_streams_of_the_wrong_length() {
+        // A single target read of 4 bytes, for shorter and longer target ROMs
+        let mut commands = Vec::new();
+        commands
+            .write_vlq(((4 - 1) << 2) | BpsCommand::TargetRead as u64)
+            .unwrap();
+        commands.write_all(b"ABCD").unwrap();
+
+        for (target_size, received_size) in [(16, 4), (2, 4)] {
+            let directory = make_dir_fixture(&[("hack.bps", &raw_patch(&[], target_size, &commands))]);
+            let patch = BpsPatch::new(&directory.path().join("hack.bps")).unwrap();
+            let err = patch.patched_rom().unwrap_err();
+            match *err.downcast::<BpsError>().unwrap() {
+                BpsError::TargetLength { expected, received } => {
+                    assert_eq!((expected, received), (target_size, received_size))
+                }
+                err => panic!("unexpected error: {}", err),
+            }
+        }
+    }
+
+    #[test]
+    fn points_out_patches_of_other_formats() {
+        let source = make_rom(1024, 1);
+        let target = make_hack(&source);
+        let directory = make_dir_fixture(&[("hack.bps", &make_ips(&source, &target))]);
+
+        let err = BpsPatch::new(&directory.path().join("hack.bps")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "not a BPS patch, the file is in the IPS format (rename it to .ips)"
+        );
+    }
+
+    #[test]
+    fn round_trips_created_patches() {
+        let source = make_rom(8192, 1);
+        let mut repeated = make_hack(&source[..1024]);
+        repeated.extend_from_within(..);
+        repeated.extend_from_slice(&[0xAA; 300]);
+        let cases: [(&[u8], Vec<u8>); 6] = [
+            (&source, source.clone()),
+            (&source, make_hack(&source)),
+            (&source, make_hack(&source[..4000])),
+            (&source, [&source[4096..], &source[..4096], &make_rom(100, 2)].concat()),
+            (&source, repeated),
+            (&[], make_rom(1000, 3)),
+        ];
+        let directory = make_dir_fixture(&[("game.sfc", &source)]);
+
+        for (index, (case_source, target)) in cases.iter().enumerate() {
+            write_file(directory.path(), "game.sfc", case_source);
+            write_file(
+                directory.path(),
+                "hack.bps",
+                &BpsPatch::create(case_source, target).unwrap(),
+            );
+
+            let mut patch = BpsPatch::new(&directory.path().join("hack.bps")).unwrap();
+            if !patch.is_source_less() {
+                patch.set_source_path(&directory.path().join("game.sfc"));
+            }
+            assert_eq!(patch.target_size(), target.len() as u64, "case {}", index);
+            assert_eq!(&patch.patched_rom().unwrap(), target, "case {}", index);
+        }
+    }
+
+    #[test]
+    fn reads_parts_of_the_metadata() {
+        let source = make_rom(1024, 1);
+        let patch_data = make_bps_with_metadata(&source, &make_hack(&source), b"<name>Hack</name>");
+        let directory = make_dir_fixture(&[("hack.bps", &patch_data)]);
+        let patch = BpsPatch::new(&directory.path().join("hack.bps")).unwrap();
+
+        assert_eq!(patch.metadata_range(0, 6), b"<name>");
+        assert_eq!(patch.metadata_range(6, 4), b"Hack");
+        assert_eq!(patch.metadata_range(10, 100), b"</name>");
+        assert_eq!(patch.metadata_range(17, 1), b"");
+        assert_eq!(patch.metadata_range(100, 1), b"");
+        assert_eq!(patch.metadata_range(1, usize::MAX), b"name>Hack</name>");
+    }
+
+    #[test]
+    fn validates_the_size_of_the_source() {
+        let source = make_rom(1024, 1);
+        let directory = make_dir_fixture(&[
+            ("game.sfc", &source),
+            ("short.sfc", &source[..512]),
+            ("hack.bps", &make_bps(&source, &make_hack(&source))),
+        ]);
+        let mut patch = BpsPatch::new(&directory.path().join("hack.bps")).unwrap();
+
+        patch.set_source_path(&directory.path().join("game.sfc"));
+        assert!(patch.validate().is_ok());
+
+        patch.set_source_path(&directory.path().join("short.sfc"));
+        let err = patch.validate().unwrap_err();
+        assert!(err.to_string().starts_with("source length mismatch"), "{}", err);
+    }
+
+    #[test]
+    fn rejects_patches_truncated_since_parsing() {
+        let source = make_rom(1024, 1);
+        let directory = make_dir_fixture(&[
+            ("game.sfc", &source),
+            ("hack.bps", &make_bps(&source, &make_hack(&source))),
+        ]);
+        let patch_path = directory.path().join("hack.bps");
+        let modified = fs::metadata(&patch_path).unwrap().modified().unwrap();
+
+        let mut patch = BpsPatch::new(&patch_path).unwrap();
+        patch.set_source_path(&directory.path().join("game.sfc"));
+        patch.set_checksum_freshness(true);
+
+        // Shorter than the footer, with a newer modification time
+        fs::write(&patch_path, b"BPS1").unwrap();
+        let err = patch.patched_rom().unwrap_err();
+        assert!(matches!(*err.downcast::<BpsError>().unwrap(), BpsError::OutdatedCache));
+
+        // Shorter than the footer, with the modification time the patch was parsed with
+        File::options()
+            .write(true)
+            .open(&patch_path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        let err = patch.patched_rom().unwrap_err();
+        assert!(matches!(
+            *err.downcast::<BpsError>().unwrap(),
+            BpsError::PatchOffset { limit: 0, .. }
+        ));
+    }
+
+    #[test]
+    fn frees_the_inputs_once_fully_patched() {
+        let source = make_rom(4096, 1);
+        let target = make_hack(&source);
+        let directory = make_dir_fixture(&[("game.sfc", &source), ("hack.bps", &make_bps(&source, &target))]);
+        let source_cache = Arc::new(SourceCache::new(false));
+
+        let mut patch = BpsPatch::new(&directory.path().join("hack.bps")).unwrap();
+        patch.set_source_path(&directory.path().join("game.sfc"));
+        patch.set_source_cache(source_cache.clone());
+        let source_in_use = || format!("{:?}", source_cache) != "[]";
+
+        // Partial reads keep the source for the reads to come
+        assert_eq!(patch.patched_range(0, 64).unwrap(), target[..64]);
+        assert!(patch.verified_inputs.lock().unwrap().is_some());
+        assert!(source_in_use());
+
+        assert_eq!(patch.patched_rom().unwrap(), target);
+        assert!(patch.verified_inputs.lock().unwrap().is_none());
+        assert!(!source_in_use());
+
+        assert_eq!(patch.patched_range(4000, 1000).unwrap(), target[4000..]);
+        assert!(!source_in_use());
     }
 }
@@ -2,19 +2,141 @@ use std::cmp;
 use std::error::Error;
 use std::fmt;
 use std::fs::{self, File};
-use std::io::{Read, Seek, SeekFrom};
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
 
-use byteorder::{BigEndian, ReadBytesExt};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 
-use crate::patch::Patch;
+use crate::patch::{detect_format, Patch};
+use crate::source_cache::{SourceCache, SourceData};
 
 const IPS_FORMAT_MARKER: [u8; 5] = [b'P', b'A', b'T', b'C', b'H'];
 const IPS_EOF_MARKER: usize = 0x454F46;
 
+/// IPS32 patches have 32-bit offsets and truncation sizes, reaching past the first 16 MiB of the ROM.
+const IPS32_FORMAT_MARKER: [u8; 5] = [b'I', b'P', b'S', b'3', b'2'];
+const IPS32_EOF_MARKER: usize = 0x45454F46;
+
+/// Offsets and sizes of records are 24 and 16 bit wide.
+const IPS_MAX_OFFSET: usize = 0xFFFFFF;
+const IPS_MAX_RECORD_SIZE: usize = 0xFFFF;
+const IPS_RECORD_HEADER_SIZE: usize = 5;
+/// Shortest run of a repeated byte stored as an RLE record when creating patches,
+/// shorter runs take fewer bytes as part of a data record.
+const IPS_MIN_RLE_LENGTH: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum IpsVariant {
+    Ips,
+    Ips32,
+}
+
+impl IpsVariant {
+    /// Recognizes the variant by the format marker at the start of the patch.
+    fn detect(patch_data: &[u8]) -> Result<Self, IpsError> {
+        let mut format_marker: [u8; 5] = [0; 5];
+        let marker_size = cmp::min(patch_data.len(), format_marker.len());
+        format_marker[..marker_size].copy_from_slice(&patch_data[..marker_size]);
+
+        match format_marker {
+            IPS_FORMAT_MARKER => Ok(IpsVariant::Ips),
+            IPS32_FORMAT_MARKER => Ok(IpsVariant::Ips32),
+            _ => match detect_format(&format_marker) {
+                Some(extension) => Err(IpsError::WrongFormat { extension }),
+                None => Err(IpsError::FormatMarker {
+                    expected: IPS_FORMAT_MARKER,
+                    received: format_marker,
+                }),
+            },
+        }
+    }
+
+    fn other(self) -> Self {
+        match self {
+            IpsVariant::Ips => IpsVariant::Ips32,
+            IpsVariant::Ips32 => IpsVariant::Ips,
+        }
+    }
+
+    fn eof_marker_name(self) -> &'static str {
+        match self {
+            IpsVariant::Ips => "EOF",
+            IpsVariant::Ips32 => "EEOF",
+        }
+    }
+
+    /// Patches running out of records are checked for ending with the `EOF` marker of the other variant,
+    /// like IPS32 patches starting with the format marker of IPS patches.
+    fn read_error(self, patch_data: &[u8], err: io::Error) -> Box<dyn Error> {
+        let other = self.other();
+        let other_eof = (0..=other.truncation_size() as usize)
+            .filter(|&size| size <= patch_data.len())
+            .any(|size| patch_data[..(patch_data.len() - size)].ends_with(other.eof_marker_name().as_bytes()));
+
+        if err.kind() == io::ErrorKind::UnexpectedEof && other_eof {
+            Box::new(IpsError::MixedMarkers {
+                eof_marker: other.eof_marker_name(),
+            })
+        } else {
+            Box::new(err)
+        }
+    }
+
+    fn format_marker(self) -> [u8; 5] {
+        match self {
+            IpsVariant::Ips => IPS_FORMAT_MARKER,
+            IpsVariant::Ips32 => IPS32_FORMAT_MARKER,
+        }
+    }
+
+    fn read_offset<R: Read>(self, patch_file: &mut R) -> io::Result<usize> {
+        match self {
+            IpsVariant::Ips => patch_file.read_u24::<BigEndian>().map(|offset| offset as usize),
+            IpsVariant::Ips32 => patch_file.read_u32::<BigEndian>().map(|offset| offset as usize),
+        }
+    }
+
+    fn eof_marker(self) -> usize {
+        match self {
+            IpsVariant::Ips => IPS_EOF_MARKER,
+            IpsVariant::Ips32 => IPS32_EOF_MARKER,
+        }
+    }
+
+    /// Size of the truncation size following the `EOF` marker.
+    fn truncation_size(self) -> u64 {
+        match self {
+            IpsVariant::Ips => 3,
+            IpsVariant::Ips32 => 4,
+        }
+    }
+
+    /// The `EOF` marker is also a valid record offset. Unless `strict_eof` is set, the marker only ends
+    /// the patch when at most a truncation size follows it, as any record would take more bytes than that.
+    fn is_eof_marker(self, patch_file: &Cursor<&[u8]>, offset: usize, strict_eof: bool) -> bool {
+        if offset != self.eof_marker() {
+            return false;
+        }
+
+        if strict_eof {
+            return true;
+        }
+
+        let remaining = (patch_file.get_ref().len() as u64).saturating_sub(patch_file.position());
+        remaining <= self.truncation_size()
+    }
+}
+
 #[derive(Debug)]
 pub enum IpsError {
     FormatMarker { expected: [u8; 5], received: [u8; 5] },
+    WrongFormat { extension: &'static str },
+    OffsetLimit { offset: usize },
+    MixedMarkers { eof_marker: &'static str },
+    RecordOutOfBounds { end: u64, target_size: u64 },
 }
 
 impl fmt::Display for IpsError {
@@ -25,6 +147,27 @@ impl fmt::Display for IpsError {
                 "invalid format marker (expected: {:?}, received: {:?})",
                 expected, received
             ),
+            IpsError::WrongFormat { extension } => write!(
+                formatter,
+                "not an IPS patch, the file is in the {} format (rename it to .{})",
+                extension.to_ascii_uppercase(),
+                extension
+            ),
+            IpsError::OffsetLimit { offset } => write!(
+                formatter,
+                "offset 0x{:X} is beyond the limit of IPS patches (limit: 0x{:X})",
+                offset, IPS_MAX_OFFSET
+            ),
+            IpsError::MixedMarkers { eof_marker } => write!(
+                formatter,
+                "the format marker doesn't match the {} marker at the end of the patch",
+                eof_marker
+            ),
+            IpsError::RecordOutOfBounds { end, target_size } => write!(
+                formatter,
+                "record ending at 0x{:X} is beyond the end of the target ROM (size: 0x{:X})",
+                end, target_size
+            ),
         }
     }
 }
@@ -33,56 +176,268 @@ impl Error for IpsError {}
 
 pub struct IpsPatch {
     source_path: PathBuf,
+    source_size: u64,
     patch_path: PathBuf,
+    patch_modified: SystemTime,
+    patch_created: Option<SystemTime>,
 
     target_size: u64,
     truncated_size: Option<u64>,
+
+    /// Target ranges overwritten by the patch records, one for each record.
+    patched_ranges: Vec<Range<u64>>,
+    variant: IpsVariant,
+    strict_eof: bool,
+
+    source_cache: Option<Arc<SourceCache>>,
+}
+
+/// A record of an IPS patch, overwriting the target ROM from an offset.
+enum IpsRecord {
+    Data { offset: usize, data: Vec<u8> },
+    Rle { offset: usize, size: usize, value: u8 },
+}
+
+impl IpsRecord {
+    fn range(&self) -> Range<usize> {
+        match self {
+            IpsRecord::Data { offset, data } => *offset..(offset + data.len()),
+            IpsRecord::Rle { offset, size, .. } => *offset..(offset + size),
+        }
+    }
+
+    /// Writes the part of the record overlapping with `target`, a part of the target ROM starting at `target_offset`.
+    fn apply(&self, target: &mut [u8], target_offset: usize) {
+        let range = self.range();
+        let start = cmp::max(range.start, target_offset);
+        let end = cmp::min(range.end, target_offset + target.len());
+        if start >= end {
+            return;
+        }
+
+        let target = &mut target[(start - target_offset)..(end - target_offset)];
+        match self {
+            IpsRecord::Data { data, .. } => target.copy_from_slice(&data[(start - range.start)..(end - range.start)]),
+            IpsRecord::Rle { value, .. } => target.fill(*value),
+        }
+    }
+}
+
+/// Writes the data of `target` in `range` as data records, split by the record size limit.
+fn write_data_records(patch: &mut Vec<u8>, target: &[u8], range: Range<usize>) -> Result<(), Box<dyn Error>> {
+    let mut offset = range.start;
+    while offset < range.end {
+        // Records at the offset of the `EOF` marker would end the patch, they start a byte earlier
+        if offset == IPS_EOF_MARKER {
+            offset -= 1;
+        }
+        if offset > IPS_MAX_OFFSET {
+            return Err(Box::new(IpsError::OffsetLimit { offset }));
+        }
+
+        let size = cmp::min(range.end - offset, IPS_MAX_RECORD_SIZE);
+        patch.write_u24::<BigEndian>(offset as u32)?;
+        patch.write_u16::<BigEndian>(size as u16)?;
+        patch.write_all(&target[offset..(offset + size)])?;
+        offset += size;
+    }
+    Ok(())
+}
+
+/// Writes a run of the same byte of `target` in `range` as RLE records, split by the record size limit.
+fn write_rle_records(patch: &mut Vec<u8>, target: &[u8], range: Range<usize>) -> Result<(), Box<dyn Error>> {
+    let mut offset = range.start;
+    while offset < range.end {
+        if offset == IPS_EOF_MARKER {
+            write_data_records(patch, target, offset..(offset + 1))?;
+            offset += 1;
+            continue;
+        }
+        if offset > IPS_MAX_OFFSET {
+            return Err(Box::new(IpsError::OffsetLimit { offset }));
+        }
+
+        let size = cmp::min(range.end - offset, IPS_MAX_RECORD_SIZE);
+        patch.write_u24::<BigEndian>(offset as u32)?;
+        patch.write_u16::<BigEndian>(0)?;
+        patch.write_u16::<BigEndian>(size as u16)?;
+        patch.write_u8(target[offset])?;
+        offset += size;
+    }
+    Ok(())
 }
 
 impl IpsPatch {
-    pub fn new(patch_path: &Path, source_path: &Path) -> Result<Self, Box<dyn Error>> {
-        let mut patch_file = File::open(patch_path)?;
+    /// Creates a patch turning `source` into `target`, in the format of an `.ips` file.
+    /// Runs of a repeated byte are stored as RLE records, targets shorter than the source are truncated.
+    pub fn create(source: &[u8], target: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let changed = |offset: usize| source.get(offset) != Some(&target[offset]);
+        let mut patch = IPS_FORMAT_MARKER.to_vec();
+
+        let mut offset = 0;
+        while offset < target.len() {
+            if !changed(offset) {
+                offset += 1;
+                continue;
+            }
+
+            // Short unchanged gaps are kept in the record, a new record would take more bytes
+            let mut end = offset + 1;
+            let mut unchanged = 0;
+            while end < target.len() && unchanged < IPS_RECORD_HEADER_SIZE {
+                unchanged = if changed(end) { 0 } else { unchanged + 1 };
+                end += 1;
+            }
+            end -= unchanged;
+
+            let mut data_start = offset;
+            while offset < end {
+                let run_length = target[offset..end].iter().take_while(|&&b| b == target[offset]).count();
+                if run_length >= IPS_MIN_RLE_LENGTH {
+                    write_data_records(&mut patch, target, data_start..offset)?;
+                    write_rle_records(&mut patch, target, offset..(offset + run_length))?;
+                    data_start = offset + run_length;
+                }
+                offset += run_length;
+            }
+            write_data_records(&mut patch, target, data_start..end)?;
+        }
+
+        patch.write_u24::<BigEndian>(IPS_EOF_MARKER as u32)?;
+        if target.len() < source.len() {
+            if target.len() > IPS_MAX_OFFSET {
+                return Err(Box::new(IpsError::OffsetLimit { offset: target.len() }));
+            }
+            patch.write_u24::<BigEndian>(target.len() as u32)?;
+        }
+
+        Ok(patch)
+    }
 
-        let mut target_size: u64 = {
+    pub fn new(patch_path: &Path, source_path: &Path, strict_eof: bool) -> Result<Self, Box<dyn Error>> {
+        // Fragmented patches take many small reads per record, which are cheaper in memory
+        let patch_data = fs::read(patch_path)?;
+        let patch_file_metadata = fs::metadata(patch_path)?;
+
+        let source_size: u64 = {
             let source_file = File::open(source_path)?;
             source_file.metadata()?.len()
         };
 
-        let mut format_marker: [u8; 5] = [0; 5];
-        patch_file.read_exact(&mut format_marker)?;
-        if format_marker != IPS_FORMAT_MARKER {
+        let variant = IpsVariant::detect(&patch_data)?;
+        let (patched_ranges, truncated_size) = read_patched_ranges(&patch_data, variant, strict_eof)
+            .map_err(|err| variant.read_error(&patch_data, err))?;
+        let target_size = patched_ranges.iter().map(|range| range.end).fold(source_size, cmp::max);
+
+        // Records are only cut off by the truncation size of corrupt patches, they are rejected before being exposed
+        if let Some(truncated_size) = truncated_size {
+            if let Some(range) = patched_ranges.iter().find(|range| range.end > truncated_size) {
+                return Err(Box::new(IpsError::RecordOutOfBounds {
+                    end: range.end,
+                    target_size: truncated_size,
+                }));
+            }
+        }
+
+        Ok(Self {
+            patch_path: patch_path.to_path_buf(),
+            patch_modified: patch_file_metadata.modified()?,
+            patch_created: patch_file_metadata.created().ok(),
+            source_path: source_path.to_path_buf(),
+            source_size,
+            target_size,
+            truncated_size,
+            patched_ranges,
+            variant,
+            strict_eof,
+            source_cache: None,
+        })
+    }
+
+    /// Reads the source ROM through a cache shared with other patches.
+    pub fn set_source_cache(&mut self, source_cache: Arc<SourceCache>) {
+        self.source_cache = Some(source_cache);
+    }
+
+    fn records(&self) -> Result<Vec<IpsRecord>, Box<dyn Error>> {
+        let patch_data = fs::read(&self.patch_path)?;
+        let variant = IpsVariant::detect(&patch_data)?;
+        if variant != self.variant {
             return Err(Box::new(IpsError::FormatMarker {
-                expected: IPS_FORMAT_MARKER,
-                received: format_marker,
+                expected: self.variant.format_marker(),
+                received: variant.format_marker(),
             }));
         }
 
+        self.read_records(&patch_data)
+            .map_err(|err| self.variant.read_error(&patch_data, err))
+    }
+
+    fn read_records(&self, patch_data: &[u8]) -> io::Result<Vec<IpsRecord>> {
+        let mut patch_file = Cursor::new(patch_data);
+        patch_file.set_position(IPS_FORMAT_MARKER.len() as u64);
+        let mut records = Vec::with_capacity(self.patched_ranges.len());
+
         loop {
-            let offset = patch_file.read_u24::<BigEndian>()? as usize;
-            if offset == IPS_EOF_MARKER {
+            let offset = self.variant.read_offset(&mut patch_file)?;
+            if self.variant.is_eof_marker(&patch_file, offset, self.strict_eof) {
                 break;
             }
 
             let size = patch_file.read_u16::<BigEndian>()? as usize;
             if size == 0 {
-                let rle_size = patch_file.read_u16::<BigEndian>()? as usize;
-                let _rle_value = patch_file.read_u8()?;
-                target_size = cmp::max(target_size, offset as u64 + rle_size as u64);
+                let size = patch_file.read_u16::<BigEndian>()? as usize;
+                let value = patch_file.read_u8()?;
+                record_end(offset, size)?;
+                records.push(IpsRecord::Rle { offset, size, value });
             } else {
-                patch_file.seek(SeekFrom::Current(size as i64))?;
-                target_size = cmp::max(target_size, offset as u64 + size as u64);
+                record_end(offset, size)?;
+                let mut data = vec![0; size];
+                patch_file.read_exact(&mut data)?;
+                records.push(IpsRecord::Data { offset, data });
             }
         }
 
-        let truncated_size = patch_file.read_u24::<BigEndian>().ok().map(u64::from);
+        Ok(records)
+    }
+}
 
-        Ok(Self {
-            patch_path: patch_path.to_path_buf(),
-            source_path: source_path.to_path_buf(),
-            target_size,
-            truncated_size,
-        })
+/// Reads the target ranges overwritten by the records of a patch, along with its truncation size.
+fn read_patched_ranges(
+    patch_data: &[u8],
+    variant: IpsVariant,
+    strict_eof: bool,
+) -> io::Result<(Vec<Range<u64>>, Option<u64>)> {
+    let mut patch_file = Cursor::new(patch_data);
+    patch_file.set_position(IPS_FORMAT_MARKER.len() as u64);
+    let mut patched_ranges = Vec::new();
+
+    loop {
+        let offset = variant.read_offset(&mut patch_file)? as u64;
+        if variant.is_eof_marker(&patch_file, offset as usize, strict_eof) {
+            break;
+        }
+
+        let size = patch_file.read_u16::<BigEndian>()? as u64;
+        if size == 0 {
+            let rle_size = patch_file.read_u16::<BigEndian>()? as u64;
+            let _rle_value = patch_file.read_u8()?;
+            patched_ranges.push(offset..record_end(offset as usize, rle_size as usize)? as u64);
+        } else {
+            patch_file.seek(SeekFrom::Current(size as i64))?;
+            patched_ranges.push(offset..record_end(offset as usize, size as usize)? as u64);
+        }
     }
+
+    let truncated_size = variant.read_offset(&mut patch_file).ok().map(|size| size as u64);
+    Ok((patched_ranges, truncated_size))
+}
+
+/// End of a record, IPS32 records may end past the address space of 32-bit platforms.
+fn record_end(offset: usize, size: usize) -> io::Result<usize> {
+    offset
+        .checked_add(size)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "record ends past the address space"))
 }
 
 impl Patch for IpsPatch {
@@ -90,35 +445,55 @@ impl Patch for IpsPatch {
         self.truncated_size.unwrap_or(self.target_size)
     }
 
-    fn patched_rom(&self) -> Result<Vec<u8>, Box<dyn Error>> {
-        let mut target = fs::read(&self.source_path)?;
-        target.resize(self.target_size as usize, 0);
+    fn source_path(&self) -> Option<&Path> {
+        Some(&self.source_path)
+    }
 
-        let mut patch_file = File::open(&self.patch_path)?;
+    fn format(&self) -> Option<&'static str> {
+        Some("ips")
+    }
 
-        let mut format_marker: [u8; 5] = [0; 5];
-        patch_file.read_exact(&mut format_marker)?;
-        if format_marker != IPS_FORMAT_MARKER {
-            return Err(Box::new(IpsError::FormatMarker {
-                expected: IPS_FORMAT_MARKER,
-                received: format_marker,
-            }));
+    fn modified(&self) -> Option<SystemTime> {
+        Some(self.patch_modified)
+    }
+
+    fn created(&self) -> Option<SystemTime> {
+        self.patch_created
+    }
+
+    fn operation_count(&self) -> Option<usize> {
+        Some(self.patched_ranges.len())
+    }
+
+    fn unchanged_range(&self, offset: u64, size: u64) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        let end = cmp::min(offset.saturating_add(size), self.target_size());
+        if offset >= end || end > self.source_size {
+            return Ok(None);
         }
 
-        loop {
-            let offset = patch_file.read_u24::<BigEndian>()? as usize;
-            if offset == IPS_EOF_MARKER {
-                break;
-            }
+        if self.patched_ranges.iter().any(|r| r.start < end && offset < r.end) {
+            return Ok(None);
+        }
 
-            let size = patch_file.read_u16::<BigEndian>()? as usize;
-            if size == 0 {
-                let rle_size = patch_file.read_u16::<BigEndian>()? as usize;
-                let rle_value = patch_file.read_u8()?;
-                target[offset..(offset + rle_size)].fill(rle_value);
-            } else {
-                patch_file.read_exact(&mut target[offset..(offset + size)])?;
-            }
+        if let Some(source_cache) = &self.source_cache {
+            let source = source_cache.get(&self.source_path)?;
+            return Ok(source.get(offset as usize..end as usize).map(<[u8]>::to_vec));
+        }
+
+        let mut source_file = File::open(&self.source_path)?;
+        source_file.seek(SeekFrom::Start(offset))?;
+
+        let mut data = vec![0; (end - offset) as usize];
+        source_file.read_exact(&mut data)?;
+        Ok(Some(data))
+    }
+
+    fn patched_rom(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut target = SourceCache::load(self.source_cache.as_deref(), &self.source_path)?.to_vec();
+        target.resize(self.target_size as usize, 0);
+
+        for record in self.records()? {
+            record.apply(&mut target, 0);
         }
 
         if let Some(truncated_size) = self.truncated_size {
@@ -127,4 +502,257 @@ impl Patch for IpsPatch {
 
         Ok(target)
     }
+
+    fn reader(&self) -> Result<Box<dyn Read + Send + '_>, Box<dyn Error>> {
+        Ok(Box::new(IpsReader {
+            source: SourceCache::load(self.source_cache.as_deref(), &self.source_path)?,
+            records: self.records()?,
+            target_size: self.target_size() as usize,
+            position: 0,
+        }))
+    }
+}
+
+/// Streams the source ROM with the records of an IPS patch laid over it, one read at a time.
+struct IpsReader {
+    source: Arc<SourceData>,
+    records: Vec<IpsRecord>,
+    target_size: usize,
+    position: usize,
+}
+
+impl Read for IpsReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let end = cmp::min(self.position + buf.len(), self.target_size);
+        if self.position >= end {
+            return Ok(0);
+        }
+
+        let chunk = &mut buf[..(end - self.position)];
+
+        // Records may extend the ROM past the end of the source ROM
+        let source_start = cmp::min(self.position, self.source.len());
+        let source_end = cmp::min(end, self.source.len());
+        let (source_chunk, extended_chunk) = chunk.split_at_mut(source_end - source_start);
+        source_chunk.copy_from_slice(&self.source[source_start..source_end]);
+        extended_chunk.fill(0);
+
+        for record in &self.records {
+            record.apply(chunk, self.position);
+        }
+
+        self.position = end;
+        Ok(chunk.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{make_bps, make_dir_fixture, make_hack, make_ips, make_rom, write_file};
+
+    #[test]
+    fn serves_unchanged_ranges_without_the_records() {
+        let source = make_rom(4096, 1);
+        let mut target = source.clone();
+        target[0x100..0x110].fill(0xAA);
+        let directory = make_dir_fixture(&[("game.sfc", &source), ("hack.ips", &make_ips(&source, &target))]);
+        let patch = IpsPatch::new(
+            &directory.path().join("hack.ips"),
+            &directory.path().join("game.sfc"),
+            false,
+        )
+        .unwrap();
+
+        // Records are read from the patch file, which is gone
+        fs::remove_file(directory.path().join("hack.ips")).unwrap();
+
+        assert_eq!(patch.unchanged_range(0, 0x100).unwrap().unwrap(), source[..0x100]);
+        assert_eq!(patch.unchanged_range(0x110, 0x1000).unwrap().unwrap(), source[0x110..]);
+        assert_eq!(patch.unchanged_range(0xF8, 0x10).unwrap(), None);
+        assert!(patch.patched_range(0xF8, 0x10).is_err());
+    }
+
+    #[test]
+    fn counts_records() {
+        // Two records, the second one run-length encoded
+        let mut patch = b"PATCH".to_vec();
+        patch.extend_from_slice(&[0x00, 0x00, 0x10, 0x00, 0x02, 0xAA, 0xBB]);
+        patch.extend_from_slice(&[0x00, 0x00, 0x20, 0x00, 0x00, 0x00, 0x08, 0xCC]);
+        patch.extend_from_slice(b"EOF");
+        let directory = make_dir_fixture(&[("game.sfc", &make_rom(64, 1)), ("hack.ips", &patch)]);
+
+        let patch = IpsPatch::new(
+            &directory.path().join("hack.ips"),
+            &directory.path().join("game.sfc"),
+            false,
+        )
+        .unwrap();
+        assert_eq!(patch.operation_count(), Some(2));
+    }
+
+    #[test]
+    fn applies_records_at_the_eof_marker_offset() {
+        // The offset of the record reads as the `EOF` marker
+        let source = make_rom(0x454F50, 1);
+        let mut patch = b"PATCH".to_vec();
+        patch.extend_from_slice(b"EOF");
+        patch.extend_from_slice(&[0x00, 0x02, 0xAA, 0xBB]);
+        patch.extend_from_slice(b"EOF");
+        let directory = make_dir_fixture(&[("game.sfc", &source), ("hack.ips", &patch)]);
+        let load = |strict_eof| {
+            IpsPatch::new(
+                &directory.path().join("hack.ips"),
+                &directory.path().join("game.sfc"),
+                strict_eof,
+            )
+            .unwrap()
+        };
+
+        let mut target = source.clone();
+        target[0x454F46..0x454F48].copy_from_slice(&[0xAA, 0xBB]);
+        assert_eq!(load(false).patched_rom().unwrap(), target);
+
+        // Strictly the first marker ends the patch, the record is taken for a truncation size
+        assert_eq!(load(true).patched_rom().unwrap(), source[..0x0002AA]);
+    }
+
+    #[test]
+    fn points_out_patches_of_other_formats() {
+        let source = make_rom(1024, 1);
+        let target = make_hack(&source);
+        let directory = make_dir_fixture(&[("game.sfc", &source), ("hack.ips", &make_bps(&source, &target))]);
+
+        let Err(err) = IpsPatch::new(
+            &directory.path().join("hack.ips"),
+            &directory.path().join("game.sfc"),
+            false,
+        ) else {
+            panic!("BPS patch accepted");
+        };
+        assert_eq!(
+            err.to_string(),
+            "not an IPS patch, the file is in the BPS format (rename it to .bps)"
+        );
+    }
+
+    #[test]
+    fn round_trips_created_patches_with_rle_records() {
+        let source = make_rom(64 * 1024, 1);
+        let mut target = source.clone();
+        target[0x100..0x110].fill(0xAA);
+        target[0x1000..0x1000 + 40000].fill(0x00);
+        target[0x8000] ^= 0xFF;
+        let mut longer = target.clone();
+        longer.extend_from_slice(&[0x11; 1000]);
+        let shorter = target[..0x9000].to_vec();
+        let directory = make_dir_fixture(&[("game.sfc", &source)]);
+
+        for target in [target, longer, shorter] {
+            let patch_data = IpsPatch::create(&source, &target).unwrap();
+            // The runs take a few RLE records rather than their length in data
+            assert!(patch_data.len() < 200, "{} bytes", patch_data.len());
+
+            write_file(directory.path(), "hack.ips", &patch_data);
+            let patch = IpsPatch::new(
+                &directory.path().join("hack.ips"),
+                &directory.path().join("game.sfc"),
+                true,
+            )
+            .unwrap();
+            assert_eq!(patch.target_size(), target.len() as u64);
+            assert_eq!(patch.patched_rom().unwrap(), target);
+        }
+    }
+
+    #[test]
+    fn parses_fragmented_patches_from_memory() {
+        // A record for every eighth byte, too far apart to be merged
+        let source = make_rom(64 * 1024, 1);
+        let mut target = source.clone();
+        for offset in (0..target.len()).step_by(8) {
+            target[offset] ^= 0xFF;
+        }
+        let patch_data = make_ips(&source, &target);
+
+        // The records are read from the patch data in memory, without any file access per record
+        let variant = IpsVariant::detect(&patch_data).unwrap();
+        let (patched_ranges, truncated_size) = read_patched_ranges(&patch_data, variant, false).unwrap();
+        assert_eq!(patched_ranges.len(), 8192);
+        assert_eq!(patched_ranges[1], 8..9);
+        assert_eq!(truncated_size, None);
+
+        let directory = make_dir_fixture(&[("game.sfc", &source), ("hack.ips", &patch_data)]);
+        let patch = IpsPatch::new(
+            &directory.path().join("hack.ips"),
+            &directory.path().join("game.sfc"),
+            false,
+        )
+        .unwrap();
+        assert_eq!(patch.operation_count(), Some(8192));
+        assert_eq!(patch.patched_rom().unwrap(), target);
+    }
+
+    #[test]
+    fn applies_ips32_records_past_16_mib() {
+        let source = make_rom(64, 1);
+        let mut records = Vec::new();
+        records.extend_from_slice(&[0x00, 0x00, 0x00, 0x10, 0x00, 0x01, 0xAA]);
+        records.extend_from_slice(&[0x01, 0x00, 0x00, 0x04, 0x00, 0x02, 0xBB, 0xCC]);
+        records.extend_from_slice(b"EEOF");
+        let mut patch = b"IPS32".to_vec();
+        patch.extend_from_slice(&records);
+        // IPS32 records behind the format marker of IPS patches
+        let mut mixed_patch = b"PATCH".to_vec();
+        mixed_patch.extend_from_slice(&records);
+        let directory = make_dir_fixture(&[("game.sfc", &source), ("hack.ips", &patch), ("mixed.ips", &mixed_patch)]);
+
+        let patch = IpsPatch::new(
+            &directory.path().join("hack.ips"),
+            &directory.path().join("game.sfc"),
+            false,
+        )
+        .unwrap();
+        assert_eq!(patch.target_size(), 0x1000006);
+
+        let mut target = source.clone();
+        target[0x10] = 0xAA;
+        target.resize(0x1000006, 0);
+        target[0x1000004..].copy_from_slice(&[0xBB, 0xCC]);
+        assert!(patch.patched_rom().unwrap() == target);
+
+        let Err(err) = IpsPatch::new(
+            &directory.path().join("mixed.ips"),
+            &directory.path().join("game.sfc"),
+            false,
+        ) else {
+            panic!("IPS32 records accepted as IPS records");
+        };
+        assert_eq!(
+            err.to_string(),
+            "the format marker doesn't match the EEOF marker at the end of the patch"
+        );
+    }
+
+    #[test]
+    fn rejects_records_past_the_truncation_size() {
+        // A record up to 0x28, while the patch truncates the target ROM to 0x20 bytes
+        let mut patch = b"PATCH".to_vec();
+        patch.extend_from_slice(&[0x00, 0x00, 0x20, 0x00, 0x00, 0x00, 0x08, 0xCC]);
+        patch.extend_from_slice(b"EOF");
+        patch.extend_from_slice(&[0x00, 0x00, 0x20]);
+        let directory = make_dir_fixture(&[("game.sfc", &make_rom(64, 1)), ("hack.ips", &patch)]);
+
+        let Err(err) = IpsPatch::new(
+            &directory.path().join("hack.ips"),
+            &directory.path().join("game.sfc"),
+            false,
+        ) else {
+            panic!("record past the truncation size accepted");
+        };
+        assert_eq!(
+            err.to_string(),
+            "record ending at 0x28 is beyond the end of the target ROM (size: 0x20)"
+        );
+    }
 }
@@ -1,10 +1,267 @@
+use std::cmp;
 use std::error::Error;
+use std::io::{Cursor, Read};
+use std::path::Path;
+use std::time::SystemTime;
 
+pub mod aps;
 pub mod bps;
 pub mod ips;
+pub mod ppf;
+pub mod rup;
+pub mod ups;
+pub mod vcdiff;
+
+/// Extensions of the patch formats that can be applied.
+pub const SUPPORTED_FORMATS: &[&str] = &["aps", "bps", "ips", "ppf", "rup", "xdelta"];
+
+/// Magic numbers at the start of patch files, along with the extension of their format.
+const FORMAT_MARKERS: &[(&[u8], &str)] = &[
+    (b"BPS1", "bps"),
+    (b"PATCH", "ips"),
+    (b"IPS32", "ips"),
+    (b"UPS1", "ups"),
+    (b"APS10", "aps"),
+    (b"PPF", "ppf"),
+    (b"NINJA2", "rup"),
+    (b"\xD6\xC3\xC4", "xdelta"),
+];
+
+/// Recognizes the format of a patch by the magic number at its start,
+/// for pointing out patches with the wrong file extension.
+fn detect_format(header: &[u8]) -> Option<&'static str> {
+    FORMAT_MARKERS
+        .iter()
+        .find(|(marker, _)| header.starts_with(marker))
+        .map(|(_, extension)| *extension)
+}
 
 pub trait Patch {
     fn target_size(&self) -> u64;
 
     fn patched_rom(&self) -> Result<Vec<u8>, Box<dyn Error>>;
+
+    /// Streams the patched ROM, producing it as it is read where the patch format allows.
+    /// Errors of the patch surface as errors of the stream, including the final verification.
+    fn reader(&self) -> Result<Box<dyn Read + Send + '_>, Box<dyn Error>> {
+        Ok(Box::new(Cursor::new(self.patched_rom()?)))
+    }
+
+    /// Checks the source ROM without patching it, like its size, so patches bound to fail are caught
+    /// before their ROMs are read. The full verification is left to patching.
+    fn validate(&self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    /// Produces a range of the patched ROM, cut short at the end of the ROM.
+    /// Patch formats supporting `partial_patching` produce it without patching the rest of the ROM.
+    fn patched_range(&self, offset: u64, length: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+        let patched_rom = self.patched_rom()?;
+        let start = cmp::min(offset, patched_rom.len() as u64) as usize;
+        let end = cmp::min(start.saturating_add(length), patched_rom.len());
+        Ok(patched_rom[start..end].to_vec())
+    }
+
+    /// Whether `patched_range` is cheaper than patching the whole ROM, for ranges near the start of the ROM.
+    fn partial_patching(&self) -> bool {
+        false
+    }
+
+    /// Same as `target_size`, the length of the patched ROM.
+    fn len(&self) -> u64 {
+        self.target_size()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.target_size() == 0
+    }
+
+    fn source_path(&self) -> Option<&Path> {
+        None
+    }
+
+    /// Size of the external header of the source ROM, like a copier header, skipped when patching.
+    fn source_header_size(&self) -> u64 {
+        0
+    }
+
+    /// Name of the patch format, like `bps`, for patches read from patch files.
+    fn format(&self) -> Option<&'static str> {
+        None
+    }
+
+    /// CRC32 of the source ROM the patch expects, for patch formats recording it.
+    fn source_checksum(&self) -> Option<u32> {
+        None
+    }
+
+    /// Number of commands or records in the patch, for gauging its complexity.
+    fn operation_count(&self) -> Option<usize> {
+        None
+    }
+
+    /// Metadata embedded in the patch, in a format specific to the patch format.
+    fn metadata(&self) -> Option<&[u8]> {
+        None
+    }
+
+    /// Title of the patched ROM, as given by the patch author.
+    fn title(&self) -> Option<&str> {
+        None
+    }
+
+    fn author(&self) -> Option<&str> {
+        None
+    }
+
+    /// Modification time of the patch file, reported as the modification time of the patched ROM.
+    fn modified(&self) -> Option<SystemTime> {
+        None
+    }
+
+    /// Creation time of the patch file, on filesystems recording it.
+    fn created(&self) -> Option<SystemTime> {
+        None
+    }
+
+    /// Reads a range of the patched ROM directly from the source ROM, if the patch leaves it unchanged.
+    /// Returns `None` when the range needs to be served from the fully patched ROM, ranges within the ROM
+    /// are never returned partially.
+    fn unchanged_range(&self, _offset: u64, _size: u64) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::patch::bps::BpsPatch;
+    use crate::patch::ips::IpsPatch;
+    use crate::testutil::{make_bps, make_dir_fixture, make_hack, make_ips, make_rom, make_source_less_bps};
+    use std::fs;
+    use std::path::PathBuf;
+
+    /// Hand-written patches along with the ROMs they apply to and the ROMs they produce,
+    /// see `testdata/corpus/README.md`.
+    fn corpus() -> Vec<(PathBuf, Box<dyn Patch>)> {
+        let corpus_directory = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/corpus");
+        let mut patch_paths: Vec<PathBuf> = fs::read_dir(&corpus_directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| matches!(path.extension().and_then(|e| e.to_str()), Some("bps" | "ips")))
+            .collect();
+        patch_paths.sort();
+
+        patch_paths
+            .into_iter()
+            .map(|patch_path| {
+                let source_path = patch_path.with_extension("source");
+                let patch: Box<dyn Patch> = match patch_path.extension().and_then(|e| e.to_str()) {
+                    Some("bps") => {
+                        let mut patch = BpsPatch::new(&patch_path).unwrap();
+                        if !patch.is_source_less() {
+                            patch.set_source_path(&source_path);
+                        }
+                        Box::new(patch)
+                    }
+                    _ => Box::new(IpsPatch::new(&patch_path, &source_path, true).unwrap()),
+                };
+                (patch_path, patch)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn matches_the_reference_outputs() {
+        let corpus = corpus();
+        assert_eq!(corpus.len(), 8);
+
+        for (patch_path, patch) in corpus {
+            let expected = fs::read(patch_path.with_extension("target")).unwrap();
+            let name = patch_path.display();
+
+            assert_eq!(patch.target_size(), expected.len() as u64, "{}", name);
+            assert_eq!(patch.patched_rom().unwrap(), expected, "{}", name);
+
+            let mut streamed = Vec::new();
+            patch.reader().unwrap().read_to_end(&mut streamed).unwrap();
+            assert_eq!(streamed, expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn matches_the_reference_output_ranges() {
+        for (patch_path, patch) in corpus() {
+            let expected = fs::read(patch_path.with_extension("target")).unwrap();
+            let name = patch_path.display();
+
+            for offset in 0..=expected.len() + 1 {
+                for length in [0, 1, 3, 16, 64] {
+                    let start = cmp::min(offset, expected.len());
+                    let end = cmp::min(start + length, expected.len());
+                    let range = patch.patched_range(offset as u64, length).unwrap();
+                    assert_eq!(range, expected[start..end], "{} at {}+{}", name, offset, length);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn is_as_long_as_the_target() {
+        let directory = make_dir_fixture(&[
+            ("full.bps", &make_source_less_bps(b"full")),
+            ("empty.bps", &make_source_less_bps(b"")),
+        ]);
+
+        let patch = BpsPatch::new(&directory.path().join("full.bps")).unwrap();
+        assert_eq!(patch.len(), 4);
+        assert!(!patch.is_empty());
+
+        let patch = BpsPatch::new(&directory.path().join("empty.bps")).unwrap();
+        assert_eq!(patch.len(), 0);
+        assert!(patch.is_empty());
+        assert_eq!(patch.patched_rom().unwrap(), b"");
+    }
+
+    #[test]
+    fn streams_the_patched_rom() {
+        let source = make_rom(64 * 1024, 1);
+        let target = make_hack(&source);
+        let directory = make_dir_fixture(&[
+            ("game.sfc", &source),
+            ("hack.bps", &make_bps(&source, &target)),
+            ("hack.ips", &make_ips(&source, &target)),
+        ]);
+        let path = |name: &str| directory.path().join(name);
+
+        let mut bps_patch = BpsPatch::new(&path("hack.bps")).unwrap();
+        bps_patch.set_source_path(&path("game.sfc"));
+        let ips_patch = IpsPatch::new(&path("hack.ips"), &path("game.sfc"), false).unwrap();
+        let patches: [&dyn Patch; 2] = [&bps_patch, &ips_patch];
+
+        for patch in patches {
+            // Reads of odd sizes, straddling the commands and the records
+            let mut reader = patch.reader().unwrap();
+            let mut streamed = Vec::new();
+            let mut buffer = [0; 1000];
+            loop {
+                let size = reader.read(&mut buffer).unwrap();
+                if size == 0 {
+                    break;
+                }
+                streamed.extend_from_slice(&buffer[..size]);
+            }
+            assert_eq!(streamed, patch.patched_rom().unwrap());
+            assert_eq!(streamed, target);
+        }
+
+        // A wrong source ROM fails the stream
+        fs::write(path("game.sfc"), make_rom(64 * 1024, 2)).unwrap();
+        let mut bps_patch = BpsPatch::new(&path("hack.bps")).unwrap();
+        bps_patch.set_source_path(&path("game.sfc"));
+        assert!(bps_patch
+            .reader()
+            .and_then(|mut reader| Ok(reader.read_to_end(&mut Vec::new())?))
+            .is_err());
+    }
 }
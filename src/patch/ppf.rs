@@ -0,0 +1,345 @@
+use std::cmp;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use byteorder::{LittleEndian, ReadBytesExt};
+
+use crate::patch::{detect_format, Patch};
+use crate::source_cache::SourceCache;
+
+const PPF_MAGIC: [u8; 3] = [b'P', b'P', b'F'];
+const PPF_DESCRIPTION_SIZE: usize = 50;
+const PPF_BLOCK_CHECK_SIZE: usize = 1024;
+
+/// Offset of the block check data in BIN and GI disc images.
+const PPF_BIN_BLOCK_CHECK_OFFSET: u64 = 0x9320;
+const PPF_GI_BLOCK_CHECK_OFFSET: u64 = 0x80A0;
+
+const FILE_ID_BEGIN_MARKER: &[u8] = b"@BEGIN_FILE_ID.DIZ";
+const FILE_ID_END_MARKER: &[u8] = b"@END_FILE_ID.DIZ";
+
+#[derive(Debug)]
+pub enum PpfError {
+    FormatMarker { received: [u8; 5] },
+    Version(u8),
+    TruncatedRecord { offset: u64 },
+    SourceSize { expected: u64, received: u64 },
+    BlockCheck,
+    MissingSource,
+    WrongFormat { extension: &'static str },
+}
+
+impl fmt::Display for PpfError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PpfError::FormatMarker { received } => {
+                write!(formatter, "invalid format marker (received: {:?})", received)
+            }
+            PpfError::Version(version) => write!(formatter, "unsupported PPF version {}", version),
+            PpfError::TruncatedRecord { offset } => {
+                write!(formatter, "truncated record at patch offset {}", offset)
+            }
+            PpfError::SourceSize { expected, received } => write!(
+                formatter,
+                "source size mismatch (expected: {}, received: {})",
+                expected, received
+            ),
+            PpfError::BlockCheck => write!(formatter, "source ROM doesn't match the block check data"),
+            PpfError::MissingSource => write!(formatter, "no source ROM was given"),
+            PpfError::WrongFormat { extension } => write!(
+                formatter,
+                "not a PPF patch, the file is in the {} format (rename it to .{})",
+                extension.to_ascii_uppercase(),
+                extension
+            ),
+        }
+    }
+}
+
+impl Error for PpfError {}
+
+/// Data of the source ROM recorded by PPF 2.0 and 3.0 patches, identifying the disc image they apply to.
+#[derive(Clone, Debug)]
+pub struct PpfBlockCheck {
+    pub offset: u64,
+    pub data: Vec<u8>,
+}
+
+impl PpfBlockCheck {
+    pub fn matches(&self, source_path: &Path) -> io::Result<bool> {
+        let mut source_file = File::open(source_path)?;
+        source_file.seek(SeekFrom::Start(self.offset))?;
+
+        let mut data = vec![0; self.data.len()];
+        match source_file.read_exact(&mut data) {
+            Ok(()) => Ok(data == self.data),
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PpfPatch {
+    source_path: Option<PathBuf>,
+    source_size: u64,
+
+    version: u8,
+    description: String,
+    input_size: Option<u64>,
+    block_check: Option<PpfBlockCheck>,
+
+    /// Bytes overwritten by the patch, at absolute offsets of the target ROM.
+    records: Vec<(u64, Vec<u8>)>,
+
+    patch_modified: SystemTime,
+    patch_created: Option<SystemTime>,
+    source_cache: Option<Arc<SourceCache>>,
+}
+
+impl PpfPatch {
+    /// Loads a PPF 1.0, 2.0 or 3.0 patch.
+    pub fn new(patch_path: &Path) -> Result<Self, Box<dyn Error>> {
+        let patch_data = fs::read(patch_path)?;
+        let patch_file_metadata = fs::metadata(patch_path)?;
+        let mut patch_file = Cursor::new(&patch_data);
+
+        let mut format_marker: [u8; 5] = [0; 5];
+        patch_file.read_exact(&mut format_marker)?;
+        if format_marker[0..3] != PPF_MAGIC {
+            if let Some(extension) = detect_format(&format_marker) {
+                return Err(Box::new(PpfError::WrongFormat { extension }));
+            }
+            return Err(Box::new(PpfError::FormatMarker {
+                received: format_marker,
+            }));
+        }
+
+        let version = match &format_marker[3..5] {
+            b"10" => 1,
+            b"20" => 2,
+            b"30" => 3,
+            _ => {
+                return Err(Box::new(PpfError::FormatMarker {
+                    received: format_marker,
+                }))
+            }
+        };
+
+        // The encoding method is the version, less one
+        let encoding_method = patch_file.read_u8()?;
+        if encoding_method != version - 1 {
+            return Err(Box::new(PpfError::Version(encoding_method + 1)));
+        }
+
+        let mut description = [0; PPF_DESCRIPTION_SIZE];
+        patch_file.read_exact(&mut description)?;
+        let description = String::from_utf8_lossy(&description)
+            .trim_end_matches(&['\0', ' '][..])
+            .to_owned();
+
+        let mut input_size = None;
+        let mut block_check = None;
+        let mut undo_data = false;
+
+        match version {
+            2 => {
+                input_size = Some(patch_file.read_u32::<LittleEndian>()? as u64);
+
+                let mut data = vec![0; PPF_BLOCK_CHECK_SIZE];
+                patch_file.read_exact(&mut data)?;
+                block_check = Some(PpfBlockCheck {
+                    offset: PPF_BIN_BLOCK_CHECK_OFFSET,
+                    data,
+                });
+            }
+            3 => {
+                let image_type = patch_file.read_u8()?;
+                let has_block_check = patch_file.read_u8()? != 0;
+                undo_data = patch_file.read_u8()? != 0;
+                let _reserved = patch_file.read_u8()?;
+
+                if has_block_check {
+                    let mut data = vec![0; PPF_BLOCK_CHECK_SIZE];
+                    patch_file.read_exact(&mut data)?;
+                    block_check = Some(PpfBlockCheck {
+                        offset: if image_type == 1 {
+                            PPF_GI_BLOCK_CHECK_OFFSET
+                        } else {
+                            PPF_BIN_BLOCK_CHECK_OFFSET
+                        },
+                        data,
+                    });
+                }
+            }
+            _ => {}
+        }
+
+        // PPF 2.0 and 3.0 patches may end with a FILE_ID.DIZ text, stored after the records
+        let records_end = {
+            let length_size = if version == 3 { 2 } else { 4 };
+            let end_marker_offset = patch_data.len().checked_sub(FILE_ID_END_MARKER.len() + length_size);
+
+            match end_marker_offset {
+                Some(offset) if version > 1 && patch_data[offset..].starts_with(FILE_ID_END_MARKER) => {
+                    let mut length = &patch_data[(offset + FILE_ID_END_MARKER.len())..];
+                    let text_length = if version == 3 {
+                        length.read_u16::<LittleEndian>()? as usize
+                    } else {
+                        length.read_u32::<LittleEndian>()? as usize
+                    };
+                    offset.saturating_sub(text_length + FILE_ID_BEGIN_MARKER.len()) as u64
+                }
+                _ => patch_data.len() as u64,
+            }
+        };
+
+        let mut records = Vec::new();
+        while patch_file.position() < records_end {
+            let record_offset = patch_file.position();
+            let truncated = |_| PpfError::TruncatedRecord { offset: record_offset };
+
+            let offset = if version == 3 {
+                patch_file.read_u64::<LittleEndian>().map_err(truncated)?
+            } else {
+                patch_file.read_u32::<LittleEndian>().map_err(truncated)? as u64
+            };
+
+            let size = patch_file.read_u8().map_err(truncated)? as usize;
+            let mut data = vec![0; size];
+            patch_file.read_exact(&mut data).map_err(truncated)?;
+
+            if undo_data {
+                patch_file.seek(SeekFrom::Current(size as i64))?;
+            }
+
+            if patch_file.position() > records_end {
+                return Err(Box::new(PpfError::TruncatedRecord { offset: record_offset }));
+            }
+
+            records.push((offset, data));
+        }
+
+        Ok(Self {
+            source_path: None,
+            source_size: 0,
+            version,
+            description,
+            input_size,
+            block_check,
+            records,
+            patch_modified: patch_file_metadata.modified()?,
+            patch_created: patch_file_metadata.created().ok(),
+            source_cache: None,
+        })
+    }
+
+    pub fn set_source_path(&mut self, source_path: &Path) -> io::Result<()> {
+        self.source_size = fs::metadata(source_path)?.len();
+        self.source_path = Some(source_path.to_path_buf());
+        Ok(())
+    }
+
+    /// Reads the source ROM through a cache shared with other patches.
+    pub fn set_source_cache(&mut self, source_cache: Arc<SourceCache>) {
+        self.source_cache = Some(source_cache);
+    }
+
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// Identifies the source ROM, PPF 1.0 patches and some PPF 3.0 patches don't record their source ROM.
+    pub fn block_check(&self) -> Option<&PpfBlockCheck> {
+        self.block_check.as_ref()
+    }
+}
+
+impl Patch for PpfPatch {
+    fn target_size(&self) -> u64 {
+        // Records may write past the end of the source ROM
+        self.records
+            .iter()
+            .map(|(offset, data)| offset + data.len() as u64)
+            .fold(self.source_size, cmp::max)
+    }
+
+    fn source_path(&self) -> Option<&Path> {
+        self.source_path.as_deref()
+    }
+
+    fn format(&self) -> Option<&'static str> {
+        Some("ppf")
+    }
+
+    fn modified(&self) -> Option<SystemTime> {
+        Some(self.patch_modified)
+    }
+
+    fn created(&self) -> Option<SystemTime> {
+        self.patch_created
+    }
+
+    fn operation_count(&self) -> Option<usize> {
+        Some(self.records.len())
+    }
+
+    fn title(&self) -> Option<&str> {
+        Some(self.description.as_str()).filter(|description| !description.is_empty())
+    }
+
+    fn validate(&self) -> Result<(), Box<dyn Error>> {
+        let source_path = self.source_path.as_deref().ok_or(PpfError::MissingSource)?;
+
+        if let Some(input_size) = self.input_size {
+            let source_size = fs::metadata(source_path)?.len();
+            if source_size != input_size {
+                return Err(Box::new(PpfError::SourceSize {
+                    expected: input_size,
+                    received: source_size,
+                }));
+            }
+        }
+
+        if let Some(block_check) = &self.block_check {
+            if !block_check.matches(source_path)? {
+                return Err(Box::new(PpfError::BlockCheck));
+            }
+        }
+        Ok(())
+    }
+
+    fn patched_rom(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let source_path = self.source_path.as_deref().ok_or(PpfError::MissingSource)?;
+        let mut target = SourceCache::load(self.source_cache.as_deref(), source_path)?.to_vec();
+
+        if let Some(input_size) = self.input_size {
+            if target.len() as u64 != input_size {
+                return Err(Box::new(PpfError::SourceSize {
+                    expected: input_size,
+                    received: target.len() as u64,
+                }));
+            }
+        }
+
+        if let Some(block_check) = &self.block_check {
+            let start = block_check.offset as usize;
+            if target.get(start..(start + block_check.data.len())) != Some(&block_check.data[..]) {
+                return Err(Box::new(PpfError::BlockCheck));
+            }
+        }
+
+        target.resize(self.target_size() as usize, 0);
+        for (offset, data) in &self.records {
+            let offset = *offset as usize;
+            target[offset..(offset + data.len())].copy_from_slice(data);
+        }
+
+        Ok(target)
+    }
+}
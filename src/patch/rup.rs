@@ -0,0 +1,397 @@
+use std::cmp;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use byteorder::ReadBytesExt;
+
+use crate::patch::{detect_format, Patch};
+use crate::source_cache::SourceCache;
+
+const RUP_FORMAT_MARKER: [u8; 6] = [b'N', b'I', b'N', b'J', b'A', b'2'];
+
+/// Offsets of the text fields of the header, the commands follow the header.
+const RUP_AUTHOR_RANGE: (usize, usize) = (0x007, 0x05B);
+const RUP_TITLE_RANGE: (usize, usize) = (0x066, 0x166);
+const RUP_COMMANDS_OFFSET: u64 = 0x800;
+
+const RUP_COMMAND_END: u8 = 0x00;
+const RUP_COMMAND_OPEN_FILE: u8 = 0x01;
+const RUP_COMMAND_XOR_RECORD: u8 = 0x02;
+
+/// Overflow modes of files changing size, the overflow data is only needed by growing files.
+const RUP_OVERFLOW_APPEND: u8 = b'A';
+const RUP_OVERFLOW_MINIFY: u8 = b'M';
+
+#[derive(Debug)]
+pub enum RupError {
+    FormatMarker { expected: [u8; 6], received: Vec<u8> },
+    Command(u8),
+    OverflowMode(u8),
+    MissingFile,
+    SourceLength { expected: u64, received: u64 },
+    SourceChecksum { expected: [u8; 16], received: [u8; 16] },
+    TargetChecksum { expected: [u8; 16], received: [u8; 16] },
+    MissingSource,
+    WrongFormat { extension: &'static str },
+}
+
+impl fmt::Display for RupError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RupError::FormatMarker { expected, received } => write!(
+                formatter,
+                "invalid format marker (expected: {:?}, received: {:?})",
+                expected, received
+            ),
+            RupError::Command(command) => write!(formatter, "unknown command 0x{:02X}", command),
+            RupError::OverflowMode(mode) => write!(formatter, "unknown overflow mode 0x{:02X}", mode),
+            RupError::MissingFile => write!(formatter, "record before the first file"),
+            RupError::SourceLength { expected, received } => write!(
+                formatter,
+                "source length mismatch (expected: {}, received: {})",
+                expected, received
+            ),
+            RupError::SourceChecksum { expected, received } => write!(
+                formatter,
+                "invalid source checksum (expected: {}, received: {})",
+                hex(expected),
+                hex(received)
+            ),
+            RupError::TargetChecksum { expected, received } => write!(
+                formatter,
+                "invalid target checksum (expected: {}, received: {})",
+                hex(expected),
+                hex(received)
+            ),
+            RupError::MissingSource => write!(formatter, "no source ROM was given"),
+            RupError::WrongFormat { extension } => write!(
+                formatter,
+                "not a RUP patch, the file is in the {} format (rename it to .{})",
+                extension.to_ascii_uppercase(),
+                extension
+            ),
+        }
+    }
+}
+
+impl Error for RupError {}
+
+fn hex(digest: &[u8; 16]) -> String {
+    digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Reads a variable length value, a byte count followed by the bytes of the value in little-endian order.
+fn read_vlv<R: Read>(reader: &mut R) -> Result<u64, Box<dyn Error>> {
+    let length = reader.read_u8()?;
+    let mut value: u64 = 0;
+    for index in 0..length {
+        let byte = reader.read_u8()? as u64;
+        if index < 8 {
+            value |= byte << (index * 8);
+        }
+    }
+    Ok(value)
+}
+
+/// Reads a variable length value followed by as many bytes of data. Lengths reaching past the end
+/// of the patch fail without allocating them, so corrupt lengths can't exhaust the memory.
+fn read_vlv_data<R: Read>(reader: &mut R) -> Result<Vec<u8>, Box<dyn Error>> {
+    let length = read_vlv(reader)?;
+    let mut data = Vec::new();
+    reader.take(length).read_to_end(&mut data)?;
+    if data.len() as u64 != length {
+        return Err(Box::new(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("data past the end of the patch (length: {})", length),
+        )));
+    }
+    Ok(data)
+}
+
+fn read_text(data: &[u8], (start, end): (usize, usize)) -> String {
+    String::from_utf8_lossy(data.get(start..end).unwrap_or_default())
+        .trim_end_matches(&['\0', ' '][..])
+        .to_owned()
+}
+
+/// A patch made by NINJA 2.0, holding patches for several files.
+#[derive(Clone, Debug)]
+pub struct RupPatch {
+    files: Vec<RupFilePatch>,
+}
+
+impl RupPatch {
+    pub fn new(patch_path: &Path) -> Result<Self, Box<dyn Error>> {
+        let patch_data = fs::read(patch_path)?;
+        let patch_file_metadata = fs::metadata(patch_path)?;
+        let patch_modified = patch_file_metadata.modified()?;
+        let patch_created = patch_file_metadata.created().ok();
+
+        if !patch_data.starts_with(&RUP_FORMAT_MARKER) {
+            if let Some(extension) = detect_format(&patch_data) {
+                return Err(Box::new(RupError::WrongFormat { extension }));
+            }
+            return Err(Box::new(RupError::FormatMarker {
+                expected: RUP_FORMAT_MARKER,
+                received: patch_data.iter().take(RUP_FORMAT_MARKER.len()).cloned().collect(),
+            }));
+        }
+
+        let title = read_text(&patch_data, RUP_TITLE_RANGE);
+        let author = read_text(&patch_data, RUP_AUTHOR_RANGE);
+
+        let mut patch_file = Cursor::new(&patch_data);
+        patch_file.seek(SeekFrom::Start(RUP_COMMANDS_OFFSET))?;
+
+        let mut files: Vec<RupFilePatch> = Vec::new();
+        loop {
+            match patch_file.read_u8()? {
+                RUP_COMMAND_END => break,
+                RUP_COMMAND_OPEN_FILE => {
+                    let file_name = read_vlv_data(&mut patch_file)?;
+                    let _rom_type = patch_file.read_u8()?;
+                    let source_size = read_vlv(&mut patch_file)?;
+                    let target_size = read_vlv(&mut patch_file)?;
+
+                    let mut source_md5 = [0; 16];
+                    patch_file.read_exact(&mut source_md5)?;
+                    let mut target_md5 = [0; 16];
+                    patch_file.read_exact(&mut target_md5)?;
+
+                    let mut overflow = Vec::new();
+                    if source_size != target_size {
+                        let overflow_mode = patch_file.read_u8()?;
+                        if overflow_mode != RUP_OVERFLOW_APPEND && overflow_mode != RUP_OVERFLOW_MINIFY {
+                            return Err(Box::new(RupError::OverflowMode(overflow_mode)));
+                        }
+
+                        overflow = read_vlv_data(&mut patch_file)?;
+                        overflow.iter_mut().for_each(|byte| *byte ^= 0xFF);
+
+                        // Shrinking files keep the cut off end of the source, for undoing the patch
+                        if overflow_mode == RUP_OVERFLOW_MINIFY {
+                            overflow.clear();
+                        }
+                    }
+
+                    files.push(RupFilePatch {
+                        file_name: String::from_utf8_lossy(&file_name).into_owned(),
+                        source_path: None,
+                        source_size,
+                        source_md5,
+                        target_size,
+                        target_md5,
+                        overflow,
+                        records: Vec::new(),
+                        title: title.clone(),
+                        author: author.clone(),
+                        patch_modified,
+                        patch_created,
+                        source_cache: None,
+                    });
+                }
+                RUP_COMMAND_XOR_RECORD => {
+                    let file = files.last_mut().ok_or(RupError::MissingFile)?;
+                    let offset = read_vlv(&mut patch_file)?;
+                    let data = read_vlv_data(&mut patch_file)?;
+                    file.records.push((offset, data));
+                }
+                command => return Err(Box::new(RupError::Command(command))),
+            }
+        }
+
+        Ok(Self { files })
+    }
+
+    /// The patches of the files, each one patching a different source ROM.
+    pub fn files(&self) -> &[RupFilePatch] {
+        &self.files
+    }
+}
+
+/// The patch of a single file of a RUP patch.
+#[derive(Clone, Debug)]
+pub struct RupFilePatch {
+    file_name: String,
+    source_path: Option<PathBuf>,
+    source_size: u64,
+    source_md5: [u8; 16],
+    target_size: u64,
+    target_md5: [u8; 16],
+
+    /// Bytes appended to the source ROM, for files growing by the patch.
+    overflow: Vec<u8>,
+    /// Bytes XORed with the source ROM, at absolute offsets of the target ROM.
+    records: Vec<(u64, Vec<u8>)>,
+
+    title: String,
+    author: String,
+    patch_modified: SystemTime,
+    patch_created: Option<SystemTime>,
+    source_cache: Option<Arc<SourceCache>>,
+}
+
+impl RupFilePatch {
+    /// Name of the patched file, as recorded by the patch.
+    pub fn file_name(&self) -> &str {
+        &self.file_name
+    }
+
+    pub fn source_md5(&self) -> [u8; 16] {
+        self.source_md5
+    }
+
+    pub fn set_source_path(&mut self, source_path: &Path) {
+        self.source_path = Some(source_path.to_path_buf());
+    }
+
+    /// Reads the source ROM through a cache shared with other patches.
+    pub fn set_source_cache(&mut self, source_cache: Arc<SourceCache>) {
+        self.source_cache = Some(source_cache);
+    }
+}
+
+impl Patch for RupFilePatch {
+    fn target_size(&self) -> u64 {
+        self.target_size
+    }
+
+    fn source_path(&self) -> Option<&Path> {
+        self.source_path.as_deref()
+    }
+
+    fn format(&self) -> Option<&'static str> {
+        Some("rup")
+    }
+
+    fn modified(&self) -> Option<SystemTime> {
+        Some(self.patch_modified)
+    }
+
+    fn created(&self) -> Option<SystemTime> {
+        self.patch_created
+    }
+
+    fn operation_count(&self) -> Option<usize> {
+        Some(self.records.len())
+    }
+
+    fn title(&self) -> Option<&str> {
+        Some(self.title.as_str()).filter(|title| !title.is_empty())
+    }
+
+    fn author(&self) -> Option<&str> {
+        Some(self.author.as_str()).filter(|author| !author.is_empty())
+    }
+
+    fn validate(&self) -> Result<(), Box<dyn Error>> {
+        let source_path = self.source_path.as_deref().ok_or(RupError::MissingSource)?;
+        let source_size = fs::metadata(source_path)?.len();
+        if source_size != self.source_size {
+            return Err(Box::new(RupError::SourceLength {
+                expected: self.source_size,
+                received: source_size,
+            }));
+        }
+        Ok(())
+    }
+
+    fn patched_rom(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let source_path = self.source_path.as_deref().ok_or(RupError::MissingSource)?;
+        let source = SourceCache::load(self.source_cache.as_deref(), source_path)?;
+
+        if source.len() as u64 != self.source_size {
+            return Err(Box::new(RupError::SourceLength {
+                expected: self.source_size,
+                received: source.len() as u64,
+            }));
+        }
+
+        let source_md5 = md5::compute(&source[..]).0;
+        if source_md5 != self.source_md5 {
+            return Err(Box::new(RupError::SourceChecksum {
+                expected: self.source_md5,
+                received: source_md5,
+            }));
+        }
+
+        let target_size = self.target_size as usize;
+        let mut target = source[..cmp::min(source.len(), target_size)].to_vec();
+        target.extend_from_slice(&self.overflow);
+        target.resize(target_size, 0);
+
+        // Records are XORed with the source ROM, past its end with zeroes
+        for (offset, data) in &self.records {
+            for (index, byte) in data.iter().enumerate() {
+                let offset = *offset as usize + index;
+                if offset < target_size {
+                    target[offset] = source.get(offset).cloned().unwrap_or(0) ^ byte;
+                }
+            }
+        }
+
+        let target_md5 = md5::compute(&target).0;
+        if target_md5 != self.target_md5 {
+            return Err(Box::new(RupError::TargetChecksum {
+                expected: self.target_md5,
+                received: target_md5,
+            }));
+        }
+
+        Ok(target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::make_dir_fixture;
+
+    /// A patch with the given commands after the header, ended by the end command.
+    fn make_rup(commands: &[u8]) -> Vec<u8> {
+        let mut patch = RUP_FORMAT_MARKER.to_vec();
+        patch.resize(RUP_COMMANDS_OFFSET as usize, 0);
+        patch.extend_from_slice(commands);
+        patch.push(RUP_COMMAND_END);
+        patch
+    }
+
+    fn parse(patch: &[u8]) -> Result<RupPatch, Box<dyn Error>> {
+        let directory = make_dir_fixture(&[("hack.rup", patch)]);
+        RupPatch::new(&directory.path().join("hack.rup"))
+    }
+
+    #[test]
+    fn parses_variable_length_data() {
+        let mut commands = vec![RUP_COMMAND_OPEN_FILE, 1, 8];
+        commands.extend_from_slice(b"game.sfc");
+        commands.extend_from_slice(&[0, 1, 4, 1, 4]);
+        commands.extend_from_slice(&[0; 32]);
+        commands.extend_from_slice(&[RUP_COMMAND_XOR_RECORD, 1, 2, 1, 3, 0xAA, 0xBB, 0xCC]);
+
+        let patch = parse(&make_rup(&commands)).unwrap();
+        let file = &patch.files()[0];
+        assert_eq!(file.file_name, "game.sfc");
+        assert_eq!(file.records, [(2, vec![0xAA, 0xBB, 0xCC])]);
+    }
+
+    #[test]
+    fn rejects_lengths_past_the_end_of_the_patch() {
+        // A file name as long as the largest length, which would be allocated upfront
+        let mut commands = vec![RUP_COMMAND_OPEN_FILE, 8];
+        commands.extend_from_slice(&[0xFF; 8]);
+        assert!(parse(&make_rup(&commands)).is_err());
+
+        // A record a byte longer than the rest of the patch, the end command included
+        let mut commands = vec![RUP_COMMAND_OPEN_FILE, 1, 1, b'a', 0, 1, 4, 1, 4];
+        commands.extend_from_slice(&[0; 32]);
+        commands.extend_from_slice(&[RUP_COMMAND_XOR_RECORD, 1, 0, 1, 4, 0xAA, 0xBB]);
+        let err = parse(&make_rup(&commands)).unwrap_err();
+        assert!(err.to_string().starts_with("data past the end of the patch"), "{}", err);
+    }
+}
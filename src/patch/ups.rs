@@ -0,0 +1,290 @@
+use std::cmp;
+use std::error::Error;
+use std::fmt;
+use std::io::{Cursor, Write};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use crc::crc32;
+
+use crate::utils::{ReadExt, WriteExt};
+
+const UPS_FORMAT_MARKER: [u8; 4] = [b'U', b'P', b'S', b'1'];
+const UPS_FOOTER_SIZE: usize = 12;
+
+#[derive(Debug)]
+pub enum UpsError {
+    FormatMarker { expected: [u8; 4], received: Vec<u8> },
+    SourceLength { expected: u64, received: u64 },
+    SourceChecksum { expected: u32, received: u32 },
+    TargetChecksum { expected: u32, received: u32 },
+    PatchChecksum { expected: u32, received: u32 },
+}
+
+impl fmt::Display for UpsError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UpsError::FormatMarker { expected, received } => write!(
+                formatter,
+                "invalid format marker (expected: {:?}, received: {:?})",
+                expected, received
+            ),
+            UpsError::SourceLength { expected, received } => write!(
+                formatter,
+                "source length mismatch (expected: {}, received: {})",
+                expected, received
+            ),
+            UpsError::SourceChecksum { expected, received } => write!(
+                formatter,
+                "invalid source checksum (expected: 0x{:08X}, received: 0x{:08X})",
+                expected, received
+            ),
+            UpsError::TargetChecksum { expected, received } => write!(
+                formatter,
+                "invalid target checksum (expected: 0x{:08X}, received: 0x{:08X})",
+                expected, received
+            ),
+            UpsError::PatchChecksum { expected, received } => write!(
+                formatter,
+                "invalid patch checksum (expected: 0x{:08X}, received: 0x{:08X})",
+                expected, received
+            ),
+        }
+    }
+}
+
+impl Error for UpsError {}
+
+/// A UPS patch, XORing the source ROM with the patch data.
+///
+/// UPS patches are only created and applied in memory so far, they aren't picked up from the base directory.
+pub struct UpsPatch {
+    source_size: u64,
+    target_size: u64,
+    source_checksum: u32,
+    target_checksum: u32,
+    patch_data: Vec<u8>,
+    /// Start of the hunks, after the header.
+    hunks_offset: u64,
+}
+
+impl UpsPatch {
+    /// Creates a patch turning `source` into `target`, in the format of a `.ups` file.
+    /// Every hunk skips the unchanged bytes since the previous hunk, then XORs the changed bytes
+    /// up to a zero byte. Bytes past the end of the source ROM are XORed with zeroes.
+    pub fn create(source: &[u8], target: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let xor = |offset: usize| source.get(offset).cloned().unwrap_or(0) ^ target[offset];
+
+        let mut patch = Vec::new();
+        patch.write_all(&UPS_FORMAT_MARKER)?;
+        patch.write_vlq(source.len() as u64)?;
+        patch.write_vlq(target.len() as u64)?;
+
+        let mut offset = 0;
+        let mut hunk_offset = 0;
+        while offset < target.len() {
+            if xor(offset) == 0 {
+                offset += 1;
+                continue;
+            }
+
+            patch.write_vlq((offset - hunk_offset) as u64)?;
+            while offset < target.len() && xor(offset) != 0 {
+                patch.write_u8(xor(offset))?;
+                offset += 1;
+            }
+
+            // The terminating zero byte stands for the unchanged byte ending the hunk
+            patch.write_u8(0)?;
+            offset += 1;
+            hunk_offset = offset;
+        }
+
+        patch.write_u32::<LittleEndian>(crc32::checksum_ieee(source))?;
+        patch.write_u32::<LittleEndian>(crc32::checksum_ieee(target))?;
+        let patch_checksum = crc32::checksum_ieee(&patch);
+        patch.write_u32::<LittleEndian>(patch_checksum)?;
+
+        Ok(patch)
+    }
+
+    pub fn parse(patch_data: Vec<u8>) -> Result<Self, Box<dyn Error>> {
+        if !patch_data.starts_with(&UPS_FORMAT_MARKER) || patch_data.len() < UPS_FORMAT_MARKER.len() + UPS_FOOTER_SIZE {
+            return Err(Box::new(UpsError::FormatMarker {
+                expected: UPS_FORMAT_MARKER,
+                received: patch_data.iter().take(UPS_FORMAT_MARKER.len()).cloned().collect(),
+            }));
+        }
+
+        let mut footer = &patch_data[(patch_data.len() - UPS_FOOTER_SIZE)..];
+        let source_checksum = footer.read_u32::<LittleEndian>()?;
+        let target_checksum = footer.read_u32::<LittleEndian>()?;
+        let patch_checksum = footer.read_u32::<LittleEndian>()?;
+
+        let received_checksum = crc32::checksum_ieee(&patch_data[..(patch_data.len() - 4)]);
+        if received_checksum != patch_checksum {
+            return Err(Box::new(UpsError::PatchChecksum {
+                expected: patch_checksum,
+                received: received_checksum,
+            }));
+        }
+
+        let mut patch_file = Cursor::new(&patch_data);
+        patch_file.set_position(UPS_FORMAT_MARKER.len() as u64);
+        let source_size = patch_file.read_vlq()?;
+        let target_size = patch_file.read_vlq()?;
+        let hunks_offset = patch_file.position();
+
+        Ok(Self {
+            source_size,
+            target_size,
+            source_checksum,
+            target_checksum,
+            patch_data,
+            hunks_offset,
+        })
+    }
+
+    pub fn source_size(&self) -> u64 {
+        self.source_size
+    }
+
+    pub fn target_size(&self) -> u64 {
+        self.target_size
+    }
+
+    /// Applies the patch to `source`, verifying both the source and the patched ROM.
+    pub fn apply(&self, source: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        if source.len() as u64 != self.source_size {
+            return Err(Box::new(UpsError::SourceLength {
+                expected: self.source_size,
+                received: source.len() as u64,
+            }));
+        }
+
+        let source_checksum = crc32::checksum_ieee(source);
+        if source_checksum != self.source_checksum {
+            return Err(Box::new(UpsError::SourceChecksum {
+                expected: self.source_checksum,
+                received: source_checksum,
+            }));
+        }
+
+        let target_size = self.target_size as usize;
+        let mut target = source[..cmp::min(source.len(), target_size)].to_vec();
+        target.resize(target_size, 0);
+
+        let hunks_end = (self.patch_data.len() - UPS_FOOTER_SIZE) as u64;
+        let mut patch_file = Cursor::new(&self.patch_data);
+        patch_file.set_position(self.hunks_offset);
+
+        let mut offset: u64 = 0;
+        while patch_file.position() < hunks_end {
+            offset = offset.saturating_add(patch_file.read_vlq()?);
+            loop {
+                let byte = patch_file.read_u8()?;
+                if byte == 0 {
+                    offset = offset.saturating_add(1);
+                    break;
+                }
+                // Hunks reaching past the end of the target ROM only XOR the bytes it keeps
+                if let Some(target_byte) = target.get_mut(offset as usize) {
+                    *target_byte ^= byte;
+                }
+                offset = offset.saturating_add(1);
+            }
+        }
+
+        let target_checksum = crc32::checksum_ieee(&target);
+        if target_checksum != self.target_checksum {
+            return Err(Box::new(UpsError::TargetChecksum {
+                expected: self.target_checksum,
+                received: target_checksum,
+            }));
+        }
+
+        Ok(target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(source: &[u8], target: &[u8]) -> Vec<u8> {
+        let patch = UpsPatch::parse(UpsPatch::create(source, target).unwrap()).unwrap();
+        assert_eq!(patch.source_size(), source.len() as u64);
+        assert_eq!(patch.target_size(), target.len() as u64);
+        patch.apply(source).unwrap()
+    }
+
+    #[test]
+    fn round_trips_same_length() {
+        let source: Vec<u8> = (0..=255).collect();
+        let mut target = source.clone();
+        target[0] = 0xFF;
+        target[10..20].fill(0xAA);
+        target[255] = 0;
+
+        assert_eq!(round_trip(&source, &target), target);
+    }
+
+    #[test]
+    fn round_trips_identical_roms() {
+        let source = vec![0x42; 64];
+        let patch = UpsPatch::create(&source, &source).unwrap();
+
+        // Only the header and the footer
+        assert_eq!(patch.len(), UPS_FORMAT_MARKER.len() + 2 + UPS_FOOTER_SIZE);
+        assert_eq!(round_trip(&source, &source), source);
+    }
+
+    #[test]
+    fn round_trips_different_lengths() {
+        // Sizes over 127 bytes take multi-byte VLQ values in the header
+        let source: Vec<u8> = (0..300).map(|i| (i * 7) as u8).collect();
+
+        let mut longer = source.clone();
+        longer.extend((0..20000).map(|i| (i % 251) as u8));
+        longer[5] ^= 0x01;
+        assert_eq!(round_trip(&source, &longer), longer);
+
+        let shorter = source[..100].to_vec();
+        assert_eq!(round_trip(&source, &shorter), shorter);
+
+        let mut changed_shorter = source[..200].to_vec();
+        changed_shorter[199] ^= 0x80;
+        assert_eq!(round_trip(&source, &changed_shorter), changed_shorter);
+    }
+
+    #[test]
+    fn writes_vlq_lengths() {
+        let source = vec![0; 128];
+        let target = vec![0; 16384 + 128];
+        let patch = UpsPatch::create(&source, &target).unwrap();
+
+        // Sizes of 128 and 16512 are the shortest ones taking two and three bytes
+        assert_eq!(&patch[4..6], &[0x00, 0x80]);
+        assert_eq!(&patch[6..9], &[0x00, 0x00, 0x80]);
+    }
+
+    #[test]
+    fn rejects_wrong_source() {
+        let source = vec![1, 2, 3, 4];
+        let patch = UpsPatch::parse(UpsPatch::create(&source, &[4, 3, 2, 1]).unwrap()).unwrap();
+
+        let err = patch.apply(&[1, 2, 3, 5]).unwrap_err();
+        assert!(err.to_string().starts_with("invalid source checksum"));
+
+        let err = patch.apply(&[1, 2, 3]).unwrap_err();
+        assert!(err.to_string().starts_with("source length mismatch"));
+    }
+
+    #[test]
+    fn rejects_corrupt_patch() {
+        let mut patch = UpsPatch::create(&[1, 2, 3, 4], &[4, 3, 2, 1]).unwrap();
+        patch[5] ^= 0xFF;
+        assert!(UpsPatch::parse(patch).is_err());
+
+        assert!(UpsPatch::parse(b"BPS1".to_vec()).is_err());
+    }
+}
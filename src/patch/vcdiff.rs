@@ -0,0 +1,538 @@
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use crate::patch::{detect_format, Patch};
+use crate::source_cache::SourceCache;
+
+const VCDIFF_FORMAT_MARKER: [u8; 3] = [0xD6, 0xC3, 0xC4];
+
+// Bits of the header indicator
+const VCD_DECOMPRESS: u8 = 0x01;
+const VCD_CODETABLE: u8 = 0x02;
+const VCD_APPHEADER: u8 = 0x04;
+
+// Bits of the window indicator, the checksum is an extension of xdelta3
+const VCD_SOURCE: u8 = 0x01;
+const VCD_TARGET: u8 = 0x02;
+const VCD_ADLER32: u8 = 0x04;
+
+const NEAR_CACHE_SIZE: usize = 4;
+const SAME_CACHE_SIZE: usize = 3;
+
+const ADLER32_MODULUS: u32 = 65521;
+
+#[derive(Debug)]
+pub enum VcdiffError {
+    FormatMarker { expected: [u8; 3], received: Vec<u8> },
+    Unsupported(&'static str),
+    Truncated,
+    InvalidAddress { address: u64, limit: u64 },
+    InvalidSegment { position: u64, size: u64, limit: u64 },
+    TargetLength { expected: u64, received: u64 },
+    TargetChecksum { expected: u32, received: u32 },
+    MissingSource,
+    WrongFormat { extension: &'static str },
+}
+
+impl fmt::Display for VcdiffError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VcdiffError::FormatMarker { expected, received } => write!(
+                formatter,
+                "invalid format marker (expected: {:?}, received: {:?})",
+                expected, received
+            ),
+            VcdiffError::Unsupported(feature) => write!(formatter, "unsupported VCDIFF feature: {}", feature),
+            VcdiffError::Truncated => write!(formatter, "truncated patch"),
+            VcdiffError::InvalidAddress { address, limit } => write!(
+                formatter,
+                "copy address out of bounds (address: {}, limit: {})",
+                address, limit
+            ),
+            VcdiffError::InvalidSegment { position, size, limit } => write!(
+                formatter,
+                "source segment out of bounds (position: {}, size: {}, limit: {})",
+                position, size, limit
+            ),
+            VcdiffError::TargetLength { expected, received } => write!(
+                formatter,
+                "target window length mismatch (expected: {}, received: {})",
+                expected, received
+            ),
+            VcdiffError::TargetChecksum { expected, received } => write!(
+                formatter,
+                "invalid target window checksum (expected: 0x{:08X}, received: 0x{:08X})",
+                expected, received
+            ),
+            VcdiffError::MissingSource => write!(formatter, "no source ROM was given"),
+            VcdiffError::WrongFormat { extension } => write!(
+                formatter,
+                "not an xdelta patch, the file is in the {} format (rename it to .{})",
+                extension.to_ascii_uppercase(),
+                extension
+            ),
+        }
+    }
+}
+
+impl Error for VcdiffError {}
+
+#[derive(Clone, Copy)]
+enum Instruction {
+    Noop,
+    Add,
+    Run,
+    Copy,
+}
+
+/// An entry of the code table, a pair of instructions along with their sizes and address modes.
+/// Instructions of size zero have their size stored in the instruction section.
+type CodeTableEntry = [(Instruction, usize, u8); 2];
+
+/// The default code table of RFC 3284, section 5.6.
+fn default_code_table() -> Vec<CodeTableEntry> {
+    use Instruction::*;
+
+    let noop = (Noop, 0, 0);
+    let mut code_table = Vec::with_capacity(256);
+
+    code_table.push([(Run, 0, 0), noop]);
+    for size in 0..18 {
+        code_table.push([(Add, size, 0), noop]);
+    }
+    for mode in 0..9 {
+        code_table.push([(Copy, 0, mode), noop]);
+        for size in 4..19 {
+            code_table.push([(Copy, size, mode), noop]);
+        }
+    }
+    for mode in 0..6 {
+        for add_size in 1..5 {
+            for copy_size in 4..7 {
+                code_table.push([(Add, add_size, 0), (Copy, copy_size, mode)]);
+            }
+        }
+    }
+    for mode in 6..9 {
+        for add_size in 1..5 {
+            code_table.push([(Add, add_size, 0), (Copy, 4, mode)]);
+        }
+    }
+    for mode in 0..9 {
+        code_table.push([(Copy, 4, mode), (Add, 1, 0)]);
+    }
+
+    code_table
+}
+
+fn read_byte(data: &[u8], position: &mut usize) -> Result<u8, VcdiffError> {
+    let byte = *data.get(*position).ok_or(VcdiffError::Truncated)?;
+    *position += 1;
+    Ok(byte)
+}
+
+/// Reads a variable length integer, stored in big-endian base 128 digits.
+fn read_integer(data: &[u8], position: &mut usize) -> Result<u64, VcdiffError> {
+    let mut value: u64 = 0;
+    loop {
+        let byte = read_byte(data, position)?;
+        value = value
+            .checked_mul(128)
+            .ok_or(VcdiffError::Unsupported("integers over 64 bits"))?
+            | (byte & 0x7F) as u64;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+}
+
+fn read_section(data: &[u8], position: &mut usize, length: u64) -> Result<Range<usize>, VcdiffError> {
+    let start = *position;
+    let end = start.checked_add(length as usize).ok_or(VcdiffError::Truncated)?;
+    if end > data.len() {
+        return Err(VcdiffError::Truncated);
+    }
+    *position = end;
+    Ok(start..end)
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in data.chunks(4096) {
+        for byte in chunk {
+            a += *byte as u32;
+            b += a;
+        }
+        a %= ADLER32_MODULUS;
+        b %= ADLER32_MODULUS;
+    }
+    (b << 16) | a
+}
+
+/// Recently used copy addresses, which addresses of later copies may be encoded relative to.
+struct AddressCache {
+    near: [u64; NEAR_CACHE_SIZE],
+    next_slot: usize,
+    same: [u64; SAME_CACHE_SIZE * 256],
+}
+
+impl AddressCache {
+    fn new() -> Self {
+        Self {
+            near: [0; NEAR_CACHE_SIZE],
+            next_slot: 0,
+            same: [0; SAME_CACHE_SIZE * 256],
+        }
+    }
+
+    fn decode(&mut self, here: u64, mode: u8, addresses: &[u8], position: &mut usize) -> Result<u64, VcdiffError> {
+        let mode = mode as usize;
+        let address = match mode {
+            0 => read_integer(addresses, position)?,
+            1 => here.wrapping_sub(read_integer(addresses, position)?),
+            _ if mode < 2 + NEAR_CACHE_SIZE => self.near[mode - 2].wrapping_add(read_integer(addresses, position)?),
+            _ => self.same[(mode - 2 - NEAR_CACHE_SIZE) * 256 + read_byte(addresses, position)? as usize],
+        };
+
+        if address >= here {
+            return Err(VcdiffError::InvalidAddress { address, limit: here });
+        }
+
+        self.near[self.next_slot] = address;
+        self.next_slot = (self.next_slot + 1) % NEAR_CACHE_SIZE;
+        self.same[(address % (SAME_CACHE_SIZE * 256) as u64) as usize] = address;
+        Ok(address)
+    }
+}
+
+/// A window of the patch, producing a part of the target ROM from a segment of the source ROM
+/// or the target ROM produced so far.
+struct Window {
+    /// Position and size of the segment, and whether the segment is in the target ROM.
+    segment: Option<(u64, u64, bool)>,
+    target_length: u64,
+    data: Range<usize>,
+    instructions: Range<usize>,
+    addresses: Range<usize>,
+    checksum: Option<u32>,
+}
+
+fn parse_windows(patch_data: &[u8]) -> Result<Vec<Window>, VcdiffError> {
+    if patch_data.get(0..3) != Some(&VCDIFF_FORMAT_MARKER[..]) {
+        if let Some(extension) = detect_format(patch_data) {
+            return Err(VcdiffError::WrongFormat { extension });
+        }
+        return Err(VcdiffError::FormatMarker {
+            expected: VCDIFF_FORMAT_MARKER,
+            received: patch_data.iter().take(3).cloned().collect(),
+        });
+    }
+
+    let mut position = 4;
+    let header_indicator = read_byte(patch_data, &mut position)?;
+    if header_indicator & VCD_DECOMPRESS != 0 {
+        return Err(VcdiffError::Unsupported("secondary compression"));
+    }
+    if header_indicator & VCD_CODETABLE != 0 {
+        return Err(VcdiffError::Unsupported("custom code tables"));
+    }
+    if header_indicator & VCD_APPHEADER != 0 {
+        let length = read_integer(patch_data, &mut position)?;
+        read_section(patch_data, &mut position, length)?;
+    }
+
+    let mut windows = Vec::new();
+    while position < patch_data.len() {
+        let window_indicator = read_byte(patch_data, &mut position)?;
+
+        let segment = if window_indicator & (VCD_SOURCE | VCD_TARGET) != 0 {
+            let size = read_integer(patch_data, &mut position)?;
+            let segment_position = read_integer(patch_data, &mut position)?;
+            Some((segment_position, size, window_indicator & VCD_TARGET != 0))
+        } else {
+            None
+        };
+
+        let _delta_length = read_integer(patch_data, &mut position)?;
+        let target_length = read_integer(patch_data, &mut position)?;
+
+        if read_byte(patch_data, &mut position)? != 0 {
+            return Err(VcdiffError::Unsupported("compressed sections"));
+        }
+
+        let data_length = read_integer(patch_data, &mut position)?;
+        let instructions_length = read_integer(patch_data, &mut position)?;
+        let addresses_length = read_integer(patch_data, &mut position)?;
+
+        let checksum = if window_indicator & VCD_ADLER32 != 0 {
+            let checksum = read_section(patch_data, &mut position, 4)?;
+            let checksum = &patch_data[checksum];
+            Some(u32::from_be_bytes([checksum[0], checksum[1], checksum[2], checksum[3]]))
+        } else {
+            None
+        };
+
+        windows.push(Window {
+            segment,
+            target_length,
+            data: read_section(patch_data, &mut position, data_length)?,
+            instructions: read_section(patch_data, &mut position, instructions_length)?,
+            addresses: read_section(patch_data, &mut position, addresses_length)?,
+            checksum,
+        });
+    }
+
+    Ok(windows)
+}
+
+/// Decodes a window, appending the produced part of the target ROM to `target`.
+fn decode_window(
+    window: &Window,
+    patch_data: &[u8],
+    source: &[u8],
+    target: &mut Vec<u8>,
+    code_table: &[CodeTableEntry],
+) -> Result<(), VcdiffError> {
+    let (segment_position, segment_size, segment_in_target) = window.segment.unwrap_or((0, 0, false));
+    let segment_limit = if segment_in_target { target.len() } else { source.len() } as u64;
+    if segment_position.saturating_add(segment_size) > segment_limit {
+        return Err(VcdiffError::InvalidSegment {
+            position: segment_position,
+            size: segment_size,
+            limit: segment_limit,
+        });
+    }
+
+    let data = &patch_data[window.data.clone()];
+    let instructions = &patch_data[window.instructions.clone()];
+    let addresses = &patch_data[window.addresses.clone()];
+    let (mut data_position, mut instruction_position, mut address_position) = (0, 0, 0);
+
+    let window_start = target.len();
+    let mut address_cache = AddressCache::new();
+
+    while instruction_position < instructions.len() {
+        let entry = code_table[read_byte(instructions, &mut instruction_position)? as usize];
+
+        for &(instruction, size, mode) in &entry {
+            let size = match (instruction, size) {
+                (Instruction::Noop, _) => continue,
+                (_, 0) => read_integer(instructions, &mut instruction_position)? as usize,
+                (_, size) => size,
+            };
+
+            if (target.len() - window_start + size) as u64 > window.target_length {
+                return Err(VcdiffError::TargetLength {
+                    expected: window.target_length,
+                    received: (target.len() - window_start + size) as u64,
+                });
+            }
+
+            match instruction {
+                Instruction::Noop => {}
+                Instruction::Add => {
+                    let range = read_section(data, &mut data_position, size as u64)?;
+                    target.extend_from_slice(&data[range]);
+                }
+                Instruction::Run => {
+                    let byte = read_byte(data, &mut data_position)?;
+                    target.resize(target.len() + size, byte);
+                }
+                Instruction::Copy => {
+                    // Addresses span the segment, followed by the target window produced so far
+                    let here = segment_size + (target.len() - window_start) as u64;
+                    let address = address_cache.decode(here, mode, addresses, &mut address_position)?;
+
+                    if address + size as u64 <= segment_size && !segment_in_target {
+                        let start = (segment_position + address) as usize;
+                        target.extend_from_slice(&source[start..(start + size)]);
+                        continue;
+                    }
+
+                    // Copies from the target window may overlap with the bytes they produce
+                    for address in address..(address + size as u64) {
+                        let byte = if address < segment_size {
+                            let offset = (segment_position + address) as usize;
+                            if segment_in_target {
+                                target[offset]
+                            } else {
+                                source[offset]
+                            }
+                        } else {
+                            target[window_start + (address - segment_size) as usize]
+                        };
+                        target.push(byte);
+                    }
+                }
+            }
+        }
+    }
+
+    let produced = &target[window_start..];
+    if produced.len() as u64 != window.target_length {
+        return Err(VcdiffError::TargetLength {
+            expected: window.target_length,
+            received: produced.len() as u64,
+        });
+    }
+
+    if let Some(expected) = window.checksum {
+        let received = adler32(produced);
+        if received != expected {
+            return Err(VcdiffError::TargetChecksum { expected, received });
+        }
+    }
+
+    Ok(())
+}
+
+/// A VCDIFF patch, as made by xdelta3. Secondary compression and custom code tables are not supported.
+#[derive(Clone, Debug)]
+pub struct XdeltaPatch {
+    source_path: Option<PathBuf>,
+    patch_path: PathBuf,
+    patch_modified: SystemTime,
+    patch_created: Option<SystemTime>,
+    target_size: u64,
+    window_count: usize,
+    source_cache: Option<Arc<SourceCache>>,
+}
+
+impl XdeltaPatch {
+    pub fn new(patch_path: &Path) -> Result<Self, Box<dyn Error>> {
+        let patch_data = fs::read(patch_path)?;
+        let patch_file_metadata = fs::metadata(patch_path)?;
+        let windows = parse_windows(&patch_data)?;
+
+        Ok(Self {
+            source_path: None,
+            patch_path: patch_path.to_owned(),
+            patch_modified: patch_file_metadata.modified()?,
+            patch_created: patch_file_metadata.created().ok(),
+            target_size: windows.iter().map(|window| window.target_length).sum(),
+            window_count: windows.len(),
+            source_cache: None,
+        })
+    }
+
+    pub fn set_source_path(&mut self, source_path: &Path) {
+        self.source_path = Some(source_path.to_path_buf());
+    }
+
+    /// Reads the source ROM through a cache shared with other patches.
+    pub fn set_source_cache(&mut self, source_cache: Arc<SourceCache>) {
+        self.source_cache = Some(source_cache);
+    }
+
+    pub fn window_count(&self) -> usize {
+        self.window_count
+    }
+}
+
+impl Patch for XdeltaPatch {
+    fn target_size(&self) -> u64 {
+        self.target_size
+    }
+
+    fn source_path(&self) -> Option<&Path> {
+        self.source_path.as_deref()
+    }
+
+    fn format(&self) -> Option<&'static str> {
+        Some("xdelta")
+    }
+
+    fn modified(&self) -> Option<SystemTime> {
+        Some(self.patch_modified)
+    }
+
+    fn created(&self) -> Option<SystemTime> {
+        self.patch_created
+    }
+
+    fn patched_rom(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let patch_data = fs::read(&self.patch_path)?;
+        let source_path = self.source_path.as_deref().ok_or(VcdiffError::MissingSource)?;
+        let source = SourceCache::load(self.source_cache.as_deref(), source_path)?;
+
+        let code_table = default_code_table();
+        let mut target = Vec::with_capacity(self.target_size as usize);
+
+        for window in parse_windows(&patch_data)? {
+            decode_window(&window, &patch_data, &source, &mut target, &code_table)?;
+        }
+
+        Ok(target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{make_dir_fixture, write_file};
+
+    /// A patch of a single window over the whole source ROM, encoded with the default code table.
+    fn single_window_patch(
+        source: &[u8],
+        target: &[u8],
+        data: &[u8],
+        instructions: &[u8],
+        addresses: &[u8],
+    ) -> Vec<u8> {
+        let mut patch = VCDIFF_FORMAT_MARKER.to_vec();
+        patch.extend_from_slice(&[0x00, 0x00]);
+        patch.push(VCD_SOURCE | VCD_ADLER32);
+        // Every size is under 128, taking a single byte
+        patch.extend_from_slice(&[source.len() as u8, 0x00, 0x00, target.len() as u8, 0x00]);
+        patch.extend_from_slice(&[data.len() as u8, instructions.len() as u8, addresses.len() as u8]);
+        patch.extend_from_slice(&adler32(target).to_be_bytes());
+        patch.extend_from_slice(data);
+        patch.extend_from_slice(instructions);
+        patch.extend_from_slice(addresses);
+        patch
+    }
+
+    #[test]
+    fn applies_hand_built_patches() {
+        let source = b"ABCDEFGHIJKLMNOP";
+        let target = b"ABCDEFGHxyz!!!!z!!!!z";
+        // Copies 8 bytes of the source, adds 3 bytes, runs 4 bytes, then copies 6 bytes overlapping their own output
+        let instructions = [24, 4, 0, 4, 38];
+        let addresses = [0, 5];
+        let directory = make_dir_fixture(&[
+            ("game.sfc", source),
+            (
+                "hack.xdelta",
+                &single_window_patch(source, target, b"xyz!", &instructions, &addresses),
+            ),
+        ]);
+        let patch = || {
+            let mut patch = XdeltaPatch::new(&directory.path().join("hack.xdelta")).unwrap();
+            patch.set_source_path(&directory.path().join("game.sfc"));
+            patch
+        };
+
+        assert_eq!(patch().window_count(), 1);
+        assert_eq!(patch().target_size(), target.len() as u64);
+        assert_eq!(patch().patched_rom().unwrap(), target);
+
+        // The checksum of the window catches a wrong source ROM
+        write_file(directory.path(), "game.sfc", b"abcdefghIJKLMNOP");
+        let err = patch().patched_rom().unwrap_err();
+        assert!(err.to_string().starts_with("invalid target window checksum"), "{}", err);
+
+        // Copies reaching past the bytes produced so far are refused
+        write_file(
+            directory.path(),
+            "hack.xdelta",
+            &single_window_patch(source, target, b"xyz!", &instructions, &[0, 0x7F]),
+        );
+        let err = patch().patched_rom().unwrap_err();
+        assert!(err.to_string().starts_with("copy address out of bounds"), "{}", err);
+    }
+}
@@ -0,0 +1,216 @@
+use std::error::Error;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::rom_manager::RomManager;
+use crate::utils::json_string;
+
+/// A summary of a target ROM, for reporting without patching anything.
+pub struct PatchInfo {
+    pub target_path: PathBuf,
+    pub target_size: u64,
+    pub source_path: Option<PathBuf>,
+    pub source_checksum: Option<u32>,
+    pub format: Option<&'static str>,
+    pub operation_count: Option<usize>,
+    pub patched: bool,
+}
+
+impl PatchInfo {
+    pub fn collect(rom_manager: &RomManager) -> Vec<PatchInfo> {
+        rom_manager
+            .target_roms
+            .iter()
+            .map(|(target_path, patch)| {
+                let source_path = patch.source_path();
+                let source_checksum = rom_manager
+                    .source_roms
+                    .iter()
+                    .find(|(_, paths)| paths.iter().any(|path| Some(path.as_path()) == source_path))
+                    .map(|(crc, _)| *crc);
+
+                PatchInfo {
+                    target_path: target_path.clone(),
+                    target_size: patch.target_size(),
+                    source_path: source_path.map(|p| p.to_owned()),
+                    source_checksum,
+                    format: patch.format(),
+                    operation_count: patch.operation_count(),
+                    patched: true,
+                }
+            })
+            .collect()
+    }
+
+    fn status(&self) -> &'static str {
+        if self.patched {
+            "patched"
+        } else {
+            "unpatched"
+        }
+    }
+}
+
+/// Renders a JSON array of the targets.
+pub fn to_json(patch_infos: &[PatchInfo]) -> String {
+    let fields: Vec<String> = patch_infos
+        .iter()
+        .map(|patch_info| {
+            format!(
+                concat!(
+                    "  {{\n",
+                    "    \"target\": {},\n",
+                    "    \"source\": {},\n",
+                    "    \"format\": {},\n",
+                    "    \"source_crc\": {},\n",
+                    "    \"target_size\": {},\n",
+                    "    \"operations\": {},\n",
+                    "    \"status\": {}\n",
+                    "  }}"
+                ),
+                json_string(&patch_info.target_path.to_string_lossy()),
+                patch_info
+                    .source_path
+                    .as_ref()
+                    .map_or_else(|| "null".to_owned(), |p| json_string(&p.to_string_lossy())),
+                patch_info.format.map_or_else(|| "null".to_owned(), json_string),
+                patch_info
+                    .source_checksum
+                    .map_or_else(|| "null".to_owned(), |crc| json_string(&format!("{:08X}", crc))),
+                patch_info.target_size,
+                patch_info
+                    .operation_count
+                    .map_or_else(|| "null".to_owned(), |count| count.to_string()),
+                json_string(patch_info.status()),
+            )
+        })
+        .collect();
+
+    if fields.is_empty() {
+        "[]\n".to_owned()
+    } else {
+        format!("[\n{}\n]\n", fields.join(",\n"))
+    }
+}
+
+/// Renders a CSV table of the targets, with a header row.
+pub fn to_csv(patch_infos: &[PatchInfo]) -> String {
+    fn csv_field(value: &str) -> String {
+        if value.contains(&[',', '"', '\n', '\r'][..]) {
+            format!("\"{}\"", value.replace('"', "\"\""))
+        } else {
+            value.to_owned()
+        }
+    }
+
+    let mut csv = String::from("target,source,format,source_crc,target_size,status,operations\r\n");
+    for patch_info in patch_infos {
+        let row = [
+            csv_field(&patch_info.target_path.to_string_lossy()),
+            csv_field(
+                &patch_info
+                    .source_path
+                    .as_ref()
+                    .map(|p| p.to_string_lossy())
+                    .unwrap_or_default(),
+            ),
+            patch_info.format.unwrap_or_default().to_owned(),
+            patch_info
+                .source_checksum
+                .map(|crc| format!("{:08X}", crc))
+                .unwrap_or_default(),
+            patch_info.target_size.to_string(),
+            patch_info.status().to_owned(),
+            patch_info
+                .operation_count
+                .map(|count| count.to_string())
+                .unwrap_or_default(),
+        ];
+        csv.push_str(&row.join(","));
+        csv.push_str("\r\n");
+    }
+    csv
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SortOrder {
+    Name,
+    Size,
+    Source,
+}
+
+impl SortOrder {
+    /// Sorts by the chosen key, ties are broken by target name.
+    /// Source-less targets come before the ones with a source ROM.
+    pub fn sort(self, patch_infos: &mut [PatchInfo]) {
+        match self {
+            SortOrder::Name => patch_infos.sort_by(|a, b| a.target_path.cmp(&b.target_path)),
+            SortOrder::Size => {
+                patch_infos.sort_by(|a, b| (a.target_size, &a.target_path).cmp(&(b.target_size, &b.target_path)))
+            }
+            SortOrder::Source => {
+                patch_infos.sort_by(|a, b| (&a.source_path, &a.target_path).cmp(&(&b.source_path, &b.target_path)))
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct UnknownSortOrder(String);
+
+impl fmt::Display for UnknownSortOrder {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            formatter,
+            "unknown sort order {:?} (expected: name, size or source)",
+            self.0
+        )
+    }
+}
+
+impl Error for UnknownSortOrder {}
+
+impl FromStr for SortOrder {
+    type Err = UnknownSortOrder;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "name" => Ok(SortOrder::Name),
+            "size" => Ok(SortOrder::Size),
+            "source" => Ok(SortOrder::Source),
+            _ => Err(UnknownSortOrder(s.to_owned())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{make_bps, make_dir_fixture, make_hack, make_rom, make_source_less_bps};
+
+    #[test]
+    fn sorts_by_each_order() {
+        let (a, b) = (make_rom(1024, 1), make_rom(2048, 2));
+        let directory = make_dir_fixture(&[
+            ("a.sfc", &a),
+            ("b.sfc", &b),
+            ("z.bps", &make_bps(&a, &make_hack(&a))),
+            ("m.bps", &make_bps(&b, &make_hack(&b))),
+            ("c.bps", &make_source_less_bps(&[0; 4096])),
+        ]);
+        let mut patch_infos = PatchInfo::collect(&RomManager::new(directory.path()).unwrap());
+
+        for (sort_order, expected) in [
+            ("name", ["c.bin", "m.sfc", "z.sfc"]),
+            ("size", ["z.sfc", "m.sfc", "c.bin"]),
+            ("source", ["c.bin", "z.sfc", "m.sfc"]),
+        ] {
+            sort_order.parse::<SortOrder>().unwrap().sort(&mut patch_infos);
+            let target_paths: Vec<PathBuf> = patch_infos.iter().map(|p| p.target_path.clone()).collect();
+            assert_eq!(target_paths, expected.map(PathBuf::from), "{}", sort_order);
+        }
+
+        assert!("date".parse::<SortOrder>().is_err());
+    }
+}
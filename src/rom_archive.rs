@@ -0,0 +1,224 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use crc::crc32;
+
+use crate::patch::Patch;
+
+const ZIP_LOCAL_HEADER_SIGNATURE: u32 = 0x04034B50;
+const ZIP_CENTRAL_HEADER_SIGNATURE: u32 = 0x02014B50;
+const ZIP_END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x06054B50;
+
+const ZIP_LOCAL_HEADER_SIZE: u64 = 30;
+const ZIP_CENTRAL_HEADER_SIZE: u64 = 46;
+const ZIP_END_OF_CENTRAL_DIRECTORY_SIZE: u64 = 22;
+
+const ZIP_VERSION: u16 = 10;
+const ZIP_FLAG_UTF8: u16 = 1 << 11;
+const ZIP_METHOD_STORED: u16 = 0;
+const ZIP_DOS_DATE_1980: u16 = 0x21;
+
+#[derive(Debug)]
+pub enum RomArchiveError {
+    TooLarge {
+        size: u64,
+    },
+    TooManyEntries {
+        count: usize,
+    },
+    EntryLength {
+        name: PathBuf,
+        expected: u64,
+        received: u64,
+    },
+}
+
+impl fmt::Display for RomArchiveError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RomArchiveError::TooLarge { size } => {
+                write!(
+                    formatter,
+                    "archive too large for a ZIP file without ZIP64 ({} bytes)",
+                    size
+                )
+            }
+            RomArchiveError::TooManyEntries { count } => {
+                write!(formatter, "too many entries for a ZIP file without ZIP64 ({})", count)
+            }
+            RomArchiveError::EntryLength {
+                name,
+                expected,
+                received,
+            } => write!(
+                formatter,
+                "entry length mismatch for {:?} (expected: {}, received: {})",
+                name, expected, received
+            ),
+        }
+    }
+}
+
+impl Error for RomArchiveError {}
+
+/// An uncompressed ZIP archive containing every patched ROM.
+///
+/// Entries are stored without compression, so the size of the archive is known
+/// upfront without patching anything. The ROMs are only patched when the archive
+/// contents are requested.
+pub struct RomArchive {
+    entries: Vec<(PathBuf, Arc<dyn Patch + Send + Sync>)>,
+}
+
+impl RomArchive {
+    pub fn new(target_roms: &HashMap<PathBuf, Arc<dyn Patch + Send + Sync>>) -> Self {
+        let mut entries: Vec<_> = target_roms.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+        entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+        Self { entries }
+    }
+
+    fn entry_name(path: &Path) -> String {
+        path.to_string_lossy().replace('\\', "/")
+    }
+}
+
+impl Patch for RomArchive {
+    fn target_size(&self) -> u64 {
+        let entries_size: u64 = self
+            .entries
+            .iter()
+            .map(|(path, patch)| {
+                let name_size = RomArchive::entry_name(path).len() as u64;
+                ZIP_LOCAL_HEADER_SIZE + ZIP_CENTRAL_HEADER_SIZE + name_size * 2 + patch.target_size()
+            })
+            .sum();
+        entries_size + ZIP_END_OF_CENTRAL_DIRECTORY_SIZE
+    }
+
+    /// The archive is as recent as its most recently modified patch.
+    fn modified(&self) -> Option<SystemTime> {
+        self.entries.iter().filter_map(|(_, patch)| patch.modified()).max()
+    }
+
+    fn patched_rom(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let archive_size = self.target_size();
+        if archive_size > u64::from(u32::MAX) {
+            return Err(Box::new(RomArchiveError::TooLarge { size: archive_size }));
+        }
+
+        if self.entries.len() > usize::from(u16::MAX) {
+            return Err(Box::new(RomArchiveError::TooManyEntries {
+                count: self.entries.len(),
+            }));
+        }
+
+        let mut archive = Vec::with_capacity(archive_size as usize);
+        let mut central_directory = Vec::new();
+
+        for (path, patch) in &self.entries {
+            let name = RomArchive::entry_name(path);
+            let data = patch.patched_rom()?;
+
+            if data.len() as u64 != patch.target_size() {
+                return Err(Box::new(RomArchiveError::EntryLength {
+                    name: path.clone(),
+                    expected: patch.target_size(),
+                    received: data.len() as u64,
+                }));
+            }
+
+            let checksum = crc32::checksum_ieee(&data);
+            let local_header_offset = archive.len() as u32;
+
+            archive.write_u32::<LittleEndian>(ZIP_LOCAL_HEADER_SIGNATURE)?;
+            archive.write_u16::<LittleEndian>(ZIP_VERSION)?;
+            archive.write_u16::<LittleEndian>(ZIP_FLAG_UTF8)?;
+            archive.write_u16::<LittleEndian>(ZIP_METHOD_STORED)?;
+            archive.write_u16::<LittleEndian>(0)?; // Modification time
+            archive.write_u16::<LittleEndian>(ZIP_DOS_DATE_1980)?;
+            archive.write_u32::<LittleEndian>(checksum)?;
+            archive.write_u32::<LittleEndian>(data.len() as u32)?; // Compressed size
+            archive.write_u32::<LittleEndian>(data.len() as u32)?; // Uncompressed size
+            archive.write_u16::<LittleEndian>(name.len() as u16)?;
+            archive.write_u16::<LittleEndian>(0)?; // Extra field length
+            archive.write_all(name.as_bytes())?;
+            archive.write_all(&data)?;
+
+            central_directory.write_u32::<LittleEndian>(ZIP_CENTRAL_HEADER_SIGNATURE)?;
+            central_directory.write_u16::<LittleEndian>(ZIP_VERSION)?; // Version made by
+            central_directory.write_u16::<LittleEndian>(ZIP_VERSION)?; // Version needed to extract
+            central_directory.write_u16::<LittleEndian>(ZIP_FLAG_UTF8)?;
+            central_directory.write_u16::<LittleEndian>(ZIP_METHOD_STORED)?;
+            central_directory.write_u16::<LittleEndian>(0)?; // Modification time
+            central_directory.write_u16::<LittleEndian>(ZIP_DOS_DATE_1980)?;
+            central_directory.write_u32::<LittleEndian>(checksum)?;
+            central_directory.write_u32::<LittleEndian>(data.len() as u32)?; // Compressed size
+            central_directory.write_u32::<LittleEndian>(data.len() as u32)?; // Uncompressed size
+            central_directory.write_u16::<LittleEndian>(name.len() as u16)?;
+            central_directory.write_u16::<LittleEndian>(0)?; // Extra field length
+            central_directory.write_u16::<LittleEndian>(0)?; // File comment length
+            central_directory.write_u16::<LittleEndian>(0)?; // Disk number start
+            central_directory.write_u16::<LittleEndian>(0)?; // Internal file attributes
+            central_directory.write_u32::<LittleEndian>(0)?; // External file attributes
+            central_directory.write_u32::<LittleEndian>(local_header_offset)?;
+            central_directory.write_all(name.as_bytes())?;
+        }
+
+        let central_directory_offset = archive.len() as u32;
+        archive.write_all(&central_directory)?;
+
+        archive.write_u32::<LittleEndian>(ZIP_END_OF_CENTRAL_DIRECTORY_SIGNATURE)?;
+        archive.write_u16::<LittleEndian>(0)?; // Number of this disk
+        archive.write_u16::<LittleEndian>(0)?; // Disk where central directory starts
+        archive.write_u16::<LittleEndian>(self.entries.len() as u16)?; // Entries on this disk
+        archive.write_u16::<LittleEndian>(self.entries.len() as u16)?; // Total entries
+        archive.write_u32::<LittleEndian>(central_directory.len() as u32)?;
+        archive.write_u32::<LittleEndian>(central_directory_offset)?;
+        archive.write_u16::<LittleEndian>(0)?; // Comment length
+
+        Ok(archive)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom_manager::RomManager;
+    use crate::testutil::{make_bps, make_dir_fixture, make_hack, make_rom, make_source_less_bps};
+    use std::io::{Cursor, Read};
+    use zip::ZipArchive;
+
+    #[test]
+    fn stores_every_target() {
+        let source = make_rom(4096, 1);
+        let target = make_hack(&source);
+        let directory = make_dir_fixture(&[
+            ("game.sfc", &source),
+            ("hack.bps", &make_bps(&source, &target)),
+            ("hacks/full.bps", &make_source_less_bps(b"full")),
+        ]);
+        let rom_manager = RomManager::new(directory.path()).unwrap();
+        let rom_archive = RomArchive::new(&rom_manager.target_roms);
+
+        let data = rom_archive.patched_rom().unwrap();
+        assert_eq!(data.len() as u64, rom_archive.target_size());
+
+        let mut archive = ZipArchive::new(Cursor::new(data)).unwrap();
+        let mut names: Vec<&str> = archive.file_names().collect();
+        names.sort();
+        assert_eq!(names, ["hack.sfc", "hacks/full.bin"]);
+
+        for (name, expected) in [("hack.sfc", &target[..]), ("hacks/full.bin", b"full")] {
+            let mut entry = archive.by_name(name).unwrap();
+            let mut entry_data = Vec::new();
+            entry.read_to_end(&mut entry_data).unwrap();
+            assert_eq!(entry_data, expected, "{}", name);
+        }
+    }
+}
@@ -0,0 +1,174 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Mutex;
+
+use log::{debug, warn};
+
+/// Patched ROMs kept on disk, so reopening a ROM doesn't need patching it again.
+///
+/// The cache lives in its own directory under the temporary directory, which is removed
+/// along with the cached ROMs when the cache is dropped.
+pub struct RomCache {
+    directory: PathBuf,
+    entries: Mutex<HashMap<PathBuf, CacheEntry>>,
+    compression_level: Option<i32>,
+}
+
+struct CacheEntry {
+    generation: u64,
+    file_path: PathBuf,
+    compressed: bool,
+}
+
+impl RomCache {
+    pub fn new(temp_directory: &Path) -> io::Result<Self> {
+        let directory = temp_directory.join(format!("bps-fuse-{}", process::id()));
+        fs::create_dir_all(&directory)?;
+        debug!("Caching patched ROMs in {:?}", directory);
+
+        Ok(Self {
+            directory,
+            entries: Mutex::new(HashMap::new()),
+            compression_level: None,
+        })
+    }
+
+    /// Stores the cached ROMs compressed with zstd at the given level, trading CPU time for disk space.
+    /// Cached ROMs are decompressed into memory on every load.
+    pub fn set_compression_level(&mut self, compression_level: Option<i32>) {
+        self.compression_level = compression_level;
+    }
+
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    /// Returns the cached ROM, if it was cached since the last refresh of the ROM manager.
+    pub fn load(&self, rom_path: &Path, generation: u64) -> Option<Vec<u8>> {
+        let entries = self.entries.lock().unwrap();
+        let entry = entries.get(rom_path).filter(|e| e.generation == generation)?;
+
+        let data = if entry.compressed {
+            File::open(&entry.file_path).and_then(zstd::decode_all)
+        } else {
+            fs::read(&entry.file_path)
+        };
+
+        match data {
+            Ok(data) => Some(data),
+            Err(err) => {
+                warn!("Failed to read cached ROM {:?}: {}", entry.file_path, err);
+                None
+            }
+        }
+    }
+
+    /// Checks whether the ROM was cached since the last refresh of the ROM manager.
+    pub fn contains(&self, rom_path: &Path, generation: u64) -> bool {
+        let entries = self.entries.lock().unwrap();
+        matches!(entries.get(rom_path), Some(entry) if entry.generation == generation)
+    }
+
+    pub fn store(&self, rom_path: &Path, generation: u64, data: &[u8]) -> io::Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+
+        let file_path = match entries.get(rom_path) {
+            Some(entry) => entry.file_path.clone(),
+            None => self.directory.join(format!("{}.rom", entries.len())),
+        };
+        match self.compression_level {
+            Some(compression_level) => fs::write(&file_path, zstd::encode_all(data, compression_level)?)?,
+            None => fs::write(&file_path, data)?,
+        }
+
+        let compressed = self.compression_level.is_some();
+        entries.insert(
+            rom_path.to_owned(),
+            CacheEntry {
+                generation,
+                file_path,
+                compressed,
+            },
+        );
+        Ok(())
+    }
+
+    /// Removes every cached ROM, the cache directory itself is kept until the cache is dropped.
+    pub fn clear(&self) {
+        let mut entries = self.entries.lock().unwrap();
+
+        for entry in entries.values() {
+            if let Err(err) = fs::remove_file(&entry.file_path) {
+                warn!("Failed to remove cached ROM {:?}: {}", entry.file_path, err);
+            }
+        }
+        entries.clear();
+    }
+}
+
+impl Drop for RomCache {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_dir_all(&self.directory) {
+            warn!("Failed to remove cache directory {:?}: {}", self.directory, err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{make_dir_fixture, make_rom};
+
+    fn cached_files(rom_cache: &RomCache) -> usize {
+        fs::read_dir(rom_cache.directory()).unwrap().count()
+    }
+
+    #[test]
+    fn keeps_files_in_the_temporary_directory() {
+        let temp_directory = make_dir_fixture(&[]);
+        let rom_cache = RomCache::new(temp_directory.path()).unwrap();
+        let cache_directory = rom_cache.directory().to_owned();
+        assert_eq!(cache_directory.parent(), Some(temp_directory.path()));
+
+        rom_cache.store(Path::new("a.sfc"), 1, b"a").unwrap();
+        rom_cache.store(Path::new("b.sfc"), 1, b"b").unwrap();
+        rom_cache.store(Path::new("a.sfc"), 2, b"aa").unwrap();
+        assert_eq!(cached_files(&rom_cache), 2);
+        assert_eq!(rom_cache.load(Path::new("a.sfc"), 2), Some(b"aa".to_vec()));
+        assert_eq!(rom_cache.load(Path::new("a.sfc"), 1), None);
+
+        rom_cache.clear();
+        assert_eq!(cached_files(&rom_cache), 0);
+        assert_eq!(rom_cache.load(Path::new("b.sfc"), 1), None);
+
+        drop(rom_cache);
+        assert!(!cache_directory.exists());
+    }
+
+    #[test]
+    fn round_trips_compressed_roms() {
+        let temp_directory = make_dir_fixture(&[]);
+        let mut rom_cache = RomCache::new(temp_directory.path()).unwrap();
+        rom_cache.set_compression_level(Some(3));
+
+        let mut rom = make_rom(4096, 1);
+        rom.resize(64 * 1024, 0xFF);
+        rom_cache.store(Path::new("hack.sfc"), 1, &rom).unwrap();
+
+        // The file holds a zstd frame, smaller than the ROM
+        let file_path = fs::read_dir(rom_cache.directory())
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .path();
+        let file_data = fs::read(file_path).unwrap();
+        assert!(file_data.starts_with(&[0x28, 0xB5, 0x2F, 0xFD]));
+        assert!(file_data.len() < rom.len() / 4);
+
+        assert_eq!(rom_cache.load(Path::new("hack.sfc"), 1), Some(rom));
+    }
+}
@@ -0,0 +1,125 @@
+use std::cmp::Reverse;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use log::{debug, warn};
+
+use crate::patch::Patch;
+use crate::rom_cache::RomCache;
+use crate::rom_manager::RomManager;
+
+/// Pause between two ROMs patched by a worker, leaving room for patching the ROMs being read.
+const WARMING_PAUSE: Duration = Duration::from_millis(100);
+
+type WarmingQueue = Mutex<Vec<(PathBuf, Arc<dyn Patch + Send + Sync>)>>;
+
+pub struct RomCacheWarmer;
+
+impl RomCacheWarmer {
+    /// Patches every target ROM in the background and stores them in the disk cache, so the
+    /// filesystem is usable right away while the cache fills. ROMs are patched by `jobs` workers
+    /// at once, taking the ROMs of the current generation of the ROM manager.
+    ///
+    /// The ROM manager is only locked while listing the ROMs, patching doesn't block the filesystem.
+    pub fn new(rom_manager: Arc<Mutex<RomManager>>, rom_cache: Arc<RomCache>, jobs: usize) -> Self {
+        let (generation, mut targets) = {
+            let rom_manager = rom_manager.lock().unwrap();
+            let targets: Vec<(PathBuf, Arc<dyn Patch + Send + Sync>)> = rom_manager
+                .target_roms
+                .iter()
+                .map(|(target_path, patch)| (target_path.clone(), patch.clone()))
+                .collect();
+            (rom_manager.generation(), targets)
+        };
+
+        // Workers pop from the end, smaller ROMs are cached first
+        targets.sort_by_key(|(_, patch)| Reverse(patch.target_size()));
+        let queue: Arc<WarmingQueue> = Arc::new(Mutex::new(targets));
+
+        for _ in 0..jobs.max(1) {
+            let rom_manager = rom_manager.clone();
+            let rom_cache = rom_cache.clone();
+            let queue = queue.clone();
+
+            thread::spawn(move || loop {
+                let (target_path, patch) = match queue.lock().unwrap().pop() {
+                    Some(target) => target,
+                    None => break,
+                };
+
+                // ROMs of previous generations would never be served from the cache
+                if rom_manager.lock().unwrap().generation() != generation {
+                    debug!("ROMs were refreshed, stopped warming the cache");
+                    break;
+                }
+
+                if rom_cache.contains(&target_path, generation) {
+                    continue;
+                }
+
+                match patch.patched_rom() {
+                    Ok(data) => {
+                        if let Err(err) = rom_cache.store(&target_path, generation, &data) {
+                            warn!("Failed to cache {:?}: {}", target_path, err);
+                        } else {
+                            debug!("Cached {:?}", target_path);
+                        }
+                    }
+                    Err(err) => warn!("Failed to patch {:?}: {}", target_path, err),
+                }
+
+                thread::sleep(WARMING_PAUSE);
+            });
+        }
+
+        Self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom_filesystem::{RomFilesystem, RomFilesystemConfig};
+    use crate::testutil::{make_bps, make_dir_fixture, make_hack, make_rom};
+    use std::path::Path;
+    use std::time::Instant;
+
+    #[test]
+    fn caches_targets_in_the_background() {
+        let source = make_rom(4096, 1);
+        let targets: Vec<Vec<u8>> = (1..=3).map(|size| make_hack(&source[..size * 1024])).collect();
+        let directory = make_dir_fixture(&[
+            ("game.sfc", &source),
+            ("a.bps", &make_bps(&source, &targets[0])),
+            ("b.bps", &make_bps(&source, &targets[1])),
+            ("c.bps", &make_bps(&source, &targets[2])),
+        ]);
+        let temp_directory = make_dir_fixture(&[]);
+        let rom_manager = Arc::new(Mutex::new(RomManager::new(directory.path()).unwrap()));
+        let rom_cache = Arc::new(RomCache::new(temp_directory.path()).unwrap());
+        let fs = RomFilesystem::with_config(
+            rom_manager.clone(),
+            RomFilesystemConfig {
+                rom_cache: Some(rom_cache.clone()),
+                ..RomFilesystemConfig::default()
+            },
+        );
+        let generation = rom_manager.lock().unwrap().generation();
+
+        // The filesystem serves reads while the cache fills
+        let _rom_cache_warmer = RomCacheWarmer::new(rom_manager, rom_cache.clone(), 1);
+        assert_eq!(fs.read_at(Path::new("/c.sfc"), 0, 16).unwrap(), targets[2][..16]);
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let target_paths = [Path::new("a.sfc"), Path::new("b.sfc"), Path::new("c.sfc")];
+        while !target_paths.iter().all(|path| rom_cache.contains(path, generation)) {
+            assert!(Instant::now() < deadline, "targets weren't cached in time");
+            thread::sleep(Duration::from_millis(10));
+        }
+        for (target_path, target) in target_paths.iter().zip(&targets) {
+            assert_eq!(rom_cache.load(target_path, generation).as_ref(), Some(target));
+        }
+    }
+}
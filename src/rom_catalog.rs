@@ -0,0 +1,76 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// A list of known ROMs living outside of the base directory, indexed by CRC32.
+///
+/// The catalog is a plain text file, with one `<crc32> <path>` pair on each line.
+/// Relative paths are resolved against the directory of the catalog file.
+/// Empty lines and lines starting with `#` are ignored.
+pub struct RomCatalog {
+    entries: HashMap<u32, PathBuf>,
+}
+
+impl RomCatalog {
+    pub fn load(catalog_path: &Path) -> io::Result<Self> {
+        let catalog_directory = catalog_path.parent().unwrap_or_else(|| Path::new(""));
+        let mut entries = HashMap::new();
+
+        for (line_number, line) in fs::read_to_string(catalog_path)?.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let invalid_line = || {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{:?}:{}: expected `<crc32> <path>`", catalog_path, line_number + 1),
+                )
+            };
+
+            let mut fields = line.splitn(2, char::is_whitespace);
+            let crc = fields
+                .next()
+                .map(|crc| crc.trim_start_matches("0x"))
+                .and_then(|crc| u32::from_str_radix(crc, 16).ok())
+                .ok_or_else(invalid_line)?;
+            let path = fields.next().map(str::trim).ok_or_else(invalid_line)?;
+
+            entries.insert(crc, catalog_directory.join(path));
+        }
+
+        Ok(Self { entries })
+    }
+
+    pub fn get(&self, crc: u32) -> Option<&Path> {
+        self.entries.get(&crc).map(PathBuf::as_path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::make_dir_fixture;
+
+    #[test]
+    fn resolves_paths_against_the_catalog_directory() {
+        let directory = make_dir_fixture(&[(
+            "catalog.txt",
+            b"# Comment\n\n  0x1234ABCD roms/a game.sfc\ndeadbeef /b.sfc\n",
+        )]);
+        let catalog = RomCatalog::load(&directory.path().join("catalog.txt")).unwrap();
+
+        assert_eq!(
+            catalog.get(0x1234ABCD),
+            Some(directory.path().join("roms/a game.sfc").as_path())
+        );
+        assert_eq!(catalog.get(0xDEADBEEF), Some(Path::new("/b.sfc")));
+        assert_eq!(catalog.get(0), None);
+
+        let directory = make_dir_fixture(&[("catalog.txt", b"1234ABCD\n")]);
+        let err = RomCatalog::load(&directory.path().join("catalog.txt")).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
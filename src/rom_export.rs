@@ -0,0 +1,88 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use log::info;
+use rayon::prelude::*;
+use rayon::ThreadPoolBuilder;
+
+use crate::rom_manager::RomManager;
+
+/// Writes every target ROM into the output directory, patching up to `jobs` ROMs at once.
+/// Zero jobs use one thread per CPU. Source ROMs are shared through the source cache of the
+/// ROM manager, so the ROMs patched from the same source ROM read it only once.
+///
+/// Failing targets don't stop the export, they are returned along with the reason of the failure.
+pub fn export(rom_manager: &RomManager, output_directory: &Path, jobs: usize) -> io::Result<Vec<(PathBuf, String)>> {
+    fs::create_dir_all(output_directory)?;
+
+    let thread_pool = ThreadPoolBuilder::new()
+        .num_threads(jobs)
+        .build()
+        .map_err(io::Error::other)?;
+
+    let mut targets: Vec<_> = rom_manager.target_roms.iter().collect();
+    targets.sort_by_key(|(target_path, _)| *target_path);
+
+    let mut failures: Vec<(PathBuf, String)> = thread_pool.install(|| {
+        targets
+            .par_iter()
+            .filter_map(|(target_path, patch)| {
+                let output_path = output_directory.join(target_path);
+                let result = patch.patched_rom().map_err(|err| err.to_string()).and_then(|data| {
+                    // Target ROMs of patches in subdirectories keep their subdirectories
+                    if let Some(parent) = output_path.parent() {
+                        fs::create_dir_all(parent).map_err(|err| err.to_string())?;
+                    }
+                    fs::write(&output_path, data).map_err(|err| err.to_string())
+                });
+
+                match result {
+                    Ok(()) => {
+                        info!("Exported {:?}", output_path);
+                        None
+                    }
+                    Err(err) => Some((target_path.to_path_buf(), err)),
+                }
+            })
+            .collect()
+    });
+
+    failures.sort();
+    Ok(failures)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{make_bps, make_dir_fixture, make_hack, make_rom, write_file};
+
+    #[test]
+    fn exports_every_target() {
+        let source = make_rom(4096, 1);
+        let directory = make_dir_fixture(&[("game.sfc", &source)]);
+        let mut targets = Vec::new();
+        for index in 0..16 {
+            let mut target = make_hack(&source);
+            target[index] ^= 0x55;
+            let patch_path = match index % 3 {
+                0 => format!("hack{}.bps", index),
+                1 => format!("hacks/hack{}.bps", index),
+                _ => format!("hacks/more/hack{}.bps", index),
+            };
+            write_file(directory.path(), &patch_path, &make_bps(&source, &target));
+            targets.push((PathBuf::from(patch_path).with_extension("sfc"), target));
+        }
+
+        let rom_manager = RomManager::new(directory.path()).unwrap();
+        assert_eq!(rom_manager.target_roms.len(), targets.len());
+
+        let output_directory = tempfile::tempdir().unwrap();
+        let failures = export(&rom_manager, output_directory.path(), 4).unwrap();
+        assert!(failures.is_empty(), "{:?}", failures);
+
+        for (target_path, target) in targets {
+            assert_eq!(fs::read(output_directory.path().join(&target_path)).unwrap(), target);
+        }
+    }
+}
@@ -1,21 +1,70 @@
 use std::cmp;
-use std::collections::HashMap;
-use std::path::Path;
-use std::sync::{Arc, Mutex};
-//use std::time::SystemTime;
-
-use fuse_mt::{DirectoryEntry, FileAttr, FileType, FilesystemMT, RequestInfo};
-use fuse_mt::{ResultEmpty, ResultEntry, ResultOpen, ResultReaddir};
-use log::error;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::error::Error;
+use std::ffi::OsStr;
+use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+use fuse_mt::{DirectoryEntry, FileAttr, FileType, FilesystemMT, RequestInfo, Statfs};
+use fuse_mt::{ResultEmpty, ResultEntry, ResultOpen, ResultReaddir, ResultStatfs, ResultXattr, Xattr};
+use log::{debug, error, info, warn};
+use sha1_smol::Sha1;
 use time::Timespec;
 
-use crate::patch::Patch;
+use crate::patch::{self, Patch};
+use crate::patch_info::{self, PatchInfo, SortOrder};
+use crate::rom_archive::RomArchive;
+use crate::rom_cache::RomCache;
+use crate::rom_header::{self, RomHeader};
 use crate::rom_manager::RomManager;
+use crate::rom_memory_cache::{self, RomMemoryCache};
+use crate::rom_system::{self, RomSystem};
+use crate::source_cache::SourceCache;
+
+/// Reads ending within this many bytes from the start of an unpatched ROM are served by patching
+/// only the start of the ROM, like the reads of file managers looking at the header.
+const PARTIAL_READ_LIMIT: u64 = 256 * 1024;
 
 const EPOCH: Timespec = Timespec { sec: 0, nsec: 0 };
 const TTL: Timespec = Timespec { sec: 1, nsec: 0 };
 
-/*
+/// Block size reported by `statfs`, the sizes of the ROMs are counted in these blocks.
+const STATFS_BLOCK_SIZE: u32 = 512;
+const STATFS_MAX_NAME_LENGTH: u32 = 255;
+
+const ARCHIVE_NAME: &str = "bps-fuse.zip";
+
+const MANIFEST_JSON_NAME: &str = ".bps-fuse.json";
+const MANIFEST_CSV_NAME: &str = ".bps-fuse.csv";
+
+const VERSION_NAME: &str = ".bps-fuse.version";
+
+const HEADER_SIDECAR_SUFFIX: &str = ".header.json";
+const HEADER_SIDECAR_EXTENSIONS: &[&str] = &["nes", "sfc", "smc"];
+
+const SOURCE_CRC_SIDECAR_SUFFIX: &str = ".source_crc";
+
+const DIFF_SIDECAR_SUFFIX: &str = ".diff";
+
+const HEADERLESS_VARIANT: &str = "headerless";
+const HEADERED_VARIANT: &str = "headered";
+
+const PATCHED_XATTR: &str = "user.bpsfuse.patched";
+const TITLE_XATTR: &str = "user.bpsfuse.title";
+const AUTHOR_XATTR: &str = "user.bpsfuse.author";
+const METADATA_XATTR: &str = "user.bps.metadata";
+const SHA1_XATTR: &str = "user.bpsfuse.sha1";
+
+#[cfg(target_os = "linux")]
+const ENOATTR: libc::c_int = libc::ENODATA;
+#[cfg(not(target_os = "linux"))]
+const ENOATTR: libc::c_int = libc::ENOATTR;
+
 fn timespec_from(st: &SystemTime) -> Timespec {
     if let Ok(dur_since_epoch) = st.duration_since(std::time::UNIX_EPOCH) {
         Timespec::new(dur_since_epoch.as_secs() as i64, dur_since_epoch.subsec_nanos() as i32)
@@ -23,7 +72,285 @@ fn timespec_from(st: &SystemTime) -> Timespec {
         Timespec::new(0, 0)
     }
 }
-*/
+
+/// Reads are only cut short at the end of the ROM, loaders of flash carts reading ROMs in fixed-size
+/// blocks rely on every other block being read whole.
+fn data_slice(data: &[u8], offset: u64, size: u32) -> &[u8] {
+    if offset >= data.len() as u64 {
+        return &[];
+    }
+
+    let offset = offset as usize;
+    let size = cmp::min(size as usize, data.len() - offset);
+    &data[offset..offset + size]
+}
+
+fn xattr_reply(data: Vec<u8>, size: u32) -> ResultXattr {
+    if size == 0 {
+        Ok(Xattr::Size(data.len() as u32))
+    } else if data.len() > size as usize {
+        Err(libc::ERANGE)
+    } else {
+        Ok(Xattr::Data(data))
+    }
+}
+
+fn has_header_sidecar(path: &Path) -> bool {
+    path.extension()
+        .and_then(OsStr::to_str)
+        .map(|e| HEADER_SIDECAR_EXTENSIONS.contains(&e.to_ascii_lowercase().as_str()))
+        .unwrap_or(false)
+}
+
+/// Ranges of the patched ROM differing from the source ROM, bytes past the end of the source ROM always differ.
+fn changed_ranges(source: &[u8], target: &[u8]) -> Vec<Range<usize>> {
+    let mut ranges: Vec<Range<usize>> = Vec::new();
+
+    for (offset, byte) in target.iter().enumerate() {
+        if source.get(offset) == Some(byte) {
+            continue;
+        }
+
+        match ranges.last_mut() {
+            Some(range) if range.end == offset => range.end += 1,
+            _ => ranges.push(offset..(offset + 1)),
+        }
+    }
+
+    ranges
+}
+
+/// Name of the other header variant of a target ROM, like `hack.headerless.sfc` for `hack.sfc`.
+/// Headered ROMs get a headerless variant, while headerless SNES ROMs get a variant with a blank copier header.
+/// iNES headers can't be made up, headerless NES ROMs have no variants.
+fn header_variant_path(path: &Path, rom_size: u64) -> Option<PathBuf> {
+    let header_size = rom_header::external_header_size(path)?;
+    let variant = if rom_size % 1024 == header_size {
+        HEADERLESS_VARIANT
+    } else if header_size == rom_header::SNES_COPIER_HEADER_SIZE as u64 {
+        HEADERED_VARIANT
+    } else {
+        return None;
+    };
+
+    let mut file_name = path.file_stem()?.to_owned();
+    file_name.push(".");
+    file_name.push(variant);
+    file_name.push(".");
+    file_name.push(path.extension()?);
+    Some(path.with_file_name(file_name))
+}
+
+/// Directories are implied by the targets of patches in the subdirectories of the base directory.
+fn is_directory(rom_manager: &RomManager, path: &Path) -> bool {
+    path == Path::new("")
+        || rom_manager
+            .target_roms
+            .keys()
+            .any(|target_path| target_path != path && target_path.starts_with(path))
+}
+
+/// Byte order of an N64 ROM, by its file extension.
+fn n64_byte_order(path: &Path) -> Option<&'static str> {
+    let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+    rom_system::N64_BYTE_ORDERS.iter().find(|b| **b == extension).copied()
+}
+
+/// Paths of an N64 ROM in the other byte orders, like `hack.v64` and `hack.n64` for `hack.z64`.
+fn byte_order_variant_paths(path: &Path) -> Vec<PathBuf> {
+    match n64_byte_order(path) {
+        Some(rom_byte_order) => rom_system::N64_BYTE_ORDERS
+            .iter()
+            .filter(|byte_order| **byte_order != rom_byte_order)
+            .map(|byte_order| path.with_extension(byte_order))
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+/// Small files generated by the filesystem, like sidecars describing the patched ROMs.
+struct GeneratedFile {
+    data: Vec<u8>,
+}
+
+impl Patch for GeneratedFile {
+    fn target_size(&self) -> u64 {
+        self.data.len() as u64
+    }
+
+    fn patched_rom(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        Ok(self.data.clone())
+    }
+}
+
+#[derive(Clone, Copy)]
+enum SidecarKind {
+    Header,
+    Diff,
+}
+
+/// Sidecars describing the patched ROM, which take patching the whole ROM. They are generated
+/// when first sized or read rather than when looked up, so lookups stay cheap while the ROM manager is locked.
+struct RomSidecar {
+    rom: Arc<dyn Patch + Send + Sync>,
+    source_cache: Arc<SourceCache>,
+    kind: SidecarKind,
+    data: OnceLock<Result<Vec<u8>, String>>,
+}
+
+impl RomSidecar {
+    fn new(rom: Arc<dyn Patch + Send + Sync>, source_cache: Arc<SourceCache>, kind: SidecarKind) -> Self {
+        Self {
+            rom,
+            source_cache,
+            kind,
+            data: OnceLock::new(),
+        }
+    }
+
+    fn data(&self) -> &Result<Vec<u8>, String> {
+        self.data.get_or_init(|| {
+            let result = match self.kind {
+                SidecarKind::Header => self.header_json(),
+                SidecarKind::Diff => self.diff(),
+            };
+            result.map_err(|err| {
+                error!("Failed to generate sidecar: {}", err);
+                err.to_string()
+            })
+        })
+    }
+
+    fn header_json(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let json = match RomHeader::parse(&self.rom.patched_rom()?) {
+            Some(header) => header.to_json(),
+            None => "null\n".to_owned(),
+        };
+        Ok(json.into_bytes())
+    }
+
+    /// Lists the changed ranges of the patched ROM, one `start-end` line of hexadecimal offsets per range.
+    /// Source ROMs are compared without the external header skipped when patching them.
+    /// Source-less patches change every byte of their patched ROMs.
+    fn diff(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let source = self.rom.source_path().map(|p| self.source_cache.get(p)).transpose()?;
+        let source = source.as_deref().map_or(&[][..], |source| {
+            &source[cmp::min(self.rom.source_header_size() as usize, source.len())..]
+        });
+        let diff: String = changed_ranges(source, &self.rom.patched_rom()?)
+            .iter()
+            .map(|range| format!("{:08X}-{:08X}\n", range.start, range.end))
+            .collect();
+        Ok(diff.into_bytes())
+    }
+}
+
+impl Patch for RomSidecar {
+    /// Sidecars failing to generate are empty, reading them fails.
+    fn target_size(&self) -> u64 {
+        self.data().as_ref().map_or(0, |data| data.len() as u64)
+    }
+
+    fn patched_rom(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        Ok(self.data().clone()?)
+    }
+}
+
+/// The source ROM of a target, served as-is while the patch is toggled off.
+struct UnpatchedRom {
+    source_path: PathBuf,
+    source_size: u64,
+}
+
+impl Patch for UnpatchedRom {
+    fn target_size(&self) -> u64 {
+        self.source_size
+    }
+
+    fn patched_rom(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        Ok(fs::read(&self.source_path)?)
+    }
+
+    fn source_path(&self) -> Option<&Path> {
+        Some(&self.source_path)
+    }
+}
+
+/// A patched ROM with its external header stripped, or with a blank one added.
+struct HeaderVariant {
+    rom: Arc<dyn Patch + Send + Sync>,
+    header_size: u64,
+    headered: bool,
+}
+
+impl Patch for HeaderVariant {
+    fn target_size(&self) -> u64 {
+        if self.headered {
+            self.rom.target_size() + self.header_size
+        } else {
+            self.rom.target_size().saturating_sub(self.header_size)
+        }
+    }
+
+    fn patched_rom(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut data = self.rom.patched_rom()?;
+        if self.headered {
+            let mut headered_data = vec![0; self.header_size as usize];
+            headered_data.append(&mut data);
+            Ok(headered_data)
+        } else {
+            Ok(data.split_off(cmp::min(self.header_size as usize, data.len())))
+        }
+    }
+
+    fn source_path(&self) -> Option<&Path> {
+        self.rom.source_path()
+    }
+
+    fn modified(&self) -> Option<SystemTime> {
+        self.rom.modified()
+    }
+
+    fn created(&self) -> Option<SystemTime> {
+        self.rom.created()
+    }
+}
+
+/// A patched N64 ROM converted to another byte order.
+struct ByteOrderVariant {
+    rom: Arc<dyn Patch + Send + Sync>,
+    /// Byte order of the patched ROM by its extension, when it can't be detected from its header.
+    rom_byte_order: &'static str,
+    byte_order: &'static str,
+}
+
+impl Patch for ByteOrderVariant {
+    fn target_size(&self) -> u64 {
+        self.rom.target_size()
+    }
+
+    fn patched_rom(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut data = self.rom.patched_rom()?;
+        let rom_byte_order = match RomSystem::detect(&data) {
+            Some(RomSystem::Nintendo64 { byte_order }) => byte_order,
+            _ => self.rom_byte_order,
+        };
+        rom_system::convert_n64_byte_order(&mut data, rom_byte_order, self.byte_order);
+        Ok(data)
+    }
+
+    fn source_path(&self) -> Option<&Path> {
+        self.rom.source_path()
+    }
+
+    fn modified(&self) -> Option<SystemTime> {
+        self.rom.modified()
+    }
+
+    fn created(&self) -> Option<SystemTime> {
+        self.rom.created()
+    }
+}
 
 enum Handle {
     Directory {
@@ -32,26 +359,418 @@ enum Handle {
     File {
         attr: FileAttr,
         patch: Arc<dyn Patch + Send + Sync>,
-        data: Option<Vec<u8>>,
+        data: Option<Arc<Vec<u8>>>,
+        cache_key: Option<(PathBuf, u64)>,
     },
 }
 
+#[derive(Default)]
+struct OperationCounters {
+    opendir: AtomicU64,
+    readdir: AtomicU64,
+    releasedir: AtomicU64,
+    access: AtomicU64,
+    statfs: AtomicU64,
+    getattr: AtomicU64,
+    open: AtomicU64,
+    read: AtomicU64,
+    release: AtomicU64,
+    bytes_read: AtomicU64,
+}
+
+impl OperationCounters {
+    fn count(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn count_bytes(&self, bytes: usize) {
+        self.bytes_read.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> Vec<(&'static str, u64)> {
+        vec![
+            ("opendir", self.opendir.load(Ordering::Relaxed)),
+            ("readdir", self.readdir.load(Ordering::Relaxed)),
+            ("releasedir", self.releasedir.load(Ordering::Relaxed)),
+            ("access", self.access.load(Ordering::Relaxed)),
+            ("statfs", self.statfs.load(Ordering::Relaxed)),
+            ("getattr", self.getattr.load(Ordering::Relaxed)),
+            ("open", self.open.load(Ordering::Relaxed)),
+            ("read", self.read.load(Ordering::Relaxed)),
+            ("release", self.release.load(Ordering::Relaxed)),
+        ]
+    }
+}
+
+/// Options of the filesystem, everything besides the memory cache is off by default.
+#[derive(Default)]
+pub struct RomFilesystemConfig {
+    /// Exposes an uncompressed ZIP archive of every patched ROM in the root directory.
+    pub expose_archive: bool,
+
+    /// Exposes a manifest of every patched ROM in the root directory, in both JSON and CSV formats.
+    pub manifest: bool,
+
+    /// Exposes the internal header of patched NES and SNES ROMs as `.header.json` sidecar files.
+    pub header_sidecars: bool,
+
+    /// Exposes the CRC32 of the source ROM expected by BPS patches as `.source_crc` sidecar files.
+    pub source_crc_sidecars: bool,
+
+    /// Exposes the ranges of patched ROMs changed by their patches as `.diff` sidecar files.
+    pub diff_sidecars: bool,
+
+    /// Exposes NES and SNES ROMs both with and without their external header,
+    /// like `hack.sfc` along with `hack.headerless.sfc`.
+    pub header_variants: bool,
+
+    /// Exposes N64 ROMs in every byte order, like `hack.z64` along with `hack.v64` and `hack.n64`.
+    pub byte_order_variants: bool,
+
+    /// Allows toggling patching on and off for individual ROMs through the
+    /// `user.bpsfuse.patched` extended attribute, for comparing the patched and
+    /// unpatched ROMs without remounting.
+    pub ab_toggle: bool,
+
+    /// Exposes the SHA-1 of patched ROMs as the `user.bpsfuse.sha1` extended attribute,
+    /// for looking them up in ROM databases.
+    pub target_sha1: bool,
+
+    /// Keeps patched ROMs in an on-disk cache after their files are closed.
+    pub rom_cache: Option<Arc<RomCache>>,
+
+    /// Limit of the total size of the patched ROMs kept in memory between opens,
+    /// 256 MiB when not set. A limit of zero turns the memory cache off.
+    pub memory_cache_size: Option<u64>,
+
+    /// The base directory is watched for changes, only reported by the version file.
+    pub watching: bool,
+
+    /// Called once the filesystem is mounted, before serving any requests.
+    pub on_mount: Option<Box<dyn Fn() + Send + Sync>>,
+}
+
+impl RomFilesystemConfig {
+    /// Names of the enabled features, as reported by the version file.
+    fn features(&self) -> Vec<&'static str> {
+        [
+            ("archive", self.expose_archive),
+            ("manifest", self.manifest),
+            ("header-sidecars", self.header_sidecars),
+            ("source-crc-sidecars", self.source_crc_sidecars),
+            ("diff-sidecars", self.diff_sidecars),
+            ("header-variants", self.header_variants),
+            ("byte-order-variants", self.byte_order_variants),
+            ("ab-toggle", self.ab_toggle),
+            ("target-sha1", self.target_sha1),
+            ("disk-cache", self.rom_cache.is_some()),
+            ("watch", self.watching),
+        ]
+        .iter()
+        .filter(|(_, enabled)| *enabled)
+        .map(|(feature, _)| *feature)
+        .collect()
+    }
+}
+
 pub struct RomFilesystem {
     rom_manager: Arc<Mutex<RomManager>>,
-    handles: Mutex<HashMap<u64, Handle>>,
+    handles: RwLock<HashMap<u64, Handle>>,
     next_handle: Mutex<u64>,
+    counters: OperationCounters,
+    config: RomFilesystemConfig,
+    unpatched_roms: Mutex<HashSet<PathBuf>>,
+    patch_timings: Mutex<HashMap<PathBuf, Duration>>,
+    /// SHA-1 of the patched ROMs, along with the generation of the ROM manager they were computed in.
+    target_sha1s: Mutex<HashMap<PathBuf, (u64, String)>>,
+    /// Sidecars looked up so far, along with the generation of the ROM manager they were looked up in.
+    sidecars: Mutex<HashMap<PathBuf, (u64, Arc<RomSidecar>)>>,
+    memory_cache: Arc<RomMemoryCache>,
 }
 
 impl RomFilesystem {
     pub fn new(rom_manager: Arc<Mutex<RomManager>>) -> Self {
+        RomFilesystem::with_config(rom_manager, RomFilesystemConfig::default())
+    }
+
+    pub fn with_config(rom_manager: Arc<Mutex<RomManager>>, config: RomFilesystemConfig) -> Self {
+        let memory_cache_size = config.memory_cache_size.unwrap_or(rom_memory_cache::DEFAULT_MAX_SIZE);
         Self {
             rom_manager,
-            handles: Mutex::new(HashMap::new()),
+            handles: RwLock::new(HashMap::new()),
             next_handle: Mutex::new(1),
+            counters: OperationCounters::default(),
+            config,
+            unpatched_roms: Mutex::new(HashSet::new()),
+            patch_timings: Mutex::new(HashMap::new()),
+            target_sha1s: Mutex::new(HashMap::new()),
+            sidecars: Mutex::new(HashMap::new()),
+            memory_cache: Arc::new(RomMemoryCache::new(memory_cache_size)),
+        }
+    }
+
+    /// Time spent on patching each ROM, as measured on the last patching of the ROM.
+    /// Slowest ROMs come first.
+    pub fn patch_timings(&self) -> Vec<(PathBuf, Duration)> {
+        let mut patch_timings: Vec<(PathBuf, Duration)> = self
+            .patch_timings
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(path, elapsed)| (path.clone(), *elapsed))
+            .collect();
+        patch_timings.sort_by(|(_, a), (_, b)| b.cmp(a));
+        patch_timings
+    }
+
+    /// The cache of the patched ROMs kept in memory, for resizing it while mounted.
+    pub fn memory_cache(&self) -> Arc<RomMemoryCache> {
+        self.memory_cache.clone()
+    }
+
+    /// Reads patched ROM data without mounting the filesystem, going through the
+    /// same open, read and release steps as a FUSE client would.
+    /// The path is relative to the mount point, starting with a `/`.
+    pub fn read_at(&self, path: &Path, offset: u64, size: u32) -> Result<Vec<u8>, libc::c_int> {
+        let req = RequestInfo {
+            unique: 0,
+            uid: unsafe { libc::geteuid() },
+            gid: unsafe { libc::getegid() },
+            pid: process::id(),
+        };
+
+        let (fh, _) = self.open(req, path, libc::O_RDONLY as u32)?;
+
+        let mut data = Err(libc::EIO);
+        self.read(req, path, fh, offset, size, |result| data = result.map(<[u8]>::to_vec));
+
+        self.release(req, path, fh, 0, 0, false)?;
+        data
+    }
+
+    /// Returns the SHA-1 of the target ROM, computing it from the patched ROM data when
+    /// it's not known yet in this generation. Hashing doesn't block looking up other SHA-1s.
+    fn target_sha1(&self, rom_path: &Path, generation: u64, data: &[u8]) -> String {
+        if let Some(sha1) = self.cached_target_sha1(rom_path, generation) {
+            return sha1;
+        }
+
+        let sha1 = Sha1::from(data).digest().to_string();
+        self.target_sha1s
+            .lock()
+            .unwrap()
+            .insert(rom_path.to_owned(), (generation, sha1.clone()));
+        sha1
+    }
+
+    fn cached_target_sha1(&self, rom_path: &Path, generation: u64) -> Option<String> {
+        match self.target_sha1s.lock().unwrap().get(rom_path) {
+            Some((sha1_generation, sha1)) if *sha1_generation == generation => Some(sha1.clone()),
+            _ => None,
+        }
+    }
+
+    /// The patched ROM of an opened file, from the caches when another handle already patched it.
+    /// Target ROMs patched here are stored in the caches, generated files are served as they are.
+    fn patched_data(
+        &self,
+        path: &Path,
+        patch: &Arc<dyn Patch + Send + Sync>,
+        cache_key: Option<&(PathBuf, u64)>,
+    ) -> Result<Arc<Vec<u8>>, Box<dyn Error>> {
+        if let Some((rom_path, generation)) = cache_key {
+            if let Some(data) = self.memory_cache.load(rom_path, *generation) {
+                return Ok(data);
+            }
+
+            if let Some(rom_cache) = &self.config.rom_cache {
+                if let Some(cached_rom) = rom_cache.load(rom_path, *generation) {
+                    let cached_rom = Arc::new(cached_rom);
+                    self.memory_cache.store(rom_path, *generation, cached_rom.clone());
+                    return Ok(cached_rom);
+                }
+            }
+        }
+
+        // Deferred ROM patching on first read
+        let started = Instant::now();
+        let patched_rom = patch.patched_rom()?;
+        let elapsed = started.elapsed();
+        debug!("Patched {:?} in {:?}", path, elapsed);
+        self.patch_timings.lock().unwrap().insert(path.to_owned(), elapsed);
+
+        if let (Some(rom_cache), Some((rom_path, generation))) = (&self.config.rom_cache, cache_key) {
+            if let Err(err) = rom_cache.store(rom_path, *generation, &patched_rom) {
+                warn!("Failed to cache {:?}: {}", rom_path, err);
+            }
+        }
+
+        // The SHA-1 is computed while the freshly patched ROM is at hand
+        if let (true, Some((rom_path, generation))) = (self.config.target_sha1, cache_key) {
+            self.target_sha1(rom_path, *generation, &patched_rom);
+        }
+
+        let patched_rom = Arc::new(patched_rom);
+        if let Some((rom_path, generation)) = cache_key {
+            self.memory_cache.store(rom_path, *generation, patched_rom.clone());
+        }
+        Ok(patched_rom)
+    }
+
+    fn lookup_file(&self, rom_manager: &RomManager, path: &Path) -> Option<Arc<dyn Patch + Send + Sync>> {
+        if self.config.expose_archive && path == Path::new(ARCHIVE_NAME) {
+            return Some(Arc::new(RomArchive::new(&rom_manager.target_roms)));
+        }
+
+        // Describes the running filesystem, one `key: value` line per property
+        if path == Path::new(VERSION_NAME) {
+            let version = format!(
+                "version: {}\nformats: {}\nfeatures: {}\n",
+                env!("CARGO_PKG_VERSION"),
+                patch::SUPPORTED_FORMATS.join(" "),
+                self.config.features().join(" ")
+            );
+
+            return Some(Arc::new(GeneratedFile {
+                data: version.into_bytes(),
+            }));
+        }
+
+        if self.config.manifest && (path == Path::new(MANIFEST_JSON_NAME) || path == Path::new(MANIFEST_CSV_NAME)) {
+            let mut patch_infos = PatchInfo::collect(rom_manager);
+            SortOrder::Name.sort(&mut patch_infos);
+
+            if self.config.ab_toggle {
+                let unpatched_roms = self.unpatched_roms.lock().unwrap();
+                for patch_info in patch_infos.iter_mut() {
+                    patch_info.patched = !unpatched_roms.contains(&patch_info.target_path);
+                }
+            }
+
+            let manifest = if path == Path::new(MANIFEST_JSON_NAME) {
+                patch_info::to_json(&patch_infos)
+            } else {
+                patch_info::to_csv(&patch_infos)
+            };
+
+            return Some(Arc::new(GeneratedFile {
+                data: manifest.into_bytes(),
+            }));
+        }
+
+        if self.config.header_sidecars {
+            if let Some(rom_path) = path.to_str().and_then(|p| p.strip_suffix(HEADER_SIDECAR_SUFFIX)) {
+                let rom_path = Path::new(rom_path);
+                if has_header_sidecar(rom_path) {
+                    return self.lookup_sidecar(rom_manager, path, rom_path, SidecarKind::Header);
+                }
+            }
+        }
+
+        if self.config.source_crc_sidecars {
+            if let Some(rom_path) = path.to_str().and_then(|p| p.strip_suffix(SOURCE_CRC_SIDECAR_SUFFIX)) {
+                let source_checksum = rom_manager.target_roms.get(Path::new(rom_path))?.source_checksum()?;
+                return Some(Arc::new(GeneratedFile {
+                    data: format!("{:08X}\n", source_checksum).into_bytes(),
+                }));
+            }
+        }
+
+        if self.config.diff_sidecars {
+            if let Some(rom_path) = path.to_str().and_then(|p| p.strip_suffix(DIFF_SIDECAR_SUFFIX)) {
+                if rom_manager.target_roms.contains_key(Path::new(rom_path)) {
+                    return self.lookup_sidecar(rom_manager, path, Path::new(rom_path), SidecarKind::Diff);
+                }
+            }
+        }
+
+        if self.config.header_variants {
+            if let Some(rom) = self.lookup_header_variant(rom_manager, path) {
+                return Some(rom);
+            }
+        }
+
+        if self.config.byte_order_variants && !rom_manager.target_roms.contains_key(path) {
+            return self.lookup_byte_order_variant(rom_manager, path);
+        }
+
+        let rom = rom_manager.target_roms.get(path)?;
+
+        if self.config.ab_toggle && self.unpatched_roms.lock().unwrap().contains(path) {
+            let source_path = rom.source_path()?;
+            let source_size = fs::metadata(source_path).ok()?.len();
+
+            Some(Arc::new(UnpatchedRom {
+                source_path: source_path.to_owned(),
+                source_size,
+            }))
+        } else {
+            Some(rom.clone())
+        }
+    }
+
+    fn lookup_header_variant(&self, rom_manager: &RomManager, path: &Path) -> Option<Arc<dyn Patch + Send + Sync>> {
+        let stem = Path::new(path.file_stem()?);
+        let variant = stem.extension()?;
+        if variant != HEADERLESS_VARIANT && variant != HEADERED_VARIANT {
+            return None;
         }
+
+        let mut rom_file_name = stem.file_stem()?.to_owned();
+        rom_file_name.push(".");
+        rom_file_name.push(path.extension()?);
+        let rom_path = path.with_file_name(rom_file_name);
+
+        let rom = rom_manager.target_roms.get(&rom_path)?;
+        if header_variant_path(&rom_path, rom.target_size()).as_deref() != Some(path) {
+            return None;
+        }
+
+        Some(Arc::new(HeaderVariant {
+            rom: self.lookup_file(rom_manager, &rom_path)?,
+            header_size: rom_header::external_header_size(&rom_path)?,
+            headered: variant == HEADERED_VARIANT,
+        }))
+    }
+
+    fn lookup_byte_order_variant(&self, rom_manager: &RomManager, path: &Path) -> Option<Arc<dyn Patch + Send + Sync>> {
+        let rom_path = byte_order_variant_paths(path)
+            .into_iter()
+            .find(|rom_path| rom_manager.target_roms.contains_key(rom_path))?;
+
+        Some(Arc::new(ByteOrderVariant {
+            rom: self.lookup_file(rom_manager, &rom_path)?,
+            rom_byte_order: n64_byte_order(&rom_path)?,
+            byte_order: n64_byte_order(path)?,
+        }))
+    }
+
+    /// Sidecars are kept while the ROM manager stays at the same generation and the ROM isn't toggled,
+    /// so they are generated once rather than on every lookup.
+    fn lookup_sidecar(
+        &self,
+        rom_manager: &RomManager,
+        path: &Path,
+        rom_path: &Path,
+        kind: SidecarKind,
+    ) -> Option<Arc<dyn Patch + Send + Sync>> {
+        let rom = self.lookup_file(rom_manager, rom_path)?;
+        let generation = rom_manager.generation();
+
+        let mut sidecars = self.sidecars.lock().unwrap();
+        if let Some((sidecar_generation, sidecar)) = sidecars.get(path) {
+            if *sidecar_generation == generation && Arc::ptr_eq(&sidecar.rom, &rom) {
+                return Some(sidecar.clone());
+            }
+        }
+
+        sidecars.retain(|_, (sidecar_generation, _)| *sidecar_generation == generation);
+        let sidecar = Arc::new(RomSidecar::new(rom, rom_manager.source_cache().clone(), kind));
+        sidecars.insert(path.to_owned(), (generation, sidecar.clone()));
+        Some(sidecar)
     }
 
-    fn get_root_attr(&self) -> FileAttr {
+    fn get_directory_attr(&self) -> FileAttr {
         FileAttr {
             size: 0,
             blocks: 0,
@@ -69,14 +788,18 @@ impl RomFilesystem {
         }
     }
 
+    /// Patched ROMs take the timestamps of their patch files, generated files are dated to the epoch.
     fn get_file_attr(&self, patch: &Arc<dyn Patch + Send + Sync>) -> FileAttr {
+        let modified = patch.modified().map_or(EPOCH, |modified| timespec_from(&modified));
+        let created = patch.created().map_or(modified, |created| timespec_from(&created));
+
         FileAttr {
             size: patch.target_size(),
             blocks: 0,
-            atime: EPOCH,  //timespec_from(&patch.access_time),
-            mtime: EPOCH,  //timespec_from(&patch.modify_time),
-            ctime: EPOCH,  //timespec_from(&patch.modify_time),
-            crtime: EPOCH, //timespec_from(&patch.create_time),
+            atime: modified,
+            mtime: modified,
+            ctime: modified,
+            crtime: created,
             kind: FileType::RegularFile,
             perm: 0o444,
             nlink: 1,
@@ -90,22 +813,48 @@ impl RomFilesystem {
 
 impl FilesystemMT for RomFilesystem {
     fn init(&self, _req: RequestInfo) -> ResultEmpty {
+        if let Some(on_mount) = &self.config.on_mount {
+            on_mount();
+        }
         Ok(())
     }
 
+    fn destroy(&self, _req: RequestInfo) {
+        for (operation, count) in self.counters.snapshot() {
+            info!("{}: {} calls", operation, count);
+        }
+        info!("{} bytes served", self.counters.bytes_read.load(Ordering::Relaxed));
+
+        for (path, elapsed) in self.patch_timings() {
+            info!("{:?}: patched in {:?}", path, elapsed);
+        }
+
+        // Patched ROMs of handles left open by the clients are dropped along with the cached ones
+        self.handles.write().unwrap().clear();
+        self.rom_manager.lock().unwrap().clear_caches();
+        self.memory_cache.clear();
+
+        if let Some(rom_cache) = &self.config.rom_cache {
+            rom_cache.clear();
+        }
+    }
+
     fn opendir(&self, _req: RequestInfo, path: &Path, _flags: u32) -> ResultOpen {
+        OperationCounters::count(&self.counters.opendir);
+
         let path = path.strip_prefix("/").unwrap();
-        let mut handles = self.handles.lock().unwrap();
+        let rom_manager = self.rom_manager.lock().unwrap();
+        let mut handles = self.handles.write().unwrap();
         let mut next_handle = self.next_handle.lock().unwrap();
 
-        if path == Path::new("") {
+        if is_directory(&rom_manager, path) {
             let handle = *next_handle;
             *next_handle += 1;
 
             handles.insert(
                 handle,
                 Handle::Directory {
-                    attr: self.get_root_attr(),
+                    attr: self.get_directory_attr(),
                 },
             );
             Ok((handle, 0))
@@ -114,9 +863,12 @@ impl FilesystemMT for RomFilesystem {
         }
     }
 
-    fn readdir(&self, _req: RequestInfo, _path: &Path, fh: u64) -> ResultReaddir {
+    fn readdir(&self, _req: RequestInfo, path: &Path, fh: u64) -> ResultReaddir {
+        OperationCounters::count(&self.counters.readdir);
+
+        let directory = path.strip_prefix("/").unwrap();
         let rom_manager = self.rom_manager.lock().unwrap();
-        let handles = self.handles.lock().unwrap();
+        let handles = self.handles.read().unwrap();
 
         if let Some(Handle::Directory { .. }) = handles.get(&fh) {
             let mut files = Vec::new();
@@ -131,120 +883,332 @@ impl FilesystemMT for RomFilesystem {
                 kind: FileType::Directory,
             });
 
-            for path in rom_manager.target_roms.keys() {
+            let mut subdirectories = BTreeSet::new();
+            let mut byte_order_variants = BTreeSet::new();
+
+            for (path, rom) in rom_manager.target_roms.iter() {
+                // Targets deeper down are listed in the subdirectories, by the name of the subdirectory here
+                let file_name = match path.strip_prefix(directory) {
+                    Ok(file_name) if file_name.components().count() > 1 => {
+                        subdirectories.extend(file_name.components().next().map(|c| c.as_os_str().to_owned()));
+                        continue;
+                    }
+                    Ok(file_name) => file_name,
+                    Err(_) => continue,
+                };
+
                 files.push(DirectoryEntry {
-                    name: path.into(),
+                    name: file_name.into(),
                     kind: FileType::RegularFile,
                 });
-            }
 
-            Ok(files)
-        } else {
-            Err(libc::ENOENT)
-        }
-    }
+                if self.config.header_variants {
+                    if let Some(variant_path) = header_variant_path(file_name, rom.target_size()) {
+                        files.push(DirectoryEntry {
+                            name: variant_path.into(),
+                            kind: FileType::RegularFile,
+                        });
+                    }
+                }
 
-    fn releasedir(&self, _req: RequestInfo, _path: &Path, fh: u64, _flags: u32) -> ResultEmpty {
-        let mut handles = self.handles.lock().unwrap();
+                // Listed after the loop, as ROMs in several byte orders share their variants
+                if self.config.byte_order_variants {
+                    byte_order_variants.extend(
+                        byte_order_variant_paths(path)
+                            .into_iter()
+                            .filter(|variant_path| !rom_manager.target_roms.contains_key(variant_path))
+                            .filter_map(|variant_path| variant_path.file_name().map(OsStr::to_owned)),
+                    );
+                }
 
-        if let Some(Handle::Directory { .. }) = handles.get(&fh) {
-            handles.remove(&fh);
-            Ok(())
-        } else {
-            Err(libc::ENOENT)
-        }
-    }
+                if self.config.header_sidecars && has_header_sidecar(path) {
+                    let mut name = file_name.as_os_str().to_owned();
+                    name.push(HEADER_SIDECAR_SUFFIX);
+
+                    files.push(DirectoryEntry {
+                        name,
+                        kind: FileType::RegularFile,
+                    });
+                }
+
+                if self.config.diff_sidecars {
+                    let mut name = file_name.as_os_str().to_owned();
+                    name.push(DIFF_SIDECAR_SUFFIX);
+
+                    files.push(DirectoryEntry {
+                        name,
+                        kind: FileType::RegularFile,
+                    });
+                }
+
+                if self.config.source_crc_sidecars && rom.source_checksum().is_some() {
+                    let mut name = file_name.as_os_str().to_owned();
+                    name.push(SOURCE_CRC_SIDECAR_SUFFIX);
+
+                    files.push(DirectoryEntry {
+                        name,
+                        kind: FileType::RegularFile,
+                    });
+                }
+            }
+
+            for name in byte_order_variants {
+                files.push(DirectoryEntry {
+                    name,
+                    kind: FileType::RegularFile,
+                });
+            }
+
+            for name in subdirectories {
+                files.push(DirectoryEntry {
+                    name,
+                    kind: FileType::Directory,
+                });
+            }
+
+            // Generated files are only listed in the root directory
+            if directory != Path::new("") {
+                return Ok(files);
+            }
+
+            files.push(DirectoryEntry {
+                name: VERSION_NAME.into(),
+                kind: FileType::RegularFile,
+            });
+
+            if self.config.expose_archive {
+                files.push(DirectoryEntry {
+                    name: ARCHIVE_NAME.into(),
+                    kind: FileType::RegularFile,
+                });
+            }
+
+            if self.config.manifest {
+                for name in &[MANIFEST_JSON_NAME, MANIFEST_CSV_NAME] {
+                    files.push(DirectoryEntry {
+                        name: name.into(),
+                        kind: FileType::RegularFile,
+                    });
+                }
+            }
+
+            Ok(files)
+        } else {
+            Err(libc::ENOENT)
+        }
+    }
+
+    fn releasedir(&self, _req: RequestInfo, _path: &Path, fh: u64, _flags: u32) -> ResultEmpty {
+        OperationCounters::count(&self.counters.releasedir);
+
+        let mut handles = self.handles.write().unwrap();
+
+        if let Some(Handle::Directory { .. }) = handles.get(&fh) {
+            handles.remove(&fh);
+            Ok(())
+        } else {
+            Err(libc::ENOENT)
+        }
+    }
+
+    fn access(&self, _req: RequestInfo, _path: &Path, _mask: u32) -> ResultEmpty {
+        OperationCounters::count(&self.counters.access);
 
-    fn access(&self, _req: RequestInfo, _path: &Path, _mask: u32) -> ResultEmpty {
         Ok(())
     }
 
-    #[allow(clippy::collapsible_if)]
+    /// The filesystem is as large as the target ROMs and always full, as nothing can be written to it.
+    fn statfs(&self, _req: RequestInfo, _path: &Path) -> ResultStatfs {
+        OperationCounters::count(&self.counters.statfs);
+
+        let rom_manager = self.rom_manager.lock().unwrap();
+        let total_size: u64 = rom_manager.target_roms.values().map(|patch| patch.target_size()).sum();
+
+        Ok(Statfs {
+            blocks: total_size.div_ceil(STATFS_BLOCK_SIZE as u64),
+            bfree: 0,
+            bavail: 0,
+            files: rom_manager.target_roms.len() as u64,
+            ffree: 0,
+            bsize: STATFS_BLOCK_SIZE,
+            namelen: STATFS_MAX_NAME_LENGTH,
+            frsize: STATFS_BLOCK_SIZE,
+        })
+    }
+
     fn getattr(&self, _req: RequestInfo, path: &Path, fh: Option<u64>) -> ResultEntry {
+        OperationCounters::count(&self.counters.getattr);
+
         let path = path.strip_prefix("/").unwrap();
-        let rom_manager = self.rom_manager.lock().unwrap();
-        let handles = self.handles.lock().unwrap();
 
         if let Some(fh) = fh {
-            match handles.get(&fh) {
+            match self.handles.read().unwrap().get(&fh) {
                 Some(Handle::Directory { attr }) => Ok((TTL, *attr)),
                 Some(Handle::File { attr, .. }) => Ok((TTL, *attr)),
                 _ => Err(libc::ENOENT),
             }
         } else {
-            if path == Path::new("") {
-                Ok((TTL, self.get_root_attr()))
-            } else if let Some(rom) = rom_manager.target_roms.get(path) {
-                Ok((TTL, self.get_file_attr(rom)))
-            } else {
-                Err(libc::ENOENT)
+            let rom = {
+                let rom_manager = self.rom_manager.lock().unwrap();
+                if is_directory(&rom_manager, path) {
+                    return Ok((TTL, self.get_directory_attr()));
+                }
+                self.lookup_file(&rom_manager, path)
+            };
+
+            // Sizing sidecars takes patching, done without holding up the other requests on the ROM manager
+            match rom {
+                Some(rom) => Ok((TTL, self.get_file_attr(&rom))),
+                None => Err(libc::ENOENT),
             }
         }
     }
 
-    fn open(&self, _req: RequestInfo, path: &Path, _flags: u32) -> ResultOpen {
+    fn open(&self, _req: RequestInfo, path: &Path, flags: u32) -> ResultOpen {
+        OperationCounters::count(&self.counters.open);
+
         let path = path.strip_prefix("/").unwrap();
-        let rom_manager = self.rom_manager.lock().unwrap();
-        let mut handles = self.handles.lock().unwrap();
-        let mut next_handle = self.next_handle.lock().unwrap();
+        let (rom, cache_key) = {
+            let rom_manager = self.rom_manager.lock().unwrap();
+            let rom = self.lookup_file(&rom_manager, path).ok_or(libc::ENOENT)?;
 
-        if let Some(rom) = rom_manager.target_roms.get(path) {
-            let handle = *next_handle;
+            // Refuse write access upfront, rather than failing on the first write.
+            // File locks are left to the kernel, which handles them locally as the lock requests of
+            // FUSE go unanswered: shared locks are granted, while exclusive ones fail on read-only files.
+            if flags & libc::O_ACCMODE as u32 != libc::O_RDONLY as u32 {
+                return Err(libc::EROFS);
+            }
+
+            // Only patched target ROMs are cached and hashed, generated files and unpatched ROMs are cheap to serve
+            let is_target_rom = matches!(rom_manager.target_roms.get(path), Some(r) if Arc::ptr_eq(r, &rom));
+            if is_target_rom {
+                // Degraded targets are bound to fail patching, refused upfront rather than on the first read
+                if let Some(err) = rom_manager.degraded_targets.get(path) {
+                    error!("Refusing to open degraded {:?}: {}", path, err);
+                    return Err(libc::EIO);
+                }
+            }
+
+            let cache_key = if is_target_rom {
+                Some((path.to_owned(), rom_manager.generation()))
+            } else {
+                None
+            };
+            (rom, cache_key)
+        };
+
+        // Sidecars are generated for their size, without holding up the other requests on the ROM manager
+        let attr = self.get_file_attr(&rom);
+
+        // ROMs patched for earlier opens are served right away
+        let data = cache_key
+            .as_ref()
+            .and_then(|(rom_path, generation)| self.memory_cache.load(rom_path, *generation));
+
+        let handle = {
+            let mut next_handle = self.next_handle.lock().unwrap();
             *next_handle += 1;
+            *next_handle - 1
+        };
 
-            handles.insert(
-                handle,
-                Handle::File {
-                    attr: self.get_file_attr(rom),
-                    patch: rom.clone(),
-                    data: None,
-                },
-            );
+        self.handles.write().unwrap().insert(
+            handle,
+            Handle::File {
+                attr,
+                patch: rom,
+                data,
+                cache_key,
+            },
+        );
 
-            Ok((handle, 0))
-        } else {
-            Err(libc::ENOENT)
-        }
+        Ok((handle, 0))
     }
 
     fn read(
         &self,
         _req: RequestInfo,
-        _path: &Path,
+        path: &Path,
         fh: u64,
         offset: u64,
         size: u32,
         result: impl FnOnce(Result<&[u8], libc::c_int>),
     ) {
-        let mut handles = self.handles.lock().unwrap();
+        OperationCounters::count(&self.counters.read);
 
-        if let Some(Handle::File { data, patch, .. }) = handles.get_mut(&fh) {
-            // Deferred ROM patching on first read
-            if data.is_none() {
-                match patch.patched_rom() {
-                    Ok(patched_rom) => *data = Some(patched_rom),
+        // Fast path for already patched ROMs, concurrent reads only need a shared lock
+        {
+            let handles = self.handles.read().unwrap();
+
+            match handles.get(&fh) {
+                Some(Handle::File { data: Some(data), .. }) => {
+                    let data = data_slice(data, offset, size);
+                    self.counters.count_bytes(data.len());
+                    result(Ok(data));
+                    return;
+                }
+                // Reads untouched by the patch are served from the source ROM, without patching it
+                Some(Handle::File { data: None, patch, .. }) => match patch.unchanged_range(offset, size as u64) {
+                    Ok(Some(data)) => {
+                        self.counters.count_bytes(data.len());
+                        result(Ok(&data));
+                        return;
+                    }
+                    Ok(None)
+                        if patch.partial_patching() && offset.saturating_add(size as u64) <= PARTIAL_READ_LIMIT =>
+                    {
+                        match patch.patched_range(offset, size as usize) {
+                            Ok(data) => {
+                                self.counters.count_bytes(data.len());
+                                result(Ok(&data));
+                            }
+                            Err(err) => {
+                                error!("Failed to patch ROM: {}", err);
+                                result(Err(libc::EIO));
+                            }
+                        }
+                        return;
+                    }
+                    Ok(None) => {}
                     Err(err) => {
-                        error!("Failed to patch ROM: {}", err);
+                        error!("Failed to read source ROM: {}", err);
                         result(Err(libc::EIO));
                         return;
                     }
-                }
+                },
+                _ => {}
             }
+        }
 
-            if let Some(data) = data {
-                if offset as usize > data.len() {
-                    result(Ok(&[]));
-                } else {
-                    let offset = offset as usize;
-                    let size = cmp::min(size as usize, data.len() - offset);
-                    result(Ok(&data[offset..offset + size]));
-                }
-            } else {
-                unreachable!();
+        // Patching is done without holding the handles, so reads of other handles go on meanwhile
+        let (patch, cache_key) = match self.handles.read().unwrap().get(&fh) {
+            Some(Handle::File { patch, cache_key, .. }) => (patch.clone(), cache_key.clone()),
+            _ => {
+                result(Err(libc::ENOENT));
+                return;
             }
-        } else {
-            result(Err(libc::ENOENT));
-        }
+        };
+
+        let patched_rom = match self.patched_data(path, &patch, cache_key.as_ref()) {
+            Ok(patched_rom) => patched_rom,
+            Err(err) => {
+                error!("Failed to patch ROM: {}", err);
+                result(Err(libc::EIO));
+                return;
+            }
+        };
+
+        // Concurrent reads of the handle may have patched the ROM too, the data stored first is kept
+        let data = match self.handles.write().unwrap().get_mut(&fh) {
+            Some(Handle::File { data, .. }) => data.get_or_insert(patched_rom).clone(),
+            _ => {
+                result(Err(libc::ENOENT));
+                return;
+            }
+        };
+
+        let data = data_slice(&data, offset, size);
+        self.counters.count_bytes(data.len());
+        result(Ok(data));
     }
 
     fn release(
@@ -256,7 +1220,9 @@ impl FilesystemMT for RomFilesystem {
         _lock_owner: u64,
         _flush: bool,
     ) -> ResultEmpty {
-        let mut handles = self.handles.lock().unwrap();
+        OperationCounters::count(&self.counters.release);
+
+        let mut handles = self.handles.write().unwrap();
 
         if let Some(Handle::File { .. }) = handles.get(&fh) {
             handles.remove(&fh);
@@ -265,4 +1231,989 @@ impl FilesystemMT for RomFilesystem {
             Err(libc::ENOENT)
         }
     }
+
+    /// Patched ROMs are named by the patches, renaming them in the filesystem is refused
+    /// like any other modification.
+    fn rename(
+        &self,
+        _req: RequestInfo,
+        parent: &Path,
+        name: &OsStr,
+        _newparent: &Path,
+        _newname: &OsStr,
+    ) -> ResultEmpty {
+        let path = parent.join(name);
+        let path = path.strip_prefix("/").unwrap();
+        let rom_manager = self.rom_manager.lock().unwrap();
+
+        if self.lookup_file(&rom_manager, path).is_some() {
+            Err(libc::EROFS)
+        } else {
+            Err(libc::ENOENT)
+        }
+    }
+
+    fn setxattr(
+        &self,
+        _req: RequestInfo,
+        path: &Path,
+        name: &OsStr,
+        value: &[u8],
+        _flags: u32,
+        _position: u32,
+    ) -> ResultEmpty {
+        let path = path.strip_prefix("/").unwrap();
+        let rom_manager = self.rom_manager.lock().unwrap();
+
+        if !rom_manager.target_roms.contains_key(path) {
+            return Err(libc::ENOENT);
+        }
+
+        if name == TITLE_XATTR || name == AUTHOR_XATTR || name == METADATA_XATTR || name == SHA1_XATTR {
+            return Err(libc::EPERM);
+        }
+
+        if !self.config.ab_toggle || name != PATCHED_XATTR {
+            return Err(libc::ENOTSUP);
+        }
+
+        let mut unpatched_roms = self.unpatched_roms.lock().unwrap();
+        match value {
+            b"0" => unpatched_roms.insert(path.to_owned()),
+            b"1" => unpatched_roms.remove(path),
+            _ => return Err(libc::EINVAL),
+        };
+
+        Ok(())
+    }
+
+    fn getxattr(&self, _req: RequestInfo, path: &Path, name: &OsStr, size: u32) -> ResultXattr {
+        let path = path.strip_prefix("/").unwrap();
+
+        // Hashing takes patching the whole ROM, done without holding up the other requests on the ROM manager
+        let (patch, generation) = {
+            let rom_manager = self.rom_manager.lock().unwrap();
+            match rom_manager.target_roms.get(path) {
+                Some(patch) => (patch.clone(), rom_manager.generation()),
+                // Directories and generated files exist, they just carry no attributes
+                None if is_directory(&rom_manager, path) || self.lookup_file(&rom_manager, path).is_some() => {
+                    return Err(ENOATTR)
+                }
+                None => return Err(libc::ENOENT),
+            }
+        };
+
+        if self.config.ab_toggle && name == PATCHED_XATTR {
+            let patched = !self.unpatched_roms.lock().unwrap().contains(path);
+            xattr_reply(if patched { b"1".to_vec() } else { b"0".to_vec() }, size)
+        } else if name == TITLE_XATTR {
+            let title = patch.title().ok_or(ENOATTR)?;
+            xattr_reply(title.as_bytes().to_vec(), size)
+        } else if name == AUTHOR_XATTR {
+            let author = patch.author().ok_or(ENOATTR)?;
+            xattr_reply(author.as_bytes().to_vec(), size)
+        } else if name == METADATA_XATTR {
+            let metadata = patch.metadata().ok_or(ENOATTR)?;
+            xattr_reply(metadata.to_vec(), size)
+        } else if self.config.target_sha1 && name == SHA1_XATTR {
+            let sha1 = match self.cached_target_sha1(path, generation) {
+                Some(sha1) => sha1,
+                None => {
+                    let data = patch.patched_rom().map_err(|err| {
+                        error!("Failed to patch ROM: {}", err);
+                        libc::EIO
+                    })?;
+                    self.target_sha1(path, generation, &data)
+                }
+            };
+            xattr_reply(sha1.into_bytes(), size)
+        } else {
+            Err(ENOATTR)
+        }
+    }
+
+    fn listxattr(&self, _req: RequestInfo, path: &Path, size: u32) -> ResultXattr {
+        let path = path.strip_prefix("/").unwrap();
+        let rom_manager = self.rom_manager.lock().unwrap();
+
+        let mut names = Vec::new();
+
+        if let Some(patch) = rom_manager.target_roms.get(path) {
+            if self.config.ab_toggle {
+                names.extend_from_slice(PATCHED_XATTR.as_bytes());
+                names.push(0);
+            }
+
+            // Read-only attributes, only listed when the patch carries them
+            if patch.title().is_some() {
+                names.extend_from_slice(TITLE_XATTR.as_bytes());
+                names.push(0);
+            }
+
+            if patch.author().is_some() {
+                names.extend_from_slice(AUTHOR_XATTR.as_bytes());
+                names.push(0);
+            }
+
+            if patch.metadata().is_some() {
+                names.extend_from_slice(METADATA_XATTR.as_bytes());
+                names.push(0);
+            }
+
+            if self.config.target_sha1 {
+                names.extend_from_slice(SHA1_XATTR.as_bytes());
+                names.push(0);
+            }
+        }
+
+        xattr_reply(names, size)
+    }
+
+    fn removexattr(&self, _req: RequestInfo, path: &Path, name: &OsStr) -> ResultEmpty {
+        let path = path.strip_prefix("/").unwrap();
+
+        if self.config.ab_toggle && name == PATCHED_XATTR {
+            self.unpatched_roms.lock().unwrap().remove(path);
+            Ok(())
+        } else if name == TITLE_XATTR || name == AUTHOR_XATTR || name == METADATA_XATTR || name == SHA1_XATTR {
+            Err(libc::EPERM)
+        } else {
+            Err(ENOATTR)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{
+        make_bps, make_bps_with_metadata, make_dir_fixture, make_hack, make_ips, make_rom, make_source_less_bps,
+    };
+    use crc::crc32;
+    use std::str;
+
+    fn request() -> RequestInfo {
+        RequestInfo {
+            unique: 0,
+            uid: 0,
+            gid: 0,
+            pid: 0,
+        }
+    }
+
+    fn xattr(fs: &RomFilesystem, path: &str, name: &str) -> Result<Vec<u8>, libc::c_int> {
+        match fs.getxattr(request(), Path::new(path), OsStr::new(name), 4096)? {
+            Xattr::Data(data) => Ok(data),
+            Xattr::Size(_) => panic!("size reply to a sized request"),
+        }
+    }
+
+    fn filesystem(base_directory: &Path, config: RomFilesystemConfig) -> RomFilesystem {
+        let rom_manager = RomManager::new(base_directory).unwrap();
+        RomFilesystem::with_config(Arc::new(Mutex::new(rom_manager)), config)
+    }
+
+    /// An NROM game turned into an MMC1 game with battery-backed RAM by its patch.
+    fn nes_fixture() -> (Vec<u8>, Vec<u8>) {
+        let mut source = make_rom(16 + 32 * 1024 + 8 * 1024, 1);
+        source[..16].copy_from_slice(b"NES\x1A\x02\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00");
+        let mut target = source.clone();
+        target[6] = 0x13;
+        (source, target)
+    }
+
+    #[test]
+    fn generates_sidecars_once_per_generation() {
+        let (source, target) = nes_fixture();
+        let directory = make_dir_fixture(&[("game.nes", &source), ("hack.bps", &make_bps(&source, &target))]);
+        let fs = filesystem(
+            directory.path(),
+            RomFilesystemConfig {
+                header_sidecars: true,
+                ..RomFilesystemConfig::default()
+            },
+        );
+        let sidecar_path = Path::new("hack.nes.header.json");
+
+        let lookup = || {
+            let rom_manager = fs.rom_manager.lock().unwrap();
+            fs.lookup_file(&rom_manager, sidecar_path).unwrap()
+        };
+        let sidecar = lookup();
+        assert!(Arc::ptr_eq(&sidecar, &lookup()));
+
+        let expected = concat!(
+            "{\n",
+            "  \"format\": \"iNES\",\n",
+            "  \"mapper\": 1,\n",
+            "  \"prg_rom_size\": 32768,\n",
+            "  \"chr_rom_size\": 8192,\n",
+            "  \"mirroring\": \"vertical\",\n",
+            "  \"battery\": true,\n",
+            "  \"trainer\": false\n",
+            "}\n"
+        );
+        let (_, attr) = fs.getattr(request(), Path::new("/hack.nes.header.json"), None).unwrap();
+        assert_eq!(attr.size, expected.len() as u64);
+        assert_eq!(
+            fs.read_at(Path::new("/hack.nes.header.json"), 0, 4096).unwrap(),
+            expected.as_bytes()
+        );
+
+        fs.rom_manager.lock().unwrap().refresh().unwrap();
+        assert!(!Arc::ptr_eq(&sidecar, &lookup()));
+    }
+
+    #[test]
+    fn lists_changed_ranges_in_diff_sidecars() {
+        let source = make_rom(4096, 1);
+        let mut target = source.clone();
+        target[0x10] ^= 0xFF;
+        target[0x100..0x108].iter_mut().for_each(|byte| *byte ^= 0x01);
+        target[0x109] ^= 0x80;
+        target.extend_from_slice(&[0; 4]);
+        let directory = make_dir_fixture(&[
+            ("game.sfc", &source),
+            ("hack.bps", &make_bps(&source, &target)),
+            ("full.bps", &make_bps(&[], &target[..8])),
+        ]);
+        let fs = filesystem(
+            directory.path(),
+            RomFilesystemConfig {
+                diff_sidecars: true,
+                ..RomFilesystemConfig::default()
+            },
+        );
+
+        // Bytes past the end of the source ROM are changed, zeroes included
+        let expected = "00000010-00000011\n00000100-00000108\n00000109-0000010A\n00001000-00001004\n";
+        assert_eq!(
+            fs.read_at(Path::new("/hack.sfc.diff"), 0, 4096).unwrap(),
+            expected.as_bytes()
+        );
+        let (_, attr) = fs.getattr(request(), Path::new("/hack.sfc.diff"), None).unwrap();
+        assert_eq!(attr.size, expected.len() as u64);
+
+        assert_eq!(
+            fs.read_at(Path::new("/full.bin.diff"), 0, 4096).unwrap(),
+            b"00000000-00000008\n"
+        );
+        assert_eq!(fs.read_at(Path::new("/game.sfc.diff"), 0, 4096), Err(libc::ENOENT));
+    }
+
+    #[test]
+    fn lists_changed_ranges_past_the_headers_of_sources() {
+        let source = make_rom(2048, 1);
+        let mut target = source.clone();
+        target[0x10] ^= 0xFF;
+        let mut headered_source = vec![0; 512];
+        headered_source.extend_from_slice(&source);
+        let directory = make_dir_fixture(&[
+            ("game.smc", &headered_source),
+            ("hack.bps", &make_bps(&source, &target)),
+        ]);
+        let fs = filesystem(
+            directory.path(),
+            RomFilesystemConfig {
+                diff_sidecars: true,
+                ..RomFilesystemConfig::default()
+            },
+        );
+
+        // The patch is made against the headerless ROM, so are the offsets
+        assert_eq!(
+            fs.read_at(Path::new("/hack.smc.diff"), 0, 4096).unwrap(),
+            b"00000010-00000011\n"
+        );
+    }
+
+    #[test]
+    fn reports_the_sha1_of_targets() {
+        let directory = make_dir_fixture(&[("abc.bps", &make_source_less_bps(b"abc"))]);
+        let fs = filesystem(
+            directory.path(),
+            RomFilesystemConfig {
+                target_sha1: true,
+                ..RomFilesystemConfig::default()
+            },
+        );
+
+        // The SHA-1 test vector of FIPS 180-1
+        let expected = b"a9993e364706816aba3e25717850c26c9cd0d89d";
+        assert_eq!(xattr(&fs, "/abc.bin", SHA1_XATTR).unwrap(), expected);
+        assert_eq!(fs.read_at(Path::new("/abc.bin"), 0, 16).unwrap(), b"abc");
+        assert_eq!(xattr(&fs, "/abc.bin", SHA1_XATTR).unwrap(), expected);
+
+        match fs.getxattr(request(), Path::new("/abc.bin"), OsStr::new(SHA1_XATTR), 0) {
+            Ok(Xattr::Size(size)) => assert_eq!(size, 40),
+            _ => panic!("expected the size of the SHA-1"),
+        }
+    }
+
+    #[test]
+    fn serves_concurrent_single_byte_reads() {
+        // Past the partial read limit, so the reads take the slow path
+        let source = make_rom(PARTIAL_READ_LIMIT as usize * 2, 1);
+        let target = make_hack(&source);
+        let directory = make_dir_fixture(&[("game.sfc", &source), ("hack.bps", &make_bps(&source, &target))]);
+        let fs = filesystem(directory.path(), RomFilesystemConfig::default());
+        let path = Path::new("/hack.sfc");
+
+        let (fh, _) = fs.open(request(), path, libc::O_RDONLY as u32).unwrap();
+        std::thread::scope(|scope| {
+            for thread in 0..8 {
+                let (fs, target) = (&fs, &target);
+                scope.spawn(move || {
+                    for offset in (thread..target.len()).step_by(target.len() / 64) {
+                        let offset = offset as u64 + PARTIAL_READ_LIMIT;
+                        let mut data = Vec::new();
+                        fs.read(request(), path, fh, offset, 1, |result| data = result.unwrap().to_vec());
+                        assert_eq!(data, data_slice(target, offset, 1));
+                    }
+                });
+            }
+        });
+        fs.release(request(), path, fh, 0, 0, false).unwrap();
+
+        // Every handle shares the ROM patched by the first read to finish
+        assert_eq!(fs.patch_timings().len(), 1);
+        let generation = fs.rom_manager.lock().unwrap().generation();
+        assert_eq!(
+            fs.memory_cache.load(Path::new("hack.sfc"), generation).as_deref(),
+            Some(&target)
+        );
+    }
+
+    #[test]
+    fn reports_missing_attributes_of_other_entries() {
+        let source = make_rom(1024, 1);
+        let directory = make_dir_fixture(&[
+            ("game.sfc", &source),
+            ("hacks/hack.bps", &make_bps(&source, &make_hack(&source))),
+        ]);
+        let fs = filesystem(
+            directory.path(),
+            RomFilesystemConfig {
+                expose_archive: true,
+                diff_sidecars: true,
+                ..RomFilesystemConfig::default()
+            },
+        );
+
+        for path in [
+            "/",
+            "/hacks",
+            "/hacks/hack.sfc.diff",
+            "/bps-fuse.zip",
+            "/.bps-fuse.version",
+        ] {
+            assert_eq!(xattr(&fs, path, TITLE_XATTR), Err(ENOATTR), "{}", path);
+        }
+        assert_eq!(xattr(&fs, "/hacks/hack.sfc", TITLE_XATTR), Err(ENOATTR));
+        assert_eq!(xattr(&fs, "/hacks/missing.sfc", TITLE_XATTR), Err(libc::ENOENT));
+        assert_eq!(xattr(&fs, "/missing", TITLE_XATTR), Err(libc::ENOENT));
+    }
+
+    #[test]
+    fn counts_operations() {
+        let directory = make_dir_fixture(&[("abc.bps", &make_source_less_bps(b"abc"))]);
+        let fs = filesystem(directory.path(), RomFilesystemConfig::default());
+
+        let (fh, _) = fs.opendir(request(), Path::new("/"), 0).unwrap();
+        fs.readdir(request(), Path::new("/"), fh).unwrap();
+        fs.releasedir(request(), Path::new("/"), fh, 0).unwrap();
+        fs.getattr(request(), Path::new("/abc.bin"), None).unwrap();
+        fs.getattr(request(), Path::new("/missing.bin"), None).unwrap_err();
+        fs.access(request(), Path::new("/abc.bin"), 0).unwrap();
+        fs.statfs(request(), Path::new("/")).unwrap();
+        assert_eq!(fs.read_at(Path::new("/abc.bin"), 1, 16).unwrap(), b"bc");
+        assert_eq!(fs.read_at(Path::new("/abc.bin"), 0, 1).unwrap(), b"a");
+
+        // Failed operations are counted too
+        assert_eq!(
+            fs.counters.snapshot(),
+            vec![
+                ("opendir", 1),
+                ("readdir", 1),
+                ("releasedir", 1),
+                ("access", 1),
+                ("statfs", 1),
+                ("getattr", 2),
+                ("open", 2),
+                ("read", 2),
+                ("release", 2),
+            ]
+        );
+        assert_eq!(fs.counters.bytes_read.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn toggles_patching() {
+        let source = make_rom(1024, 1);
+        let target = make_hack(&source);
+        let directory = make_dir_fixture(&[("game.sfc", &source), ("hack.bps", &make_bps(&source, &target))]);
+        let fs = filesystem(
+            directory.path(),
+            RomFilesystemConfig {
+                ab_toggle: true,
+                ..RomFilesystemConfig::default()
+            },
+        );
+        let path = Path::new("/hack.sfc");
+        let toggle = |value: &[u8]| fs.setxattr(request(), path, OsStr::new(PATCHED_XATTR), value, 0, 0);
+
+        assert_eq!(xattr(&fs, "/hack.sfc", PATCHED_XATTR).unwrap(), b"1");
+        assert_eq!(fs.read_at(path, 0, 4096).unwrap(), target);
+
+        toggle(b"0").unwrap();
+        assert_eq!(xattr(&fs, "/hack.sfc", PATCHED_XATTR).unwrap(), b"0");
+        assert_eq!(fs.read_at(path, 0, 4096).unwrap(), source);
+
+        toggle(b"1").unwrap();
+        assert_eq!(fs.read_at(path, 0, 4096).unwrap(), target);
+
+        assert_eq!(toggle(b"yes"), Err(libc::EINVAL));
+        assert_eq!(
+            fs.setxattr(request(), Path::new("/game.sfc"), OsStr::new(PATCHED_XATTR), b"0", 0, 0),
+            Err(libc::ENOENT)
+        );
+    }
+
+    #[test]
+    fn reads_at_offsets() {
+        let source = make_rom(PARTIAL_READ_LIMIT as usize * 2, 1);
+        let target = make_hack(&source);
+        let directory = make_dir_fixture(&[("game.sfc", &source), ("hacks/hack.bps", &make_bps(&source, &target))]);
+        let fs = filesystem(directory.path(), RomFilesystemConfig::default());
+        let path = Path::new("/hacks/hack.sfc");
+
+        // Reads of the header, around changed bytes and at the end of the ROM, past the partial read limit
+        let step = target.len() / 8;
+        for (offset, size) in [
+            (0, 64),
+            (1, 1),
+            (step - 4, 8),
+            (step + 1, 16),
+            (target.len() - 16, 4096),
+        ] {
+            assert_eq!(
+                fs.read_at(path, offset as u64, size as u32).unwrap(),
+                data_slice(&target, offset as u64, size as u32),
+                "{} bytes at {}",
+                size,
+                offset
+            );
+        }
+
+        assert_eq!(fs.read_at(Path::new("/hacks/missing.sfc"), 0, 16), Err(libc::ENOENT));
+        assert_eq!(fs.read_at(Path::new("/hacks"), 0, 16), Err(libc::ENOENT));
+        assert!(fs.handles.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn refuses_write_access() {
+        let directory = make_dir_fixture(&[("abc.bps", &make_source_less_bps(b"abc"))]);
+        let fs = filesystem(directory.path(), RomFilesystemConfig::default());
+        let path = Path::new("/abc.bin");
+
+        let (fh, _) = fs
+            .open(request(), path, (libc::O_RDONLY | libc::O_NONBLOCK) as u32)
+            .unwrap();
+        fs.release(request(), path, fh, 0, 0, false).unwrap();
+
+        for flags in [libc::O_WRONLY, libc::O_RDWR, libc::O_RDWR | libc::O_APPEND] {
+            assert_eq!(fs.open(request(), path, flags as u32).err(), Some(libc::EROFS));
+        }
+        assert_eq!(
+            fs.open(request(), Path::new("/missing.bin"), libc::O_WRONLY as u32)
+                .err(),
+            Some(libc::ENOENT)
+        );
+        assert!(fs.handles.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn records_patching_timings() {
+        let source = make_rom(PARTIAL_READ_LIMIT as usize * 2, 1);
+        let directory = make_dir_fixture(&[
+            ("game.sfc", &source),
+            ("hack.bps", &make_bps(&source, &make_hack(&source))),
+        ]);
+        let fs = filesystem(directory.path(), RomFilesystemConfig::default());
+        let path = Path::new("/hack.sfc");
+
+        // Reads of the header are served without patching the whole ROM
+        fs.read_at(path, 0, 16).unwrap();
+        assert!(fs.patch_timings().is_empty());
+
+        fs.read_at(path, PARTIAL_READ_LIMIT, 16).unwrap();
+        let patch_timings = fs.patch_timings();
+        assert_eq!(patch_timings.len(), 1);
+        assert_eq!(patch_timings[0].0, path);
+    }
+
+    #[test]
+    fn lists_targets_in_the_csv_manifest() {
+        let source = make_rom(1024, 1);
+        let directory = make_dir_fixture(&[
+            ("game.sfc", &source),
+            ("hacks/hack, v1.bps", &make_bps(&source, &make_hack(&source))),
+            ("full.bps", &make_source_less_bps(b"full")),
+        ]);
+        let fs = filesystem(
+            directory.path(),
+            RomFilesystemConfig {
+                manifest: true,
+                ..RomFilesystemConfig::default()
+            },
+        );
+
+        let operation_count = {
+            let rom_manager = fs.rom_manager.lock().unwrap();
+            rom_manager.target_roms[Path::new("hacks/hack, v1.sfc")]
+                .operation_count()
+                .unwrap()
+        };
+
+        // Fields with commas are quoted
+        let csv = fs.read_at(Path::new("/.bps-fuse.csv"), 0, 4096).unwrap();
+        let rows: Vec<&str> = str::from_utf8(&csv).unwrap().split_terminator("\r\n").collect();
+        assert_eq!(
+            rows,
+            [
+                "target,source,format,source_crc,target_size,status,operations",
+                "full.bin,,bps,,4,patched,1",
+                &format!(
+                    "\"hacks/hack, v1.sfc\",{},bps,{:08X},1024,patched,{}",
+                    directory.path().join("game.sfc").display(),
+                    crc32::checksum_ieee(&source),
+                    operation_count
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn exposes_header_variants() {
+        let (headered, headerless) = (make_rom(512 + 2048, 1), make_rom(2048, 2));
+        let (headered_target, headerless_target) = (make_hack(&headered), make_hack(&headerless));
+        let directory = make_dir_fixture(&[
+            ("a.smc", &headered),
+            ("b.sfc", &headerless),
+            ("hack_a.bps", &make_bps(&headered, &headered_target)),
+            ("hack_b.bps", &make_bps(&headerless, &headerless_target)),
+        ]);
+        let fs = filesystem(
+            directory.path(),
+            RomFilesystemConfig {
+                header_variants: true,
+                ..RomFilesystemConfig::default()
+            },
+        );
+        let size = |path: &str| fs.getattr(request(), Path::new(path), None).unwrap().1.size;
+        let read = |path: &str| fs.read_at(Path::new(path), 0, 4096).unwrap();
+
+        // The headered ROM loses its header
+        assert_eq!(size("/hack_a.smc") - size("/hack_a.headerless.smc"), 512);
+        assert_eq!(read("/hack_a.smc"), headered_target);
+        assert_eq!(read("/hack_a.headerless.smc"), headered_target[512..]);
+
+        // The headerless ROM gains a blank one
+        assert_eq!(size("/hack_b.headered.sfc") - size("/hack_b.sfc"), 512);
+        assert_eq!(read("/hack_b.sfc"), headerless_target);
+        assert_eq!(read("/hack_b.headered.sfc")[..512], [0; 512]);
+        assert_eq!(read("/hack_b.headered.sfc")[512..], headerless_target[..]);
+
+        // Only the other variant of each ROM is exposed
+        assert_eq!(
+            fs.read_at(Path::new("/hack_a.headered.smc"), 0, 4096),
+            Err(libc::ENOENT)
+        );
+        assert_eq!(
+            fs.read_at(Path::new("/hack_b.headerless.sfc"), 0, 4096),
+            Err(libc::ENOENT)
+        );
+    }
+
+    #[test]
+    fn serves_targets_during_refreshes() {
+        let source = make_rom(4096, 1);
+        let target = make_hack(&source);
+        let directory = make_dir_fixture(&[("game.sfc", &source), ("hack.bps", &make_bps(&source, &target))]);
+        let fs = filesystem(directory.path(), RomFilesystemConfig::default());
+        let path = Path::new("/hack.sfc");
+
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                for _ in 0..50 {
+                    fs.rom_manager.lock().unwrap().refresh().unwrap();
+                }
+            });
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..50 {
+                        assert_eq!(fs.getattr(request(), path, None).unwrap().1.size, target.len() as u64);
+                        assert_eq!(fs.read_at(path, 0, 16).unwrap(), target[..16]);
+                    }
+                });
+            }
+        });
+
+        // A failed refresh keeps the ROMs of the previous one
+        let moved_directory = directory.path().with_extension("moved");
+        fs::rename(directory.path(), &moved_directory).unwrap();
+        assert!(fs.rom_manager.lock().unwrap().refresh().is_err());
+        assert_eq!(fs.getattr(request(), path, None).unwrap().1.size, target.len() as u64);
+        fs::rename(&moved_directory, directory.path()).unwrap();
+        assert_eq!(fs.read_at(path, 0, 4096).unwrap(), target);
+    }
+
+    #[test]
+    fn exposes_the_configured_files() {
+        let directory = make_dir_fixture(&[("abc.bps", &make_source_less_bps(b"abc"))]);
+        let entry_names = |fs: &RomFilesystem| -> Vec<String> {
+            let (fh, _) = fs.opendir(request(), Path::new("/"), 0).unwrap();
+            let entries = fs.readdir(request(), Path::new("/"), fh).unwrap();
+            fs.releasedir(request(), Path::new("/"), fh, 0).unwrap();
+            let mut entry_names: Vec<String> = entries
+                .into_iter()
+                .map(|entry| entry.name.to_string_lossy().into_owned())
+                .filter(|name| name != "." && name != "..")
+                .collect();
+            entry_names.sort();
+            entry_names
+        };
+
+        let fs = filesystem(directory.path(), RomFilesystemConfig::default());
+        assert_eq!(entry_names(&fs), [".bps-fuse.version", "abc.bin"]);
+        assert_eq!(fs.read_at(Path::new("/bps-fuse.zip"), 0, 16), Err(libc::ENOENT));
+
+        let fs = filesystem(
+            directory.path(),
+            RomFilesystemConfig {
+                expose_archive: true,
+                manifest: true,
+                memory_cache_size: Some(0),
+                ..RomFilesystemConfig::default()
+            },
+        );
+        assert_eq!(
+            entry_names(&fs),
+            [
+                ".bps-fuse.csv",
+                ".bps-fuse.json",
+                ".bps-fuse.version",
+                "abc.bin",
+                "bps-fuse.zip"
+            ]
+        );
+        assert_eq!(fs.read_at(Path::new("/bps-fuse.zip"), 0, 2).unwrap(), b"PK");
+
+        // Patched ROMs aren't kept in memory without a memory cache
+        assert_eq!(fs.read_at(Path::new("/abc.bin"), 0, 16).unwrap(), b"abc");
+        let generation = fs.rom_manager.lock().unwrap().generation();
+        assert!(fs.memory_cache.load(Path::new("abc.bin"), generation).is_none());
+    }
+
+    #[test]
+    fn reads_titles_and_authors() {
+        let source = make_rom(1024, 1);
+        let target = make_hack(&source);
+        let directory = make_dir_fixture(&[
+            ("game.sfc", &source),
+            (
+                "full.bps",
+                &make_bps_with_metadata(&source, &target, b"<title> Hack </title><author>Someone</author>"),
+            ),
+            (
+                "named.bps",
+                &make_bps_with_metadata(&source, &target, b"<name>Named</name>"),
+            ),
+            ("plain.bps", &make_bps(&source, &target)),
+        ]);
+        let fs = filesystem(directory.path(), RomFilesystemConfig::default());
+
+        assert_eq!(xattr(&fs, "/full.sfc", TITLE_XATTR).unwrap(), b"Hack");
+        assert_eq!(xattr(&fs, "/full.sfc", AUTHOR_XATTR).unwrap(), b"Someone");
+
+        // The name of beat metadata stands in for the title
+        assert_eq!(xattr(&fs, "/named.sfc", TITLE_XATTR).unwrap(), b"Named");
+        assert_eq!(xattr(&fs, "/named.sfc", AUTHOR_XATTR), Err(ENOATTR));
+
+        assert_eq!(xattr(&fs, "/plain.sfc", TITLE_XATTR), Err(ENOATTR));
+        assert_eq!(xattr(&fs, "/plain.sfc", AUTHOR_XATTR), Err(ENOATTR));
+
+        // Only the attributes of the patch are listed
+        let names = |path: &str| match fs.listxattr(request(), Path::new(path), 4096).unwrap() {
+            Xattr::Data(data) => data,
+            Xattr::Size(_) => panic!("size reply to a sized request"),
+        };
+        assert!(names("/full.sfc").starts_with(b"user.bpsfuse.title\0user.bpsfuse.author\0"));
+        assert!(!names("/plain.sfc")
+            .windows(TITLE_XATTR.len())
+            .any(|name| name == TITLE_XATTR.as_bytes()));
+    }
+
+    #[test]
+    fn clears_caches_when_destroyed() {
+        // Past the partial read limit, so the reads patch the whole ROM
+        let source = make_rom(PARTIAL_READ_LIMIT as usize * 2, 1);
+        let target = make_hack(&source);
+        let directory = make_dir_fixture(&[("game.sfc", &source), ("hack.bps", &make_bps(&source, &target))]);
+        let temp_directory = make_dir_fixture(&[]);
+        let rom_cache = Arc::new(RomCache::new(temp_directory.path()).unwrap());
+        let fs = filesystem(
+            directory.path(),
+            RomFilesystemConfig {
+                rom_cache: Some(rom_cache.clone()),
+                ..RomFilesystemConfig::default()
+            },
+        );
+        let path = Path::new("/hack.sfc");
+        let cached_files = || fs::read_dir(rom_cache.directory()).unwrap().count();
+
+        assert_eq!(
+            fs.read_at(path, PARTIAL_READ_LIMIT, 16).unwrap(),
+            data_slice(&target, PARTIAL_READ_LIMIT, 16)
+        );
+        let (fh, _) = fs.open(request(), path, libc::O_RDONLY as u32).unwrap();
+        let generation = fs.rom_manager.lock().unwrap().generation();
+        assert_eq!(cached_files(), 1);
+        assert!(fs.memory_cache.load(Path::new("hack.sfc"), generation).is_some());
+
+        // The handle left open is dropped too
+        fs.destroy(request());
+        assert!(fs.handles.read().unwrap().is_empty());
+        assert!(fs.memory_cache.load(Path::new("hack.sfc"), generation).is_none());
+        assert_eq!(cached_files(), 0);
+        assert_eq!(fs.release(request(), path, fh, 0, 0, false), Err(libc::ENOENT));
+    }
+
+    #[test]
+    fn exposes_source_crc_sidecars() {
+        let source = make_rom(1024, 1);
+        let target = make_hack(&source);
+        let directory = make_dir_fixture(&[
+            ("game.sfc", &source),
+            ("hack.bps", &make_bps(&source, &target)),
+            ("other.ips", &make_ips(&source, &target)),
+        ]);
+        let fs = filesystem(
+            directory.path(),
+            RomFilesystemConfig {
+                source_crc_sidecars: true,
+                ..RomFilesystemConfig::default()
+            },
+        );
+
+        let expected = format!("{:08X}\n", crc32::checksum_ieee(&source));
+        assert_eq!(
+            fs.read_at(Path::new("/hack.sfc.source_crc"), 0, 4096).unwrap(),
+            expected.as_bytes()
+        );
+        let (_, attr) = fs.getattr(request(), Path::new("/hack.sfc.source_crc"), None).unwrap();
+        assert_eq!(attr.size, 9);
+
+        // IPS patches don't know the checksum of their source ROMs
+        assert_eq!(
+            fs.read_at(Path::new("/other.sfc.source_crc"), 0, 4096),
+            Err(libc::ENOENT)
+        );
+    }
+
+    #[test]
+    fn refuses_renaming_targets() {
+        let directory = make_dir_fixture(&[("abc.bps", &make_source_less_bps(b"abc"))]);
+        let fs = filesystem(directory.path(), RomFilesystemConfig::default());
+        let rename = |name: &str| {
+            fs.rename(
+                request(),
+                Path::new("/"),
+                OsStr::new(name),
+                Path::new("/"),
+                OsStr::new("renamed.bin"),
+            )
+        };
+
+        assert_eq!(rename("abc.bin"), Err(libc::EROFS));
+        assert_eq!(rename(".bps-fuse.version"), Err(libc::EROFS));
+        assert_eq!(rename("missing.bin"), Err(libc::ENOENT));
+
+        // The target keeps its name
+        assert_eq!(fs.read_at(Path::new("/abc.bin"), 0, 16).unwrap(), b"abc");
+        assert_eq!(fs.read_at(Path::new("/renamed.bin"), 0, 16), Err(libc::ENOENT));
+    }
+
+    #[test]
+    fn describes_itself_in_the_version_file() {
+        let directory = make_dir_fixture(&[]);
+        let fs = filesystem(
+            directory.path(),
+            RomFilesystemConfig {
+                manifest: true,
+                target_sha1: true,
+                watching: true,
+                ..RomFilesystemConfig::default()
+            },
+        );
+
+        let version = fs.read_at(Path::new("/.bps-fuse.version"), 0, 4096).unwrap();
+        let properties: HashMap<&str, &str> = str::from_utf8(&version)
+            .unwrap()
+            .lines()
+            .map(|line| line.split_once(": ").unwrap())
+            .collect();
+
+        assert_eq!(properties.len(), 3);
+        assert_eq!(properties["version"], env!("CARGO_PKG_VERSION"));
+        let formats: Vec<&str> = properties["formats"].split(' ').collect();
+        assert!(formats.contains(&"bps") && formats.contains(&"ips"));
+        assert_eq!(properties["features"], "manifest target-sha1 watch");
+    }
+
+    #[test]
+    fn opens_targets_for_shared_locks_only() {
+        let directory = make_dir_fixture(&[("abc.bps", &make_source_less_bps(b"abc"))]);
+        let fs = filesystem(directory.path(), RomFilesystemConfig::default());
+        let path = Path::new("/abc.bin");
+
+        // Locks are handled by the kernel, which only needs the files opened. Shared locks work on any
+        // number of read-only handles at once
+        let handles: Vec<u64> = (0..4)
+            .map(|_| fs.open(request(), path, libc::O_RDONLY as u32).unwrap().0)
+            .collect();
+        for &fh in &handles {
+            let mut data = Vec::new();
+            fs.read(request(), path, fh, 0, 16, |result| data = result.unwrap().to_vec());
+            assert_eq!(data, b"abc");
+        }
+        for fh in handles {
+            fs.release(request(), path, fh, 0, 0, false).unwrap();
+        }
+
+        // Exclusive record locks need a handle open for writing, which is refused
+        assert_eq!(fs.open(request(), path, libc::O_RDWR as u32).err(), Some(libc::EROFS));
+        assert!(fs.handles.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn exposes_byte_swapped_n64_roms() {
+        let mut source = make_rom(4096, 1);
+        source[..4].copy_from_slice(&[0x80, 0x37, 0x12, 0x40]);
+        let mut target = make_hack(&source);
+        target[..4].copy_from_slice(&[0x80, 0x37, 0x12, 0x40]);
+        let directory = make_dir_fixture(&[("game.z64", &source), ("hack.bps", &make_bps(&source, &target))]);
+        let fs = filesystem(
+            directory.path(),
+            RomFilesystemConfig {
+                byte_order_variants: true,
+                ..RomFilesystemConfig::default()
+            },
+        );
+        let read = |path: &str| fs.read_at(Path::new(path), 0, 8192).unwrap();
+
+        assert_eq!(read("/hack.z64"), target);
+
+        let byte_swapped = read("/hack.v64");
+        assert_eq!(byte_swapped[..4], [0x37, 0x80, 0x40, 0x12]);
+        for (word, swapped_word) in target.chunks(2).zip(byte_swapped.chunks(2)) {
+            assert_eq!([word[1], word[0]], swapped_word);
+        }
+
+        let little_endian = read("/hack.n64");
+        assert_eq!(little_endian[..4], [0x40, 0x12, 0x37, 0x80]);
+        for (word, swapped_word) in target.chunks(4).zip(little_endian.chunks(4)) {
+            assert_eq!([word[3], word[2], word[1], word[0]], swapped_word);
+        }
+    }
+
+    #[test]
+    fn reads_whole_blocks_until_the_end() {
+        // Both a ROM served by partial patching and one past the partial read limit
+        let small_source = make_rom(10_000, 1);
+        let large_source = make_rom(PARTIAL_READ_LIMIT as usize + 10_000, 2);
+        let (small_target, large_target) = (make_hack(&small_source), make_hack(&large_source));
+        let directory = make_dir_fixture(&[
+            ("small.sfc", &small_source),
+            ("large.gba", &large_source),
+            ("small_hack.bps", &make_bps(&small_source, &small_target)),
+            ("large_hack.bps", &make_bps(&large_source, &large_target)),
+        ]);
+        let fs = filesystem(directory.path(), RomFilesystemConfig::default());
+
+        for (path, target) in [("/small_hack.sfc", &small_target), ("/large_hack.gba", &large_target)] {
+            for block_size in [512, 4096, 65536, 131072] {
+                let mut data = Vec::new();
+                loop {
+                    let block = fs.read_at(Path::new(path), data.len() as u64, block_size).unwrap();
+                    if block.is_empty() {
+                        break;
+                    }
+                    // Only the last block is short
+                    if block.len() < block_size as usize {
+                        assert_eq!(
+                            data.len() + block.len(),
+                            target.len(),
+                            "{} in {} byte blocks",
+                            path,
+                            block_size
+                        );
+                    }
+                    data.extend_from_slice(&block);
+                }
+                assert_eq!(&data, target, "{} in {} byte blocks", path, block_size);
+            }
+        }
+    }
+
+    #[test]
+    fn reads_nothing_at_and_past_the_end() {
+        assert_eq!(data_slice(b"abc", 2, 16), b"c");
+        for offset in [3, 4, u64::MAX] {
+            assert_eq!(data_slice(b"abc", offset, 16), b"", "at {}", offset);
+        }
+
+        // Both a ROM served by partial patching and one past the partial read limit
+        let source = make_rom(PARTIAL_READ_LIMIT as usize * 2, 1);
+        let target = make_hack(&source);
+        let directory = make_dir_fixture(&[
+            ("game.sfc", &source),
+            ("hack.bps", &make_bps(&source, &target)),
+            ("abc.bps", &make_source_less_bps(b"abc")),
+        ]);
+        let fs = filesystem(directory.path(), RomFilesystemConfig::default());
+
+        for (path, size) in [("/abc.bin", 3), ("/hack.sfc", target.len() as u64)] {
+            assert_eq!(fs.read_at(Path::new(path), size - 1, 16).unwrap().len(), 1, "{}", path);
+            assert_eq!(fs.read_at(Path::new(path), size, 16).unwrap(), b"", "{}", path);
+            assert_eq!(fs.read_at(Path::new(path), size + 1, 16).unwrap(), b"", "{}", path);
+        }
+    }
+
+    #[test]
+    fn refuses_opening_degraded_targets() {
+        let source = make_rom(1024, 1);
+        let directory = make_dir_fixture(&[
+            ("game.sfc", &source),
+            ("hack.bps", &make_bps(&source, &make_hack(&source))),
+            ("abc.bps", &make_source_less_bps(b"abc")),
+        ]);
+        let rom_manager = Arc::new(Mutex::new(RomManager::new(directory.path()).unwrap()));
+        let fs = RomFilesystem::new(rom_manager.clone());
+        assert!(rom_manager.lock().unwrap().degraded_targets.is_empty());
+
+        rom_manager
+            .lock()
+            .unwrap()
+            .degraded_targets
+            .insert(PathBuf::from("hack.sfc"), "source length mismatch".to_owned());
+
+        // Degraded targets are still listed, other targets are unaffected
+        assert!(fs.getattr(request(), Path::new("/hack.sfc"), None).is_ok());
+        assert_eq!(
+            fs.open(request(), Path::new("/hack.sfc"), libc::O_RDONLY as u32).err(),
+            Some(libc::EIO)
+        );
+        assert!(fs.open(request(), Path::new("/abc.bin"), libc::O_RDONLY as u32).is_ok());
+    }
 }
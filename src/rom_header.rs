@@ -0,0 +1,204 @@
+use std::ffi::OsStr;
+use std::path::Path;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::utils::json_string;
+
+const INES_FORMAT_MARKER: [u8; 4] = [b'N', b'E', b'S', 0x1A];
+const INES_HEADER_SIZE: usize = 16;
+
+pub const SNES_COPIER_HEADER_SIZE: usize = 512;
+const SNES_LOROM_HEADER_OFFSET: usize = 0x7FC0;
+const SNES_HIROM_HEADER_OFFSET: usize = 0xFFC0;
+const SNES_HEADER_SIZE: usize = 0x20;
+
+/// Size of the header prepended to ROMs of this type by dumpers and copiers,
+/// decided by the file extension. `None` for ROM types without such headers.
+pub fn external_header_size(path: &Path) -> Option<u64> {
+    match path.extension().and_then(OsStr::to_str)?.to_ascii_lowercase().as_str() {
+        "nes" => Some(INES_HEADER_SIZE as u64),
+        "sfc" | "smc" => Some(SNES_COPIER_HEADER_SIZE as u64),
+        _ => None,
+    }
+}
+
+/// Internal header of a ROM, as found in the first few kilobytes of the ROM data.
+#[derive(Debug)]
+pub enum RomHeader {
+    Ines {
+        mapper: u16,
+        prg_rom_size: usize,
+        chr_rom_size: usize,
+        vertical_mirroring: bool,
+        battery: bool,
+        trainer: bool,
+        nes2: bool,
+    },
+    Snes {
+        title: String,
+        map_mode: u8,
+        rom_type: u8,
+        rom_size: usize,
+        ram_size: usize,
+        region: u8,
+        version: u8,
+        checksum: u16,
+        copier_header: bool,
+    },
+}
+
+impl RomHeader {
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        RomHeader::parse_ines(data).or_else(|| RomHeader::parse_snes(data))
+    }
+
+    fn parse_ines(data: &[u8]) -> Option<Self> {
+        if data.len() < INES_HEADER_SIZE || data[0..4] != INES_FORMAT_MARKER {
+            return None;
+        }
+
+        let nes2 = data[7] & 0x0C == 0x08;
+        let mut mapper = u16::from(data[6] >> 4) | u16::from(data[7] & 0xF0);
+        if nes2 {
+            mapper |= u16::from(data[8] & 0x0F) << 8;
+        }
+
+        Some(RomHeader::Ines {
+            mapper,
+            prg_rom_size: usize::from(data[4]) * 16 * 1024,
+            chr_rom_size: usize::from(data[5]) * 8 * 1024,
+            vertical_mirroring: data[6] & 0x01 != 0,
+            battery: data[6] & 0x02 != 0,
+            trainer: data[6] & 0x04 != 0,
+            nes2,
+        })
+    }
+
+    fn parse_snes(data: &[u8]) -> Option<Self> {
+        // Copier headers make the ROM size an odd multiple of 512 bytes
+        let copier_header = data.len() % 1024 == SNES_COPIER_HEADER_SIZE;
+        let rom = if copier_header {
+            &data[SNES_COPIER_HEADER_SIZE..]
+        } else {
+            data
+        };
+
+        [SNES_LOROM_HEADER_OFFSET, SNES_HIROM_HEADER_OFFSET]
+            .iter()
+            .filter_map(|&offset| rom.get(offset..offset + SNES_HEADER_SIZE))
+            .find(|header| {
+                let checksum_complement = LittleEndian::read_u16(&header[0x1C..0x1E]);
+                let checksum = LittleEndian::read_u16(&header[0x1E..0x20]);
+                checksum ^ checksum_complement == 0xFFFF
+            })
+            .map(|header| RomHeader::Snes {
+                title: String::from_utf8_lossy(&header[0x00..0x15]).trim_end().to_owned(),
+                map_mode: header[0x15],
+                rom_type: header[0x16],
+                rom_size: 1024 << header[0x17].min(24),
+                ram_size: if header[0x18] == 0 {
+                    0
+                } else {
+                    1024 << header[0x18].min(24)
+                },
+                region: header[0x19],
+                version: header[0x1B],
+                checksum: LittleEndian::read_u16(&header[0x1E..0x20]),
+                copier_header,
+            })
+    }
+
+    pub fn to_json(&self) -> String {
+        match self {
+            RomHeader::Ines {
+                mapper,
+                prg_rom_size,
+                chr_rom_size,
+                vertical_mirroring,
+                battery,
+                trainer,
+                nes2,
+            } => format!(
+                concat!(
+                    "{{\n",
+                    "  \"format\": {},\n",
+                    "  \"mapper\": {},\n",
+                    "  \"prg_rom_size\": {},\n",
+                    "  \"chr_rom_size\": {},\n",
+                    "  \"mirroring\": {},\n",
+                    "  \"battery\": {},\n",
+                    "  \"trainer\": {}\n",
+                    "}}\n"
+                ),
+                json_string(if *nes2 { "NES 2.0" } else { "iNES" }),
+                mapper,
+                prg_rom_size,
+                chr_rom_size,
+                json_string(if *vertical_mirroring { "vertical" } else { "horizontal" }),
+                battery,
+                trainer,
+            ),
+            RomHeader::Snes {
+                title,
+                map_mode,
+                rom_type,
+                rom_size,
+                ram_size,
+                region,
+                version,
+                checksum,
+                copier_header,
+            } => format!(
+                concat!(
+                    "{{\n",
+                    "  \"format\": {},\n",
+                    "  \"title\": {},\n",
+                    "  \"map_mode\": {},\n",
+                    "  \"rom_type\": {},\n",
+                    "  \"rom_size\": {},\n",
+                    "  \"ram_size\": {},\n",
+                    "  \"region\": {},\n",
+                    "  \"version\": {},\n",
+                    "  \"checksum\": {},\n",
+                    "  \"copier_header\": {}\n",
+                    "}}\n"
+                ),
+                json_string("SNES"),
+                json_string(title),
+                map_mode,
+                rom_type,
+                rom_size,
+                ram_size,
+                json_string(snes_region_name(*region)),
+                version,
+                json_string(&format!("0x{:04X}", checksum)),
+                copier_header,
+            ),
+        }
+    }
+}
+
+fn snes_region_name(region: u8) -> &'static str {
+    match region {
+        0x00 => "Japan",
+        0x01 => "North America",
+        0x02 => "Europe",
+        0x03 => "Sweden",
+        0x04 => "Finland",
+        0x05 => "Denmark",
+        0x06 => "France",
+        0x07 => "Netherlands",
+        0x08 => "Spain",
+        0x09 => "Germany",
+        0x0A => "Italy",
+        0x0B => "China",
+        0x0C => "Indonesia",
+        0x0D => "South Korea",
+        0x0E => "International",
+        0x0F => "Canada",
+        0x10 => "Brazil",
+        0x11 => "Australia",
+        _ => "Unknown",
+    }
+}
@@ -1,19 +1,35 @@
-use std::collections::HashMap;
-use std::ffi::OsStr;
-use std::fs::{self, DirEntry};
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, DirEntry, File};
 use std::io;
-use std::path::{Path, PathBuf};
+use std::mem;
+use std::os::unix::fs::{DirEntryExt, MetadataExt};
+use std::path::{Component, Path, PathBuf};
 use std::sync::Arc;
+use std::time::SystemTime;
 
 use crc::crc32;
-use log::{error, info, warn};
+use log::{debug, error, info, warn};
+use rayon::prelude::*;
+use zip::result::ZipError;
+use zip::ZipArchive;
 
-use crate::patch::bps::BpsPatch;
+use crate::patch::aps::{self, ApsPatch};
+use crate::patch::bps::{BpsError, BpsPatch};
 use crate::patch::ips::IpsPatch;
+use crate::patch::ppf::PpfPatch;
+use crate::patch::rup::RupPatch;
+use crate::patch::vcdiff::XdeltaPatch;
 use crate::patch::Patch;
+use crate::rom_catalog::RomCatalog;
+use crate::rom_header;
+use crate::rom_system::RomSystem;
+use crate::source_cache::SourceCache;
 
+/// File extensions recognized as source ROMs by default.
 #[rustfmt::skip]
-const ROM_EXTENSIONS: &[&str] = &[
+pub const ROM_EXTENSIONS: &[&str] = &[
     // Generic
     "bin", "rom", "crt",
     // Nintendo
@@ -28,100 +44,1988 @@ const ROM_EXTENSIONS: &[&str] = &[
     "3ds",               // Nintendo 3DS
 ];
 
-pub struct RomManager {
-    pub base_directory: PathBuf,
-    pub source_roms: HashMap<u32, PathBuf>,
-    pub target_roms: HashMap<PathBuf, Arc<dyn Patch + Send + Sync>>,
+/// Extensions not telling which system the ROM belongs to.
+const GENERIC_ROM_EXTENSIONS: &[&str] = &["bin", "rom"];
+
+/// Source-less patches have no source ROM to borrow the file extension from.
+const SOURCE_LESS_TARGET_EXTENSION: &str = "bin";
+
+/// Extension of the text files next to patches holding the name of their target ROMs.
+const NAME_SIDECAR_EXTENSION: &str = "name";
+
+/// A parsed patch, along with the modification times and the lengths of the files it was parsed from.
+struct CachedPatch<T> {
+    stamps: Vec<(SystemTime, u64)>,
+    patch: T,
 }
 
-impl RomManager {
-    pub fn new(base_directory: &Path) -> io::Result<RomManager> {
-        let mut result = Self {
+/// Checksums of a source ROM, reused by later refreshes while its size and modification time are unchanged.
+#[derive(Clone)]
+struct HashedRom {
+    modified: SystemTime,
+    size: u64,
+    crc: u32,
+    system: Option<RomSystem>,
+    /// Internal checksum of N64 ROMs, which APS patches identify their source ROMs by.
+    n64_checksum: Option<Vec<u8>>,
+    /// Only computed while there are RUP patches, which identify their source ROMs by it.
+    md5: Option<[u8; 16]>,
+}
+
+impl HashedRom {
+    fn hash(path: &Path, metadata: &fs::Metadata, with_md5: bool) -> io::Result<Self> {
+        let data = fs::read(path)?;
+        let system = RomSystem::detect(&data);
+        let n64_checksum = match system {
+            Some(RomSystem::Nintendo64 { .. }) => data
+                .get(aps::N64_CHECKSUM_OFFSET..aps::N64_CHECKSUM_OFFSET + 8)
+                .map(<[u8]>::to_vec),
+            _ => None,
+        };
+
+        Ok(Self {
+            modified: metadata.modified()?,
+            size: metadata.len(),
+            crc: crc32::checksum_ieee(&data),
+            system,
+            n64_checksum,
+            md5: if with_md5 { Some(md5::compute(&data).0) } else { None },
+        })
+    }
+
+    fn is_fresh(&self, metadata: &fs::Metadata, with_md5: bool) -> bool {
+        metadata.modified().ok() == Some(self.modified)
+            && metadata.len() == self.size
+            && (self.md5.is_some() || !with_md5)
+    }
+}
+
+/// The ROMs to hash during a refresh, the ones without fresh checksums.
+/// Only the first one of the hard and symbolic links to the same ROM is hashed.
+fn unhashed_roms<'a>(
+    rom_files: &'a [(PathBuf, fs::Metadata)],
+    hashed_roms: &HashMap<PathBuf, HashedRom>,
+    with_md5: bool,
+) -> Vec<&'a (PathBuf, fs::Metadata)> {
+    let mut listed_files: HashSet<(u64, u64)> = HashSet::new();
+    rom_files
+        .iter()
+        .filter(|(path, metadata)| {
+            let fresh = matches!(hashed_roms.get(path), Some(h) if h.is_fresh(metadata, with_md5));
+            listed_files.insert((metadata.dev(), metadata.ino())) && !fresh
+        })
+        .collect()
+}
+
+/// The directory the filesystem is mounted on, as it was before mounting.
+struct MountPoint {
+    path: PathBuf,
+    dev: u64,
+    ino: u64,
+}
+
+/// Resolves `.` and `..` components without accessing the filesystem.
+fn normalize_path(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+/// Parsed patches from previous refreshes, reused while the underlying files are unmodified.
+/// Patches are cached by all of their dependencies, a patch applied to several source ROMs is cached once per source ROM.
+struct PatchCache<T> {
+    entries: HashMap<Vec<PathBuf>, CachedPatch<T>>,
+}
+
+impl<T: Clone> PatchCache<T> {
+    fn new() -> Self {
+        Self {
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Returns the cached patch if none of its dependencies were modified, or parses it again.
+    /// The first dependency is the patch file itself.
+    fn load<E>(&mut self, dependencies: &[&Path], parse: impl FnOnce() -> Result<T, E>) -> Result<T, E> {
+        let key: Vec<PathBuf> = dependencies.iter().map(|path| path.to_path_buf()).collect();
+        let stamps: Option<Vec<(SystemTime, u64)>> = dependencies
+            .iter()
+            .map(|path| fs::metadata(path).and_then(|m| Ok((m.modified()?, m.len()))).ok())
+            .collect();
+
+        if let (Some(cached), Some(stamps)) = (self.entries.get(&key), &stamps) {
+            if cached.stamps == *stamps {
+                return Ok(cached.patch.clone());
+            }
+        }
+
+        let patch = parse()?;
+        if let Some(stamps) = stamps {
+            let cached = CachedPatch {
+                stamps,
+                patch: patch.clone(),
+            };
+            self.entries.insert(key, cached);
+        }
+        Ok(patch)
+    }
+
+    fn retain(&mut self, patch_paths: &[PathBuf]) {
+        self.entries
+            .retain(|dependencies, _| patch_paths.contains(&dependencies[0]));
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+/// Makes a single path component of a name from untrusted sources, like patch metadata.
+/// Path separators and control characters are replaced, so names can't escape the mount point.
+fn valid_name(name: &str) -> Option<String> {
+    let name: String = name
+        .trim_start_matches('\u{FEFF}')
+        .trim()
+        .chars()
+        .map(|c| {
+            if c == '/' || c == '\\' || c.is_control() {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect();
+
+    if name.is_empty() || name == "." || name == ".." {
+        None
+    } else {
+        Some(name)
+    }
+}
+
+fn normalize_extensions<S: AsRef<str>>(extensions: &[S]) -> Vec<String> {
+    extensions
+        .iter()
+        .map(|e| e.as_ref().trim_start_matches('.').to_ascii_lowercase())
+        .collect()
+}
+
+pub struct RomManagerBuilder {
+    base_directory: PathBuf,
+    source_directory: Option<PathBuf>,
+    catalog: Option<RomCatalog>,
+    rom_extensions: Vec<String>,
+    max_target_size: Option<u64>,
+    name_from_metadata: bool,
+    name_sidecars: bool,
+    name_template: Option<String>,
+    checksum_freshness: bool,
+    verify_targets: bool,
+    require_all_sources: bool,
+    strict_ips_eof: bool,
+    follow_symlinks: bool,
+    map_sources: bool,
+    included_target_extensions: Option<Vec<String>>,
+    excluded_target_extensions: Vec<String>,
+    pairings: Vec<(PathBuf, PathBuf)>,
+    mount_point: Option<PathBuf>,
+}
+
+impl RomManagerBuilder {
+    pub fn new(base_directory: &Path) -> Self {
+        Self {
             base_directory: base_directory.to_owned(),
+            source_directory: None,
+            catalog: None,
+            rom_extensions: ROM_EXTENSIONS.iter().map(|e| e.to_string()).collect(),
+            max_target_size: None,
+            name_from_metadata: false,
+            name_sidecars: false,
+            name_template: None,
+            checksum_freshness: false,
+            verify_targets: true,
+            require_all_sources: false,
+            strict_ips_eof: false,
+            follow_symlinks: true,
+            map_sources: false,
+            included_target_extensions: None,
+            excluded_target_extensions: Vec::new(),
+            pairings: Vec::new(),
+            mount_point: None,
+        }
+    }
+
+    /// Looks for source ROMs in a separate directory, like a ROM library, instead of the base directory.
+    pub fn source_directory(mut self, source_directory: &Path) -> Self {
+        self.source_directory = Some(source_directory.to_owned());
+        self
+    }
+
+    /// Known ROMs outside of the base directory, consulted when no source ROM matches a patch.
+    pub fn catalog(mut self, catalog: RomCatalog) -> Self {
+        self.catalog = Some(catalog);
+        self
+    }
+
+    /// File extensions recognized as source ROMs, replacing the default list.
+    pub fn rom_extensions<S: AsRef<str>>(mut self, rom_extensions: &[S]) -> Self {
+        self.rom_extensions = normalize_extensions(rom_extensions);
+        self
+    }
+
+    /// Only target ROMs with these file extensions are exposed.
+    pub fn include_target_extensions<S: AsRef<str>>(mut self, extensions: &[S]) -> Self {
+        self.included_target_extensions = Some(normalize_extensions(extensions));
+        self
+    }
+
+    /// Target ROMs with these file extensions are hidden.
+    pub fn exclude_target_extensions<S: AsRef<str>>(mut self, extensions: &[S]) -> Self {
+        self.excluded_target_extensions = normalize_extensions(extensions);
+        self
+    }
+
+    /// Patches producing ROMs larger than this are skipped.
+    pub fn max_target_size(mut self, max_target_size: u64) -> Self {
+        self.max_target_size = Some(max_target_size);
+        self
+    }
+
+    /// Names target ROMs after the name stored in the metadata of BPS patches.
+    pub fn name_from_metadata(mut self, name_from_metadata: bool) -> Self {
+        self.name_from_metadata = name_from_metadata;
+        self
+    }
+
+    /// Names target ROMs after the first line of `<patch>.name` files next to the patches.
+    pub fn name_sidecars(mut self, name_sidecars: bool) -> Self {
+        self.name_sidecars = name_sidecars;
+        self
+    }
+
+    /// Names target ROMs using a template, `{patch}` and `{source}` are replaced
+    /// with the file names of the patch and the source ROM, without extensions.
+    pub fn name_template(mut self, name_template: &str) -> Self {
+        self.name_template = Some(name_template.to_owned());
+        self
+    }
+
+    /// Checks whether BPS patches changed since they were parsed by their checksum,
+    /// instead of their modification time.
+    pub fn checksum_freshness(mut self, checksum_freshness: bool) -> Self {
+        self.checksum_freshness = checksum_freshness;
+        self
+    }
+
+    /// Verifies the checksum of ROMs patched by BPS patches, on by default.
+    pub fn verify_targets(mut self, verify_targets: bool) -> Self {
+        self.verify_targets = verify_targets;
+        self
+    }
+
+    /// Makes refreshing fail when any of the patches has no source ROM, instead of skipping them.
+    pub fn require_all_sources(mut self, require_all_sources: bool) -> Self {
+        self.require_all_sources = require_all_sources;
+        self
+    }
+
+    /// Always ends IPS patches at the `EOF` marker, even when a record at offset `0x454F46` seems to follow.
+    pub fn strict_ips_eof(mut self, strict_ips_eof: bool) -> Self {
+        self.strict_ips_eof = strict_ips_eof;
+        self
+    }
+
+    /// Picks up symbolic links to ROMs and patches in the base directory, on by default.
+    pub fn follow_symlinks(mut self, follow_symlinks: bool) -> Self {
+        self.follow_symlinks = follow_symlinks;
+        self
+    }
+
+    /// Memory maps source ROMs instead of reading them into memory, sparing the memory of large ROMs.
+    /// Source ROMs must not be truncated while mapped, the process is killed with `SIGBUS` reading them.
+    pub fn map_sources(mut self, map_sources: bool) -> Self {
+        self.map_sources = map_sources;
+        self
+    }
+
+    /// The directory the filesystem is mounted on, skipped when it is inside the base directory.
+    /// Must be set before mounting, while the directory still refers to the underlying filesystem.
+    pub fn mount_point(mut self, mount_point: &Path) -> Self {
+        self.mount_point = Some(mount_point.to_owned());
+        self
+    }
+
+    /// Applies a patch to the given source ROM, without matching source ROMs by checksum.
+    /// The patch may live outside of the base directory, its target ROM is exposed in the root directory.
+    pub fn pairing(mut self, patch_path: &Path, source_path: &Path) -> Self {
+        self.pairings.push((patch_path.to_owned(), source_path.to_owned()));
+        self
+    }
+
+    pub fn build(self) -> io::Result<RomManager> {
+        let mut result = RomManager {
+            base_directory: self.base_directory,
+            source_directory: self.source_directory,
             source_roms: HashMap::new(),
             target_roms: HashMap::new(),
+            degraded_targets: HashMap::new(),
+            catalog: self.catalog,
+            rom_extensions: self.rom_extensions,
+            max_target_size: self.max_target_size,
+            name_from_metadata: self.name_from_metadata,
+            name_sidecars: self.name_sidecars,
+            name_template: self.name_template,
+            checksum_freshness: self.checksum_freshness,
+            verify_targets: self.verify_targets,
+            require_all_sources: self.require_all_sources,
+            strict_ips_eof: self.strict_ips_eof,
+            follow_symlinks: self.follow_symlinks,
+            included_target_extensions: self.included_target_extensions,
+            excluded_target_extensions: self.excluded_target_extensions,
+            pairings: self.pairings,
+            mount_point: self.mount_point.and_then(|path| {
+                let metadata = fs::metadata(&path).ok()?;
+                Some(MountPoint {
+                    path: fs::canonicalize(&path).ok()?,
+                    dev: metadata.dev(),
+                    ino: metadata.ino(),
+                })
+            }),
+            generation: 0,
+            source_cache: Arc::new(SourceCache::new(self.map_sources)),
+            bps_cache: PatchCache::new(),
+            ips_cache: PatchCache::new(),
+            aps_cache: PatchCache::new(),
+            ppf_cache: PatchCache::new(),
+            xdelta_cache: PatchCache::new(),
+            rup_cache: PatchCache::new(),
+            hashed_roms: HashMap::new(),
         };
         result.refresh()?;
         Ok(result)
     }
+}
+
+pub struct RomManager {
+    pub base_directory: PathBuf,
+    pub source_directory: Option<PathBuf>,
+    /// Source ROMs by their CRC32, copies of a ROM and colliding ROMs are in the order of their paths.
+    pub source_roms: HashMap<u32, Vec<PathBuf>>,
+    pub target_roms: HashMap<PathBuf, Arc<dyn Patch + Send + Sync>>,
+    /// Target ROMs whose patches failed validation with the error, still listed but not readable.
+    pub degraded_targets: HashMap<PathBuf, String>,
+    catalog: Option<RomCatalog>,
+    rom_extensions: Vec<String>,
+    max_target_size: Option<u64>,
+    name_from_metadata: bool,
+    name_sidecars: bool,
+    name_template: Option<String>,
+    checksum_freshness: bool,
+    verify_targets: bool,
+    require_all_sources: bool,
+    strict_ips_eof: bool,
+    follow_symlinks: bool,
+    included_target_extensions: Option<Vec<String>>,
+    excluded_target_extensions: Vec<String>,
+    pairings: Vec<(PathBuf, PathBuf)>,
+    mount_point: Option<MountPoint>,
+    generation: u64,
+    source_cache: Arc<SourceCache>,
+    bps_cache: PatchCache<BpsPatch>,
+    ips_cache: PatchCache<Arc<IpsPatch>>,
+    aps_cache: PatchCache<ApsPatch>,
+    ppf_cache: PatchCache<PpfPatch>,
+    xdelta_cache: PatchCache<XdeltaPatch>,
+    rup_cache: PatchCache<RupPatch>,
+    hashed_roms: HashMap<PathBuf, HashedRom>,
+}
+
+impl RomManager {
+    pub fn new(base_directory: &Path) -> io::Result<RomManager> {
+        RomManagerBuilder::new(base_directory).build()
+    }
+
+    /// The source ROMs read for patching, shared with the patches.
+    pub fn source_cache(&self) -> &Arc<SourceCache> {
+        &self.source_cache
+    }
+
+    /// Number of refreshes so far, target ROMs of the same generation are patched the same way.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Replaces the file extensions recognized as source ROMs, applied by the next refresh.
+    pub fn set_rom_extensions<S: AsRef<str>>(&mut self, rom_extensions: &[S]) {
+        self.rom_extensions = normalize_extensions(rom_extensions);
+    }
+
+    /// Replaces the file extensions of the exposed target ROMs, `None` exposes every target ROM.
+    pub fn set_included_target_extensions<S: AsRef<str>>(&mut self, extensions: Option<&[S]>) {
+        self.included_target_extensions = extensions.map(normalize_extensions);
+    }
+
+    pub fn set_excluded_target_extensions<S: AsRef<str>>(&mut self, extensions: &[S]) {
+        self.excluded_target_extensions = normalize_extensions(extensions);
+    }
+
+    pub fn set_checksum_freshness(&mut self, checksum_freshness: bool) {
+        self.checksum_freshness = checksum_freshness;
+    }
 
+    pub fn set_verify_targets(&mut self, verify_targets: bool) {
+        self.verify_targets = verify_targets;
+    }
+
+    pub fn set_require_all_sources(&mut self, require_all_sources: bool) {
+        self.require_all_sources = require_all_sources;
+    }
+
+    /// IPS patches parsed the other way are dropped from the cache, the next refresh parses them again.
+    pub fn set_strict_ips_eof(&mut self, strict_ips_eof: bool) {
+        if self.strict_ips_eof != strict_ips_eof {
+            self.strict_ips_eof = strict_ips_eof;
+            self.ips_cache.clear();
+        }
+    }
+
+    /// Drops the loaded source ROMs, the parsed patches and the checksums kept between refreshes.
+    /// The next refresh hashes every ROM and parses every patch again.
+    pub fn clear_caches(&mut self) {
+        self.hashed_roms.clear();
+        self.source_cache.clear();
+        self.bps_cache.clear();
+        self.ips_cache.clear();
+        self.aps_cache.clear();
+        self.ppf_cache.clear();
+        self.xdelta_cache.clear();
+        self.rup_cache.clear();
+    }
+
+    /// Resolves the name of a target ROM, without its extension.
+    /// The enabled naming sources are tried in order: patch metadata, name sidecar and name template,
+    /// falling back to the name of the patch.
+    fn target_stem(&self, patch_path: &Path, metadata_name: Option<&str>, source_path: Option<&Path>) -> OsString {
+        let patch_stem = patch_path.file_stem().unwrap_or_default();
+
+        if self.name_from_metadata {
+            if let Some(name) = metadata_name.and_then(valid_name) {
+                return OsString::from(name);
+            }
+        }
+
+        if self.name_sidecars {
+            let sidecar_path = patch_path.with_extension(NAME_SIDECAR_EXTENSION);
+            if let Ok(sidecar) = fs::read_to_string(&sidecar_path) {
+                if let Some(name) = sidecar.lines().next().and_then(valid_name) {
+                    return OsString::from(name);
+                }
+            }
+        }
+
+        if let Some(name_template) = &self.name_template {
+            let source_stem = source_path.and_then(Path::file_stem).unwrap_or_default();
+            let name = name_template
+                .replace("{patch}", &patch_stem.to_string_lossy())
+                .replace("{source}", &source_stem.to_string_lossy());
+            if let Some(name) = valid_name(&name) {
+                return OsString::from(name);
+            }
+        }
+
+        patch_stem.to_owned()
+    }
+
+    fn add_target(
+        &mut self,
+        patch_path: &Path,
+        target_stem: &OsStr,
+        target_extension: &OsStr,
+        patch: Arc<dyn Patch + Send + Sync>,
+    ) {
+        let target_file_name = |suffixes: &[&OsStr]| {
+            let mut file_name = target_stem.to_owned();
+            for suffix in suffixes.iter().filter(|s| !s.is_empty()) {
+                file_name.push(".");
+                file_name.push(suffix);
+            }
+            file_name
+        };
+
+        // Paired patches outside of the base directory have their targets in the root directory
+        let relative_patch_path = patch_path
+            .strip_prefix(&self.base_directory)
+            .unwrap_or_else(|_| Path::new(patch_path.file_name().unwrap_or_default()));
+        let mut target_path = relative_patch_path.with_file_name(target_file_name(&[target_extension]));
+
+        // Patches of different formats may share a name, like `hack.bps` and `hack.ips`
+        if self.target_roms.contains_key(&target_path) {
+            let patch_extension = patch_path.extension().unwrap_or_default();
+            let renamed_path =
+                relative_patch_path.with_file_name(target_file_name(&[patch_extension, target_extension]));
+            warn!(
+                "Target ROM {:?} of {:?} already exists, exposing it as {:?}",
+                target_path, patch_path, renamed_path
+            );
+            target_path = renamed_path;
+        }
+
+        // A target named after a source ROM would be mistaken for the source once copied next to it,
+        // like a patch named after the ROM it patches with a name template or metadata
+        let source_path = self.base_directory.join(&target_path);
+        if self.source_roms.values().flatten().any(|path| *path == source_path) {
+            let patch_extension = patch_path.extension().unwrap_or_default();
+            let renamed_path =
+                relative_patch_path.with_file_name(target_file_name(&[patch_extension, target_extension]));
+            warn!(
+                "Target ROM {:?} of {:?} has the name of a source ROM, exposing it as {:?}",
+                target_path, patch_path, renamed_path
+            );
+            target_path = renamed_path;
+        }
+
+        // Filtering only hides targets, the source ROMs with filtered extensions are still indexed
+        let extension = target_extension.to_string_lossy().to_ascii_lowercase();
+        let included = match &self.included_target_extensions {
+            Some(included_extensions) => included_extensions.contains(&extension),
+            None => true,
+        };
+        if !included || self.excluded_target_extensions.contains(&extension) {
+            debug!("Hiding {:?}, its extension is filtered", target_path);
+            return;
+        }
+
+        if let Some(max_target_size) = self.max_target_size {
+            if patch.target_size() > max_target_size {
+                warn!(
+                    "Skipping {:?}, target ROM is too large ({} bytes, limit: {} bytes)",
+                    target_path,
+                    patch.target_size(),
+                    max_target_size
+                );
+                return;
+            }
+        }
+
+        if let Some(operation_count) = patch.operation_count() {
+            debug!("{:?} is patched in {} operations", target_path, operation_count);
+        }
+
+        self.target_roms.insert(target_path, patch);
+    }
+
+    /// Loads an explicitly paired BPS patch, verifying that the source ROM is the one the patch expects.
+    fn load_paired_bps(&mut self, patch_path: &Path, source_path: &Path) -> Result<BpsPatch, Box<dyn Error>> {
+        let mut patch = self.bps_cache.load(&[patch_path], || BpsPatch::new(patch_path))?;
+
+        if !patch.is_source_less() {
+            let source_checksum = crc32::checksum_ieee(&self.source_cache.get(source_path)?);
+            if source_checksum != patch.source_checksum() {
+                return Err(Box::new(BpsError::SourceChecksum {
+                    expected: patch.source_checksum(),
+                    received: source_checksum,
+                }));
+            }
+            patch.set_source_path(source_path);
+        }
+
+        patch.set_checksum_freshness(self.checksum_freshness);
+        patch.set_verify_target(self.verify_targets);
+        patch.set_source_cache(self.source_cache.clone());
+        Ok(patch)
+    }
+
+    /// Finds the source ROM of a BPS patch among the ones with the expected CRC32, telling collisions apart by size.
+    /// Copies of the same ROM are interchangeable, the first one is chosen by its path.
+    fn find_bps_source(&self, patch_path: &Path, patch: &BpsPatch) -> Option<&Path> {
+        let source_paths: Vec<&PathBuf> = self
+            .source_roms
+            .get(&patch.source_checksum())?
+            .iter()
+            .filter(|source_path| fs::metadata(source_path).is_ok_and(|m| m.len() == patch.source_size()))
+            .collect();
+
+        if source_paths.len() > 1 {
+            warn!(
+                "Multiple source ROMs were found for {:?}, choosing {:?} out of {:?}",
+                patch_path, source_paths[0], source_paths
+            );
+        }
+
+        source_paths.first().map(|source_path| source_path.as_path())
+    }
+
+    /// The source ROM of patches not identifying theirs: the only one to choose from, or among several
+    /// the one named like the patch, like `game.sfc` for `game.ips`. Copies of the same ROM count as one.
+    /// Source ROMs in the directory of the patch are chosen from first, then the ones in its parent directories
+    /// up to the base directory, like a ROM in the base directory for hacks in subdirectories. Subdirectories
+    /// aren't searched, they usually hold other games. With a separate source directory, every source ROM
+    /// in it is chosen from.
+    fn find_unidentified_source(&self, patch_path: &Path) -> Option<PathBuf> {
+        if self.source_directory.is_some() {
+            return self.choose_unidentified_source(patch_path, |_| true);
+        }
+
+        for directory in patch_path.ancestors().skip(1) {
+            if !directory.starts_with(&self.base_directory) {
+                break;
+            }
+
+            let has_candidates = self
+                .source_roms
+                .values()
+                .flatten()
+                .any(|path| path.parent() == Some(directory));
+            if has_candidates {
+                return self.choose_unidentified_source(patch_path, |path| path.parent() == Some(directory));
+            }
+        }
+        None
+    }
+
+    /// Chooses the source ROM of a patch among the ones accepted by `filter`, logging when several are left.
+    fn choose_unidentified_source(&self, patch_path: &Path, filter: impl Fn(&Path) -> bool) -> Option<PathBuf> {
+        let candidates: Vec<Vec<&PathBuf>> = self
+            .source_roms
+            .values()
+            .map(|source_paths| source_paths.iter().filter(|path| filter(path)).collect::<Vec<_>>())
+            .filter(|source_paths| !source_paths.is_empty())
+            .collect();
+
+        if let [source_paths] = &candidates[..] {
+            return source_paths.first().map(|path| path.to_path_buf());
+        }
+
+        let patch_stem = patch_path.file_stem();
+        let matching_roms: Vec<&PathBuf> = candidates
+            .iter()
+            .filter_map(|source_paths| source_paths.iter().find(|path| path.file_stem() == patch_stem))
+            .copied()
+            .collect();
+
+        match matching_roms[..] {
+            [source_path] => Some(source_path.clone()),
+            _ if candidates.len() > 1 => {
+                warn!(
+                    "Multiple source ROMs were found for {:?}, none of them is named like the patch",
+                    patch_path
+                );
+                None
+            }
+            _ => None,
+        }
+    }
+
+    /// Finds a source ROM matching the patch once its external header, like a copier header, is skipped.
+    /// Tried when no source ROM matches the patch as a whole, its size tells the header apart.
+    fn find_headered_source(&self, patch: &BpsPatch) -> Option<(PathBuf, u64)> {
+        let mut source_paths: Vec<&PathBuf> = self.source_roms.values().flatten().collect();
+        source_paths.sort();
+
+        source_paths.into_iter().find_map(|source_path| {
+            let header_size = rom_header::external_header_size(source_path)?;
+            let source_size = fs::metadata(source_path).ok()?.len();
+            if source_size != patch.source_size() + header_size {
+                return None;
+            }
+
+            let source = self.source_cache.get(source_path).ok()?;
+            if crc32::checksum_ieee(&source[(header_size as usize)..]) != patch.source_checksum() {
+                return None;
+            }
+
+            Some((source_path.clone(), header_size))
+        })
+    }
+
+    /// Lists the files of a directory and its subdirectories, skipping the mount point and directories listed before.
+    /// The mount point may be inside the base directory, accessing it while refreshing would deadlock the filesystem.
+    fn list_files(&self, directory: &Path, visited_directories: &mut HashSet<(u64, u64)>) -> io::Result<Vec<DirEntry>> {
+        let metadata = fs::metadata(directory)?;
+        if !visited_directories.insert((metadata.dev(), metadata.ino())) {
+            warn!("Skipping {:?}, it was already scanned through another path", directory);
+            return Ok(Vec::new());
+        }
+
+        let inside_mount_point = |entry: &DirEntry| -> bool {
+            let mount_point = match &self.mount_point {
+                Some(mount_point) => mount_point,
+                None => return false,
+            };
+
+            // Inode numbers of directory entries are read without accessing the mount point itself
+            if metadata.dev() == mount_point.dev && entry.ino() == mount_point.ino {
+                return true;
+            }
+
+            match fs::read_link(entry.path()) {
+                Ok(link_target) => {
+                    let directory = fs::canonicalize(directory).unwrap_or_else(|_| directory.to_owned());
+                    normalize_path(&directory.join(link_target)).starts_with(&mount_point.path)
+                }
+                Err(_) => false,
+            }
+        };
+
+        let mut files = Vec::new();
+        let mut subdirectories = Vec::new();
+
+        for entry in fs::read_dir(directory)?.filter_map(Result::ok) {
+            if inside_mount_point(&entry) {
+                debug!("Skipping {:?}, it is inside the mount point", entry.path());
+                continue;
+            }
+
+            // Symbolic links are followed to both files and directories, unless disabled
+            let (is_file, is_directory) = match entry.file_type() {
+                Ok(file_type) if file_type.is_symlink() => {
+                    let path = entry.path();
+                    (
+                        self.follow_symlinks && path.is_file(),
+                        self.follow_symlinks && path.is_dir(),
+                    )
+                }
+                Ok(file_type) => (file_type.is_file(), file_type.is_dir()),
+                Err(_) => (false, false),
+            };
+
+            if is_file {
+                files.push(entry);
+            } else if is_directory {
+                subdirectories.push(entry.path());
+            }
+        }
+
+        // Subdirectories failing to be listed are skipped, rather than failing the whole refresh
+        for subdirectory in subdirectories {
+            match self.list_files(&subdirectory, visited_directories) {
+                Ok(mut subdirectory_files) => files.append(&mut subdirectory_files),
+                Err(err) => warn!("Skipping {:?}: {}", subdirectory, err),
+            }
+        }
+
+        Ok(files)
+    }
+
+    /// Rescans the base directory. On failure the ROMs of the previous refresh are kept,
+    /// rather than leaving them partially cleared.
     pub fn refresh(&mut self) -> io::Result<()> {
         info!("Refreshing");
-        self.source_roms.clear();
-        self.target_roms.clear();
+        self.source_cache.clear();
+        let source_roms = mem::take(&mut self.source_roms);
+        let target_roms = mem::take(&mut self.target_roms);
+
+        if let Err(err) = self.scan() {
+            self.source_roms = source_roms;
+            self.target_roms = target_roms;
+            return Err(err);
+        }
+
+        self.degraded_targets = self.validate_targets();
+        self.generation += 1;
+        Ok(())
+    }
+
+    /// Validates the patches of the target ROMs, returning the failing ones with their errors.
+    fn validate_targets(&self) -> HashMap<PathBuf, String> {
+        let mut degraded_targets = HashMap::new();
+        for (target_path, patch) in &self.target_roms {
+            if let Err(err) = patch.validate() {
+                warn!("Failed to validate {:?}: {}", target_path, err);
+                degraded_targets.insert(target_path.clone(), err.to_string());
+            }
+        }
+        degraded_targets
+    }
 
-        fn extension_matches(path: &Path, extensions: &[&str]) -> bool {
+    fn scan(&mut self) -> io::Result<()> {
+        fn extension_matches<S: AsRef<str>>(path: &Path, extensions: &[S]) -> bool {
             let extension = path
                 .extension()
                 .and_then(OsStr::to_str)
                 .map(str::to_ascii_lowercase)
                 .unwrap_or_default();
-            extensions.contains(&extension.as_str())
+            extensions.iter().any(|e| e.as_ref() == extension)
         }
 
-        let entries: Vec<DirEntry> = fs::read_dir(&self.base_directory)?
-            .filter_map(Result::ok)
-            .filter(|e| !e.file_type().unwrap().is_dir())
+        // Paired patches are applied only to their own source ROMs
+        let paired_files: HashSet<(u64, u64)> = self
+            .pairings
+            .iter()
+            .filter_map(|(patch_path, _)| fs::metadata(patch_path).ok())
+            .map(|m| (m.dev(), m.ino()))
             .collect();
 
-        for entry in entries.iter().filter(|e| extension_matches(&e.path(), ROM_EXTENSIONS)) {
-            let crc = crc32::checksum_ieee(&fs::read(entry.path())?);
-            self.source_roms.insert(crc, entry.path().to_owned());
+        let mut visited_directories = HashSet::new();
+        let entries: Vec<DirEntry> = self
+            .list_files(&self.base_directory, &mut visited_directories)?
+            .into_iter()
+            .filter(|e| match fs::metadata(e.path()) {
+                Ok(metadata) => !paired_files.contains(&(metadata.dev(), metadata.ino())),
+                Err(_) => true,
+            })
+            .collect();
+
+        // Hard and symbolic links to the same ROM are hashed only once
+        let mut hashed_files: HashMap<(u64, u64), HashedRom> = HashMap::new();
+        let mut hashed_roms: HashMap<PathBuf, HashedRom> = HashMap::new();
+        let mut source_systems: HashMap<PathBuf, RomSystem> = HashMap::new();
+        // N64 ROMs by their internal checksum, which APS patches identify their source ROMs by
+        let mut n64_checksums: HashMap<Vec<u8>, PathBuf> = HashMap::new();
+        // ROMs by their MD5 checksum, which RUP patches identify their source ROMs by
+        let mut source_md5s: HashMap<[u8; 16], PathBuf> = HashMap::new();
+        let rup_patches = entries.iter().any(|e| extension_matches(&e.path(), &["rup"]));
+
+        // Source ROMs are looked for in the source directory instead of the base directory, when there is one
+        let source_paths: Vec<PathBuf> = match &self.source_directory {
+            Some(source_directory) => self
+                .list_files(source_directory, &mut HashSet::new())?
+                .iter()
+                .map(DirEntry::path)
+                .collect(),
+            None => entries.iter().map(DirEntry::path).collect(),
+        };
+
+        // ROMs are visited in the order of their paths, which orders the ROMs sharing a CRC32 too
+        let rom_extensions = &self.rom_extensions;
+        let mut rom_files: Vec<(PathBuf, fs::Metadata)> = source_paths
+            .into_iter()
+            .filter(|path| extension_matches(path, rom_extensions))
+            .map(|path| {
+                let metadata = fs::metadata(&path)?;
+                Ok((path, metadata))
+            })
+            .collect::<io::Result<_>>()?;
+        rom_files.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        // Only new and modified ROMs are hashed, in parallel, the rest keep their checksums of the previous refresh
+        let unhashed_roms = unhashed_roms(&rom_files, &self.hashed_roms, rup_patches);
+        let rehashed_roms: Vec<(PathBuf, HashedRom)> = unhashed_roms
+            .par_iter()
+            .map(|(path, metadata)| Ok((path.clone(), HashedRom::hash(path, metadata, rup_patches)?)))
+            .collect::<io::Result<_>>()?;
+        self.hashed_roms.extend(rehashed_roms);
+
+        for (path, metadata) in rom_files {
+            let hashed_rom = match hashed_files.get(&(metadata.dev(), metadata.ino())) {
+                Some(hashed_file) => hashed_file.clone(),
+                None => {
+                    let hashed_file = match self.hashed_roms.remove(&path) {
+                        Some(hashed_rom) => hashed_rom,
+                        None => HashedRom::hash(&path, &metadata, rup_patches)?,
+                    };
+                    if let Some(checksum) = &hashed_file.n64_checksum {
+                        n64_checksums.insert(checksum.clone(), path.clone());
+                    }
+                    if let Some(md5) = hashed_file.md5 {
+                        source_md5s.insert(md5, path.clone());
+                    }
+                    hashed_files.insert((metadata.dev(), metadata.ino()), hashed_file.clone());
+                    hashed_file
+                }
+            };
+
+            self.source_roms.entry(hashed_rom.crc).or_default().push(path.clone());
+            if let Some(system) = hashed_rom.system {
+                source_systems.insert(path.clone(), system);
+            }
+            hashed_roms.insert(path, hashed_rom);
         }
+        self.hashed_roms = hashed_roms;
 
+        // Targets of sources with generic extensions get the extension of the detected system instead
+        let target_extension = |source_path: &Path| -> OsString {
+            let source_extension = source_path.extension().unwrap_or_default();
+            match source_systems.get(source_path) {
+                Some(system) if extension_matches(source_path, GENERIC_ROM_EXTENSIONS) => {
+                    OsString::from(system.extension())
+                }
+                _ => source_extension.to_owned(),
+            }
+        };
+
+        let patch_paths: Vec<PathBuf> = entries
+            .iter()
+            .map(DirEntry::path)
+            .chain(self.pairings.iter().map(|(patch_path, _)| patch_path.clone()))
+            .collect();
+        self.bps_cache.retain(&patch_paths);
+        self.ips_cache.retain(&patch_paths);
+        self.aps_cache.retain(&patch_paths);
+        self.ppf_cache.retain(&patch_paths);
+        self.xdelta_cache.retain(&patch_paths);
+        self.rup_cache.retain(&patch_paths);
+
+        // Patching continues even without source ROMs, source-less patches don't need one
         if self.source_roms.is_empty() {
-            warn!("No source ROMs were found in {:?}", self.base_directory);
-            return Ok(());
+            let source_directory = self.source_directory.as_ref().unwrap_or(&self.base_directory);
+            warn!("No source ROMs were found in {:?}", source_directory);
         }
 
+        let mut missing_sources = 0;
+        let mut bps_patches = Vec::new();
+
         for entry in entries.iter().filter(|e| extension_matches(&e.path(), &["bps"])) {
-            match BpsPatch::new(&entry.path()) {
+            let patch_path = entry.path();
+            let patch = self.bps_cache.load(&[&patch_path], || BpsPatch::new(&patch_path));
+            bps_patches.push((patch_path, patch));
+        }
+
+        // Patch packs, archives bundling patches for one or more ROMs
+        for entry in entries.iter().filter(|e| extension_matches(&e.path(), &["zip"])) {
+            let archive_path = entry.path();
+
+            let archive = File::open(&archive_path)
+                .map_err(ZipError::from)
+                .and_then(ZipArchive::new);
+
+            let entry_names: Vec<String> = match archive {
+                Ok(archive) => archive.file_names().map(str::to_owned).collect(),
+                Err(err) => {
+                    error!("Failed to load {:?}: {}", archive_path, err);
+                    continue;
+                }
+            };
+
+            for entry_name in entry_names.iter().filter(|n| extension_matches(Path::new(n), &["bps"])) {
+                // Targets of archived patches are named after the patch inside the archive
+                let patch_name = Path::new(entry_name).file_name().unwrap_or_default();
+                let patch_path = archive_path.with_file_name(patch_name);
+                let patch = BpsPatch::from_archive(&archive_path, entry_name);
+                bps_patches.push((patch_path, patch));
+            }
+        }
+
+        // Patch order decides which one of the duplicate patches is kept
+        bps_patches.sort_by(|(a, _), (b, _)| a.cmp(b));
+        let mut patch_checksums: HashMap<u32, PathBuf> = HashMap::new();
+
+        for (patch_path, patch) in bps_patches {
+            if let Ok(patch) = &patch {
+                if let Some(original_path) = patch_checksums.get(&patch.patch_checksum()) {
+                    warn!("Skipping {:?}, it is a duplicate of {:?}", patch_path, original_path);
+                    continue;
+                }
+                patch_checksums.insert(patch.patch_checksum(), patch_path.clone());
+            }
+
+            if let Ok(Some(metadata)) = patch.as_ref().map(BpsPatch::metadata_str) {
+                debug!("Metadata of {:?}: {}", patch_path, metadata);
+            }
+
+            let patch = patch.map(|mut patch| {
+                patch.set_checksum_freshness(self.checksum_freshness);
+                patch.set_verify_target(self.verify_targets);
+                patch.set_source_cache(self.source_cache.clone());
+                patch
+            });
+
+            match patch {
+                Ok(patch) if patch.is_source_less() => {
+                    let target_stem = self.target_stem(&patch_path, patch.metadata_name(), None);
+                    let target_extension = OsStr::new(SOURCE_LESS_TARGET_EXTENSION);
+                    self.add_target(&patch_path, &target_stem, target_extension, Arc::new(patch));
+                }
                 Ok(mut patch) => {
-                    if let Some(source_path) = self.source_roms.get(&patch.source_checksum()) {
+                    // The catalog is only consulted when the base directory has no matching ROM
+                    let source_path = self
+                        .find_bps_source(&patch_path, &patch)
+                        .or_else(|| self.catalog.as_ref().and_then(|c| c.get(patch.source_checksum())));
+
+                    if let Some(source_path) = source_path {
                         patch.set_source_path(source_path);
 
-                        let mut target_path = entry.path().strip_prefix(&self.base_directory).unwrap().to_owned();
-                        target_path.set_extension(source_path.extension().unwrap_or_default());
-                        self.target_roms.insert(target_path, Arc::new(patch));
+                        let target_stem = self.target_stem(&patch_path, patch.metadata_name(), Some(source_path));
+                        let target_extension = target_extension(source_path);
+                        self.add_target(&patch_path, &target_stem, &target_extension, Arc::new(patch));
+                    } else if let Some((source_path, header_size)) = self.find_headered_source(&patch) {
+                        info!(
+                            "Applying {:?} to {:?} without its {} byte header",
+                            patch_path, source_path, header_size
+                        );
+                        patch.set_source_path(&source_path);
+                        patch.set_source_header_size(header_size);
+
+                        let target_stem = self.target_stem(&patch_path, patch.metadata_name(), Some(&source_path));
+                        let target_extension = target_extension(&source_path);
+                        self.add_target(&patch_path, &target_stem, &target_extension, Arc::new(patch));
                     } else {
                         warn!(
                             "No source ROM was found for {:?} (CRC32=0x{:08X})",
-                            entry.path(),
+                            patch_path,
                             patch.source_checksum()
                         );
+                        missing_sources += 1;
                     }
                 }
                 Err(err) => {
-                    error!("Failed to load {:?}: {}", entry.path(), err);
+                    error!("Failed to load {:?}: {}", patch_path, err);
                 }
             }
         }
 
         for entry in entries.iter().filter(|e| extension_matches(&e.path(), &["ips"])) {
-            if self.source_roms.len() > 1 {
-                warn!(
-                    "Multiple source ROMs were found for {:?}, cannot decide which one to choose",
-                    entry.path()
-                );
+            if let Some(source_path) = self.find_unidentified_source(&entry.path()) {
+                let patch_path = entry.path();
+                let dependencies = [patch_path.as_path(), source_path.as_path()];
+                let source_cache = &self.source_cache;
+                let strict_eof = self.strict_ips_eof;
+                match self.ips_cache.load(&dependencies, || {
+                    IpsPatch::new(&patch_path, &source_path, strict_eof).map(|mut patch| {
+                        patch.set_source_cache(source_cache.clone());
+                        Arc::new(patch)
+                    })
+                }) {
+                    Ok(patch) => {
+                        let target_stem = self.target_stem(&patch_path, None, Some(&source_path));
+                        let target_extension = target_extension(&source_path);
+                        self.add_target(&patch_path, &target_stem, &target_extension, patch);
+                    }
+                    Err(err) => {
+                        error!("Failed to load {:?}: {}", entry.path(), err);
+                    }
+                }
             } else {
-                let source_path = self.source_roms.values().next().unwrap();
+                warn!("No source ROM was found for {:?}", entry.path());
+                missing_sources += 1;
+            }
+        }
 
-                match IpsPatch::new(&entry.path(), source_path) {
+        for (patch_path, source_path) in self.pairings.clone() {
+            if extension_matches(&patch_path, &["bps"]) {
+                match self.load_paired_bps(&patch_path, &source_path) {
                     Ok(patch) => {
-                        let mut target_path = entry.path().strip_prefix(&self.base_directory).unwrap().to_owned();
-                        target_path.set_extension(source_path.extension().unwrap_or_default());
-                        self.target_roms.insert(target_path, Arc::new(patch));
+                        let source_path = patch.source_path().map(Path::to_owned);
+                        let target_stem = self.target_stem(&patch_path, patch.metadata_name(), source_path.as_deref());
+                        let target_extension = match &source_path {
+                            Some(source_path) => target_extension(source_path),
+                            None => OsString::from(SOURCE_LESS_TARGET_EXTENSION),
+                        };
+                        self.add_target(&patch_path, &target_stem, &target_extension, Arc::new(patch));
                     }
                     Err(err) => {
-                        error!("Failed to load {:?}: {}", entry.path(), err);
+                        error!("Failed to load {:?} with {:?}: {}", patch_path, source_path, err);
+                        missing_sources += 1;
                     }
                 }
+            } else if extension_matches(&patch_path, &["ips"]) {
+                let dependencies = [patch_path.as_path(), source_path.as_path()];
+                let source_cache = &self.source_cache;
+                let strict_eof = self.strict_ips_eof;
+                match self.ips_cache.load(&dependencies, || {
+                    IpsPatch::new(&patch_path, &source_path, strict_eof).map(|mut patch| {
+                        patch.set_source_cache(source_cache.clone());
+                        Arc::new(patch)
+                    })
+                }) {
+                    Ok(patch) => {
+                        let target_stem = self.target_stem(&patch_path, None, Some(&source_path));
+                        let target_extension = target_extension(&source_path);
+                        self.add_target(&patch_path, &target_stem, &target_extension, patch);
+                    }
+                    Err(err) => {
+                        error!("Failed to load {:?} with {:?}: {}", patch_path, source_path, err);
+                        missing_sources += 1;
+                    }
+                }
+            } else {
+                error!("Failed to load {:?}: unsupported patch format", patch_path);
+            }
+        }
+
+        for entry in entries.iter().filter(|e| extension_matches(&e.path(), &["aps"])) {
+            let patch_path = entry.path();
+            let mut patch = match self.aps_cache.load(&[&patch_path], || ApsPatch::new(&patch_path)) {
+                Ok(patch) => patch,
+                Err(err) => {
+                    error!("Failed to load {:?}: {}", patch_path, err);
+                    continue;
+                }
+            };
+
+            // N64 patches name their source ROM, simple patches are matched like IPS patches
+            let source_path = match patch.n64_header() {
+                Some(n64_header) => n64_checksums.get(&n64_header.checksum[..]).cloned(),
+                None => self.find_unidentified_source(&patch_path),
+            };
+
+            match source_path {
+                Some(source_path) => {
+                    patch.set_source_path(&source_path);
+                    patch.set_source_cache(self.source_cache.clone());
+
+                    let target_stem = self.target_stem(&patch_path, None, Some(&source_path));
+                    let target_extension = target_extension(&source_path);
+                    self.add_target(&patch_path, &target_stem, &target_extension, Arc::new(patch));
+                }
+                None => {
+                    warn!("No source ROM was found for {:?}", patch_path);
+                    missing_sources += 1;
+                }
+            }
+        }
+
+        for entry in entries.iter().filter(|e| extension_matches(&e.path(), &["ppf"])) {
+            let patch_path = entry.path();
+            let mut patch = match self.ppf_cache.load(&[&patch_path], || PpfPatch::new(&patch_path)) {
+                Ok(patch) => patch,
+                Err(err) => {
+                    error!("Failed to load {:?}: {}", patch_path, err);
+                    continue;
+                }
+            };
+
+            // Patches with block check data are matched by the data, the others like IPS patches
+            let source_path = match patch.block_check() {
+                Some(block_check) => {
+                    let mut source_paths: Vec<&PathBuf> = self.source_roms.values().flatten().collect();
+                    source_paths.sort();
+                    source_paths
+                        .into_iter()
+                        .find(|source_path| block_check.matches(source_path).unwrap_or(false))
+                        .cloned()
+                }
+                None => self.find_unidentified_source(&patch_path),
+            };
+
+            match source_path {
+                Some(source_path) => {
+                    if let Err(err) = patch.set_source_path(&source_path) {
+                        error!("Failed to load {:?}: {}", source_path, err);
+                        continue;
+                    }
+                    patch.set_source_cache(self.source_cache.clone());
+
+                    let target_stem = self.target_stem(&patch_path, None, Some(&source_path));
+                    let target_extension = target_extension(&source_path);
+                    self.add_target(&patch_path, &target_stem, &target_extension, Arc::new(patch));
+                }
+                None => {
+                    warn!("No source ROM was found for {:?}", patch_path);
+                    missing_sources += 1;
+                }
+            }
+        }
+
+        for entry in entries
+            .iter()
+            .filter(|e| extension_matches(&e.path(), &["xdelta", "vcdiff"]))
+        {
+            let patch_path = entry.path();
+            let mut patch = match self.xdelta_cache.load(&[&patch_path], || XdeltaPatch::new(&patch_path)) {
+                Ok(patch) => patch,
+                Err(err) => {
+                    error!("Failed to load {:?}: {}", patch_path, err);
+                    continue;
+                }
+            };
+
+            // VCDIFF doesn't identify its source ROM, matched like IPS patches
+            if let Some(source_path) = self.find_unidentified_source(&patch_path) {
+                patch.set_source_path(&source_path);
+                patch.set_source_cache(self.source_cache.clone());
+
+                let target_stem = self.target_stem(&patch_path, None, Some(&source_path));
+                let target_extension = target_extension(&source_path);
+                self.add_target(&patch_path, &target_stem, &target_extension, Arc::new(patch));
+            } else {
+                warn!("No source ROM was found for {:?}", patch_path);
+                missing_sources += 1;
+            }
+        }
+
+        for entry in entries.iter().filter(|e| extension_matches(&e.path(), &["rup"])) {
+            let patch_path = entry.path();
+            let patch = match self.rup_cache.load(&[&patch_path], || RupPatch::new(&patch_path)) {
+                Ok(patch) => patch,
+                Err(err) => {
+                    error!("Failed to load {:?}: {}", patch_path, err);
+                    continue;
+                }
+            };
+
+            // A patch may hold files for several variants of a ROM, only the ones with a source ROM are exposed
+            let file_patches: Vec<_> = patch
+                .files()
+                .iter()
+                .filter_map(|file_patch| {
+                    let source_path = source_md5s.get(&file_patch.source_md5())?;
+                    let mut file_patch = file_patch.clone();
+                    file_patch.set_source_path(source_path);
+                    file_patch.set_source_cache(self.source_cache.clone());
+                    Some((source_path.clone(), file_patch))
+                })
+                .collect();
+
+            if file_patches.is_empty() {
+                warn!("No source ROM was found for {:?}", patch_path);
+                missing_sources += 1;
+            }
+
+            let multiple_targets = file_patches.len() > 1;
+            for (source_path, file_patch) in file_patches {
+                let mut target_stem = self.target_stem(&patch_path, None, Some(&source_path));
+                if multiple_targets {
+                    // Targets are told apart by the names of the patched files
+                    let file_stem = Path::new(file_patch.file_name()).file_stem().unwrap_or_default();
+                    if let Some(file_stem) = valid_name(&file_stem.to_string_lossy()) {
+                        target_stem.push(format!(" ({})", file_stem));
+                    }
+                }
+
+                let target_extension = target_extension(&source_path);
+                self.add_target(&patch_path, &target_stem, &target_extension, Arc::new(file_patch));
             }
         }
 
         // TODO: UPS support
         // With the same CRC32-matching logic as BPS
 
+        if self.require_all_sources && missing_sources > 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                match missing_sources {
+                    1 => "no source ROM was found for 1 patch".to_owned(),
+                    _ => format!("no source ROM was found for {} patches", missing_sources),
+                },
+            ));
+        }
+
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source_cache::SourceData;
+    use crate::testutil::{
+        capture_logs, make_bps, make_bps_with_metadata, make_dir_fixture, make_hack, make_ips, make_rom,
+        make_source_less_bps, write_file,
+    };
+    use std::cell::Cell;
+    use std::convert::Infallible;
+    use std::io::Write;
+
+    #[test]
+    fn reuses_unchanged_patches() {
+        let directory = make_dir_fixture(&[("hack.ips", b"patch"), ("a.sfc", b"a"), ("b.sfc", b"b")]);
+        let path = |name: &str| directory.path().join(name);
+        let (patch_path, a_path, b_path) = (path("hack.ips"), path("a.sfc"), path("b.sfc"));
+
+        let mut patch_cache = PatchCache::new();
+        let parses = Cell::new(0);
+        let mut load = |dependencies: &[&Path]| {
+            patch_cache
+                .load(dependencies, || {
+                    parses.set(parses.get() + 1);
+                    Ok::<_, Infallible>(parses.get())
+                })
+                .unwrap()
+        };
+
+        // Two refreshes in a row, the second one reuses both patches
+        assert_eq!(load(&[&patch_path, &a_path]), 1);
+        assert_eq!(load(&[&patch_path, &b_path]), 2);
+        assert_eq!(load(&[&patch_path, &a_path]), 1);
+        assert_eq!(load(&[&patch_path, &b_path]), 2);
+        assert_eq!(parses.get(), 2);
+
+        // Same modification time, but a different length
+        let modified = fs::metadata(&b_path).unwrap().modified().unwrap();
+        write_file(directory.path(), "b.sfc", b"bb");
+        File::options()
+            .write(true)
+            .open(&b_path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        assert_eq!(load(&[&patch_path, &a_path]), 1);
+        assert_eq!(load(&[&patch_path, &b_path]), 3);
+    }
+
+    #[test]
+    fn drops_patches_gone_from_the_base_directory() {
+        let directory = make_dir_fixture(&[("a.ips", b"a"), ("b.ips", b"b"), ("game.sfc", b"game")]);
+        let path = |name: &str| directory.path().join(name);
+
+        let mut patch_cache = PatchCache::new();
+        for name in ["a.ips", "b.ips"] {
+            patch_cache
+                .load(&[&path(name), &path("game.sfc")], || Ok::<_, Infallible>(()))
+                .unwrap();
+        }
+
+        patch_cache.retain(&[path("b.ips")]);
+        let keys: Vec<&Vec<PathBuf>> = patch_cache.entries.keys().collect();
+        assert_eq!(keys, [&vec![path("b.ips"), path("game.sfc")]]);
+    }
+
+    #[test]
+    fn chooses_unidentified_sources_next_to_the_patch() {
+        let (a, b) = (make_rom(1024, 1), make_rom(1024, 2));
+        let directory = make_dir_fixture(&[
+            ("a/game.sfc", &a),
+            ("a/hack.ips", &make_ips(&a, &make_hack(&a))),
+            ("b/other.sfc", &b),
+            ("b/hack.ips", &make_ips(&b, &make_hack(&b))),
+            ("c/hack.ips", &make_ips(&b, &make_hack(&b))),
+        ]);
+
+        let rom_manager = RomManager::new(directory.path()).unwrap();
+        let source_path = |target_path: &str| {
+            let patch = rom_manager.target_roms.get(Path::new(target_path)).unwrap();
+            patch
+                .source_path()
+                .unwrap()
+                .strip_prefix(directory.path())
+                .unwrap()
+                .to_owned()
+        };
+
+        assert_eq!(source_path("a/hack.sfc"), Path::new("a/game.sfc"));
+        assert_eq!(source_path("b/hack.sfc"), Path::new("b/other.sfc"));
+        assert_eq!(rom_manager.target_roms.len(), 2);
+        assert_eq!(
+            rom_manager
+                .target_roms
+                .get(Path::new("a/hack.sfc"))
+                .unwrap()
+                .patched_rom()
+                .unwrap(),
+            make_hack(&a)
+        );
+    }
+
+    #[test]
+    fn finds_sources_in_the_catalog() {
+        let source = make_rom(1024, 1);
+        let target = make_hack(&source);
+        let vault = make_dir_fixture(&[
+            ("roms/game.sfc", &source),
+            (
+                "catalog.txt",
+                format!("# ROM vault\n\n0x{:08X} roms/game.sfc\n", crc32::checksum_ieee(&source)).as_bytes(),
+            ),
+        ]);
+        let directory = make_dir_fixture(&[("hack.bps", &make_bps(&source, &target))]);
+
+        // Without the catalog the base directory has no source ROM for the patch
+        assert!(RomManager::new(directory.path()).unwrap().target_roms.is_empty());
+
+        let rom_manager = RomManagerBuilder::new(directory.path())
+            .catalog(RomCatalog::load(&vault.path().join("catalog.txt")).unwrap())
+            .build()
+            .unwrap();
+        let patch = rom_manager.target_roms.get(Path::new("hack.sfc")).unwrap();
+        assert_eq!(patch.source_path(), Some(vault.path().join("roms/game.sfc").as_path()));
+        assert_eq!(patch.patched_rom().unwrap(), target);
+    }
+
+    #[test]
+    fn loads_source_less_patches_without_source_roms() {
+        let directory = make_dir_fixture(&[("full.bps", &make_source_less_bps(b"full"))]);
+        let rom_manager = RomManager::new(directory.path()).unwrap();
+
+        assert!(rom_manager.source_roms.is_empty());
+        let patch = rom_manager.target_roms.get(Path::new("full.bin")).unwrap();
+        assert_eq!(patch.patched_rom().unwrap(), b"full");
+        assert_eq!(rom_manager.target_roms.len(), 1);
+    }
+
+    #[test]
+    fn builds_with_options() {
+        let source = make_rom(1024, 1);
+        let target = make_hack(&source);
+        let directory = make_dir_fixture(&[
+            ("game.sfc", &source),
+            ("game.rom", &source),
+            ("hack.bps", &make_bps(&source, &target)),
+            ("large.bps", &make_source_less_bps(&[0; 4096])),
+            ("small.bps", &make_source_less_bps(&[0; 16])),
+        ]);
+
+        let rom_manager = RomManagerBuilder::new(directory.path())
+            .rom_extensions(&[".SFC"])
+            .name_template("{patch} ({source})")
+            .max_target_size(2048)
+            .verify_targets(false)
+            .build()
+            .unwrap();
+
+        let mut source_paths: Vec<&PathBuf> = rom_manager.source_roms.values().flatten().collect();
+        source_paths.sort();
+        assert_eq!(source_paths, [&directory.path().join("game.sfc")]);
+
+        let mut target_paths: Vec<&PathBuf> = rom_manager.target_roms.keys().collect();
+        target_paths.sort();
+        assert_eq!(target_paths, [Path::new("hack (game).sfc"), Path::new("small ().bin")]);
+
+        let rom_manager = RomManagerBuilder::new(directory.path())
+            .exclude_target_extensions(&["bin"])
+            .build()
+            .unwrap();
+        // Every ROM extension is recognized again, the first copy of the source ROM is chosen by its path
+        let target_paths: Vec<&PathBuf> = rom_manager.target_roms.keys().collect();
+        assert_eq!(target_paths, [Path::new("hack.rom")]);
+    }
+
+    #[test]
+    fn exposes_patches_of_different_formats_sharing_a_name() {
+        let source = make_rom(1024, 1);
+        let bps_target = make_hack(&source);
+        let ips_target = make_hack(&bps_target[..512]);
+        let directory = make_dir_fixture(&[
+            ("game.sfc", &source),
+            ("hack.bps", &make_bps(&source, &bps_target)),
+            ("hack.ips", &make_ips(&source, &ips_target)),
+        ]);
+        let rom_manager = RomManager::new(directory.path()).unwrap();
+        let patched_rom = |target_path: &str| {
+            let patch = rom_manager.target_roms.get(Path::new(target_path)).unwrap();
+            patch.patched_rom().unwrap()
+        };
+
+        assert_eq!(rom_manager.target_roms.len(), 2);
+        assert_eq!(patched_rom("hack.sfc"), bps_target);
+        assert_eq!(patched_rom("hack.ips.sfc"), ips_target);
+    }
+
+    #[test]
+    fn loads_patch_packs() {
+        let (a, b) = (make_rom(1024, 1), make_rom(2048, 2));
+        let (a_target, b_target) = (make_hack(&a), make_hack(&b));
+
+        let mut pack = zip::ZipWriter::new(io::Cursor::new(Vec::new()));
+        let options = zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+        for (name, patch) in [
+            ("a.bps", make_bps(&a, &a_target)),
+            ("patches/b.bps", make_bps(&b, &b_target)),
+        ] {
+            pack.start_file(name, options).unwrap();
+            pack.write_all(&patch).unwrap();
+        }
+        let pack = pack.finish().unwrap().into_inner();
+
+        let directory = make_dir_fixture(&[("a.sfc", &a), ("b.smc", &b), ("hacks/pack.zip", &pack)]);
+        let rom_manager = RomManager::new(directory.path()).unwrap();
+        let patched_rom = |target_path: &str| {
+            let patch = rom_manager.target_roms.get(Path::new(target_path)).unwrap();
+            patch.patched_rom().unwrap()
+        };
+
+        // Targets are named after the patches in the archive, next to the archive
+        assert_eq!(rom_manager.target_roms.len(), 2);
+        assert_eq!(patched_rom("hacks/a.sfc"), a_target);
+        assert_eq!(patched_rom("hacks/b.smc"), b_target);
+    }
+
+    #[test]
+    fn names_targets_by_the_first_available_source() {
+        let source = make_rom(1024, 1);
+        let target = |index: u8| {
+            let mut target = make_hack(&source);
+            target[1] ^= index;
+            target
+        };
+        let directory = make_dir_fixture(&[
+            ("game.sfc", &source),
+            ("a.bps", &make_bps_with_metadata(&source, &target(1), b"Metadata Name")),
+            ("a.name", b"Sidecar A\n"),
+            (
+                "b.bps",
+                &make_bps_with_metadata(&source, &target(2), "\u{FEFF} \n".as_bytes()),
+            ),
+            ("b.name", b"Sidecar B\nSecond line\n"),
+            ("c.bps", &make_bps(&source, &target(3))),
+            ("c.name", b"\n"),
+        ]);
+        let target_paths = |rom_manager: RomManager| {
+            let mut target_paths: Vec<PathBuf> = rom_manager.target_roms.into_keys().collect();
+            target_paths.sort();
+            target_paths
+        };
+
+        let rom_manager = RomManagerBuilder::new(directory.path())
+            .name_from_metadata(true)
+            .name_sidecars(true)
+            .name_template("{patch} [{source}]")
+            .build()
+            .unwrap();
+        assert_eq!(
+            target_paths(rom_manager),
+            [
+                Path::new("Metadata Name.sfc"),
+                Path::new("Sidecar B.sfc"),
+                Path::new("c [game].sfc")
+            ]
+        );
+
+        let rom_manager = RomManagerBuilder::new(directory.path())
+            .name_sidecars(true)
+            .build()
+            .unwrap();
+        assert_eq!(
+            target_paths(rom_manager),
+            [
+                Path::new("Sidecar A.sfc"),
+                Path::new("Sidecar B.sfc"),
+                Path::new("c.sfc")
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_duplicate_patches() {
+        let source = make_rom(1024, 1);
+        let patch = make_bps(&source, &make_hack(&source));
+        let directory = make_dir_fixture(&[("game.sfc", &source), ("hack.bps", &patch), ("hack (copy).bps", &patch)]);
+        let (rom_manager, logs) = capture_logs(|| RomManager::new(directory.path()).unwrap());
+
+        // The first patch by path is kept
+        let target_paths: Vec<&PathBuf> = rom_manager.target_roms.keys().collect();
+        assert_eq!(target_paths, [Path::new("hack (copy).sfc")]);
+        let skipped_message = format!(
+            "Skipping {:?}, it is a duplicate of {:?}",
+            directory.path().join("hack.bps"),
+            directory.path().join("hack (copy).bps")
+        );
+        assert!(logs.contains(&skipped_message), "{:?}", logs);
+    }
+
+    #[test]
+    fn hashes_hard_links_once() {
+        let source = make_rom(1024, 1);
+        let target = make_hack(&source);
+        let directory = make_dir_fixture(&[("a.sfc", &source), ("hack.bps", &make_bps(&source, &target))]);
+        let path = |name: &str| directory.path().join(name);
+        fs::hard_link(path("a.sfc"), path("b.sfc")).unwrap();
+
+        let rom_files: Vec<(PathBuf, fs::Metadata)> = ["a.sfc", "b.sfc"]
+            .iter()
+            .map(|name| (path(name), fs::metadata(path(name)).unwrap()))
+            .collect();
+        let unhashed_paths: Vec<&PathBuf> = unhashed_roms(&rom_files, &HashMap::new(), false)
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect();
+        assert_eq!(unhashed_paths, [&path("a.sfc")]);
+
+        // Both links are sources of the patch, neither is hashed again by the next refresh
+        let rom_manager = RomManager::new(directory.path()).unwrap();
+        assert_eq!(
+            rom_manager.source_roms[&crc32::checksum_ieee(&source)],
+            [path("a.sfc"), path("b.sfc")]
+        );
+        assert!(unhashed_roms(&rom_files, &rom_manager.hashed_roms, false).is_empty());
+    }
+
+    #[test]
+    fn filters_targets_by_extension() {
+        let (snes, gb) = (make_rom(1024, 1), make_rom(1024, 2));
+        let directory = make_dir_fixture(&[
+            ("game.sfc", &snes),
+            ("game.gb", &gb),
+            ("snes.bps", &make_bps(&snes, &make_hack(&snes))),
+            ("gb.bps", &make_bps(&gb, &make_hack(&gb))),
+            ("full.bps", &make_source_less_bps(b"full")),
+        ]);
+        let target_paths = |rom_manager: &RomManager| {
+            let mut target_paths: Vec<PathBuf> = rom_manager.target_roms.keys().cloned().collect();
+            target_paths.sort();
+            target_paths
+        };
+
+        let rom_manager = RomManagerBuilder::new(directory.path())
+            .include_target_extensions(&[".SFC", "bin"])
+            .build()
+            .unwrap();
+        assert_eq!(
+            target_paths(&rom_manager),
+            [Path::new("full.bin"), Path::new("snes.sfc")]
+        );
+        // The source ROMs of hidden targets are still indexed
+        assert_eq!(rom_manager.source_roms.len(), 2);
+
+        let rom_manager = RomManagerBuilder::new(directory.path())
+            .include_target_extensions(&["sfc", "bin"])
+            .exclude_target_extensions(&["BIN"])
+            .build()
+            .unwrap();
+        assert_eq!(target_paths(&rom_manager), [Path::new("snes.sfc")]);
+
+        let rom_manager = RomManagerBuilder::new(directory.path())
+            .exclude_target_extensions(&["sfc"])
+            .build()
+            .unwrap();
+        assert_eq!(target_paths(&rom_manager), [Path::new("full.bin"), Path::new("gb.gb")]);
+    }
+
+    #[test]
+    fn names_targets_of_generic_sources_by_their_system() {
+        let mut source = make_rom(0x8000, 1);
+        source[0x104..0x108].copy_from_slice(&[0xCE, 0xED, 0x66, 0x66]);
+        let unknown_source = make_rom(0x8000, 2);
+        let directory = make_dir_fixture(&[
+            ("game.bin", &source),
+            ("unknown.rom", &unknown_source),
+            ("hack.bps", &make_bps(&source, &make_hack(&source))),
+            ("other.bps", &make_bps(&unknown_source, &make_hack(&unknown_source))),
+        ]);
+        let rom_manager = RomManager::new(directory.path()).unwrap();
+
+        let mut target_paths: Vec<&PathBuf> = rom_manager.target_roms.keys().collect();
+        target_paths.sort();
+        assert_eq!(target_paths, [Path::new("hack.gb"), Path::new("other.rom")]);
+    }
+
+    #[test]
+    fn maps_shared_sources_once() {
+        let source = make_rom(4096, 1);
+        let (a, b) = (make_hack(&source), make_hack(&source[..2048]));
+        let directory = make_dir_fixture(&[
+            ("game.sfc", &source),
+            ("a.bps", &make_bps(&source, &a)),
+            ("b.bps", &make_bps(&source, &b)),
+            ("c.ips", &make_ips(&source, &b)),
+        ]);
+        let rom_manager = RomManagerBuilder::new(directory.path())
+            .map_sources(true)
+            .build()
+            .unwrap();
+
+        for (target_path, target) in [("a.sfc", &a), ("b.sfc", &b), ("c.sfc", &b)] {
+            let patch = rom_manager.target_roms.get(Path::new(target_path)).unwrap();
+            assert_eq!(&patch.patched_rom().unwrap(), target);
+        }
+
+        // Patches read at the same time share the source ROM, the source cache lists the ones in use
+        let readers: Vec<_> = ["a.sfc", "b.sfc", "c.sfc"]
+            .iter()
+            .map(|target_path| rom_manager.target_roms[Path::new(target_path)].reader().unwrap())
+            .collect();
+        let source_path = directory.path().join("game.sfc");
+        assert_eq!(
+            format!("{:?}", rom_manager.source_cache),
+            format!("[{:?}]", source_path)
+        );
+        assert!(matches!(
+            *rom_manager.source_cache.get(&source_path).unwrap(),
+            SourceData::Mapped(_)
+        ));
+
+        drop(readers);
+        assert_eq!(format!("{:?}", rom_manager.source_cache), "[]");
+    }
+
+    #[test]
+    fn requires_all_sources_when_asked() {
+        let (source, missing_source) = (make_rom(1024, 1), make_rom(1024, 2));
+        let directory = make_dir_fixture(&[
+            ("game.sfc", &source),
+            ("hack.bps", &make_bps(&source, &make_hack(&source))),
+            ("orphan.bps", &make_bps(&missing_source, &make_hack(&missing_source))),
+        ]);
+        let build = || {
+            let Err(err) = RomManagerBuilder::new(directory.path())
+                .require_all_sources(true)
+                .build()
+            else {
+                panic!("missing source ROM accepted");
+            };
+            err
+        };
+
+        // Patches without source ROMs are skipped by default
+        let rom_manager = RomManager::new(directory.path()).unwrap();
+        let target_paths: Vec<&PathBuf> = rom_manager.target_roms.keys().collect();
+        assert_eq!(target_paths, [Path::new("hack.sfc")]);
+
+        let err = build();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert_eq!(err.to_string(), "no source ROM was found for 1 patch");
+
+        // Patches not identifying their source ROM count as missing one when several are left to choose from
+        write_file(directory.path(), "more/a.sfc", &make_rom(1024, 3));
+        write_file(directory.path(), "more/b.sfc", &make_rom(1024, 4));
+        write_file(
+            directory.path(),
+            "more/hack.ips",
+            &make_ips(&source, &make_hack(&source)),
+        );
+        assert_eq!(build().to_string(), "no source ROM was found for 2 patches");
+    }
+
+    #[test]
+    fn applies_paired_patches_to_their_sources() {
+        let (source, other_source) = (make_rom(1024, 1), make_rom(1024, 2));
+        let (target, ips_target) = (make_hack(&source), make_hack(&source[..512]));
+        let directory = make_dir_fixture(&[("game.sfc", &source)]);
+        let patches = make_dir_fixture(&[
+            ("hack.bps", &make_bps(&source, &target)),
+            ("extra.ips", &make_ips(&source, &ips_target)),
+            ("wrong.bps", &make_bps(&other_source, &make_hack(&other_source))),
+        ]);
+        let source_path = directory.path().join("game.sfc");
+
+        let mut rom_manager_builder = RomManagerBuilder::new(directory.path());
+        for patch_name in ["hack.bps", "extra.ips", "wrong.bps"] {
+            rom_manager_builder = rom_manager_builder.pairing(&patches.path().join(patch_name), &source_path);
+        }
+        let rom_manager = rom_manager_builder.build().unwrap();
+
+        // Targets of patches outside of the base directory are in the root directory
+        let mut target_paths: Vec<&PathBuf> = rom_manager.target_roms.keys().collect();
+        target_paths.sort();
+        assert_eq!(target_paths, [Path::new("extra.sfc"), Path::new("hack.sfc")]);
+
+        let patched_rom = |target_path: &str| {
+            let patch = rom_manager.target_roms.get(Path::new(target_path)).unwrap();
+            patch.patched_rom().unwrap()
+        };
+        assert_eq!(patched_rom("hack.sfc"), target);
+        assert_eq!(patched_rom("extra.sfc"), ips_target);
+    }
+
+    #[test]
+    fn follows_symlinks_when_asked() {
+        let source = make_rom(1024, 1);
+        let target = make_hack(&source);
+        let extra_target = make_hack(&source[..512]);
+        let directory = make_dir_fixture(&[("game.sfc", &source)]);
+        let patches = make_dir_fixture(&[
+            ("hack.bps", &make_bps(&source, &target)),
+            ("linked/extra.bps", &make_bps(&source, &extra_target)),
+        ]);
+        std::os::unix::fs::symlink(patches.path().join("hack.bps"), directory.path().join("hack.bps")).unwrap();
+        // Links to directories are followed like links to files
+        std::os::unix::fs::symlink(patches.path().join("linked"), directory.path().join("linked")).unwrap();
+
+        let rom_manager = RomManager::new(directory.path()).unwrap();
+        let mut target_paths: Vec<&PathBuf> = rom_manager.target_roms.keys().collect();
+        target_paths.sort();
+        assert_eq!(target_paths, [Path::new("hack.sfc"), Path::new("linked/extra.sfc")]);
+        let patched_rom = |target_path: &str| {
+            let patch = rom_manager.target_roms.get(Path::new(target_path)).unwrap();
+            patch.patched_rom().unwrap()
+        };
+        assert_eq!(patched_rom("hack.sfc"), target);
+        assert_eq!(patched_rom("linked/extra.sfc"), extra_target);
+
+        let rom_manager = RomManagerBuilder::new(directory.path())
+            .follow_symlinks(false)
+            .build()
+            .unwrap();
+        assert!(rom_manager.target_roms.is_empty());
+    }
+
+    #[test]
+    fn sanitizes_names_from_metadata() {
+        let source = make_rom(1024, 1);
+        let patch = |index: u8, metadata: &str| {
+            let mut target = make_hack(&source);
+            target[1] ^= index;
+            make_bps_with_metadata(&source, &target, metadata.as_bytes())
+        };
+        let directory = make_dir_fixture(&[
+            ("game.sfc", &source),
+            ("a.bps", &patch(1, "\u{FEFF}Marked Hack")),
+            ("b.bps", &patch(2, "  Spaced  Hack \n")),
+            ("c.bps", &patch(3, "../../escape")),
+            ("d.bps", &patch(4, "..")),
+            ("e.bps", &patch(5, "back\\slash\tand tab")),
+        ]);
+
+        let rom_manager = RomManagerBuilder::new(directory.path())
+            .name_from_metadata(true)
+            .build()
+            .unwrap();
+        let mut target_paths: Vec<&PathBuf> = rom_manager.target_roms.keys().collect();
+        target_paths.sort();
+
+        // Names stay single path components inside the mount point, unusable names fall back to the patch name
+        assert_eq!(
+            target_paths,
+            [
+                Path::new(".._.._escape.sfc"),
+                Path::new("Marked Hack.sfc"),
+                Path::new("Spaced  Hack.sfc"),
+                Path::new("back_slash_and tab.sfc"),
+                Path::new("d.sfc")
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_the_headers_of_sources() {
+        let (snes, nes) = (make_rom(2048, 1), make_rom(1024, 2));
+        let (snes_target, nes_target) = (make_hack(&snes), make_hack(&nes));
+        let mut headered_snes = vec![0; 512];
+        headered_snes.extend_from_slice(&snes);
+        let mut headered_nes = b"NES\x1A\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00".to_vec();
+        headered_nes.extend_from_slice(&nes);
+
+        // Both patches are made against the headerless ROMs
+        let directory = make_dir_fixture(&[
+            ("game.smc", &headered_snes),
+            ("game.nes", &headered_nes),
+            ("snes.bps", &make_bps(&snes, &snes_target)),
+            ("nes.bps", &make_bps(&nes, &nes_target)),
+        ]);
+        let rom_manager = RomManager::new(directory.path()).unwrap();
+        let patched_rom = |target_path: &str| {
+            let patch = rom_manager.target_roms.get(Path::new(target_path)).unwrap();
+            patch.patched_rom().unwrap()
+        };
+
+        assert_eq!(patched_rom("snes.smc"), snes_target);
+        assert_eq!(patched_rom("nes.nes"), nes_target);
+    }
+
+    #[test]
+    fn skips_the_mount_point_inside_the_base_directory() {
+        let source = make_rom(1024, 1);
+        let (target, other_target) = (make_hack(&source), make_hack(&source[..512]));
+        let directory = make_dir_fixture(&[
+            ("game.sfc", &source),
+            ("hack.bps", &make_bps(&source, &target)),
+            ("mnt/other.bps", &make_bps(&source, &other_target)),
+        ]);
+        let mount_point = directory.path().join("mnt");
+        std::os::unix::fs::symlink(&mount_point, directory.path().join("link")).unwrap();
+
+        // Without a mount point, the subdirectory is scanned like any other one
+        let rom_manager = RomManager::new(directory.path()).unwrap();
+        assert_eq!(rom_manager.target_roms.len(), 2);
+
+        // Scanning the mounted filesystem would list its own targets, both directly and through the link
+        let rom_manager = RomManagerBuilder::new(directory.path())
+            .mount_point(&mount_point)
+            .build()
+            .unwrap();
+        let target_paths: Vec<&PathBuf> = rom_manager.target_roms.keys().collect();
+        assert_eq!(target_paths, [Path::new("hack.sfc")]);
+    }
+
+    #[test]
+    fn renames_targets_named_like_sources() {
+        let source = make_rom(1024, 1);
+        let (target, other_target) = (make_hack(&source), make_hack(&source[..512]));
+        let directory = make_dir_fixture(&[
+            ("game.sfc", &source),
+            ("game.bps", &make_bps(&source, &target)),
+            ("hacks/game.bps", &make_bps(&source, &other_target)),
+        ]);
+        let rom_manager = RomManager::new(directory.path()).unwrap();
+
+        // Only the target next to the source ROM would take its name
+        let mut target_paths: Vec<&PathBuf> = rom_manager.target_roms.keys().collect();
+        target_paths.sort();
+        assert_eq!(target_paths, [Path::new("game.bps.sfc"), Path::new("hacks/game.sfc")]);
+
+        let patch = rom_manager.target_roms.get(Path::new("game.bps.sfc")).unwrap();
+        assert_eq!(patch.patched_rom().unwrap(), target);
+    }
+
+    #[test]
+    fn hashes_the_same_in_parallel_and_serially() {
+        let mut files: Vec<(String, Vec<u8>)> = (0..32)
+            .map(|index| (format!("roms/{:02}.sfc", index), make_rom(16 * 1024, index)))
+            .collect();
+        // Copies sharing a checksum keep the order of their paths
+        files.push(("copies/00.sfc".to_owned(), files[0].1.clone()));
+        let fixture_files: Vec<(&str, &[u8])> = files
+            .iter()
+            .map(|(path, data)| (path.as_str(), data.as_slice()))
+            .collect();
+        let directory = make_dir_fixture(&fixture_files);
+
+        let parallel = RomManager::new(directory.path()).unwrap();
+        let serial = rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .build()
+            .unwrap()
+            .install(|| RomManager::new(directory.path()).unwrap());
+
+        assert_eq!(parallel.source_roms.len(), 32);
+        assert_eq!(parallel.source_roms, serial.source_roms);
+        assert_eq!(
+            parallel.source_roms[&crc32::checksum_ieee(&files[0].1)],
+            [
+                directory.path().join("copies/00.sfc"),
+                directory.path().join("roms/00.sfc")
+            ]
+        );
+    }
+
+    #[test]
+    fn finds_sources_in_the_source_directory() {
+        let (a, b) = (make_rom(1024, 1), make_rom(2048, 2));
+        let (a_target, b_target) = (make_hack(&a), make_hack(&b));
+        let sources = make_dir_fixture(&[("a.sfc", &a), ("nested/b.gba", &b)]);
+        // Source ROMs in the patch directory are ignored with a source directory
+        let directory = make_dir_fixture(&[
+            ("a.bps", &make_bps(&a, &a_target)),
+            ("hacks/b.bps", &make_bps(&b, &b_target)),
+            ("local.sfc", &make_rom(512, 3)),
+            ("local.ips", &make_ips(&make_rom(512, 3), &make_rom(512, 4))),
+        ]);
+
+        let rom_manager = RomManagerBuilder::new(directory.path())
+            .source_directory(sources.path())
+            .build()
+            .unwrap();
+        let mut target_paths: Vec<&PathBuf> = rom_manager.target_roms.keys().collect();
+        target_paths.sort();
+        assert_eq!(target_paths, [Path::new("a.sfc"), Path::new("hacks/b.gba")]);
+
+        let patch = rom_manager.target_roms.get(Path::new("hacks/b.gba")).unwrap();
+        assert_eq!(patch.source_path(), Some(sources.path().join("nested/b.gba").as_path()));
+        assert_eq!(patch.patched_rom().unwrap(), b_target);
+    }
+
+    #[test]
+    fn falls_back_to_sources_in_parent_directories() {
+        let (game, other) = (make_rom(1024, 1), make_rom(1024, 2));
+        let directory = make_dir_fixture(&[
+            ("game.sfc", &game),
+            ("hacks/hack.ips", &make_ips(&game, &make_hack(&game))),
+            ("hacks/more/extra.ips", &make_ips(&game, &make_hack(&game))),
+            ("other/other.sfc", &other),
+            ("other/hack.ips", &make_ips(&other, &make_hack(&other))),
+        ]);
+
+        let rom_manager = RomManager::new(directory.path()).unwrap();
+        let source_path = |target_path: &str| {
+            let patch = rom_manager.target_roms.get(Path::new(target_path)).unwrap();
+            patch
+                .source_path()
+                .unwrap()
+                .strip_prefix(directory.path())
+                .unwrap()
+                .to_owned()
+        };
+
+        assert_eq!(source_path("hacks/hack.sfc"), Path::new("game.sfc"));
+        assert_eq!(source_path("hacks/more/extra.sfc"), Path::new("game.sfc"));
+        // Source ROMs closer to the patch come first
+        assert_eq!(source_path("other/hack.sfc"), Path::new("other/other.sfc"));
+        assert_eq!(
+            rom_manager
+                .target_roms
+                .get(Path::new("hacks/more/extra.sfc"))
+                .unwrap()
+                .patched_rom()
+                .unwrap(),
+            make_hack(&game)
+        );
+    }
+}
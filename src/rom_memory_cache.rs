@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use log::debug;
+
+/// Default limit of the total size of the ROMs kept in memory.
+pub const DEFAULT_MAX_SIZE: u64 = 256 * 1024 * 1024;
+
+/// Recently patched ROMs kept in memory, shared by every open file of the same ROM.
+///
+/// Once the cached ROMs exceed the size limit, the least recently used ones are evicted.
+/// ROMs larger than the limit are not cached at all.
+pub struct RomMemoryCache {
+    state: Mutex<CacheState>,
+}
+
+struct CacheState {
+    max_size: u64,
+    entries: HashMap<PathBuf, CacheEntry>,
+    size: u64,
+    /// Incremented on every access, orders the entries by their last use.
+    clock: u64,
+}
+
+struct CacheEntry {
+    generation: u64,
+    data: Arc<Vec<u8>>,
+    last_used: u64,
+}
+
+impl RomMemoryCache {
+    pub fn new(max_size: u64) -> Self {
+        Self {
+            state: Mutex::new(CacheState {
+                max_size,
+                entries: HashMap::new(),
+                size: 0,
+                clock: 0,
+            }),
+        }
+    }
+
+    /// Returns the cached ROM, if it was cached since the last refresh of the ROM manager.
+    pub fn load(&self, rom_path: &Path, generation: u64) -> Option<Arc<Vec<u8>>> {
+        let mut state = self.state.lock().unwrap();
+        state.clock += 1;
+
+        let clock = state.clock;
+        let entry = state.entries.get_mut(rom_path).filter(|e| e.generation == generation)?;
+        entry.last_used = clock;
+        Some(entry.data.clone())
+    }
+
+    pub fn store(&self, rom_path: &Path, generation: u64, data: Arc<Vec<u8>>) {
+        let data_size = data.len() as u64;
+
+        let mut state = self.state.lock().unwrap();
+        if data_size > state.max_size {
+            return;
+        }
+        state.clock += 1;
+
+        if let Some(entry) = state.entries.remove(rom_path) {
+            state.size -= entry.data.len() as u64;
+        }
+
+        state.evict(data_size);
+
+        let last_used = state.clock;
+        state.size += data_size;
+        state.entries.insert(
+            rom_path.to_owned(),
+            CacheEntry {
+                generation,
+                data,
+                last_used,
+            },
+        );
+    }
+
+    /// Changes the size limit, evicting the least recently used ROMs exceeding the new limit.
+    pub fn set_max_size(&self, max_size: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.max_size = max_size;
+        state.evict(0);
+    }
+
+    /// Total size of the cached ROMs.
+    pub fn size(&self) -> u64 {
+        self.state.lock().unwrap().size
+    }
+
+    pub fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.entries.clear();
+        state.size = 0;
+    }
+}
+
+impl CacheState {
+    /// Evicts the least recently used ROMs, until there is room for `data_size` more bytes.
+    fn evict(&mut self, data_size: u64) {
+        while self.size + data_size > self.max_size {
+            let evicted_path = match self.entries.iter().min_by_key(|(_, entry)| entry.last_used) {
+                Some((evicted_path, _)) => evicted_path.clone(),
+                None => break,
+            };
+
+            debug!("Evicting {:?} from the memory cache", evicted_path);
+            if let Some(entry) = self.entries.remove(&evicted_path) {
+                self.size -= entry.data.len() as u64;
+            }
+        }
+    }
+}
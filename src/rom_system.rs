@@ -0,0 +1,153 @@
+use crate::rom_header::RomHeader;
+
+const GB_LOGO_OFFSET: usize = 0x104;
+const GB_LOGO_PREFIX: [u8; 4] = [0xCE, 0xED, 0x66, 0x66];
+const GB_CGB_FLAG_OFFSET: usize = 0x143;
+
+const GBA_LOGO_OFFSET: usize = 0x04;
+const GBA_LOGO_PREFIX: [u8; 4] = [0x24, 0xFF, 0xAE, 0x51];
+const GBA_FIXED_VALUE_OFFSET: usize = 0xB2;
+const GBA_FIXED_VALUE: u8 = 0x96;
+
+const N64_BIG_ENDIAN_MARKER: [u8; 4] = [0x80, 0x37, 0x12, 0x40];
+const N64_BYTE_SWAPPED_MARKER: [u8; 4] = [0x37, 0x80, 0x40, 0x12];
+const N64_LITTLE_ENDIAN_MARKER: [u8; 4] = [0x40, 0x12, 0x37, 0x80];
+
+/// Byte orders of N64 ROMs by their file extensions: big-endian, byte-swapped and little-endian.
+pub const N64_BYTE_ORDERS: &[&str] = &["z64", "v64", "n64"];
+
+/// Converts an N64 ROM between byte orders, given by their file extensions.
+pub fn convert_n64_byte_order(data: &mut [u8], from: &str, to: &str) {
+    // Both swaps undo themselves, so ROMs are converted by way of the big-endian byte order
+    for byte_order in &[from, to] {
+        match *byte_order {
+            "v64" => data.chunks_exact_mut(2).for_each(|word| word.swap(0, 1)),
+            "n64" => data.chunks_exact_mut(4).for_each(|word| word.reverse()),
+            _ => {}
+        }
+    }
+}
+
+/// The system a ROM was made for, recognized by the signatures in the ROM data.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RomSystem {
+    Nes,
+    Snes,
+    GameBoy,
+    GameBoyColor,
+    GameBoyAdvance,
+    Nintendo64 { byte_order: &'static str },
+}
+
+impl RomSystem {
+    pub fn detect(data: &[u8]) -> Option<Self> {
+        if data.get(GB_LOGO_OFFSET..GB_LOGO_OFFSET + 4) == Some(&GB_LOGO_PREFIX) {
+            return match data.get(GB_CGB_FLAG_OFFSET) {
+                Some(0x80) | Some(0xC0) => Some(RomSystem::GameBoyColor),
+                _ => Some(RomSystem::GameBoy),
+            };
+        }
+
+        if data.get(GBA_LOGO_OFFSET..GBA_LOGO_OFFSET + 4) == Some(&GBA_LOGO_PREFIX)
+            && data.get(GBA_FIXED_VALUE_OFFSET) == Some(&GBA_FIXED_VALUE)
+        {
+            return Some(RomSystem::GameBoyAdvance);
+        }
+
+        match data.get(0..4) {
+            Some(marker) if marker == N64_BIG_ENDIAN_MARKER => {
+                return Some(RomSystem::Nintendo64 { byte_order: "z64" });
+            }
+            Some(marker) if marker == N64_BYTE_SWAPPED_MARKER => {
+                return Some(RomSystem::Nintendo64 { byte_order: "v64" });
+            }
+            Some(marker) if marker == N64_LITTLE_ENDIAN_MARKER => {
+                return Some(RomSystem::Nintendo64 { byte_order: "n64" });
+            }
+            _ => {}
+        }
+
+        // SNES ROMs have no magic number, only a header validated by its checksum complement
+        match RomHeader::parse(data)? {
+            RomHeader::Ines { .. } => Some(RomSystem::Nes),
+            RomHeader::Snes { .. } => Some(RomSystem::Snes),
+        }
+    }
+
+    /// The customary file extension of ROMs of this system.
+    pub fn extension(self) -> &'static str {
+        match self {
+            RomSystem::Nes => "nes",
+            RomSystem::Snes => "sfc",
+            RomSystem::GameBoy => "gb",
+            RomSystem::GameBoyColor => "gbc",
+            RomSystem::GameBoyAdvance => "gba",
+            RomSystem::Nintendo64 { byte_order } => byte_order,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::make_rom;
+
+    /// A LoROM game, its header validated by a checksum and its complement.
+    fn snes_rom() -> Vec<u8> {
+        let mut data = make_rom(0x8000, 1);
+        data[0x7FDC..0x7FE0].copy_from_slice(&[0xCB, 0xED, 0x34, 0x12]);
+        data
+    }
+
+    fn nes_rom() -> Vec<u8> {
+        let mut data = make_rom(16 + 16 * 1024, 2);
+        data[..16].copy_from_slice(b"NES\x1A\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00");
+        data
+    }
+
+    fn gb_rom(cgb_flag: u8) -> Vec<u8> {
+        let mut data = make_rom(0x8000, 3);
+        data[GB_LOGO_OFFSET..GB_LOGO_OFFSET + 4].copy_from_slice(&GB_LOGO_PREFIX);
+        data[GB_CGB_FLAG_OFFSET] = cgb_flag;
+        data
+    }
+
+    #[test]
+    fn detects_systems_by_their_headers() {
+        let detected_extension = |data: &[u8]| RomSystem::detect(data).map(RomSystem::extension);
+
+        assert_eq!(detected_extension(&snes_rom()), Some("sfc"));
+        assert_eq!(detected_extension(&nes_rom()), Some("nes"));
+        assert_eq!(detected_extension(&gb_rom(0x00)), Some("gb"));
+        assert_eq!(detected_extension(&gb_rom(0x80)), Some("gbc"));
+
+        // Copier headers are skipped, while other ROMs have no recognized signatures
+        let mut headered_snes_rom = vec![0; 512];
+        headered_snes_rom.extend(snes_rom());
+        assert_eq!(detected_extension(&headered_snes_rom), Some("sfc"));
+        assert_eq!(detected_extension(&make_rom(0x8000, 4)), None);
+        assert_eq!(detected_extension(&[]), None);
+    }
+
+    #[test]
+    fn converts_n64_byte_orders() {
+        let big_endian = [0x80, 0x37, 0x12, 0x40, 0x01, 0x02, 0x03, 0x04];
+        let expected = [
+            ("z64", big_endian),
+            ("v64", [0x37, 0x80, 0x40, 0x12, 0x02, 0x01, 0x04, 0x03]),
+            ("n64", [0x40, 0x12, 0x37, 0x80, 0x04, 0x03, 0x02, 0x01]),
+        ];
+
+        for (from, from_data) in expected {
+            assert_eq!(
+                RomSystem::detect(&from_data),
+                Some(RomSystem::Nintendo64 { byte_order: from })
+            );
+            for (to, to_data) in expected {
+                let mut data = from_data;
+                convert_n64_byte_order(&mut data, from, to);
+                assert_eq!(data, to_data, "{} to {}", from, to);
+            }
+        }
+    }
+}
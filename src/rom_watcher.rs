@@ -1,55 +1,133 @@
-use std::io;
+use std::sync::mpsc::{self, RecvTimeoutError};
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
-use inotify::{EventMask, Inotify, WatchMask};
 use log::error;
+use notify::event::{AccessKind, AccessMode, ModifyKind};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
 
 use crate::rom_manager::RomManager;
 
+pub const DEFAULT_MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+pub const DEFAULT_DEBOUNCE_WINDOW: Duration = Duration::from_millis(500);
+
+/// Longest delay of a refresh by a steady trickle of changes.
+const MAX_DEBOUNCE_DELAY: Duration = Duration::from_secs(10);
+
+/// Whether an event changes the ROM list. Files being written are picked up once they are closed,
+/// rather than on every write of a large copy.
+fn is_change(event: &Event) -> bool {
+    match event.kind {
+        EventKind::Remove(_) => true,
+        EventKind::Modify(ModifyKind::Name(_)) => true,
+        EventKind::Access(AccessKind::Close(AccessMode::Write)) => true,
+        // Backends without close events, like FSEvents and kqueue, only report files being created and written
+        EventKind::Create(_) | EventKind::Modify(ModifyKind::Data(_)) | EventKind::Modify(ModifyKind::Any) => {
+            !cfg!(target_os = "linux")
+        }
+        _ => false,
+    }
+}
+
 pub struct RomWatcher {
     #[allow(dead_code)]
-    inotify: Arc<Mutex<Inotify>>,
+    watcher: RecommendedWatcher,
 }
 
 impl RomWatcher {
-    pub fn new(rom_manager: Arc<Mutex<RomManager>>) -> io::Result<Self> {
-        let inotify = Arc::new(Mutex::new(Inotify::init()?));
+    /// Refreshes the ROM list on changes in the base directory, once no more changes arrive for `debounce_window`,
+    /// but no more often than `min_refresh_interval`. Changes arriving in the meantime are coalesced
+    /// into a single refresh.
+    pub fn new(
+        rom_manager: Arc<Mutex<RomManager>>,
+        min_refresh_interval: Duration,
+        debounce_window: Duration,
+    ) -> notify::Result<Self> {
+        let (sender, receiver) = mpsc::channel();
+        let mut watcher = notify::recommended_watcher(sender)?;
 
         {
             let rom_manager = rom_manager.lock().unwrap();
             let base_directory = rom_manager.base_directory.as_path();
-            inotify
-                .lock()
-                .unwrap()
-                .add_watch(base_directory, WatchMask::ALL_EVENTS)?;
+            watcher.watch(base_directory, RecursiveMode::Recursive)?;
+            if let Some(source_directory) = &rom_manager.source_directory {
+                watcher.watch(source_directory, RecursiveMode::Recursive)?;
+            }
         }
 
-        {
-            let inotify = inotify.clone();
-            thread::spawn(move || {
-                let mut buffer = [0; 4096];
-                loop {
-                    let events = inotify.lock().unwrap().read_events_blocking(&mut buffer).unwrap();
-
-                    let mut changed = false;
-
-                    for event in events {
-                        changed |= event.mask.contains(EventMask::MOVED_FROM);
-                        changed |= event.mask.contains(EventMask::MOVED_TO);
-                        changed |= event.mask.contains(EventMask::DELETE);
-                        changed |= event.mask.contains(EventMask::CLOSE_WRITE);
+        thread::spawn(move || {
+            let mut last_refresh: Option<Instant> = None;
+
+            for result in receiver.iter() {
+                let changed = match result {
+                    Ok(event) => is_change(&event),
+                    Err(err) => {
+                        error!("Failed to watch ROMs: {}", err);
+                        false
+                    }
+                };
+
+                if changed {
+                    // Bursts of changes, like a batch of copied patches, are waited out
+                    let first_change = Instant::now();
+                    while first_change.elapsed() < MAX_DEBOUNCE_DELAY {
+                        match receiver.recv_timeout(debounce_window) {
+                            Ok(_) => continue,
+                            Err(RecvTimeoutError::Timeout) => break,
+                            Err(RecvTimeoutError::Disconnected) => return,
+                        }
                     }
 
-                    if changed {
-                        if let Err(err) = rom_manager.lock().unwrap().refresh() {
-                            error!("Failed to refresh ROMs: {}", err);
+                    if let Some(elapsed) = last_refresh.map(|t| t.elapsed()) {
+                        if elapsed < min_refresh_interval {
+                            thread::sleep(min_refresh_interval - elapsed);
                         }
                     }
+
+                    // Events queued up while waiting are covered by the upcoming refresh
+                    while receiver.try_recv().is_ok() {}
+
+                    if let Err(err) = rom_manager.lock().unwrap().refresh() {
+                        error!("Failed to refresh ROMs: {}", err);
+                    }
+
+                    last_refresh = Some(Instant::now());
                 }
-            });
+            }
+        });
+
+        Ok(Self { watcher })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{make_dir_fixture, write_file};
+
+    #[test]
+    fn limits_the_refresh_rate_during_churn() {
+        let directory = make_dir_fixture(&[]);
+        let rom_manager = Arc::new(Mutex::new(RomManager::new(directory.path()).unwrap()));
+        let generation = || rom_manager.lock().unwrap().generation();
+        let started_generation = generation();
+
+        let min_refresh_interval = Duration::from_millis(400);
+        let _rom_watcher =
+            RomWatcher::new(rom_manager.clone(), min_refresh_interval, Duration::from_millis(10)).unwrap();
+
+        // A file rewritten every 20 ms for 1.2 s, refreshes may start at 0, 0.4, 0.8 and 1.2 s at most
+        let started = Instant::now();
+        for index in 0.. {
+            if started.elapsed() >= Duration::from_millis(1200) {
+                break;
+            }
+            write_file(directory.path(), "game.sfc", &[index as u8]);
+            thread::sleep(Duration::from_millis(20));
         }
 
-        Ok(Self { inotify })
+        let refreshes = generation() - started_generation;
+        assert!((1..=4).contains(&refreshes), "{} refreshes", refreshes);
     }
 }
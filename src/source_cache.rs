@@ -0,0 +1,163 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+
+use log::debug;
+use memmap::Mmap;
+
+/// Source ROMs read into memory once and shared by every patch applied to the same source ROM.
+///
+/// The cache only keeps weak references, sources are freed as soon as no patch is being applied to them.
+/// They don't pile up in memory when the ROM manager is never refreshed.
+#[derive(Default)]
+pub struct SourceCache {
+    sources: Mutex<HashMap<PathBuf, Weak<SourceData>>>,
+    map_sources: bool,
+}
+
+impl SourceCache {
+    /// Memory mapping the source ROMs saves reading them whole, but the ROMs must not be truncated
+    /// while mapped, as reading the missing part of a mapping kills the process with `SIGBUS`.
+    pub fn new(map_sources: bool) -> Self {
+        Self {
+            sources: Mutex::default(),
+            map_sources,
+        }
+    }
+
+    pub fn get(&self, source_path: &Path) -> io::Result<Arc<SourceData>> {
+        let mut sources = self.sources.lock().unwrap();
+
+        if let Some(source) = sources.get(source_path).and_then(Weak::upgrade) {
+            return Ok(source);
+        }
+
+        let source_file = File::open(source_path)?;
+        let source = if source_file.metadata()?.len() == 0 {
+            // Empty files can't be mapped
+            Arc::new(SourceData::Empty)
+        } else if self.map_sources {
+            debug!("Mapping {:?}", source_path);
+            // Safety: the mapping is only valid while nothing truncates the file, see `SourceCache::new`
+            Arc::new(SourceData::Mapped(unsafe { Mmap::map(&source_file)? }))
+        } else {
+            debug!("Loading {:?}", source_path);
+            let mut source_data = Vec::new();
+            (&source_file).read_to_end(&mut source_data)?;
+            Arc::new(SourceData::Loaded(source_data))
+        };
+
+        sources.retain(|_, source| source.strong_count() > 0);
+        sources.insert(source_path.to_owned(), Arc::downgrade(&source));
+        Ok(source)
+    }
+
+    /// Reads the source ROM through the cache, or straight from the disk without one.
+    pub fn load(source_cache: Option<&SourceCache>, source_path: &Path) -> io::Result<Arc<SourceData>> {
+        match source_cache {
+            Some(source_cache) => source_cache.get(source_path),
+            None => Ok(Arc::new(SourceData::Loaded(fs::read(source_path)?))),
+        }
+    }
+
+    pub fn clear(&self) {
+        self.sources.lock().unwrap().clear();
+    }
+}
+
+/// Lists the paths of the sources in use.
+impl fmt::Debug for SourceCache {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        let sources = self.sources.lock().unwrap();
+        formatter
+            .debug_list()
+            .entries(
+                sources
+                    .iter()
+                    .filter(|(_, source)| source.strong_count() > 0)
+                    .map(|(path, _)| path),
+            )
+            .finish()
+    }
+}
+
+pub enum SourceData {
+    Mapped(Mmap),
+    Loaded(Vec<u8>),
+    Empty,
+}
+
+impl Deref for SourceData {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            SourceData::Mapped(mmap) => mmap,
+            SourceData::Loaded(data) => data,
+            SourceData::Empty => &[],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{make_dir_fixture, make_rom};
+
+    #[test]
+    fn shares_one_copy_of_a_source() {
+        let source = make_rom(1024, 1);
+        let directory = make_dir_fixture(&[("game.sfc", &source), ("empty.sfc", &[])]);
+        let source_path = directory.path().join("game.sfc");
+
+        for map_sources in [false, true] {
+            let source_cache = SourceCache::new(map_sources);
+            let first = source_cache.get(&source_path).unwrap();
+            let second = SourceCache::load(Some(&source_cache), &source_path).unwrap();
+            assert!(Arc::ptr_eq(&first, &second));
+            assert_eq!(first[..], source[..]);
+            assert_eq!(matches!(*first, SourceData::Mapped(_)), map_sources);
+
+            assert!(source_cache
+                .get(&directory.path().join("empty.sfc"))
+                .unwrap()
+                .is_empty());
+
+            source_cache.clear();
+            assert!(!Arc::ptr_eq(&first, &source_cache.get(&source_path).unwrap()));
+        }
+    }
+
+    #[test]
+    fn loaded_sources_survive_truncation() {
+        let source = make_rom(1024, 1);
+        let directory = make_dir_fixture(&[("game.sfc", &source)]);
+        let source_path = directory.path().join("game.sfc");
+
+        let source_cache = SourceCache::new(false);
+        let loaded = source_cache.get(&source_path).unwrap();
+        fs::write(&source_path, b"").unwrap();
+        assert_eq!(loaded[..], source[..]);
+    }
+
+    #[test]
+    fn frees_sources_no_longer_used() {
+        let directory = make_dir_fixture(&[("game.sfc", &make_rom(1024, 1))]);
+        let source_path = directory.path().join("game.sfc");
+        let source_cache = SourceCache::new(false);
+
+        let source = source_cache.get(&source_path).unwrap();
+        assert_eq!(format!("{:?}", source_cache), format!("[{:?}]", source_path));
+        drop(source);
+        assert_eq!(format!("{:?}", source_cache), "[]");
+
+        // Sources are read again once freed
+        let changed_source = make_rom(512, 2);
+        fs::write(&source_path, &changed_source).unwrap();
+        assert_eq!(source_cache.get(&source_path).unwrap()[..], changed_source[..]);
+    }
+}
@@ -0,0 +1,222 @@
+//! Builders of the ROMs, patches and directories the tests run on, so the tests don't depend on
+//! ROMs and patches that can't be redistributed.
+
+use std::cell::RefCell;
+use std::fs;
+use std::path::Path;
+use std::sync::Once;
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use crc::crc32;
+use log::{LevelFilter, Log, Metadata, Record};
+use tempfile::TempDir;
+
+use crate::patch::bps::BpsPatch;
+use crate::patch::ips::IpsPatch;
+use crate::patch::ups::UpsPatch;
+use crate::utils::{ReadExt, WriteExt};
+
+/// A ROM of pseudo-random bytes, the same for the same seed.
+pub fn make_rom(size: usize, seed: u32) -> Vec<u8> {
+    // Xorshift, zero would be a fixed point
+    let mut state = seed.wrapping_mul(0x9E37_79B9) | 1;
+    (0..size)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        })
+        .collect()
+}
+
+/// A copy of `source` with a few bytes changed across the whole ROM, like a small hack.
+pub fn make_hack(source: &[u8]) -> Vec<u8> {
+    let mut target = source.to_vec();
+    let step = (target.len() / 8).max(1);
+    for offset in (0..target.len()).step_by(step) {
+        target[offset] = !target[offset];
+    }
+    target
+}
+
+pub fn make_bps(source: &[u8], target: &[u8]) -> Vec<u8> {
+    BpsPatch::create(source, target).unwrap()
+}
+
+/// A patch carrying `metadata`, like the XML metadata of beat.
+pub fn make_bps_with_metadata(source: &[u8], target: &[u8], metadata: &[u8]) -> Vec<u8> {
+    let patch = make_bps(source, target);
+
+    // The metadata size follows the source and target sizes, patches are created without metadata
+    let mut header = &patch[4..];
+    header.read_vlq().unwrap();
+    header.read_vlq().unwrap();
+    let metadata_offset = patch.len() - header.len();
+
+    let mut result = patch[..metadata_offset].to_vec();
+    result.write_vlq(metadata.len() as u64).unwrap();
+    result.extend_from_slice(metadata);
+    result.extend_from_slice(&patch[(metadata_offset + 1)..(patch.len() - 4)]);
+    let patch_checksum = crc32::checksum_ieee(&result);
+    result.write_u32::<LittleEndian>(patch_checksum).unwrap();
+    result
+}
+
+/// A patch embedding the whole target ROM, applied without a source ROM.
+pub fn make_source_less_bps(target: &[u8]) -> Vec<u8> {
+    make_bps(&[], target)
+}
+
+pub fn make_ips(source: &[u8], target: &[u8]) -> Vec<u8> {
+    IpsPatch::create(source, target).unwrap()
+}
+
+pub fn make_ups(source: &[u8], target: &[u8]) -> Vec<u8> {
+    UpsPatch::create(source, target).unwrap()
+}
+
+/// Writes files into a new temporary directory, along with the subdirectories in their paths.
+/// The directory is removed once dropped.
+pub fn make_dir_fixture(files: &[(&str, &[u8])]) -> TempDir {
+    let directory = tempfile::tempdir().unwrap();
+    for (path, data) in files {
+        write_file(directory.path(), path, data);
+    }
+    directory
+}
+
+/// Writes a file into a fixture, creating the subdirectories in its path.
+pub fn write_file(directory: &Path, path: &str, data: &[u8]) {
+    let path = directory.join(path);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).unwrap();
+    }
+    fs::write(path, data).unwrap();
+}
+
+thread_local! {
+    static CAPTURED_LOGS: RefCell<Option<Vec<String>>> = const { RefCell::new(None) };
+}
+
+/// Keeps the messages logged by threads capturing them, tests run on threads of their own.
+struct CapturingLogger;
+
+impl Log for CapturingLogger {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        CAPTURED_LOGS.with(|logs| {
+            if let Some(logs) = logs.borrow_mut().as_mut() {
+                logs.push(record.args().to_string());
+            }
+        });
+    }
+
+    fn flush(&self) {}
+}
+
+/// Runs `f`, returning its result along with the messages it logged on the current thread.
+pub fn capture_logs<T>(f: impl FnOnce() -> T) -> (T, Vec<String>) {
+    static LOGGER_INIT: Once = Once::new();
+    LOGGER_INIT.call_once(|| {
+        log::set_logger(&CapturingLogger).unwrap();
+        log::set_max_level(LevelFilter::Trace);
+    });
+
+    CAPTURED_LOGS.with(|logs| *logs.borrow_mut() = Some(Vec::new()));
+    let result = f();
+    let logs = CAPTURED_LOGS.with(|logs| logs.borrow_mut().take()).unwrap_or_default();
+    (result, logs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::patch::Patch;
+
+    #[test]
+    fn roms_depend_on_the_seed() {
+        assert_eq!(make_rom(1024, 1), make_rom(1024, 1));
+        assert_ne!(make_rom(1024, 1), make_rom(1024, 2));
+        assert_eq!(make_rom(1024, 1)[..16], make_rom(16, 1)[..]);
+    }
+
+    #[test]
+    fn hacks_differ_from_the_source() {
+        let source = make_rom(1000, 1);
+        let target = make_hack(&source);
+
+        assert_eq!(target.len(), source.len());
+        assert_eq!(source.iter().zip(&target).filter(|(a, b)| a != b).count(), 8);
+        assert_eq!(make_hack(&[0]), vec![0xFF]);
+        assert!(make_hack(&[]).is_empty());
+    }
+
+    #[test]
+    fn builds_patches_applying_to_the_source() {
+        let source = make_rom(4096, 1);
+        let target = make_hack(&source);
+        let directory = make_dir_fixture(&[
+            ("game.sfc", &source),
+            ("hack.bps", &make_bps(&source, &target)),
+            ("hack.ips", &make_ips(&source, &target)),
+            ("embedded.bps", &make_source_less_bps(&target)),
+            (
+                "metadata.bps",
+                &make_bps_with_metadata(&source, &target, b"<name>Hack</name>"),
+            ),
+        ]);
+        let path = |name: &str| directory.path().join(name);
+
+        let mut bps_patch = BpsPatch::new(&path("hack.bps")).unwrap();
+        bps_patch.set_source_path(&path("game.sfc"));
+        assert_eq!(bps_patch.patched_rom().unwrap(), target);
+
+        let ips_patch = IpsPatch::new(&path("hack.ips"), &path("game.sfc"), false).unwrap();
+        assert_eq!(ips_patch.patched_rom().unwrap(), target);
+
+        let source_less_patch = BpsPatch::new(&path("embedded.bps")).unwrap();
+        assert!(source_less_patch.is_source_less());
+        assert_eq!(source_less_patch.patched_rom().unwrap(), target);
+
+        let mut metadata_patch = BpsPatch::new(&path("metadata.bps")).unwrap();
+        metadata_patch.set_source_path(&path("game.sfc"));
+        assert_eq!(metadata_patch.metadata(), Some(&b"<name>Hack</name>"[..]));
+        assert_eq!(metadata_patch.patched_rom().unwrap(), target);
+
+        let ups_patch = UpsPatch::parse(make_ups(&source, &target)).unwrap();
+        assert_eq!(ups_patch.apply(&source).unwrap(), target);
+    }
+
+    #[test]
+    fn captures_the_logs_of_the_current_thread() {
+        let (result, logs) = capture_logs(|| {
+            log::warn!("first {}", 1);
+            std::thread::spawn(|| log::warn!("elsewhere")).join().unwrap();
+            log::debug!("second");
+            42
+        });
+        assert_eq!(result, 42);
+        assert_eq!(logs, ["first 1", "second"]);
+
+        log::warn!("uncaptured");
+        assert!(capture_logs(|| ()).1.is_empty());
+    }
+
+    #[test]
+    fn builds_nested_directories() {
+        let directory = make_dir_fixture(&[("a.sfc", b"a"), ("game/b/c.sfc", b"c")]);
+        assert_eq!(fs::read(directory.path().join("a.sfc")).unwrap(), b"a");
+        assert_eq!(fs::read(directory.path().join("game/b/c.sfc")).unwrap(), b"c");
+
+        write_file(directory.path(), "game/d.sfc", b"d");
+        assert_eq!(fs::read(directory.path().join("game/d.sfc")).unwrap(), b"d");
+
+        let path = directory.path().to_owned();
+        drop(directory);
+        assert!(!path.exists());
+    }
+}
@@ -1,19 +1,23 @@
-use std::io::{self, Read};
+use std::io::{self, Read, Write};
 
-use byteorder::ReadBytesExt;
+use byteorder::{ReadBytesExt, WriteBytesExt};
 
 pub trait ReadExt: Read {
     fn read_vlq(&mut self) -> io::Result<u64> {
-        let mut data = 0;
-        let mut shift = 1;
+        let overflow = || io::Error::new(io::ErrorKind::InvalidData, "variable-length value overflows 64 bits");
+        let mut data: u64 = 0;
+        let mut shift: u64 = 1;
         loop {
             let x = self.read_u8()?;
-            data += ((x as u64) & 0x7F) * shift;
+            data = ((x as u64) & 0x7F)
+                .checked_mul(shift)
+                .and_then(|value| data.checked_add(value))
+                .ok_or_else(overflow)?;
             if x & 0x80 != 0 {
                 break;
             }
-            shift <<= 7;
-            data += shift;
+            shift = shift.checked_mul(0x80).ok_or_else(overflow)?;
+            data = data.checked_add(shift).ok_or_else(overflow)?;
         }
         Ok(data)
     }
@@ -31,3 +35,57 @@ pub trait ReadExt: Read {
 }
 
 impl<T> ReadExt for T where T: Read {}
+
+pub trait WriteExt: Write {
+    fn write_vlq(&mut self, mut data: u64) -> io::Result<()> {
+        loop {
+            let x = (data & 0x7F) as u8;
+            data >>= 7;
+            if data == 0 {
+                return self.write_u8(x | 0x80);
+            }
+            self.write_u8(x)?;
+            data -= 1;
+        }
+    }
+
+    fn write_signed_vlq(&mut self, value: i64) -> io::Result<()> {
+        self.write_vlq((value.unsigned_abs() << 1) | (value < 0) as u64)
+    }
+}
+
+impl<T> WriteExt for T where T: Write {}
+
+/// Quotes and escapes a string for embedding into JSON.
+pub fn json_string(value: &str) -> String {
+    let mut result = String::with_capacity(value.len() + 2);
+    result.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\t' => result.push_str("\\t"),
+            c if (c as u32) < 0x20 => result.push_str(&format!("\\u{:04x}", c as u32)),
+            c => result.push(c),
+        }
+    }
+    result.push('"');
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_overflowing_vlq_values() {
+        let mut data = Vec::new();
+        data.write_vlq(u64::MAX).unwrap();
+        assert_eq!(data.as_slice().read_vlq().unwrap(), u64::MAX);
+
+        let mut data: &[u8] = &[0x00; 16];
+        assert_eq!(data.read_vlq().unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}